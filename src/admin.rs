@@ -0,0 +1,643 @@
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+
+use async_std::io;
+use async_std::net::TcpStream;
+use async_std::prelude::*;
+use async_std::task;
+
+use super::events::{self, CloseReason, PortEvent};
+use super::listener;
+
+// Bumped whenever a field changes meaning or is removed, adding fields
+// keeps the version.
+pub const ADMIN_PROTOCOL_VERSION: u64 = 1;
+pub const CMD_STATUS: u8 = 1;
+pub const CMD_DRAIN: u8 = 2;
+pub const CMD_CONFIG_DIFF: u8 = 3;
+pub const CMD_CONFIG_APPLY: u8 = 4;
+pub const CMD_SHUTDOWN: u8 = 5;
+
+const ADMIN_TIMEOUT_MS: u64 = 5000;
+const MAX_DOCUMENT_SIZE: usize = 16 * 1024 * 1024;
+const MAX_RECENT_ERRORS: usize = 20;
+const MAX_HTTP_REQUEST_SIZE: usize = 8192;
+const STATUS_PAGE_REFRESH_SECS: u32 = 5;
+
+// The subset of MessagePack used by admin responses, so any MessagePack
+// library can read them.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    UInt(u64),
+    Str(String),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Map(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Value::Nil => buf.push(0xc0),
+            Value::Bool(false) => buf.push(0xc2),
+            Value::Bool(true) => buf.push(0xc3),
+            Value::UInt(u) => encode_uint(*u, buf),
+            Value::Str(s) => {
+                encode_len(s.len(), 0xa0, 32, [0xd9, 0xda, 0xdb], buf);
+                buf.extend_from_slice(s.as_bytes());
+            }
+            Value::Array(values) => {
+                encode_len(values.len(), 0x90, 16, [0, 0xdc, 0xdd], buf);
+                for v in values.iter() {
+                    v.encode(buf);
+                }
+            }
+            Value::Map(entries) => {
+                encode_len(entries.len(), 0x80, 16, [0, 0xde, 0xdf], buf);
+                for (k, v) in entries.iter() {
+                    Value::Str(k.clone()).encode(buf);
+                    v.encode(buf);
+                }
+            }
+        }
+    }
+
+    // Returns the value and the number of bytes it occupied.
+    pub fn decode(buf: &[u8]) -> Option<(Value, usize)> {
+        let tag = *buf.first()?;
+        let body = &buf[1..];
+
+        match tag {
+            0x00..=0x7f => Some((Value::UInt(tag as u64), 1)),
+            0x80..=0x8f => decode_map((tag & 0x0f) as usize, body, 1),
+            0x90..=0x9f => decode_array((tag & 0x0f) as usize, body, 1),
+            0xa0..=0xbf => decode_str((tag & 0x1f) as usize, body, 1),
+            0xc0 => Some((Value::Nil, 1)),
+            0xc2 => Some((Value::Bool(false), 1)),
+            0xc3 => Some((Value::Bool(true), 1)),
+            0xcc => Some((Value::UInt(read_be(body, 1)?), 2)),
+            0xcd => Some((Value::UInt(read_be(body, 2)?), 3)),
+            0xce => Some((Value::UInt(read_be(body, 4)?), 5)),
+            0xcf => Some((Value::UInt(read_be(body, 8)?), 9)),
+            0xd9 => decode_str(read_be(body, 1)? as usize, &body[1..], 2),
+            0xda => decode_str(read_be(body, 2)? as usize, &body[2..], 3),
+            0xdb => decode_str(read_be(body, 4)? as usize, &body[4..], 5),
+            0xdc => decode_array(read_be(body, 2)? as usize, &body[2..], 3),
+            0xdd => decode_array(read_be(body, 4)? as usize, &body[4..], 5),
+            0xde => decode_map(read_be(body, 2)? as usize, &body[2..], 3),
+            0xdf => decode_map(read_be(body, 4)? as usize, &body[4..], 5),
+            _ => None,
+        }
+    }
+}
+
+fn encode_uint(u: u64, buf: &mut Vec<u8>) {
+    if u < 0x80 {
+        buf.push(u as u8);
+    } else if u <= u8::MAX as u64 {
+        buf.push(0xcc);
+        buf.push(u as u8);
+    } else if u <= u16::MAX as u64 {
+        buf.push(0xcd);
+        buf.extend_from_slice(&(u as u16).to_be_bytes());
+    } else if u <= u32::MAX as u64 {
+        buf.push(0xce);
+        buf.extend_from_slice(&(u as u32).to_be_bytes());
+    } else {
+        buf.push(0xcf);
+        buf.extend_from_slice(&u.to_be_bytes());
+    }
+}
+
+// Tags for the 8, 16 and 32 bit length forms, a zero tag means the
+// type has no 8 bit form.
+fn encode_len(len: usize, fix: u8, fix_limit: usize, tags: [u8; 3], buf: &mut Vec<u8>) {
+    if len < fix_limit {
+        buf.push(fix | len as u8);
+    } else if len <= u8::MAX as usize && tags[0] != 0 {
+        buf.push(tags[0]);
+        buf.push(len as u8);
+    } else if len <= u16::MAX as usize {
+        buf.push(tags[1]);
+        buf.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        buf.push(tags[2]);
+        buf.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+}
+
+fn read_be(buf: &[u8], size: usize) -> Option<u64> {
+    let bytes = buf.get(..size)?;
+    Some(bytes.iter().fold(0, |u, &b| (u << 8) | b as u64))
+}
+
+fn decode_str(len: usize, buf: &[u8], head: usize) -> Option<(Value, usize)> {
+    let bytes = buf.get(..len)?;
+    let s = String::from_utf8(bytes.to_vec()).ok()?;
+    Some((Value::Str(s), head + len))
+}
+
+fn decode_array(len: usize, buf: &[u8], head: usize) -> Option<(Value, usize)> {
+    let mut values = Vec::new();
+    let mut pos = 0;
+
+    for _ in 0..len {
+        let (value, size) = Value::decode(buf.get(pos..)?)?;
+        values.push(value);
+        pos += size;
+    }
+
+    Some((Value::Array(values), head + pos))
+}
+
+fn decode_map(len: usize, buf: &[u8], head: usize) -> Option<(Value, usize)> {
+    let mut entries = Vec::new();
+    let mut pos = 0;
+
+    for _ in 0..len {
+        let (key, size) = Value::decode(buf.get(pos..)?)?;
+        pos += size;
+        let (value, size) = Value::decode(buf.get(pos..)?)?;
+        pos += size;
+
+        match key {
+            Value::Str(key) => entries.push((key, value)),
+            _ => return None,
+        }
+    }
+
+    Some((Value::Map(entries), head + pos))
+}
+
+// Port counters fed by the tunnel events, shared by client and server.
+pub struct AdminStats {
+    role: &'static str,
+    start: Instant,
+    opened: AtomicU64,
+    connected: AtomicU64,
+    closed: AtomicU64,
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+    // Uptime in seconds and the port of ports closed by an error.
+    recent_errors: Mutex<VecDeque<(u64, String, CloseReason)>>,
+}
+
+impl AdminStats {
+    pub fn new(role: &'static str) -> Arc<AdminStats> {
+        let stats = Arc::new(AdminStats {
+            role,
+            start: Instant::now(),
+            opened: AtomicU64::new(0),
+            connected: AtomicU64::new(0),
+            closed: AtomicU64::new(0),
+            uploaded: AtomicU64::new(0),
+            downloaded: AtomicU64::new(0),
+            recent_errors: Mutex::new(VecDeque::new()),
+        });
+
+        let handler = stats.clone();
+        events::add_handler(Box::new(move |event: &PortEvent| {
+            handler.port_event(event);
+        }));
+
+        stats
+    }
+
+    fn port_event(&self, event: &PortEvent) {
+        match event {
+            PortEvent::Opened { .. } => {
+                self.opened.fetch_add(1, Ordering::Relaxed);
+            }
+
+            PortEvent::Connected { .. } => {
+                self.connected.fetch_add(1, Ordering::Relaxed);
+            }
+
+            PortEvent::Closed {
+                host,
+                port,
+                reason,
+                uploaded,
+                downloaded,
+                ..
+            } => {
+                self.closed.fetch_add(1, Ordering::Relaxed);
+                self.uploaded.fetch_add(*uploaded, Ordering::Relaxed);
+                self.downloaded.fetch_add(*downloaded, Ordering::Relaxed);
+
+                if matches!(
+                    reason,
+                    CloseReason::Error | CloseReason::TunnelBroken | CloseReason::TimedOut
+                ) {
+                    let mut errors = self.recent_errors.lock().unwrap();
+                    if errors.len() == MAX_RECENT_ERRORS {
+                        errors.pop_front();
+                    }
+                    let at = self.start.elapsed().as_secs();
+                    errors.push_back((at, format!("{}:{}", host, port), *reason));
+                }
+            }
+        }
+    }
+
+    // Version 1 schema, `extra` carries the role specific fields.
+    pub fn status(&self, extra: Vec<(String, Value)>) -> Value {
+        let opened = self.opened.load(Ordering::Relaxed);
+        let closed = self.closed.load(Ordering::Relaxed);
+
+        let mut entries = vec![
+            ("version".to_string(), Value::UInt(ADMIN_PROTOCOL_VERSION)),
+            ("role".to_string(), Value::Str(self.role.to_string())),
+            (
+                "uptime".to_string(),
+                Value::UInt(self.start.elapsed().as_secs()),
+            ),
+            (
+                "ports".to_string(),
+                Value::Map(vec![
+                    ("opened".to_string(), Value::UInt(opened)),
+                    (
+                        "connected".to_string(),
+                        Value::UInt(self.connected.load(Ordering::Relaxed)),
+                    ),
+                    ("closed".to_string(), Value::UInt(closed)),
+                    (
+                        "active".to_string(),
+                        Value::UInt(opened.saturating_sub(closed)),
+                    ),
+                ]),
+            ),
+            (
+                "bytes".to_string(),
+                Value::Map(vec![
+                    (
+                        "uploaded".to_string(),
+                        Value::UInt(self.uploaded.load(Ordering::Relaxed)),
+                    ),
+                    (
+                        "downloaded".to_string(),
+                        Value::UInt(self.downloaded.load(Ordering::Relaxed)),
+                    ),
+                ]),
+            ),
+        ];
+
+        entries.push(("recent_errors".to_string(), self.recent_errors()));
+        entries.push(("listeners".to_string(), listeners()));
+        entries.push(("fds".to_string(), fds()));
+        entries.extend(extra);
+        Value::Map(entries)
+    }
+
+    fn recent_errors(&self) -> Value {
+        let errors = self.recent_errors.lock().unwrap();
+        let errors = errors
+            .iter()
+            .rev()
+            .map(|(at, port, reason)| {
+                let reason = match reason {
+                    CloseReason::TunnelBroken => "tunnel broken",
+                    CloseReason::TimedOut => "timed out",
+                    _ => "error",
+                };
+
+                Value::Map(vec![
+                    ("uptime".to_string(), Value::UInt(*at)),
+                    ("port".to_string(), Value::Str(port.clone())),
+                    ("reason".to_string(), Value::Str(reason.to_string())),
+                ])
+            })
+            .collect();
+
+        Value::Array(errors)
+    }
+}
+
+fn fds() -> Value {
+    let (open, limit) = listener::fd_usage();
+    Value::Map(vec![
+        ("open".to_string(), open.map_or(Value::Nil, Value::UInt)),
+        ("limit".to_string(), limit.map_or(Value::Nil, Value::UInt)),
+        (
+            "exhausted".to_string(),
+            Value::UInt(listener::fd_exhaustion_count()),
+        ),
+    ])
+}
+
+fn listeners() -> Value {
+    let listeners = listener::status()
+        .into_iter()
+        .map(|status| {
+            Value::Map(vec![
+                ("name".to_string(), Value::Str(status.name.to_string())),
+                ("addr".to_string(), Value::Str(status.addr)),
+                ("bound".to_string(), Value::Bool(status.bound)),
+                (
+                    "error".to_string(),
+                    status.error.map_or(Value::Nil, Value::Str),
+                ),
+                ("attempts".to_string(), Value::UInt(status.attempts as u64)),
+            ])
+        })
+        .collect();
+
+    Value::Array(listeners)
+}
+
+// A pushed config maps long option names to a string, or to an array
+// of strings for repeatable options. Options left out keep their value.
+pub fn config_options(document: &Value) -> Result<Vec<(String, Vec<String>)>, String> {
+    let entries = match document {
+        Value::Map(entries) => entries,
+        _ => return Err("config is not a map".to_string()),
+    };
+
+    let mut options = Vec::new();
+    for (name, value) in entries.iter() {
+        let values = match value {
+            Value::Str(s) => vec![s.clone()],
+            Value::Array(values) => values
+                .iter()
+                .map(|v| match v {
+                    Value::Str(s) => Ok(s.clone()),
+                    _ => Err(format!("{} is not a list of strings", name)),
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            _ => return Err(format!("{} is not a string", name)),
+        };
+
+        options.push((name.clone(), values));
+    }
+
+    Ok(options)
+}
+
+// The single value of a non-repeatable option.
+pub fn config_value<T: FromStr>(name: &str, values: &[String]) -> Result<T, String> {
+    match values {
+        [value] => value
+            .parse()
+            .map_err(|_| format!("invalid value for {}: {}", name, value)),
+        _ => Err(format!("{} takes one value", name)),
+    }
+}
+
+pub struct ConfigChange {
+    pub name: String,
+    pub old: String,
+    pub new: String,
+}
+
+// Changes are applied together, and only if there are no errors.
+pub fn config_response(changes: &[ConfigChange], errors: &[String], applied: bool) -> Value {
+    let changes = changes
+        .iter()
+        .map(|c| {
+            Value::Map(vec![
+                ("name".to_string(), Value::Str(c.name.clone())),
+                ("old".to_string(), Value::Str(c.old.clone())),
+                ("new".to_string(), Value::Str(c.new.clone())),
+            ])
+        })
+        .collect();
+    let errors = errors.iter().map(|e| Value::Str(e.clone())).collect();
+
+    Value::Map(vec![
+        ("version".to_string(), Value::UInt(ADMIN_PROTOCOL_VERSION)),
+        ("changes".to_string(), Value::Array(changes)),
+        ("errors".to_string(), Value::Array(errors)),
+        ("applied".to_string(), Value::Bool(applied)),
+    ])
+}
+
+pub fn unknown_command() -> Value {
+    Value::Map(vec![(
+        "error".to_string(),
+        Value::Str("unknown command".to_string()),
+    )])
+}
+
+// The config commands carry a document.
+fn has_document(cmd: u8) -> bool {
+    matches!(cmd, CMD_CONFIG_DIFF | CMD_CONFIG_APPLY)
+}
+
+// Each connection sends one command byte, followed by a length prefixed
+// MessagePack document for the config commands, and receives a length
+// prefixed MessagePack document from `handler`.
+pub async fn serve<F>(listen_addr: String, handler: F)
+where
+    F: Fn(u8, &Value) -> Value + Send + Sync + 'static,
+{
+    let listener = listener::bind_tcp("admin", &listen_addr).await;
+    let handler = Arc::new(handler);
+    let mut incoming = listener.incoming();
+
+    while let Some(stream) = incoming.next().await {
+        if let Ok(stream) = stream {
+            let handler = handler.clone();
+            task::spawn(async move {
+                let _ = serve_request(stream, handler.as_ref()).await;
+            });
+        }
+    }
+}
+
+async fn serve_request<F: Fn(u8, &Value) -> Value>(
+    mut stream: TcpStream,
+    handler: &F,
+) -> std::io::Result<()> {
+    let timeout = Duration::from_millis(ADMIN_TIMEOUT_MS);
+    let mut cmd = [0u8; 1];
+    io::timeout(timeout, stream.read_exact(&mut cmd)).await?;
+
+    let document = if has_document(cmd[0]) {
+        io::timeout(timeout, read_document(&mut stream)).await?
+    } else {
+        Value::Nil
+    };
+
+    let value = handler(cmd[0], &document);
+
+    io::timeout(timeout, write_document(&mut stream, &value)).await
+}
+
+async fn write_document(stream: &mut TcpStream, value: &Value) -> std::io::Result<()> {
+    let mut body = Vec::new();
+    value.encode(&mut body);
+
+    stream.write_all(&(body.len() as u32).to_be_bytes()).await?;
+    stream.write_all(&body).await
+}
+
+async fn read_document(stream: &mut TcpStream) -> std::io::Result<Value> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len).await?;
+
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_DOCUMENT_SIZE {
+        return Err(std::io::Error::from(std::io::ErrorKind::InvalidData));
+    }
+
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body).await?;
+
+    match Value::decode(&body) {
+        Some((value, _)) => Ok(value),
+        None => Err(std::io::Error::from(std::io::ErrorKind::InvalidData)),
+    }
+}
+
+pub async fn request(admin_addr: &str, cmd: u8) -> std::io::Result<Value> {
+    request_with_document(admin_addr, cmd, &Value::Nil).await
+}
+
+// `document` is sent only for commands that carry one.
+pub async fn request_with_document(
+    admin_addr: &str,
+    cmd: u8,
+    document: &Value,
+) -> std::io::Result<Value> {
+    let timeout = Duration::from_millis(ADMIN_TIMEOUT_MS);
+
+    io::timeout(timeout, async {
+        let mut stream = TcpStream::connect(admin_addr).await?;
+        stream.write_all(&[cmd]).await?;
+
+        if has_document(cmd) {
+            write_document(&mut stream, document).await?;
+        }
+
+        read_document(&mut stream).await
+    })
+    .await
+}
+
+// A read-only HTML rendering of `status`, for a browser instead of
+// stunnel_admin. It refreshes itself every few seconds.
+pub async fn serve_status_page<F>(listen_addr: String, status: F)
+where
+    F: Fn() -> Value + Send + Sync + 'static,
+{
+    let listener = listener::bind_tcp("status page", &listen_addr).await;
+    info!("status page on http://{}/", listen_addr);
+
+    let status = Arc::new(status);
+    let mut incoming = listener.incoming();
+
+    while let Some(stream) = incoming.next().await {
+        if let Ok(stream) = stream {
+            let status = status.clone();
+            task::spawn(async move {
+                let _ = serve_status_request(stream, status.as_ref()).await;
+            });
+        }
+    }
+}
+
+async fn serve_status_request<F: Fn() -> Value>(
+    mut stream: TcpStream,
+    status: &F,
+) -> std::io::Result<()> {
+    let timeout = Duration::from_millis(ADMIN_TIMEOUT_MS);
+    let head = io::timeout(timeout, read_http_head(&mut stream)).await?;
+    let request_line = head.lines().next().unwrap_or_default();
+
+    let response = match request_line.split(' ').collect::<Vec<_>>().as_slice() {
+        ["GET", "/", _] => http_response("200 OK", &status_page(&status())),
+        ["GET", _, _] => http_response("404 Not Found", "not found"),
+        _ => http_response("405 Method Not Allowed", "method not allowed"),
+    };
+
+    io::timeout(timeout, stream.write_all(response.as_bytes())).await
+}
+
+async fn read_http_head(stream: &mut TcpStream) -> std::io::Result<String> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+
+    while !head.ends_with(b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || head.len() + n > MAX_HTTP_REQUEST_SIZE {
+            return Err(std::io::Error::from(std::io::ErrorKind::InvalidData));
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+fn http_response(status: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+fn status_page(status: &Value) -> String {
+    let mut body = String::new();
+    render_html(status, &mut body);
+
+    format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+         <meta http-equiv=\"refresh\" content=\"{}\"><title>stunnel status</title>\
+         <style>body{{font-family:sans-serif}}table{{border-collapse:collapse}}\
+         th,td{{border:1px solid #ccc;padding:2px 8px;text-align:left;vertical-align:top}}</style>\
+         </head><body><h1>stunnel status</h1>{}</body></html>\n",
+        STATUS_PAGE_REFRESH_SECS, body
+    )
+}
+
+// Maps become two column tables, arrays numbered rows.
+fn render_html(value: &Value, html: &mut String) {
+    match value {
+        Value::Map(entries) => {
+            html.push_str("<table>");
+            for (key, value) in entries.iter() {
+                html.push_str(&format!("<tr><th>{}</th><td>", escape_html(key)));
+                render_html(value, html);
+                html.push_str("</td></tr>");
+            }
+            html.push_str("</table>");
+        }
+
+        Value::Array(values) if values.is_empty() => html.push_str("none"),
+
+        Value::Array(values) => {
+            html.push_str("<table>");
+            for (i, value) in values.iter().enumerate() {
+                html.push_str(&format!("<tr><th>{}</th><td>", i));
+                render_html(value, html);
+                html.push_str("</td></tr>");
+            }
+            html.push_str("</table>");
+        }
+
+        Value::Nil => html.push('-'),
+        Value::Bool(b) => html.push_str(&b.to_string()),
+        Value::UInt(u) => html.push_str(&u.to_string()),
+        Value::Str(s) => html.push_str(&escape_html(s)),
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,171 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_std::task;
+
+pub const DEFAULT_INTERACTIVE_QUEUE_LIMIT: usize = 4 * 1024 * 1024;
+pub const DEFAULT_BULK_QUEUE_LIMIT: usize = 1024 * 1024;
+const QUEUE_POLL_INTERVAL_MS: u64 = 5;
+// Bytes a port may have sent the other end of its tunnel that aren't
+// written to its socket yet, and how many written bytes are granted at
+// once.
+pub const PORT_WINDOW: usize = 512 * 1024;
+pub const PORT_WINDOW_STEP: usize = PORT_WINDOW / 4;
+
+// Remote shells and desktops, their ports pause last.
+const INTERACTIVE_PORTS: [u16; 4] = [22, 23, 3389, 5900];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PortClass {
+    Interactive,
+    Bulk,
+}
+
+impl PortClass {
+    pub fn of_port(port: u16) -> PortClass {
+        if INTERACTIVE_PORTS.contains(&port) {
+            PortClass::Interactive
+        } else {
+            PortClass::Bulk
+        }
+    }
+}
+
+// Bytes a tunnel may have queued and not yet written before the ports
+// of a class stop reading their sockets, so the smaller limit throttles
+// its class harder.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QueueLimits {
+    pub interactive: usize,
+    pub bulk: usize,
+}
+
+impl Default for QueueLimits {
+    fn default() -> Self {
+        QueueLimits {
+            interactive: DEFAULT_INTERACTIVE_QUEUE_LIMIT,
+            bulk: DEFAULT_BULK_QUEUE_LIMIT,
+        }
+    }
+}
+
+impl QueueLimits {
+    pub fn limit(&self, class: PortClass) -> usize {
+        match class {
+            PortClass::Interactive => self.interactive,
+            PortClass::Bulk => self.bulk,
+        }
+    }
+
+    // Sets the limit of a class from a `class=bytes` option.
+    pub fn set(&mut self, option: &str) -> Result<(), String> {
+        let (class, value) = option
+            .split_once('=')
+            .ok_or_else(|| format!("queue limit {} is not class=bytes", option))?;
+        let bytes = value
+            .parse()
+            .map_err(|_| format!("invalid queue limit for {}: {}", class, value))?;
+
+        match class {
+            "interactive" => self.interactive = bytes,
+            "bulk" => self.bulk = bytes,
+            _ => return Err(format!("unknown port class {}", class)),
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for QueueLimits {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "interactive={},bulk={}", self.interactive, self.bulk)
+    }
+}
+
+// Data the ports of a tunnel handed over and its writer didn't take yet.
+// It only builds up while writes to the tunnel block, as the send buffer
+// of the connection is full, so waiting on it passes that back to the
+// ports instead of holding their data in memory.
+#[derive(Default)]
+pub struct TunnelQueue {
+    queued: AtomicUsize,
+    frames: AtomicUsize,
+    closed: AtomicBool,
+}
+
+impl TunnelQueue {
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    // The data frames making up what is queued.
+    pub fn frames(&self) -> usize {
+        self.frames.load(Ordering::Relaxed)
+    }
+
+    pub fn push(&self, bytes: usize) {
+        self.queued.fetch_add(bytes, Ordering::Relaxed);
+        self.frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn pop(&self, bytes: usize) {
+        self.queued.fetch_sub(bytes, Ordering::Relaxed);
+        self.frames.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    // Releases the ports waiting, the tunnel won't take their data.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+    }
+
+    pub async fn wait(&self, limit: usize) {
+        while self.queued() > limit && !self.closed.load(Ordering::Relaxed) {
+            task::sleep(Duration::from_millis(QUEUE_POLL_INTERVAL_MS)).await;
+        }
+    }
+}
+
+// The credit of one port, what it may still send before the other end
+// grants more, see PORT_WINDOW. A slow reader at the other end so holds
+// up its own port rather than filling the memory of that end, or the
+// tunnel for the other ports. It only waits while the other end grants
+// credit, ends from before don't.
+pub struct PortWindow {
+    credit: AtomicIsize,
+    granting: Arc<AtomicBool>,
+    closed: AtomicBool,
+}
+
+impl PortWindow {
+    pub fn new(granting: Arc<AtomicBool>) -> PortWindow {
+        PortWindow {
+            credit: AtomicIsize::new(PORT_WINDOW as isize),
+            granting,
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    pub fn grant(&self, bytes: usize) {
+        self.credit.fetch_add(bytes as isize, Ordering::Relaxed);
+    }
+
+    // Releases the port waiting, its data goes nowhere.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+    }
+
+    // Waits until there is credit left and takes `bytes` of it, which may
+    // overdraw it by the last write.
+    pub async fn take(&self, bytes: usize) {
+        while self.credit.load(Ordering::Relaxed) <= 0
+            && self.granting.load(Ordering::Relaxed)
+            && !self.closed.load(Ordering::Relaxed)
+        {
+            task::sleep(Duration::from_millis(QUEUE_POLL_INTERVAL_MS)).await;
+        }
+
+        self.credit.fetch_sub(bytes as isize, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use super::client::Tunnel;
+
+// How a new port picks one of the tunnels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Balance {
+    // Each tunnel in turn.
+    RoundRobin,
+    // The tunnel with the fewest open ports.
+    LeastPorts,
+    // The tunnel whose heartbeats came back the fastest, see Tunnel::rtt.
+    LowestRtt,
+    // The tunnels in proportion to the weights of their servers.
+    Weighted,
+}
+
+impl FromStr for Balance {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Balance, String> {
+        match s {
+            "round-robin" => Ok(Balance::RoundRobin),
+            "least-ports" => Ok(Balance::LeastPorts),
+            "lowest-rtt" => Ok(Balance::LowestRtt),
+            "weighted" => Ok(Balance::Weighted),
+            _ => Err(format!(
+                "unknown balance {}, use round-robin, least-ports, lowest-rtt or weighted",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Balance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Balance::RoundRobin => "round-robin",
+            Balance::LeastPorts => "least-ports",
+            Balance::LowestRtt => "lowest-rtt",
+            Balance::Weighted => "weighted",
+        };
+        f.write_str(name)
+    }
+}
+
+// Picks the tunnel of each new port. Connected tunnels are picked over
+// those reconnecting, which fail their ports at once, and ties go to the
+// tunnels in turn.
+pub struct Balancer {
+    balance: Balance,
+    next: usize,
+    // Servers weigh 1 unless set, the tunnels to a server of weight 0
+    // are picked only when no other is connected.
+    weights: HashMap<String, u32>,
+    // The smooth weighted round robin credit of each tunnel.
+    credits: Vec<i64>,
+}
+
+impl Balancer {
+    pub fn new(balance: Balance) -> Balancer {
+        Balancer {
+            balance,
+            next: 0,
+            weights: HashMap::new(),
+            credits: Vec::new(),
+        }
+    }
+
+    pub fn balance(&self) -> Balance {
+        self.balance
+    }
+
+    // Takes an option such as `10.0.0.1:8080=3`.
+    pub fn set_weight(&mut self, option: &str) -> Result<(), String> {
+        let (server, weight) = option
+            .rsplit_once('=')
+            .ok_or_else(|| format!("invalid weight {}, expected server=weight", option))?;
+        let weight = weight
+            .parse()
+            .map_err(|_| format!("invalid weight {}", option))?;
+        self.weights.insert(server.to_string(), weight);
+        Ok(())
+    }
+
+    fn weight(&self, tunnel: &Tunnel) -> u32 {
+        self.weights.get(&tunnel.server()).copied().unwrap_or(1)
+    }
+
+    // The index of the tunnel, tunnels must not be empty.
+    pub fn pick(&mut self, tunnels: &[Tunnel]) -> usize {
+        let start = self.next;
+        self.next = (self.next + 1) % tunnels.len();
+
+        let order: Vec<usize> = (0..tunnels.len())
+            .map(|i| (start + i) % tunnels.len())
+            .collect();
+        let connected: Vec<usize> = order
+            .iter()
+            .copied()
+            .filter(|&i| tunnels[i].is_connected())
+            .collect();
+        let candidates = if connected.is_empty() {
+            order
+        } else {
+            connected
+        };
+
+        match self.balance {
+            Balance::RoundRobin => candidates[0],
+            Balance::LeastPorts => *candidates
+                .iter()
+                .min_by_key(|&&i| tunnels[i].open_ports())
+                .unwrap(),
+            Balance::LowestRtt => *candidates
+                .iter()
+                .min_by_key(|&&i| tunnels[i].rtt().unwrap_or(Duration::MAX))
+                .unwrap(),
+            Balance::Weighted => self.pick_weighted(tunnels, &candidates),
+        }
+    }
+
+    // Each candidate gains its weight, the one with the most credit is
+    // picked and pays the weights of all.
+    fn pick_weighted(&mut self, tunnels: &[Tunnel], candidates: &[usize]) -> usize {
+        self.credits.resize(tunnels.len(), 0);
+
+        let weights: Vec<(usize, i64)> = candidates
+            .iter()
+            .map(|&i| (i, self.weight(&tunnels[i]) as i64))
+            .collect();
+        let total: i64 = weights.iter().map(|(_, weight)| weight).sum();
+        if total == 0 {
+            return candidates[0];
+        }
+
+        for &(i, weight) in weights.iter() {
+            self.credits[i] += weight;
+        }
+
+        let picked = weights
+            .iter()
+            .filter(|(_, weight)| *weight > 0)
+            .map(|&(i, _)| i)
+            .max_by_key(|&i| self.credits[i])
+            .unwrap();
+        self.credits[picked] -= total;
+        picked
+    }
+}
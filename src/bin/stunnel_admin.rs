@@ -0,0 +1,201 @@
+extern crate async_std;
+extern crate getopts;
+extern crate stunnel;
+
+use std::env;
+use std::fs;
+use std::io::Write;
+
+use async_std::task;
+
+use stunnel::admin::{
+    self, Value, ADMIN_PROTOCOL_VERSION, CMD_CONFIG_APPLY, CMD_CONFIG_DIFF, CMD_DRAIN,
+    CMD_SHUTDOWN, CMD_STATUS,
+};
+
+fn print_value(value: &Value, indent: usize) {
+    match value {
+        Value::Map(entries) => {
+            for (key, value) in entries.iter() {
+                match value {
+                    Value::Map(_) | Value::Array(_) => {
+                        println!("{:indent$}{}:", "", key, indent = indent);
+                        print_value(value, indent + 2);
+                    }
+                    _ => {
+                        print!("{:indent$}{}: ", "", key, indent = indent);
+                        print_value(value, 0);
+                    }
+                }
+            }
+        }
+
+        Value::Array(values) => {
+            for (i, value) in values.iter().enumerate() {
+                println!("{:indent$}- {}", "", i, indent = indent);
+                print_value(value, indent + 2);
+            }
+        }
+
+        Value::Nil => println!("-"),
+        Value::Bool(b) => println!("{}", b),
+        Value::UInt(u) => println!("{}", u),
+        Value::Str(s) => println!("{}", s),
+    }
+}
+
+// The counters of each tunnel of a client status, see Tunnel::stats.
+fn print_stats(value: &Value) {
+    let tunnels = match value.get("tunnels") {
+        Some(Value::Array(tunnels)) => tunnels,
+        _ => {
+            println!("no tunnels in the status, --stats takes a client");
+            return;
+        }
+    };
+
+    for (i, tunnel) in tunnels.iter().enumerate() {
+        let server = match tunnel.get("server") {
+            Some(Value::Str(server)) => server.as_str(),
+            _ => "-",
+        };
+        println!("- {} {}", i, server);
+
+        for key in ["stats", "remote_stats"].iter() {
+            match tunnel.get(key) {
+                Some(stats @ Value::Map(_)) => {
+                    println!("  {}:", key);
+                    print_value(stats, 4);
+                }
+                _ => println!("  {}: -", key),
+            }
+        }
+    }
+}
+
+// One name=value per line, blank lines and lines starting with # are
+// skipped. A repeated name becomes a list, like a repeated option.
+fn read_config(path: &str) -> Result<Value, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("read {} error: {}", path, e))?;
+    let mut options: Vec<(String, Vec<String>)> = Vec::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (name, value) = match line.split_once('=') {
+            Some((name, value)) => (name.trim().to_string(), value.trim().to_string()),
+            None => return Err(format!("{}:{}: expected name=value", path, i + 1)),
+        };
+
+        match options.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, values)) => values.push(value),
+            None => options.push((name, vec![value])),
+        }
+    }
+
+    let entries = options
+        .into_iter()
+        .map(|(name, mut values)| {
+            let value = if values.len() == 1 {
+                Value::Str(values.pop().unwrap())
+            } else {
+                Value::Array(values.into_iter().map(Value::Str).collect())
+            };
+            (name, value)
+        })
+        .collect();
+
+    Ok(Value::Map(entries))
+}
+
+fn main() {
+    let args: Vec<_> = env::args().collect();
+    let program = args[0].clone();
+
+    let mut opts = getopts::Options::new();
+    opts.reqopt("a", "admin", "admin address", "admin-address");
+    opts.optflag("", "raw", "write the MessagePack response to stdout");
+    opts.optflag(
+        "",
+        "stats",
+        "print only the open ports, traffic and queue of each tunnel of a client",
+    );
+    opts.optflag(
+        "",
+        "drain",
+        "ask a server to move clients elsewhere, then print its status",
+    );
+    opts.optflag(
+        "",
+        "shutdown",
+        "ask a client to shut down once its open ports finish, then print its status",
+    );
+
+    opts.optopt(
+        "",
+        "config",
+        "print how a config file differs from the running config",
+        "config-file",
+    );
+    opts.optflag("", "apply", "apply the --config file if all of it can be");
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(_) => {
+            println!("{}", opts.short_usage(&program));
+            return;
+        }
+    };
+
+    let admin_addr = matches.opt_str("a").unwrap();
+    let raw = matches.opt_present("raw");
+    let config = match matches.opt_str("config").map(|path| read_config(&path)) {
+        Some(Ok(config)) => config,
+        Some(Err(e)) => {
+            println!("{}", e);
+            return;
+        }
+        None => Value::Nil,
+    };
+
+    let cmd = if matches.opt_present("drain") {
+        CMD_DRAIN
+    } else if matches.opt_present("shutdown") {
+        CMD_SHUTDOWN
+    } else if matches.opt_present("config") && matches.opt_present("apply") {
+        CMD_CONFIG_APPLY
+    } else if matches.opt_present("config") {
+        CMD_CONFIG_DIFF
+    } else {
+        CMD_STATUS
+    };
+
+    let value = match task::block_on(admin::request_with_document(&admin_addr, cmd, &config)) {
+        Ok(value) => value,
+        Err(e) => {
+            println!("request {} error: {}", admin_addr, e);
+            return;
+        }
+    };
+
+    if raw {
+        let mut buf = Vec::new();
+        value.encode(&mut buf);
+        let _ = std::io::stdout().write_all(&buf);
+        return;
+    }
+
+    match value.get("version") {
+        Some(&Value::UInt(version)) if version <= ADMIN_PROTOCOL_VERSION => {}
+        _ => println!("warning: unsupported status version"),
+    }
+
+    if matches.opt_present("stats") {
+        print_stats(&value);
+    } else {
+        print_value(&value, 0);
+    }
+}
@@ -1,29 +1,56 @@
 #[macro_use]
 extern crate log;
 extern crate async_std;
+extern crate futures;
 extern crate getopts;
 extern crate stunnel;
 
 use std::env;
+use std::io::ErrorKind;
 use std::net::Shutdown;
 use std::net::ToSocketAddrs;
 use std::str::from_utf8;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use std::vec::Vec;
 
+use async_std::future;
+use async_std::io;
 use async_std::net::TcpListener;
 use async_std::net::TcpStream;
 use async_std::prelude::*;
 use async_std::task;
+use futures::future::join_all;
 
+use stunnel::admin::{
+    self, AdminStats, ConfigChange, Value, CMD_CONFIG_APPLY, CMD_CONFIG_DIFF, CMD_SHUTDOWN,
+    CMD_STATUS,
+};
+use stunnel::backpressure::{PortClass, QueueLimits};
+use stunnel::balance::{Balance, Balancer};
+use stunnel::buffer::{self, DataBuf};
 use stunnel::client::*;
 use stunnel::cryptor::Cryptor;
+use stunnel::doctor;
+use stunnel::hostname;
+use stunnel::listener::{self, AcceptBackoff, Backoff};
 use stunnel::logger;
+use stunnel::selector::{ServerSelector, PROBE_INTERVAL_MS};
+use stunnel::shutdown;
 use stunnel::socks5;
+use stunnel::stats::TunnelStats;
+use stunnel::timer::Watchdog;
+#[cfg(feature = "ucp")]
+use stunnel::ucp::{self, UcpConfig};
 
-async fn process_read(stream: &mut &TcpStream, mut write_port: TunnelWritePort) {
+async fn process_read(
+    stream: &mut &TcpStream,
+    mut write_port: TunnelWritePort,
+    watchdog: &Watchdog,
+) {
     loop {
-        let mut buf = vec![0; 1024];
-        match stream.read(&mut buf).await {
+        let mut buf = DataBuf::new(1024);
+        match io::timeout(watchdog.period(), stream.read(&mut buf)).await {
             Ok(0) => {
                 let _ = stream.shutdown(Shutdown::Read);
                 write_port.shutdown_write().await;
@@ -32,10 +59,20 @@ async fn process_read(stream: &mut &TcpStream, mut write_port: TunnelWritePort)
             }
 
             Ok(n) => {
+                watchdog.feed();
                 buf.truncate(n);
-                write_port.write(buf).await;
+                if future::timeout(watchdog.period(), write_port.write(buf))
+                    .await
+                    .is_err()
+                {
+                    let _ = stream.shutdown(Shutdown::Both);
+                    write_port.close().await;
+                    break;
+                }
             }
 
+            Err(ref e) if e.kind() == ErrorKind::TimedOut && !watchdog.expired() => {}
+
             Err(_) => {
                 let _ = stream.shutdown(Shutdown::Both);
                 write_port.close().await;
@@ -45,18 +82,24 @@ async fn process_read(stream: &mut &TcpStream, mut write_port: TunnelWritePort)
     }
 }
 
-async fn process_write(stream: &mut &TcpStream, mut read_port: TunnelReadPort) {
+async fn process_write(
+    stream: &mut &TcpStream,
+    mut read_port: TunnelReadPort,
+    watchdog: &Watchdog,
+) {
     loop {
-        let buf = match read_port.read().await {
-            TunnelPortMsg::Data(buf) => buf,
+        let buf = match future::timeout(watchdog.period(), read_port.read()).await {
+            Ok(TunnelPortMsg::Data(buf)) => buf,
 
-            TunnelPortMsg::ShutdownWrite => {
+            Ok(TunnelPortMsg::ShutdownWrite) => {
                 let _ = stream.shutdown(Shutdown::Write);
                 read_port.drain();
                 read_port.drop().await;
                 break;
             }
 
+            Err(_) if !watchdog.expired() => continue,
+
             _ => {
                 let _ = stream.shutdown(Shutdown::Both);
                 read_port.drain();
@@ -65,12 +108,17 @@ async fn process_write(stream: &mut &TcpStream, mut read_port: TunnelReadPort) {
             }
         };
 
-        if stream.write_all(&buf).await.is_err() {
+        watchdog.feed();
+        if io::timeout(watchdog.period(), stream.write_all(&buf))
+            .await
+            .is_err()
+        {
             let _ = stream.shutdown(Shutdown::Both);
             read_port.drain();
             read_port.close().await;
             break;
         }
+        read_port.consumed(buf.len()).await;
     }
 }
 
@@ -78,25 +126,56 @@ async fn run_tunnel_port(
     mut stream: TcpStream,
     mut read_port: TunnelReadPort,
     mut write_port: TunnelWritePort,
+    idle_timeout: Duration,
+    queue_limits: QueueLimits,
 ) {
-    match socks5::handshake(&mut stream).await {
+    let class = match socks5::handshake(&mut stream).await {
         Ok(socks5::Destination::Address(addr)) => {
+            let class = PortClass::of_port(addr.port());
+            write_port.set_queue_limit(queue_limits.limit(class));
             let mut buf = Vec::new();
             let _ = std::io::Write::write_fmt(&mut buf, format_args!("{}", addr));
             write_port.connect(buf).await;
+            class
         }
 
         Ok(socks5::Destination::DomainName(domain_name, port)) => {
-            write_port.connect_domain_name(domain_name, port).await;
+            let class = PortClass::of_port(port);
+            write_port.set_queue_limit(queue_limits.limit(class));
+            match hostname::canonicalize(&domain_name) {
+                Some(host) => write_port.connect_domain_name(host, port).await,
+                None => {
+                    error!(
+                        "invalid destination hostname: {}",
+                        String::from_utf8_lossy(&domain_name)
+                    );
+                    let _ = socks5::destination_unreached(&mut stream).await;
+                    return write_port.close().await;
+                }
+            }
+            class
         }
 
-        _ => {
+        Err(e) => {
+            match stream.peer_addr() {
+                Ok(peer) => error!("{} from {}", e, peer),
+                Err(_) => error!("{}", e),
+            }
             return write_port.close().await;
         }
-    }
+    };
 
     let addr = match read_port.read().await {
-        TunnelPortMsg::ConnectOk(buf) => from_utf8(&buf).unwrap().to_socket_addrs().unwrap().nth(0),
+        TunnelPortMsg::ConnectOk(buf) => from_utf8(&buf)
+            .ok()
+            .and_then(|addr| addr.to_socket_addrs().ok())
+            .and_then(|mut addrs| addrs.next()),
+
+        // Fails the connection now rather than after the tunnel is back.
+        TunnelPortMsg::TunnelReconnecting => {
+            info!("tunnel reconnecting, refusing the connection");
+            None
+        }
 
         _ => None,
     };
@@ -109,9 +188,13 @@ async fn run_tunnel_port(
     };
 
     if success {
+        write_port.set_class(class);
+        read_port.set_class(class);
+        // Shared by both directions, see Watchdog.
+        let watchdog = Watchdog::new(idle_timeout);
         let (reader, writer) = &mut (&stream, &stream);
-        let r = process_read(reader, write_port);
-        let w = process_write(writer, read_port);
+        let r = process_read(reader, write_port, &watchdog);
+        let w = process_write(writer, read_port, &watchdog);
         let _ = r.join(w).await;
     } else {
         let _ = stream.shutdown(Shutdown::Both);
@@ -120,62 +203,737 @@ async fn run_tunnel_port(
     }
 }
 
-fn run_tunnels(
+const TUNNEL_MAINTENANCE_INTERVAL_MS: u64 = 1000;
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_STATUS_PAGE_ADDR: &str = "127.0.0.1:1088";
+
+// Health of the tunnels, refreshed by run_tunnels for the status.
+type TunnelTable = Arc<Mutex<Vec<Value>>>;
+
+fn port_status(stats: &PortStats) -> Value {
+    Value::Map(vec![
+        ("id".to_string(), Value::UInt(stats.id as u64)),
+        (
+            "destination".to_string(),
+            Value::Str(format!("{}:{}", stats.host, stats.port)),
+        ),
+        ("age".to_string(), Value::UInt(stats.age.as_secs())),
+        ("uploaded".to_string(), Value::UInt(stats.uploaded)),
+        ("downloaded".to_string(), Value::UInt(stats.downloaded)),
+        ("upload_rate".to_string(), Value::UInt(stats.upload_rate)),
+        (
+            "download_rate".to_string(),
+            Value::UInt(stats.download_rate),
+        ),
+        (
+            "average_upload_rate".to_string(),
+            Value::UInt(stats.average_upload_rate),
+        ),
+        (
+            "average_download_rate".to_string(),
+            Value::UInt(stats.average_download_rate),
+        ),
+        (
+            "stalled".to_string(),
+            stats
+                .stalled
+                .map_or(Value::Nil, |stalled| Value::UInt(stalled.as_secs())),
+        ),
+    ])
+}
+
+fn stats_status(stats: &TunnelStats) -> Value {
+    Value::Map(vec![
+        ("open_ports".to_string(), Value::UInt(stats.open_ports)),
+        ("uploaded".to_string(), Value::UInt(stats.uploaded)),
+        ("downloaded".to_string(), Value::UInt(stats.downloaded)),
+        (
+            "queued_frames".to_string(),
+            Value::UInt(stats.queued_frames),
+        ),
+        ("queued_bytes".to_string(), Value::UInt(stats.queued_bytes)),
+    ])
+}
+
+fn tunnel_status(kind: &str, tunnel: &Tunnel) -> Value {
+    Value::Map(vec![
+        ("kind".to_string(), Value::Str(kind.to_string())),
+        ("server".to_string(), Value::Str(tunnel.server())),
+        (
+            "server_version".to_string(),
+            tunnel.server_version().map_or(Value::Nil, Value::Str),
+        ),
+        (
+            "connected".to_string(),
+            tunnel
+                .age()
+                .map_or(Value::Nil, |age| Value::UInt(age.as_secs())),
+        ),
+        (
+            "features".to_string(),
+            tunnel.features().map_or(Value::Nil, |features| {
+                Value::Array(features.names().into_iter().map(Value::Str).collect())
+            }),
+        ),
+        ("quality".to_string(), Value::UInt(tunnel.quality() as u64)),
+        (
+            "queued".to_string(),
+            Value::UInt(tunnel.queued_bytes() as u64),
+        ),
+        ("draining".to_string(), Value::Bool(tunnel.is_draining())),
+        (
+            "rtt_ms".to_string(),
+            tunnel
+                .rtt()
+                .map_or(Value::Nil, |rtt| Value::UInt(rtt.as_millis() as u64)),
+        ),
+        ("stats".to_string(), stats_status(&tunnel.stats())),
+        (
+            "remote_stats".to_string(),
+            tunnel
+                .remote_stats()
+                .map_or(Value::Nil, |stats| stats_status(&stats)),
+        ),
+        (
+            "ports".to_string(),
+            Value::Array(tunnel.port_stats().iter().map(port_status).collect()),
+        ),
+    ])
+}
+
+#[derive(Clone)]
+struct TunnelOptions {
     listen_addr: String,
-    server_addr: String,
+    port_idle_timeout: Duration,
+    max_age: Option<Duration>,
+    queue_limits: QueueLimits,
+    shutdown_timeout: Duration,
+}
+
+fn max_age_secs(max_age: Option<Duration>) -> String {
+    max_age.map_or(0, |age| age.as_secs()).to_string()
+}
+
+// The servers, the listen address, the port idle timeout, the queue
+// limits and the tunnel max age can change while running, ports and
+// tunnels pick them up as they are replaced.
+fn update_options(
+    tunnel_options: &TunnelOptions,
+    selector: &ServerSelector,
+    options: Vec<(String, Vec<String>)>,
+) -> (
+    TunnelOptions,
+    Option<Vec<String>>,
+    Vec<ConfigChange>,
+    Vec<String>,
+) {
+    let mut new_options = tunnel_options.clone();
+    let mut new_servers = None;
+    let mut changes = Vec::new();
+    let mut errors = Vec::new();
+
+    for (name, values) in options {
+        match name.as_str() {
+            "server" => {
+                let servers: Vec<String> = selector.status().into_iter().map(|s| s.0).collect();
+                if values.is_empty() {
+                    errors.push("server needs at least one address".to_string());
+                } else if values != servers {
+                    changes.push(ConfigChange {
+                        name,
+                        old: servers.join(" "),
+                        new: values.join(" "),
+                    });
+                    new_servers = Some(values);
+                }
+            }
+
+            "listen" => match admin::config_value::<String>(&name, &values) {
+                Ok(addr) if addr != new_options.listen_addr => {
+                    changes.push(ConfigChange {
+                        name,
+                        old: new_options.listen_addr.clone(),
+                        new: addr.clone(),
+                    });
+                    new_options.listen_addr = addr;
+                }
+                Ok(_) => {}
+                Err(e) => errors.push(e),
+            },
+
+            "port-idle-timeout" => match admin::config_value(&name, &values) {
+                Ok(millis) if Duration::from_millis(millis) != new_options.port_idle_timeout => {
+                    changes.push(ConfigChange {
+                        name,
+                        old: new_options.port_idle_timeout.as_millis().to_string(),
+                        new: millis.to_string(),
+                    });
+                    new_options.port_idle_timeout = Duration::from_millis(millis);
+                }
+                Ok(_) => {}
+                Err(e) => errors.push(e),
+            },
+
+            "tunnel-max-age" => match admin::config_value(&name, &values) {
+                Ok(secs) => {
+                    let max_age = Some(Duration::from_secs(secs)).filter(|_| secs > 0);
+                    if max_age != new_options.max_age {
+                        changes.push(ConfigChange {
+                            name,
+                            old: max_age_secs(new_options.max_age),
+                            new: max_age_secs(max_age),
+                        });
+                        new_options.max_age = max_age;
+                    }
+                }
+                Err(e) => errors.push(e),
+            },
+
+            "queue-limit" => {
+                let mut limits = new_options.queue_limits;
+                match values.iter().try_for_each(|value| limits.set(value)) {
+                    Ok(()) if limits != new_options.queue_limits => {
+                        changes.push(ConfigChange {
+                            name,
+                            old: new_options.queue_limits.to_string(),
+                            new: limits.to_string(),
+                        });
+                        new_options.queue_limits = limits;
+                    }
+                    Ok(()) => {}
+                    Err(e) => errors.push(e),
+                }
+            }
+
+            _ => errors.push(format!("{} can't be changed while running", name)),
+        }
+    }
+
+    (new_options, new_servers, changes, errors)
+}
+
+fn config_command(
+    cmd: u8,
+    document: &Value,
+    tunnel_options: &Mutex<TunnelOptions>,
+    selector: &Arc<ServerSelector>,
+) -> Value {
+    let mut tunnel_options = tunnel_options.lock().unwrap();
+    let (new_options, new_servers, changes, errors) = match admin::config_options(document) {
+        Ok(options) => update_options(&tunnel_options, selector, options),
+        Err(e) => (tunnel_options.clone(), None, Vec::new(), vec![e]),
+    };
+
+    let apply = cmd == CMD_CONFIG_APPLY && errors.is_empty();
+    if apply {
+        info!("config applied, {} changes", changes.len());
+        *tunnel_options = new_options;
+
+        if let Some(servers) = new_servers {
+            selector.set_servers(servers);
+            let selector = selector.clone();
+            task::spawn(async move { selector.probe_all().await });
+        }
+    }
+
+    admin::config_response(&changes, &errors, apply)
+}
+
+#[cfg(feature = "ucp")]
+type UcpOptions = UcpConfig;
+#[cfg(not(feature = "ucp"))]
+struct UcpOptions;
+
+#[cfg(feature = "ucp")]
+fn ucp_options(matches: &getopts::Matches) -> Result<UcpOptions, String> {
+    let mut config = UcpConfig::default();
+
+    if let Some(congestion) = matches.opt_str("ucp-congestion") {
+        config.congestion = congestion.parse()?;
+    }
+
+    if let Some(fec_group) = matches.opt_str("ucp-fec").and_then(|s| s.parse().ok()) {
+        config.fec_group = fec_group;
+    }
+
+    if let Some(size) = matches
+        .opt_str("ucp-max-packet-size")
+        .and_then(|s| s.parse().ok())
+    {
+        config.max_packet_size = size;
+    }
+
+    config.encrypt = matches.opt_present("ucp-encrypt");
+
+    for option in matches.opt_strs("ucp-set") {
+        config.set(&option)?;
+    }
+
+    Ok(config)
+}
+
+#[cfg(feature = "ucp")]
+fn new_ucp_tunnel(
+    tid: u32,
+    selector: Arc<ServerSelector>,
+    key: Vec<u8>,
+    options: &UcpOptions,
+) -> Option<Tunnel> {
+    Some(UcpTunnel::new(tid, selector, key, options.clone()))
+}
+
+#[cfg(not(feature = "ucp"))]
+fn new_ucp_tunnel(
+    _tid: u32,
+    _selector: Arc<ServerSelector>,
+    _key: Vec<u8>,
+    _options: &UcpOptions,
+) -> Option<Tunnel> {
+    None
+}
+
+// A tunnel on a draining server is replaced right away. One older than
+// `max_age` keeps taking ports until its replacement has connected, then
+// closes once the ports it has have finished.
+fn maintain_tunnel<F>(
+    tunnel: &mut Tunnel,
+    replacement: &mut Option<Tunnel>,
+    max_age: Option<Duration>,
+    new_tunnel: F,
+) where
+    F: FnOnce() -> Option<Tunnel>,
+{
+    let retire = tunnel.should_retire();
+    let expired = max_age.is_some_and(|max_age| tunnel.age().is_some_and(|age| age >= max_age));
+    if !retire && !expired {
+        *replacement = None;
+        return;
+    }
+
+    if replacement.is_none() {
+        if expired {
+            info!("tunnel reached its max age, replacing it");
+        }
+        *replacement = new_tunnel();
+    }
+
+    if retire || replacement.as_ref().is_some_and(|t| t.is_connected()) {
+        if let Some(new_tunnel) = replacement.take() {
+            let old_tunnel = std::mem::replace(tunnel, new_tunnel);
+            task::spawn(old_tunnel.retire());
+        }
+    }
+}
+
+// Moves the SOCKS listener to a changed listen address once it binds,
+// the old one serves until then.
+async fn rebind_listener(
+    listener: &mut TcpListener,
+    listen_addr: &mut String,
+    new_addr: &str,
+    backoff: &mut Backoff,
+) {
+    if new_addr == listen_addr || !backoff.is_due() {
+        return;
+    }
+
+    let result = TcpListener::bind(new_addr).await;
+    listener::report("socks5", new_addr, &result);
+
+    match result {
+        Ok(new_listener) => {
+            info!("socks5 moved from {}", listen_addr);
+            *listener = new_listener;
+            *listen_addr = new_addr.to_string();
+            *backoff = Backoff::default();
+        }
+        Err(e) => {
+            let delay = backoff.failed();
+            error!(
+                "socks5 bind {} error: {}, retrying in {}s",
+                new_addr,
+                e,
+                delay.as_secs()
+            );
+        }
+    }
+}
+
+// Stripes the port opened on tunnels[index] over ports of the other
+// tunnels to the same server, up to `bond` tunnels in all.
+async fn bond_port(
+    tunnels: &mut [Tunnel],
+    index: usize,
+    bond: usize,
+    write_port: &mut TunnelWritePort,
+    read_port: &mut TunnelReadPort,
+) {
+    if !tunnels[index].can_bond() {
+        return;
+    }
+
+    let server = tunnels[index].server();
+    let mut members = Vec::new();
+    for (i, tunnel) in tunnels.iter_mut().enumerate() {
+        if members.len() + 1 >= bond {
+            break;
+        }
+        if i != index && tunnel.can_bond() && tunnel.server() == server {
+            members.push(tunnel.open_port().await);
+        }
+    }
+
+    write_port.bond(read_port, members).await;
+}
+
+// Lets the ports open on the tunnels finish, up to `timeout`, telling the
+// servers the tunnels are closing. Tunnels that aren't connected have no
+// ports and close right away.
+async fn shut_down(tunnels: Vec<Tunnel>, timeout: Duration) {
+    let ports: usize = tunnels.iter().map(|t| t.open_ports()).sum();
+    info!(
+        "shutting down, waiting up to {}s for {} open ports",
+        timeout.as_secs(),
+        ports
+    );
+
+    let retiring = tunnels
+        .into_iter()
+        .filter(|tunnel| tunnel.is_connected())
+        .map(|tunnel| tunnel.retire());
+    if future::timeout(timeout, join_all(retiring)).await.is_err() {
+        info!("shutdown timed out, closing the ports left");
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn run_tunnels(
+    selector: Arc<ServerSelector>,
     count: u32,
+    bond: usize,
+    mut balancer: Balancer,
     key: Vec<u8>,
-    enable_ucp: bool,
+    ucp_options: Option<UcpOptions>,
+    tunnel_options: Arc<Mutex<TunnelOptions>>,
+    tunnel_table: TunnelTable,
 ) {
     task::block_on(async move {
+        if selector.server_count() > 1 {
+            selector.probe_all().await;
+        }
+
+        let interval = Duration::from_millis(PROBE_INTERVAL_MS);
+        ServerSelector::start_probing(selector.clone(), interval);
+
+        // With ucp enabled the tcp tunnels serve as fallback while the
+        // ucp tunnel is degraded.
         let mut tunnels = Vec::new();
-        if enable_ucp {
-            let tunnel = UcpTunnel::new(0, server_addr.clone(), key.clone());
+        for i in 0..count {
+            let tunnel = TcpTunnel::new(i, selector.clone(), key.clone());
             tunnels.push(tunnel);
-        } else {
-            for i in 0..count {
-                let tunnel = TcpTunnel::new(i, server_addr.clone(), key.clone());
-                tunnels.push(tunnel);
-            }
         }
 
+        let mut ucp_tunnel = ucp_options
+            .as_ref()
+            .and_then(|options| new_ucp_tunnel(count, selector.clone(), key.clone(), options));
+
+        let mut replacements: Vec<Option<Tunnel>> = tunnels.iter().map(|_| None).collect();
+        let mut ucp_replacement = None;
+
         let mut index = 0;
-        let listener = TcpListener::bind(listen_addr.as_str()).await.unwrap();
-        let mut incoming = listener.incoming();
+        info!("new ports balanced {} over tcp tunnels", balancer.balance());
+        let mut next_tid = count + 1;
+        let mut degraded = false;
+        let interval = Duration::from_millis(TUNNEL_MAINTENANCE_INTERVAL_MS);
+        let mut listen_addr = tunnel_options.lock().unwrap().listen_addr.clone();
+        let mut listener = listener::bind_tcp("socks5", &listen_addr).await;
+        let mut rebind_backoff = Backoff::default();
+        let mut accept_backoff = AcceptBackoff::default();
+
+        loop {
+            if shutdown::is_requested() {
+                drop(listener);
+                let timeout = tunnel_options.lock().unwrap().shutdown_timeout;
+                tunnels.extend(ucp_tunnel);
+                shut_down(tunnels, timeout).await;
+                return;
+            }
+
+            let stream = match future::timeout(interval, listener.accept()).await {
+                Ok(Ok((stream, _))) => {
+                    accept_backoff.accepted();
+                    Some(stream)
+                }
+                Ok(Err(e)) => {
+                    task::sleep(accept_backoff.failed("socks5", &e)).await;
+                    None
+                }
+                Err(_) => None,
+            };
+
+            let TunnelOptions {
+                listen_addr: new_listen_addr,
+                port_idle_timeout: idle_timeout,
+                max_age,
+                queue_limits,
+                ..
+            } = tunnel_options.lock().unwrap().clone();
+
+            rebind_listener(
+                &mut listener,
+                &mut listen_addr,
+                &new_listen_addr,
+                &mut rebind_backoff,
+            )
+            .await;
+
+            for (tunnel, replacement) in tunnels.iter_mut().zip(replacements.iter_mut()) {
+                maintain_tunnel(tunnel, replacement, max_age, || {
+                    next_tid += 1;
+                    Some(TcpTunnel::new(next_tid - 1, selector.clone(), key.clone()))
+                });
+            }
+
+            if let Some(tunnel) = ucp_tunnel.as_mut() {
+                maintain_tunnel(tunnel, &mut ucp_replacement, max_age, || {
+                    next_tid += 1;
+                    let options = ucp_options.as_ref()?;
+                    new_ucp_tunnel(next_tid - 1, selector.clone(), key.clone(), options)
+                });
+            }
+
+            let mut table: Vec<Value> = tunnels.iter().map(|t| tunnel_status("tcp", t)).collect();
+            table.extend(ucp_tunnel.iter().map(|t| tunnel_status("ucp", t)));
+            *tunnel_table.lock().unwrap() = table;
 
-        while let Some(stream) = incoming.next().await {
             match stream {
-                Ok(stream) => {
-                    {
-                        let tunnel: &mut Tunnel = tunnels.get_mut(index).unwrap();
-                        let (write_port, read_port) = tunnel.open_port().await;
-                        task::spawn(async move {
-                            run_tunnel_port(stream, read_port, write_port).await;
-                        });
+                Some(stream) => {
+                    let tunnel: &mut Tunnel = match ucp_tunnel {
+                        Some(ref mut tunnel) => {
+                            if tunnel.is_degraded() != degraded {
+                                degraded = tunnel.is_degraded();
+                                info!(
+                                    "ucp tunnel quality {}, {} tcp tunnels",
+                                    tunnel.quality(),
+                                    if degraded { "switch to" } else { "leave" }
+                                );
+                            }
+
+                            if degraded {
+                                index = balancer.pick(&tunnels);
+                                tunnels.get_mut(index).unwrap()
+                            } else {
+                                tunnel
+                            }
+                        }
+
+                        None => {
+                            index = balancer.pick(&tunnels);
+                            tunnels.get_mut(index).unwrap()
+                        }
+                    };
+
+                    let (mut write_port, mut read_port) = tunnel.open_port().await;
+                    if bond > 1 && (ucp_tunnel.is_none() || degraded) {
+                        bond_port(&mut tunnels, index, bond, &mut write_port, &mut read_port).await;
                     }
 
-                    index = (index + 1) % tunnels.len();
+                    task::spawn(async move {
+                        run_tunnel_port(stream, read_port, write_port, idle_timeout, queue_limits)
+                            .await;
+                    });
                 }
 
-                Err(_) => {}
+                _ => {}
             }
         }
     });
 }
 
+fn client_status(selector: &ServerSelector, tunnel_table: &TunnelTable) -> Vec<(String, Value)> {
+    let servers = selector
+        .status()
+        .into_iter()
+        .map(|(addr, rtt, draining, failures)| {
+            Value::Map(vec![
+                ("addr".to_string(), Value::Str(addr)),
+                (
+                    "rtt_ms".to_string(),
+                    rtt.map_or(Value::Nil, |rtt| Value::UInt(rtt.as_millis() as u64)),
+                ),
+                ("draining".to_string(), Value::Bool(draining)),
+                ("failures".to_string(), Value::UInt(failures as u64)),
+            ])
+        })
+        .collect();
+
+    vec![
+        ("servers".to_string(), Value::Array(servers)),
+        ("selected".to_string(), Value::Str(selector.best())),
+        (
+            "shutting_down".to_string(),
+            Value::Bool(shutdown::is_requested()),
+        ),
+        ("reaped_ports".to_string(), Value::UInt(reaped_port_count())),
+        (
+            "tunnels".to_string(),
+            Value::Array(tunnel_table.lock().unwrap().clone()),
+        ),
+        #[cfg(feature = "ucp")]
+        (
+            "ucp_recv_dropped".to_string(),
+            Value::UInt(ucp::recv_dropped_packets()),
+        ),
+        ("buffer_pool".to_string(), {
+            let pool = buffer::stats();
+            Value::Map(vec![
+                ("buffers".to_string(), Value::UInt(pool.buffers as u64)),
+                ("bytes".to_string(), Value::UInt(pool.bytes as u64)),
+                ("reused".to_string(), Value::UInt(pool.reused)),
+                ("allocated".to_string(), Value::UInt(pool.allocated)),
+            ])
+        }),
+        #[cfg(feature = "ucp")]
+        ("ucp_packet_pool".to_string(), {
+            let pool = ucp::packet_pool_stats();
+            Value::Map(vec![
+                ("buffers".to_string(), Value::UInt(pool.buffers as u64)),
+                ("bytes".to_string(), Value::UInt(pool.bytes as u64)),
+                ("reused".to_string(), Value::UInt(pool.reused)),
+                ("allocated".to_string(), Value::UInt(pool.allocated)),
+            ])
+        }),
+    ]
+}
+
 fn main() {
     let args: Vec<_> = env::args().collect();
     let program = args[0].clone();
 
     let mut opts = getopts::Options::new();
-    opts.reqopt("s", "server", "server address", "server-address");
+    opts.optmulti(
+        "s",
+        "server",
+        "server address, repeatable",
+        "server-address",
+    );
     opts.reqopt("k", "key", "secret key", "key");
     opts.optopt("c", "tunnel-count", "tunnel count", "tunnel-count");
     opts.optopt("l", "listen", "listen address", "listen-address");
     opts.optopt("", "log", "log path", "log-path");
+    opts.optopt("", "admin", "admin listen address", "admin-address");
+    opts.optflagopt(
+        "",
+        "status-page",
+        &format!(
+            "serve a status web page, on {} by default",
+            DEFAULT_STATUS_PAGE_ADDR
+        ),
+        "listen-address",
+    );
+    opts.optflag(
+        "",
+        "doctor",
+        "test the environment and the servers, print a report and exit",
+    );
+    #[cfg(feature = "ucp")]
     opts.optflag("", "enable-ucp", "enable ucp");
+    #[cfg(feature = "ucp")]
+    opts.optopt(
+        "",
+        "ucp-congestion",
+        "ucp congestion control: fixed, reno, cubic or bbr",
+        "algorithm",
+    );
+    #[cfg(feature = "ucp")]
+    opts.optopt(
+        "",
+        "ucp-fec",
+        "ucp parity packet per group of data packets, 0 disables",
+        "group-size",
+    );
+    #[cfg(feature = "ucp")]
+    opts.optopt(
+        "",
+        "ucp-max-packet-size",
+        "largest ucp datagram probed on the path, 576 to 9000",
+        "bytes",
+    );
+    #[cfg(feature = "ucp")]
+    opts.optflag("", "ucp-encrypt", "encrypt ucp packet headers and payloads");
+    #[cfg(feature = "ucp")]
+    opts.optmulti(
+        "",
+        "ucp-set",
+        "ucp tuning option such as window=1024 or heartbeat=5000, repeatable",
+        "name=value",
+    );
+    opts.optopt(
+        "",
+        "tunnel-max-age",
+        "replace tunnel connections older than this, 0 keeps them",
+        "seconds",
+    );
+    opts.optopt(
+        "",
+        "port-idle-timeout",
+        "tunnel port idle timeout in milliseconds",
+        "milliseconds",
+    );
+    opts.optopt(
+        "",
+        "port-connect-timeout",
+        "milliseconds the server may take to connect a tunnel port before it is closed",
+        "milliseconds",
+    );
+    opts.optmulti(
+        "",
+        "queue-limit",
+        "bytes queued to a tunnel before interactive or bulk ports stop reading, repeatable",
+        "class=bytes",
+    );
+    opts.optflag("", "compress", "compress tunnel data with lz4");
+    opts.optopt(
+        "",
+        "missed-heartbeats",
+        "reconnect tcp tunnels whose server missed answering more heartbeats, 0 to disable",
+        "count",
+    );
+    opts.optflag(
+        "",
+        "failback",
+        "prefer the servers in the order given, moving tunnels back to the first healthy one",
+    );
+    opts.optopt(
+        "",
+        "bond",
+        "stripe each port over this many tcp tunnels to the same server",
+        "tunnels",
+    );
+    opts.optopt(
+        "",
+        "shutdown-timeout",
+        "seconds open ports may take to finish on SIGINT, SIGTERM or the admin shutdown",
+        "seconds",
+    );
+    opts.optopt(
+        "",
+        "balance",
+        "how new ports pick a tcp tunnel: round-robin, least-ports, lowest-rtt or weighted",
+        "strategy",
+    );
+    opts.optmulti(
+        "",
+        "weight",
+        "weight of a server for --balance weighted, repeatable",
+        "server=weight",
+    );
 
     let matches = match opts.parse(&args[1..]) {
+        Ok(ref m) if !m.opt_present("s") => {
+            println!("{}", opts.short_usage(&program));
+            return;
+        }
         Ok(m) => m,
         Err(_) => {
             println!("{}", opts.short_usage(&program));
@@ -183,12 +941,76 @@ fn main() {
         }
     };
 
-    let server_addr = matches.opt_str("s").unwrap();
+    let server_addrs = matches.opt_strs("s");
     let tunnel_count = matches.opt_str("c").unwrap_or(String::new());
     let key = matches.opt_str("k").unwrap().into_bytes();
     let log_path = matches.opt_str("log").unwrap_or(String::new());
-    let enable_ucp = matches.opt_present("enable-ucp");
+    let admin_addr = matches.opt_str("admin");
+    let status_page_addr = matches.opt_default("status-page", DEFAULT_STATUS_PAGE_ADDR);
+    let enable_ucp = cfg!(feature = "ucp") && matches.opt_present("enable-ucp");
+    #[cfg(feature = "ucp")]
+    let ucp_options = match ucp_options(&matches) {
+        Ok(options) => options,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    #[cfg(not(feature = "ucp"))]
+    let ucp_options = UcpOptions;
     let listen_addr = matches.opt_str("l").unwrap_or("127.0.0.1:1080".to_string());
+    let idle_timeout = matches
+        .opt_str("port-idle-timeout")
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_PORT_IDLE_TIMEOUT_MS);
+    let max_age = matches
+        .opt_str("tunnel-max-age")
+        .and_then(|s| s.parse().ok())
+        .filter(|&secs| secs > 0)
+        .map(Duration::from_secs);
+    let shutdown_timeout = matches
+        .opt_str("shutdown-timeout")
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_SECS);
+    let mut queue_limits = QueueLimits::default();
+    for option in matches.opt_strs("queue-limit") {
+        if let Err(e) = queue_limits.set(&option) {
+            println!("{}", e);
+            return;
+        }
+    }
+    set_compress(matches.opt_present("compress"));
+    set_port_connect_timeout(Duration::from_millis(
+        matches
+            .opt_str("port-connect-timeout")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(DEFAULT_PORT_CONNECT_TIMEOUT_MS),
+    ));
+    set_missed_heartbeats(
+        matches
+            .opt_str("missed-heartbeats")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(DEFAULT_MISSED_HEARTBEATS),
+    );
+    let bond = matches
+        .opt_str("bond")
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(1);
+    let balance = match matches.opt_str("balance").map(|s| s.parse()) {
+        Some(Ok(balance)) => balance,
+        Some(Err(e)) => {
+            println!("{}", e);
+            return;
+        }
+        None => Balance::RoundRobin,
+    };
+    let mut balancer = Balancer::new(balance);
+    for option in matches.opt_strs("weight") {
+        if let Err(e) = balancer.set_weight(&option) {
+            println!("{}", e);
+            return;
+        }
+    }
     let (min, max) = Cryptor::key_size_range();
 
     if key.len() < min || key.len() > max {
@@ -196,13 +1018,77 @@ fn main() {
         return;
     }
 
+    if matches.opt_present("doctor") {
+        let checks = task::block_on(doctor::run(&server_addrs, &key, enable_ucp));
+        print!("{}", doctor::report(&checks));
+        std::process::exit(if checks.iter().all(|c| c.ok) { 0 } else { 1 });
+    }
+
     let count: u32 = match tunnel_count.parse() {
         Err(_) | Ok(0) => 1,
         Ok(count) => count,
     };
 
     logger::init(log::Level::Info, log_path, 1, 2000000).unwrap();
-    info!("starting up");
+    info!(
+        "starting up, version {} on {} {}",
+        env!("CARGO_PKG_VERSION"),
+        std::env::consts::OS,
+        std::env::consts::ARCH
+    );
+    shutdown::handle_signals();
+
+    let selector = ServerSelector::new(server_addrs, key.clone());
+    selector.set_failback(matches.opt_present("failback"));
+
+    let tunnel_options = Arc::new(Mutex::new(TunnelOptions {
+        listen_addr,
+        port_idle_timeout: Duration::from_millis(idle_timeout),
+        max_age,
+        queue_limits,
+        shutdown_timeout: Duration::from_secs(shutdown_timeout),
+    }));
+
+    let tunnel_table = TunnelTable::default();
+    let stats = if admin_addr.is_some() || status_page_addr.is_some() {
+        Some(AdminStats::new("client"))
+    } else {
+        None
+    };
+
+    if let (Some(status_page_addr), Some(stats)) = (status_page_addr, stats.clone()) {
+        let selector = selector.clone();
+        let tunnel_table = tunnel_table.clone();
+        task::spawn(admin::serve_status_page(status_page_addr, move || {
+            stats.status(client_status(&selector, &tunnel_table))
+        }));
+    }
+
+    if let (Some(admin_addr), Some(stats)) = (admin_addr, stats) {
+        let selector = selector.clone();
+        let tunnel_table = tunnel_table.clone();
+        let tunnel_options = tunnel_options.clone();
+        task::spawn(admin::serve(admin_addr, move |cmd, document| match cmd {
+            CMD_STATUS => stats.status(client_status(&selector, &tunnel_table)),
+            CMD_SHUTDOWN => {
+                shutdown::request();
+                stats.status(client_status(&selector, &tunnel_table))
+            }
+            CMD_CONFIG_DIFF | CMD_CONFIG_APPLY => {
+                config_command(cmd, document, &tunnel_options, &selector)
+            }
+            _ => admin::unknown_command(),
+        }));
+    }
 
-    run_tunnels(listen_addr, server_addr, count, key, enable_ucp);
+    run_tunnels(
+        selector,
+        count,
+        bond,
+        balancer,
+        key,
+        Some(ucp_options).filter(|_| enable_ucp),
+        tunnel_options,
+        tunnel_table,
+    );
 }
@@ -5,15 +5,151 @@ extern crate getopts;
 extern crate stunnel;
 
 use std::env;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use async_std::net::TcpListener;
 use async_std::prelude::*;
 use async_std::task;
 
+use stunnel::admin::{
+    self, AdminStats, ConfigChange, Value, CMD_CONFIG_APPLY, CMD_CONFIG_DIFF, CMD_DRAIN, CMD_STATUS,
+};
+use stunnel::backpressure::QueueLimits;
+use stunnel::buffer;
 use stunnel::cryptor::Cryptor;
+use stunnel::events::{self, PortEvent};
+use stunnel::hook::{self, ConnectHook, DEFAULT_HOOK_TIMEOUT_MS};
+use stunnel::listener::{self, AcceptBackoff};
 use stunnel::logger;
 use stunnel::server::*;
-use stunnel::ucp::UcpListener;
+#[cfg(feature = "ucp")]
+use stunnel::ucp::rendezvous::Introducer;
+#[cfg(feature = "ucp")]
+use stunnel::ucp::{self, UcpConfig, UcpListener};
+
+#[cfg(feature = "ucp")]
+fn ucp_config(matches: &getopts::Matches) -> Result<UcpConfig, String> {
+    let mut config = UcpConfig::default();
+
+    if let Some(congestion) = matches.opt_str("ucp-congestion") {
+        config.congestion = congestion.parse()?;
+    }
+
+    if let Some(size) = matches
+        .opt_str("ucp-max-packet-size")
+        .and_then(|s| s.parse().ok())
+    {
+        config.max_packet_size = size;
+    }
+
+    for option in matches.opt_strs("ucp-set") {
+        config.set(&option)?;
+    }
+
+    Ok(config)
+}
+
+// Records a change of `value` to the option's value when it differs.
+fn update_value<T: FromStr + PartialEq + ToString>(
+    name: String,
+    values: &[String],
+    value: &mut T,
+    changes: &mut Vec<ConfigChange>,
+    errors: &mut Vec<String>,
+) {
+    match admin::config_value::<T>(&name, values) {
+        Ok(new) if new != *value => {
+            changes.push(ConfigChange {
+                name,
+                old: value.to_string(),
+                new: new.to_string(),
+            });
+            *value = new;
+        }
+        Ok(_) => {}
+        Err(e) => errors.push(e),
+    }
+}
+
+fn check_handshake_timeout(millis: u64) -> Result<u64, String> {
+    if millis < MIN_HANDSHAKE_TIMEOUT_MS {
+        return Err(format!(
+            "handshake-timeout must be at least {}ms, the heartbeat interval",
+            MIN_HANDSHAKE_TIMEOUT_MS
+        ));
+    }
+    Ok(millis)
+}
+
+// Only the timeouts, open budget and queue limits of new tunnels can
+// change while running.
+fn update_config(
+    config: &TunnelConfig,
+    options: Vec<(String, Vec<String>)>,
+) -> (TunnelConfig, Vec<ConfigChange>, Vec<String>) {
+    let mut new_config = config.clone();
+    let mut changes = Vec::new();
+    let mut errors = Vec::new();
+
+    for (name, values) in options {
+        let timeout = match name.as_str() {
+            "handshake-timeout" => &mut new_config.handshake_timeout,
+            "port-idle-timeout" => &mut new_config.port_idle_timeout,
+            "port-connect-timeout" => &mut new_config.port_connect_timeout,
+            "open-burst" => {
+                let value = &mut new_config.open_burst;
+                update_value(name, &values, value, &mut changes, &mut errors);
+                continue;
+            }
+            "open-rate" => {
+                let value = &mut new_config.open_rate;
+                update_value(name, &values, value, &mut changes, &mut errors);
+                continue;
+            }
+            "queue-limit" => {
+                let mut limits = new_config.queue_limits;
+                match values.iter().try_for_each(|value| limits.set(value)) {
+                    Ok(()) if limits != new_config.queue_limits => {
+                        changes.push(ConfigChange {
+                            name,
+                            old: new_config.queue_limits.to_string(),
+                            new: limits.to_string(),
+                        });
+                        new_config.queue_limits = limits;
+                    }
+                    Ok(()) => {}
+                    Err(e) => errors.push(e),
+                }
+                continue;
+            }
+            _ => {
+                errors.push(format!("{} can't be changed while running", name));
+                continue;
+            }
+        };
+
+        let value = admin::config_value(&name, &values);
+        let value = match name.as_str() {
+            "handshake-timeout" => value.and_then(check_handshake_timeout),
+            _ => value,
+        };
+        match value {
+            Ok(millis) if Duration::from_millis(millis) != *timeout => {
+                changes.push(ConfigChange {
+                    name,
+                    old: timeout.as_millis().to_string(),
+                    new: millis.to_string(),
+                });
+                *timeout = Duration::from_millis(millis);
+            }
+            Ok(_) => {}
+            Err(e) => errors.push(e),
+        }
+    }
+
+    (new_config, changes, errors)
+}
 
 fn main() {
     let args: Vec<_> = env::args().collect();
@@ -23,7 +159,86 @@ fn main() {
     opts.reqopt("l", "listen", "listen address", "listen-address");
     opts.reqopt("k", "key", "secret key", "key");
     opts.optopt("", "log", "log path", "log-path");
+    opts.optopt("", "admin", "admin listen address", "admin-address");
+    #[cfg(feature = "ucp")]
     opts.optflag("", "enable-ucp", "enable ucp");
+    #[cfg(feature = "ucp")]
+    opts.optopt(
+        "",
+        "ucp-congestion",
+        "ucp congestion control: fixed, reno, cubic or bbr",
+        "algorithm",
+    );
+    #[cfg(feature = "ucp")]
+    opts.optopt(
+        "",
+        "ucp-max-packet-size",
+        "largest ucp datagram probed on the path, 576 to 9000",
+        "bytes",
+    );
+    #[cfg(feature = "ucp")]
+    opts.optopt(
+        "",
+        "ucp-introducer",
+        "address to introduce ucp peers behind nats to each other on",
+        "listen-address",
+    );
+    #[cfg(feature = "ucp")]
+    opts.optmulti(
+        "",
+        "ucp-set",
+        "ucp tuning option such as window=1024 or heartbeat=5000, repeatable",
+        "name=value",
+    );
+    opts.optopt(
+        "",
+        "handshake-timeout",
+        "handshake timeout in milliseconds, at least the 5000 of the heartbeat",
+        "milliseconds",
+    );
+    opts.optopt(
+        "",
+        "port-idle-timeout",
+        "tunnel port idle timeout in milliseconds",
+        "milliseconds",
+    );
+    opts.optopt(
+        "",
+        "port-connect-timeout",
+        "milliseconds a tunnel port may take to connect before it is closed",
+        "milliseconds",
+    );
+    opts.optopt(
+        "",
+        "open-burst",
+        "ports a client address may open at once, 0 for no limit",
+        "ports",
+    );
+    opts.optopt(
+        "",
+        "open-rate",
+        "ports per second refilling the open burst, 10 by default",
+        "ports",
+    );
+    opts.optmulti(
+        "",
+        "queue-limit",
+        "bytes queued to a tunnel before interactive or bulk ports stop reading, repeatable",
+        "class=bytes",
+    );
+    opts.optopt(
+        "",
+        "connect-hook",
+        "shell command that allows, denies or rewrites each destination",
+        "command",
+    );
+    opts.optopt(
+        "",
+        "connect-hook-timeout",
+        "milliseconds the connect hook may take before denying, 1000 by default",
+        "milliseconds",
+    );
+    opts.optflag("", "disable-udp", "refuse the udp ports of clients");
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
@@ -36,7 +251,70 @@ fn main() {
     let listen_addr = matches.opt_str("l").unwrap();
     let key = matches.opt_str("k").unwrap().into_bytes();
     let log_path = matches.opt_str("log").unwrap_or(String::new());
+    let admin_addr = matches.opt_str("admin");
+    #[cfg(feature = "ucp")]
     let enable_ucp = matches.opt_present("enable-ucp");
+    #[cfg(feature = "ucp")]
+    let introducer_addr = matches.opt_str("ucp-introducer");
+    #[cfg(feature = "ucp")]
+    let ucp_config = match ucp_config(&matches) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    let handshake_timeout = matches
+        .opt_str("handshake-timeout")
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_HANDSHAKE_TIMEOUT_MS);
+    if let Err(e) = check_handshake_timeout(handshake_timeout) {
+        println!("{}", e);
+        return;
+    }
+    let port_idle_timeout = matches
+        .opt_str("port-idle-timeout")
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_PORT_IDLE_TIMEOUT_MS);
+    let port_connect_timeout = matches
+        .opt_str("port-connect-timeout")
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_PORT_CONNECT_TIMEOUT_MS);
+    let open_burst = matches
+        .opt_str("open-burst")
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(0);
+    let open_rate = matches
+        .opt_str("open-rate")
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_OPEN_RATE);
+    let mut queue_limits = QueueLimits::default();
+    for option in matches.opt_strs("queue-limit") {
+        if let Err(e) = queue_limits.set(&option) {
+            println!("{}", e);
+            return;
+        }
+    }
+    let connect_hook = matches.opt_str("connect-hook").map(|command| {
+        let mut hook = ConnectHook::new(command);
+        hook.timeout = Duration::from_millis(
+            matches
+                .opt_str("connect-hook-timeout")
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(DEFAULT_HOOK_TIMEOUT_MS),
+        );
+        Arc::new(hook)
+    });
+    let config = Arc::new(Mutex::new(TunnelConfig {
+        handshake_timeout: Duration::from_millis(handshake_timeout),
+        port_idle_timeout: Duration::from_millis(port_idle_timeout),
+        port_connect_timeout: Duration::from_millis(port_connect_timeout),
+        open_burst,
+        open_rate,
+        queue_limits,
+        connect_hook,
+        udp_relay: !matches.opt_present("disable-udp"),
+    }));
     let (min, max) = Cryptor::key_size_range();
 
     if key.len() < min || key.len() > max {
@@ -47,30 +325,173 @@ fn main() {
     logger::init(log::Level::Info, log_path, 1, 2000000).unwrap();
     info!("starting up");
 
+    if let Some(admin_addr) = admin_addr {
+        let stats = AdminStats::new("server");
+        let config = config.clone();
+        task::spawn(admin::serve(admin_addr, move |cmd, document| {
+            match cmd {
+                CMD_STATUS => {}
+                CMD_DRAIN => start_draining(),
+                CMD_CONFIG_DIFF | CMD_CONFIG_APPLY => {
+                    let mut config = config.lock().unwrap();
+                    let (new_config, changes, errors) = match admin::config_options(document) {
+                        Ok(options) => update_config(&config, options),
+                        Err(e) => (config.clone(), Vec::new(), vec![e]),
+                    };
+
+                    let apply = cmd == CMD_CONFIG_APPLY && errors.is_empty();
+                    if apply {
+                        info!("config applied, {} changes", changes.len());
+                        *config = new_config;
+                    }
+
+                    return admin::config_response(&changes, &errors, apply);
+                }
+                _ => return admin::unknown_command(),
+            }
+
+            stats.status(vec![
+                (
+                    "handshake_timeouts".to_string(),
+                    Value::UInt(handshake_timeout_count() as u64),
+                ),
+                (
+                    "reaped_ports".to_string(),
+                    Value::UInt(reaped_port_count() as u64),
+                ),
+                ("draining".to_string(), Value::Bool(is_draining())),
+                ("shedding".to_string(), Value::Bool(is_shedding())),
+                (
+                    "throttled_opens".to_string(),
+                    Value::UInt(throttled_open_count() as u64),
+                ),
+                (
+                    "hook_denied".to_string(),
+                    Value::UInt(hook::denied_connect_count() as u64),
+                ),
+                #[cfg(feature = "ucp")]
+                (
+                    "ucp_recv_dropped".to_string(),
+                    Value::UInt(ucp::recv_dropped_packets()),
+                ),
+                ("buffer_pool".to_string(), {
+                    let pool = buffer::stats();
+                    Value::Map(vec![
+                        ("buffers".to_string(), Value::UInt(pool.buffers as u64)),
+                        ("bytes".to_string(), Value::UInt(pool.bytes as u64)),
+                        ("reused".to_string(), Value::UInt(pool.reused)),
+                        ("allocated".to_string(), Value::UInt(pool.allocated)),
+                    ])
+                }),
+                #[cfg(feature = "ucp")]
+                ("ucp_packet_pool".to_string(), {
+                    let pool = ucp::packet_pool_stats();
+                    Value::Map(vec![
+                        ("buffers".to_string(), Value::UInt(pool.buffers as u64)),
+                        ("bytes".to_string(), Value::UInt(pool.bytes as u64)),
+                        ("reused".to_string(), Value::UInt(pool.reused)),
+                        ("allocated".to_string(), Value::UInt(pool.allocated)),
+                    ])
+                }),
+                #[cfg(feature = "ucp")]
+                (
+                    "ucp_refused_syns".to_string(),
+                    Value::UInt(ucp::refused_syns()),
+                ),
+                #[cfg(feature = "ucp")]
+                ("ucp_stale_syns".to_string(), Value::UInt(ucp::stale_syns())),
+                #[cfg(feature = "ucp")]
+                (
+                    "ucp_sent_resets".to_string(),
+                    Value::UInt(ucp::sent_resets()),
+                ),
+                (
+                    "client_versions".to_string(),
+                    Value::Map(
+                        client_versions()
+                            .into_iter()
+                            .map(|(version, count)| (version, Value::UInt(count)))
+                            .collect(),
+                    ),
+                ),
+                (
+                    "tunnel_features".to_string(),
+                    Value::Map(
+                        tunnel_features()
+                            .into_iter()
+                            .map(|(features, count)| (features, Value::UInt(count)))
+                            .collect(),
+                    ),
+                ),
+            ])
+        }));
+    }
+
+    events::add_handler(Box::new(|event: &PortEvent| {
+        if let PortEvent::Closed {
+            tunnel,
+            id,
+            host,
+            port,
+            reason,
+            uploaded,
+            downloaded,
+        } = event
+        {
+            info!(
+                "{}.{}: {}:{} closed ({:?}), up {} bytes, down {} bytes",
+                tunnel, id, host, port, reason, uploaded, downloaded
+            );
+        }
+    }));
+
+    #[cfg(feature = "ucp")]
     if enable_ucp {
         let k = key.clone();
         let addr = listen_addr.clone();
+        let c = config.clone();
         task::spawn(async move {
-            let mut listener = UcpListener::bind(&addr).await;
+            let mut listener = listener::bind("ucp", &addr, || {
+                UcpListener::bind_with_config(&addr, ucp_config.clone())
+            })
+            .await;
+            listener.set_auth_key(&k);
 
             loop {
                 let stream = listener.incoming().await;
-                UcpTunnel::new(k.clone(), stream);
+                let config = c.lock().unwrap().clone();
+                UcpTunnel::new(k.clone(), stream, config);
             }
         });
     }
 
+    #[cfg(feature = "ucp")]
+    if let Some(addr) = introducer_addr {
+        task::spawn(async move {
+            let introducer = listener::bind("introducer", &addr, || Introducer::bind(&addr)).await;
+            introducer.run().await;
+        });
+    }
+
     task::block_on(async move {
-        let listener = TcpListener::bind(&listen_addr).await.unwrap();
+        let listener = listener::bind_tcp("tunnel", &listen_addr).await;
         let mut incoming = listener.incoming();
+        let mut backoff = AcceptBackoff::default();
 
         while let Some(stream) = incoming.next().await {
             match stream {
                 Ok(stream) => {
-                    TcpTunnel::new(key.clone(), stream);
+                    backoff.accepted();
+                    let config = config.lock().unwrap().clone();
+                    TcpTunnel::new(key.clone(), stream, config);
                 }
 
-                Err(_) => {}
+                Err(e) => {
+                    if listener::is_fd_exhaustion(&e) {
+                        shed_idle_ports();
+                    }
+                    task::sleep(backoff.failed("tunnel", &e)).await;
+                }
             }
         }
     });
@@ -0,0 +1,100 @@
+// A bonded port stripes its data over ports of several tunnels to the
+// same server, so a network throttling each connection carries it at
+// the sum of their rates. Each member port joins the bond with its key
+// and index, data frames carry a sequence number, and the other end
+// puts them back in order.
+use std::collections::BTreeMap;
+
+use rand::random;
+
+use super::backpressure::PORT_WINDOW;
+use super::buffer::DataBuf;
+
+pub const BOND_KEY_SIZE: usize = 16;
+pub const MAX_BOND_MEMBERS: usize = 8;
+// How long the server keeps the members of a bond waiting for the rest.
+pub const BOND_JOIN_TIMEOUT_MS: u64 = 10000;
+
+pub fn new_key() -> [u8; BOND_KEY_SIZE] {
+    random()
+}
+
+// The data of JOIN_BOND: the key of the bond, the index of the member
+// and the number of members.
+pub fn join_data(key: &[u8; BOND_KEY_SIZE], index: usize, count: usize) -> Vec<u8> {
+    let mut data = key.to_vec();
+    data.push(index as u8);
+    data.push(count as u8);
+    data
+}
+
+pub fn parse_join(data: &[u8]) -> Option<(&[u8], usize, usize)> {
+    if data.len() != BOND_KEY_SIZE + 2 {
+        return None;
+    }
+
+    let index = data[BOND_KEY_SIZE] as usize;
+    let count = data[BOND_KEY_SIZE + 1] as usize;
+    if !(2..=MAX_BOND_MEMBERS).contains(&count) || index >= count {
+        return None;
+    }
+    Some((&data[..BOND_KEY_SIZE], index, count))
+}
+
+// The data a bond received out of order, with the member it came on, so
+// the member is granted credit once its data is passed on. The windows
+// of the members bound what waits, more means the other end ignores
+// them.
+pub struct Reassembly {
+    next: u64,
+    pending: BTreeMap<u64, (usize, DataBuf)>,
+    pending_bytes: usize,
+    shut: Vec<bool>,
+}
+
+impl Reassembly {
+    pub fn new(members: usize) -> Reassembly {
+        Reassembly {
+            next: 0,
+            pending: BTreeMap::new(),
+            pending_bytes: 0,
+            shut: vec![false; members],
+        }
+    }
+
+    // False when the data can't be right: sent before, or more than the
+    // windows allow.
+    pub fn push(&mut self, seq: u64, member: usize, buf: DataBuf) -> bool {
+        if seq < self.next || self.pending.contains_key(&seq) {
+            return false;
+        }
+
+        self.pending_bytes += buf.len();
+        self.pending.insert(seq, (member, buf));
+        self.pending_bytes <= self.shut.len() * PORT_WINDOW
+    }
+
+    // The next data in order with its member.
+    pub fn pop(&mut self) -> Option<(usize, DataBuf)> {
+        let (member, buf) = self.pending.remove(&self.next)?;
+        self.next += 1;
+        self.pending_bytes -= buf.len();
+        Some((member, buf))
+    }
+
+    // The member sends no more data.
+    pub fn shut(&mut self, member: usize) {
+        if let Some(shut) = self.shut.get_mut(member) {
+            *shut = true;
+        }
+    }
+
+    pub fn is_shut(&self, member: usize) -> bool {
+        self.shut.get(member).copied().unwrap_or(true)
+    }
+
+    // Every member shut and all their data was passed on.
+    pub fn finished(&self) -> bool {
+        self.pending.is_empty() && self.shut.iter().all(|&shut| shut)
+    }
+}
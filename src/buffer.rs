@@ -0,0 +1,162 @@
+// Buffers of the data path: the data a port reads from its socket and
+// the data frames of the tunnel, read and written. Like the packet pool
+// of ucp, a buffer is taken from the pool of the thread making it and
+// given back to the pool of the thread dropping it, so forwarding at a
+// steady rate allocates none.
+use std::cell::RefCell;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use super::protocol::MAX_DATA_FRAME_SIZE;
+
+// Capacities buffers are pooled by: what a port reads at once with the
+// header of its frame, and a data frame of the largest size.
+const CLASSES: [usize; 2] = [2048, MAX_DATA_FRAME_SIZE + 64];
+
+// Bytes of free buffers a thread keeps of each class at most.
+const MAX_POOLED_BYTES: usize = 4 << 20;
+
+static POOLED_BUFFERS: AtomicUsize = AtomicUsize::new(0);
+static POOLED_BYTES: AtomicUsize = AtomicUsize::new(0);
+static REUSED_BUFFERS: AtomicU64 = AtomicU64::new(0);
+static ALLOCATED_BUFFERS: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    static FREE: RefCell<FreeLists> = RefCell::new(FreeLists::default());
+}
+
+#[derive(Default)]
+struct FreeLists([Vec<Vec<u8>>; CLASSES.len()]);
+
+impl Drop for FreeLists {
+    fn drop(&mut self) {
+        for list in self.0.iter() {
+            POOLED_BUFFERS.fetch_sub(list.len(), Ordering::Relaxed);
+            POOLED_BYTES.fetch_sub(
+                list.iter().map(|buf| buf.capacity()).sum(),
+                Ordering::Relaxed,
+            );
+        }
+    }
+}
+
+// Buffers held for reuse by all threads and their bytes, and how many
+// buffers were taken from the pool instead of allocated.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BufferPoolStats {
+    pub buffers: usize,
+    pub bytes: usize,
+    pub reused: u64,
+    pub allocated: u64,
+}
+
+pub fn stats() -> BufferPoolStats {
+    BufferPoolStats {
+        buffers: POOLED_BUFFERS.load(Ordering::Relaxed),
+        bytes: POOLED_BYTES.load(Ordering::Relaxed),
+        reused: REUSED_BUFFERS.load(Ordering::Relaxed),
+        allocated: ALLOCATED_BUFFERS.load(Ordering::Relaxed),
+    }
+}
+
+// Data of a port, given back to the pool once written to the tunnel or
+// to the socket at the other end.
+pub struct DataBuf(Vec<u8>);
+
+impl DataBuf {
+    // `size` zeroed bytes.
+    pub fn new(size: usize) -> DataBuf {
+        let mut buf = take(size);
+        buf.resize(size, 0);
+        DataBuf(buf)
+    }
+}
+
+impl Clone for DataBuf {
+    fn clone(&self) -> DataBuf {
+        DataBuf::from(&self.0[..])
+    }
+}
+
+impl Drop for DataBuf {
+    fn drop(&mut self) {
+        give(mem::take(&mut self.0));
+    }
+}
+
+impl Deref for DataBuf {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.0
+    }
+}
+
+impl DerefMut for DataBuf {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.0
+    }
+}
+
+impl From<&[u8]> for DataBuf {
+    fn from(data: &[u8]) -> DataBuf {
+        let mut buf = take(data.len());
+        buf.extend_from_slice(data);
+        DataBuf(buf)
+    }
+}
+
+// Kept as it is, and pooled once dropped if it has the capacity of a
+// class.
+impl From<Vec<u8>> for DataBuf {
+    fn from(buf: Vec<u8>) -> DataBuf {
+        DataBuf(buf)
+    }
+}
+
+fn take(size: usize) -> Vec<u8> {
+    let class = match CLASSES.iter().position(|&capacity| size <= capacity) {
+        Some(class) => class,
+        None => {
+            ALLOCATED_BUFFERS.fetch_add(1, Ordering::Relaxed);
+            return Vec::with_capacity(size);
+        }
+    };
+
+    let pooled = FREE
+        .try_with(|free| free.borrow_mut().0[class].pop())
+        .ok()
+        .flatten();
+    match pooled {
+        Some(buf) => {
+            POOLED_BUFFERS.fetch_sub(1, Ordering::Relaxed);
+            POOLED_BYTES.fetch_sub(buf.capacity(), Ordering::Relaxed);
+            REUSED_BUFFERS.fetch_add(1, Ordering::Relaxed);
+            buf
+        }
+        None => {
+            ALLOCATED_BUFFERS.fetch_add(1, Ordering::Relaxed);
+            Vec::with_capacity(CLASSES[class])
+        }
+    }
+}
+
+// Only buffers of a class' capacity are kept, others are freed.
+fn give(mut buf: Vec<u8>) {
+    let capacity = buf.capacity();
+    let class = match CLASSES.iter().position(|&c| c == capacity) {
+        Some(class) => class,
+        None => return,
+    };
+
+    buf.clear();
+    let _ = FREE.try_with(|free| {
+        let list = &mut free.borrow_mut().0[class];
+        if (list.len() + 1) * capacity <= MAX_POOLED_BYTES {
+            list.push(buf);
+            POOLED_BUFFERS.fetch_add(1, Ordering::Relaxed);
+            POOLED_BYTES.fetch_add(capacity, Ordering::Relaxed);
+        }
+    });
+}
@@ -1,66 +1,250 @@
-use std::collections::HashMap;
-use std::net::Shutdown;
+use std::collections::{HashMap, VecDeque};
+use std::iter::once;
+use std::mem;
+use std::net::{Shutdown, SocketAddr};
+use std::str::from_utf8;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use std::vec::Vec;
 
+use async_std::future;
 use async_std::io::{Read, Write};
 use async_std::net::TcpStream;
 use async_std::prelude::*;
-use async_std::task;
+use async_std::task::{self, JoinHandle};
 
 use futures::channel::mpsc::{channel, Receiver, Sender};
+use futures::future::select_all;
 use futures::sink::SinkExt;
 
+use super::backpressure::{
+    PortClass, PortWindow, TunnelQueue, DEFAULT_BULK_QUEUE_LIMIT, PORT_WINDOW_STEP,
+};
+use super::bond::{self, Reassembly, MAX_BOND_MEMBERS};
+use super::buffer::DataBuf;
+use super::compress;
 use super::cryptor::*;
+use super::events::{self, CloseReason, PortEvent, TunnelEvent};
+use super::features::TunnelFeatures;
+use super::listener::Backoff;
 use super::protocol::*;
+use super::resume::{self, PortOffsets, Transfer, SESSION_TOKEN_SIZE};
+use super::selector::ServerSelector;
+use super::stats::TunnelStats;
 use super::timer;
-use super::ucp::UcpStream;
+#[cfg(feature = "ucp")]
+use super::ucp::{ResumeTicket, UcpConfig, UcpStats, UcpStream};
 use super::util::*;
 
+pub const DEFAULT_PORT_IDLE_TIMEOUT_MS: u64 = 300000;
+pub const MAX_TUNNEL_QUALITY: u32 = 1000;
+pub const DEGRADED_TUNNEL_QUALITY: u32 = 800;
+#[cfg(feature = "ucp")]
+const QUALITY_SAMPLE_INTERVAL_MS: u64 = 5000;
+const PORT_STALL_TIMEOUT_MS: u128 = 15000;
+// Heartbeats waiting for their response, more are sent unmeasured.
+const MAX_PENDING_HEARTBEATS: usize = 16;
+pub const DEFAULT_MISSED_HEARTBEATS: u32 = 3;
+// Longer than the servers' own, which answer ports they give up on.
+pub const DEFAULT_PORT_CONNECT_TIMEOUT_MS: u64 = 30000;
+
+static COMPRESS: AtomicBool = AtomicBool::new(false);
+static MISSED_HEARTBEATS: AtomicU32 = AtomicU32::new(DEFAULT_MISSED_HEARTBEATS);
+static PORT_CONNECT_TIMEOUT_MS: AtomicU64 = AtomicU64::new(DEFAULT_PORT_CONNECT_TIMEOUT_MS);
+static REAPED_PORTS: AtomicU64 = AtomicU64::new(0);
+
+// Asks the servers of tunnels connecting from now on to take and send
+// data frames compressed, see compress. Servers from before, or built
+// without it, keep them plain.
+pub fn set_compress(enabled: bool) {
+    COMPRESS.store(enabled, Ordering::Relaxed);
+}
+
+fn compress_asked() -> bool {
+    COMPRESS.load(Ordering::Relaxed) && compress::available()
+}
+
+// TCP tunnels whose server misses answering more heartbeats in a row are
+// taken for dead and reconnect, 0 waits for the alive timeout instead.
+pub fn set_missed_heartbeats(missed: u32) {
+    MISSED_HEARTBEATS.store(missed, Ordering::Relaxed);
+}
+
+// Ports the server hasn't answered the connect of within the timeout are
+// closed at both ends, see PortHub::reap.
+pub fn set_port_connect_timeout(timeout: Duration) {
+    PORT_CONNECT_TIMEOUT_MS.store(timeout.as_millis() as u64, Ordering::Relaxed);
+}
+
+// Ports the tunnels closed for not connecting in time.
+pub fn reaped_port_count() -> u64 {
+    REAPED_PORTS.load(Ordering::Relaxed)
+}
+
 #[derive(Clone)]
 enum TunnelMsg {
-    CSOpenPort(u32, Sender<TunnelPortMsg>),
+    CSOpenPort(u32, Sender<TunnelPortMsg>, Arc<PortWindow>),
     CSConnect(u32, Vec<u8>),
     CSConnectDN(u32, Vec<u8>, u16),
     CSShutdownWrite(u32),
     CSClosePort(u32),
-    CSData(u32, Vec<u8>),
+    CSData(u32, DataBuf),
+    CSWindow(u32, u32),
+    CSOpenUdp(u32, Sender<Vec<u8>>),
+    CSUdpData(u32, Vec<u8>),
+    CSCloseUdp(u32),
+    CSJoinBond(u32, Vec<u8>),
+    CSStripedData(u32, u64, DataBuf),
+    CSClosing,
 
     SCHeartbeat,
+    SCDraining,
+    SCHello(Vec<u8>),
+    SCRefused(Vec<u8>),
     SCClosePort(u32),
     SCShutdownWrite(u32),
     SCConnectOk(u32, Vec<u8>),
-    SCData(u32, Vec<u8>),
+    SCData(u32, DataBuf),
+    SCWindow(u32, u32),
+    SCUdpData(u32, Vec<u8>),
+    SCCloseUdp(u32),
+    SCStripedData(u32, u64, DataBuf),
+    SCStats(Vec<u8>),
+    SCSession(Vec<u8>),
+    SCResumed(Vec<u8>),
 
     Heartbeat,
     TunnelPortHalfDrop(u32),
+    CloseTunnel,
 }
 
 pub enum TunnelPortMsg {
     ConnectOk(Vec<u8>),
-    Data(Vec<u8>),
+    Data(DataBuf),
     ShutdownWrite,
     ClosePort,
+    // The connection of the tunnel broke, the port is gone with it. Ports
+    // opened while the tunnel reconnects get it at once as well.
+    TunnelReconnecting,
+    // Data of a bonded port, TunnelReadPort::read passes it on in order
+    // as Data.
+    StripedData(u64, DataBuf),
 }
 
 pub struct Tunnel {
     id: u32,
     senders: SubSenders<TunnelMsg>,
     main_sender: MainSender<TunnelMsg>,
+    priority_sender: Sender<TunnelMsg>,
+    state: Arc<TunnelState>,
+    core: Option<JoinHandle<()>>,
+}
+
+// Shared by a tunnel and its core task, which reconnects through the
+// selector whenever the connection ends.
+struct TunnelState {
+    selector: Arc<ServerSelector>,
+    server: Mutex<String>,
+    server_version: Mutex<Option<String>>,
+    features: Mutex<Option<TunnelFeatures>>,
+    connected_time: Mutex<Option<Instant>>,
+    quality: AtomicU32,
+    closed: AtomicBool,
+    draining: AtomicBool,
+    retiring: AtomicBool,
+    port_stats: Mutex<Vec<PortStats>>,
+    queue: TunnelQueue,
+    // The server of the connection grants the ports credit, see
+    // backpressure::PortWindow.
+    window: Arc<AtomicBool>,
+    // Data frames to the server may be compressed, see set_compress.
+    compress: AtomicBool,
+    // The server relays UDP ports, see Tunnel::open_udp.
+    udp: AtomicBool,
+    // The server bonds ports, see TunnelWritePort::bond.
+    bond: AtomicBool,
+    // The server is told when the tunnel retires, see Tunnel::retire.
+    closing: AtomicBool,
+    // The smoothed round trip of heartbeats and the ports of the
+    // connection.
+    rtt: Mutex<Option<Duration>>,
+    heartbeats: Mutex<Heartbeats>,
+    open_ports: AtomicUsize,
+    // Payload of data frames over every connection of the tunnel.
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+    // The server of the connection tells its view, see Tunnel::remote_stats.
+    stats: AtomicBool,
+    remote_stats: Mutex<Option<TunnelStats>>,
+    // The server keeps the ports when the connection breaks, see resume.
+    resume: AtomicBool,
+}
+
+// The heartbeats the server hasn't answered yet in the order sent, and
+// when it last answered one. Answers are read as they arrive, so they
+// count even while writes to the server are stuck.
+struct Heartbeats {
+    sent: VecDeque<Instant>,
+    answered: Instant,
+}
+
+// Transfer of one port as of the last heartbeat, rates in bytes per
+// second. A port stalls when it sent data since it last received any,
+// and nothing arrived for a while.
+#[derive(Clone, Debug)]
+pub struct PortStats {
+    pub id: u32,
+    pub host: String,
+    pub port: u16,
+    pub age: Duration,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub upload_rate: u64,
+    pub download_rate: u64,
+    pub average_upload_rate: u64,
+    pub average_download_rate: u64,
+    pub stalled: Option<Duration>,
 }
 
 pub struct TcpTunnel;
+#[cfg(feature = "ucp")]
 pub struct UcpTunnel;
 
 pub struct TunnelWritePort {
     id: u32,
     tx: Sender<TunnelMsg>,
+    priority_tx: Sender<TunnelMsg>,
+    state: Arc<TunnelState>,
+    window: Arc<PortWindow>,
+    queue_limit: usize,
+    // The other members of its bond, see bond, and the sequence number
+    // of the next data.
+    stripes: Vec<TunnelWritePort>,
+    seq: u64,
 }
 
 pub struct TunnelReadPort {
     id: u32,
     tx: Sender<TunnelMsg>,
+    priority_tx: Sender<TunnelMsg>,
     rx: Option<Receiver<TunnelPortMsg>>,
+    // Bytes written to the socket and not granted to the server yet.
+    consumed: usize,
+    // The other members of its bond, the data they received out of
+    // order, and the member the last data came on.
+    stripes: Vec<TunnelReadPort>,
+    reassembly: Option<Reassembly>,
+    last: usize,
+}
+
+// Datagrams to and from any address, relayed by the server from a
+// socket of its own. Like UDP, a datagram the tunnel has no room for is
+// dropped.
+pub struct TunnelUdpPort {
+    id: u32,
+    tx: Sender<TunnelMsg>,
+    rx: Receiver<Vec<u8>>,
 }
 
 impl Tunnel {
@@ -69,7 +253,11 @@ impl Tunnel {
         self.id += 1;
 
         let (tx, rx) = channel(1000);
-        let _ = self.main_sender.send(TunnelMsg::CSOpenPort(id, tx)).await;
+        let window = Arc::new(PortWindow::new(self.state.window.clone()));
+        let _ = self
+            .main_sender
+            .send(TunnelMsg::CSOpenPort(id, tx, window.clone()))
+            .await;
 
         let sender = self.senders.get_one_sender();
 
@@ -77,35 +265,362 @@ impl Tunnel {
             TunnelWritePort {
                 id: id,
                 tx: sender.clone(),
+                priority_tx: self.priority_sender.clone(),
+                state: self.state.clone(),
+                window,
+                queue_limit: DEFAULT_BULK_QUEUE_LIMIT,
+                stripes: Vec::new(),
+                seq: 0,
             },
             TunnelReadPort {
                 id: id,
                 tx: sender.clone(),
+                priority_tx: self.priority_sender.clone(),
                 rx: Some(rx),
+                consumed: 0,
+                stripes: Vec::new(),
+                reassembly: None,
+                last: 0,
             },
         )
     }
+
+    // None unless the server of the current connection relays UDP. The
+    // port ends with the connection, or when the server closes it after
+    // it idled for the port idle timeout.
+    pub async fn open_udp(&mut self) -> Option<TunnelUdpPort> {
+        if !self.state.udp.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        let id = self.id;
+        self.id += 1;
+
+        let (tx, rx) = channel(1000);
+        let _ = self.main_sender.send(TunnelMsg::CSOpenUdp(id, tx)).await;
+
+        Some(TunnelUdpPort {
+            id,
+            tx: self.senders.get_one_sender(),
+            rx,
+        })
+    }
+
+    // Score in [0, MAX_TUNNEL_QUALITY], derived from the loss and
+    // retransmission telemetry of the underlying transport.
+    pub fn quality(&self) -> u32 {
+        self.state.quality.load(Ordering::Relaxed)
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        self.quality() < DEGRADED_TUNNEL_QUALITY
+    }
+
+    // The server of the current connection takes the ports of this
+    // tunnel into bonds.
+    pub fn can_bond(&self) -> bool {
+        self.state.bond.load(Ordering::Relaxed)
+    }
+
+    // How long the server of the current connection takes to answer a
+    // heartbeat, behind the data queued on the connection, None until it
+    // answered one.
+    pub fn rtt(&self) -> Option<Duration> {
+        *self.state.rtt.lock().unwrap()
+    }
+
+    // Ports open on the current connection.
+    pub fn open_ports(&self) -> usize {
+        self.state.open_ports.load(Ordering::Relaxed)
+    }
+
+    // The server asked for new ports to go elsewhere.
+    pub fn is_draining(&self) -> bool {
+        self.state.draining.load(Ordering::Relaxed)
+    }
+
+    // Draining while the selector has another server to replace it with,
+    // or connected to a server that was removed from the list.
+    pub fn should_retire(&self) -> bool {
+        let server = self.state.server.lock().unwrap().clone();
+        let removed = !server.is_empty() && !self.state.selector.has_server(&server);
+        let fail_back = !server.is_empty() && self.state.selector.should_fail_back(&server);
+        removed || fail_back || self.is_draining() && self.state.selector.best() != server
+    }
+
+    // How long the current connection to the server has been up, None
+    // while connecting.
+    pub fn age(&self) -> Option<Duration> {
+        self.state
+            .connected_time
+            .lock()
+            .unwrap()
+            .map(|time| time.elapsed())
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.age().is_some()
+    }
+
+    // Data of the ports not yet written to the connection.
+    pub fn queued_bytes(&self) -> usize {
+        self.state.queue.queued()
+    }
+
+    // What the tunnel is doing as this end sees it, data counted since
+    // the tunnel was created.
+    pub fn stats(&self) -> TunnelStats {
+        TunnelStats {
+            open_ports: self.open_ports() as u64,
+            uploaded: self.state.uploaded.load(Ordering::Relaxed),
+            downloaded: self.state.downloaded.load(Ordering::Relaxed),
+            queued_frames: self.state.queue.frames() as u64,
+            queued_bytes: self.state.queue.queued() as u64,
+        }
+    }
+
+    // What the server of the current connection saw as of the last
+    // heartbeat, data counted since it connected. None until it
+    // answered, or for servers from before they did.
+    pub fn remote_stats(&self) -> Option<TunnelStats> {
+        *self.state.remote_stats.lock().unwrap()
+    }
+
+    pub fn port_stats(&self) -> Vec<PortStats> {
+        self.state.port_stats.lock().unwrap().clone()
+    }
+
+    // The server of the current connection, or the one being connected.
+    pub fn server(&self) -> String {
+        self.state.server.lock().unwrap().clone()
+    }
+
+    // The software the server of the current connection named, None for
+    // servers from before they did.
+    pub fn server_version(&self) -> Option<String> {
+        self.state.server_version.lock().unwrap().clone()
+    }
+
+    // What the current connection negotiated, None while connecting.
+    pub fn features(&self) -> Option<TunnelFeatures> {
+        self.state.features.lock().unwrap().clone()
+    }
+
+    // Takes no new ports, closes once the open ones have finished.
+    pub async fn retire(mut self) {
+        self.state.retiring.store(true, Ordering::Relaxed);
+        let _ = self.priority_sender.send(TunnelMsg::CSClosing).await;
+
+        if let Some(core) = self.core.take() {
+            core.await;
+        }
+    }
+
+    // Closes the connection to the server, which closes every open port
+    // on both sides, and waits until the core task has exited.
+    pub async fn close(mut self) {
+        self.state.close();
+        let _ = self.main_sender.send(TunnelMsg::CloseTunnel).await;
+
+        if let Some(core) = self.core.take() {
+            core.await;
+        }
+    }
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        self.state.close();
+        let _ = self.main_sender.try_send(TunnelMsg::CloseTunnel);
+    }
+}
+
+impl TunnelState {
+    fn new(selector: Arc<ServerSelector>) -> Arc<TunnelState> {
+        Arc::new(TunnelState {
+            selector,
+            server: Mutex::new(String::new()),
+            server_version: Mutex::new(None),
+            features: Mutex::new(None),
+            connected_time: Mutex::new(None),
+            quality: AtomicU32::new(MAX_TUNNEL_QUALITY),
+            closed: AtomicBool::new(false),
+            draining: AtomicBool::new(false),
+            retiring: AtomicBool::new(false),
+            port_stats: Mutex::new(Vec::new()),
+            queue: TunnelQueue::default(),
+            window: Arc::new(AtomicBool::new(false)),
+            compress: AtomicBool::new(false),
+            udp: AtomicBool::new(false),
+            bond: AtomicBool::new(false),
+            closing: AtomicBool::new(false),
+            rtt: Mutex::new(None),
+            heartbeats: Mutex::new(Heartbeats {
+                sent: VecDeque::new(),
+                answered: Instant::now(),
+            }),
+            open_ports: AtomicUsize::new(0),
+            uploaded: AtomicU64::new(0),
+            downloaded: AtomicU64::new(0),
+            stats: AtomicBool::new(false),
+            remote_stats: Mutex::new(None),
+            resume: AtomicBool::new(false),
+        })
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.queue.close();
+    }
+
+    // Picks the server for the next connection.
+    fn next_server(&self) -> String {
+        let server = self.selector.best();
+        *self.server.lock().unwrap() = server.clone();
+        self.draining.store(false, Ordering::Relaxed);
+        server
+    }
+
+    // The next connections go to another server for a while, unless the
+    // tunnel closed, see ServerSelector::report_failure.
+    fn server_failed(&self) {
+        if !self.is_closed() {
+            let server = self.server.lock().unwrap().clone();
+            self.selector.report_failure(&server);
+        }
+    }
+
+    // The ports of a connection end with it.
+    fn set_connected(&self, connected: bool) {
+        *self.connected_time.lock().unwrap() = if connected {
+            Some(Instant::now())
+        } else {
+            None
+        };
+        *self.heartbeats.lock().unwrap() = Heartbeats {
+            sent: VecDeque::new(),
+            answered: Instant::now(),
+        };
+
+        if !connected {
+            self.port_stats.lock().unwrap().clear();
+            *self.server_version.lock().unwrap() = None;
+            *self.features.lock().unwrap() = None;
+            self.window.store(false, Ordering::Relaxed);
+            self.compress.store(false, Ordering::Relaxed);
+            self.udp.store(false, Ordering::Relaxed);
+            self.bond.store(false, Ordering::Relaxed);
+            self.closing.store(false, Ordering::Relaxed);
+            *self.rtt.lock().unwrap() = None;
+            self.open_ports.store(0, Ordering::Relaxed);
+            self.stats.store(false, Ordering::Relaxed);
+            *self.remote_stats.lock().unwrap() = None;
+        }
+    }
+
+    fn heartbeat_sent(&self) {
+        let mut heartbeats = self.heartbeats.lock().unwrap();
+        if heartbeats.sent.len() < MAX_PENDING_HEARTBEATS {
+            heartbeats.sent.push_back(Instant::now());
+        }
+    }
+
+    // The server answers heartbeats in order.
+    fn heartbeat_answered(&self) {
+        let mut heartbeats = self.heartbeats.lock().unwrap();
+        heartbeats.answered = Instant::now();
+        let sent = match heartbeats.sent.pop_front() {
+            Some(sent) => sent,
+            None => return,
+        };
+
+        let sample = heartbeats.answered - sent;
+        let mut rtt = self.rtt.lock().unwrap();
+        *rtt = Some(match *rtt {
+            Some(rtt) => (rtt * 7 + sample) / 8,
+            None => sample,
+        });
+    }
+
+    // Heartbeat intervals since the server last answered, or since the
+    // connection came up. A heartbeat goes out every interval, so a live
+    // server keeps this at 0 or 1.
+    fn missed_heartbeats(&self) -> u32 {
+        let answered = self.heartbeats.lock().unwrap().answered;
+        (answered.elapsed().as_millis() / HEARTBEAT_INTERVAL_MS as u128) as u32
+    }
+
+    fn server_draining(&self, tid: u32) {
+        if !self.draining.swap(true, Ordering::Relaxed) {
+            let server = self.server.lock().unwrap().clone();
+            info!("tunnel {} server {} draining", tid, server);
+            self.selector.set_draining(&server);
+        }
+    }
+
+    fn is_retired(&self, port_hub: &PortHub) -> bool {
+        self.retiring.load(Ordering::Relaxed) && port_hub.is_empty()
+    }
 }
 
 impl TcpTunnel {
-    pub fn new(tid: u32, server_addr: String, key: Vec<u8>) -> Tunnel {
+    pub fn new(tid: u32, selector: Arc<ServerSelector>, key: Vec<u8>) -> Tunnel {
         let (main_sender, sub_senders, receivers) = channel_bus(10, 1000);
-        let core_sender = main_sender.clone();
+        let (priority_sender, priority_receiver) = channel(1000);
+
+        let state = TunnelState::new(selector);
+        let core_state = state.clone();
 
-        task::spawn(async move {
+        let core = task::spawn(async move {
             let duration = Duration::from_millis(HEARTBEAT_INTERVAL_MS);
             let timer_stream = timer::interval(duration, TunnelMsg::Heartbeat);
-            let mut msg_stream = timer_stream.merge(receivers);
-
-            loop {
-                tcp_tunnel_core_task(
+            let mut msg_stream = prioritized(priority_receiver, timer_stream.merge(receivers));
+            let mut port_hub = PortHub::new(tid);
+
+            // Connections that failed wait longer and longer before the
+            // next, and once one was up, the attempts since.
+            let mut backoff = Backoff::default();
+            let mut reconnects: Option<u32> = None;
+
+            while !core_state.is_closed() {
+                let attempts = reconnects.map_or(0, |attempts| attempts + 1);
+                let reconnected = |server: String| {
+                    if reconnects.is_some() {
+                        events::emit_tunnel(|| TunnelEvent::Reconnected {
+                            tunnel: tid,
+                            server,
+                            attempts,
+                            resumed: false,
+                        });
+                    }
+                };
+                let connected = tcp_tunnel_core_task(
                     tid,
-                    server_addr.clone(),
                     key.clone(),
                     &mut msg_stream,
-                    core_sender.clone(),
+                    &mut port_hub,
+                    &core_state,
+                    reconnected,
                 )
                 .await;
+
+                if connected {
+                    backoff = Backoff::default();
+                    reconnects = Some(0);
+                } else {
+                    reconnects = reconnects.map(|attempts| attempts + 1);
+                    wait_reconnect(
+                        &mut msg_stream,
+                        &mut port_hub,
+                        &core_state,
+                        backoff.failed(),
+                    )
+                    .await;
+                }
             }
         });
 
@@ -113,43 +628,202 @@ impl TcpTunnel {
             id: 1,
             senders: sub_senders,
             main_sender: main_sender,
+            priority_sender,
+            state,
+            core: Some(core),
         }
     }
 }
 
+#[cfg(feature = "ucp")]
 impl UcpTunnel {
-    pub fn new(tid: u32, server_addr: String, key: Vec<u8>) -> Tunnel {
+    pub fn new(tid: u32, selector: Arc<ServerSelector>, key: Vec<u8>, config: UcpConfig) -> Tunnel {
         let (main_sender, sub_senders, receivers) = channel_bus(10, 1000);
-        let core_sender = main_sender.clone();
+        let (priority_sender, priority_receiver) = channel(1000);
+        let state = TunnelState::new(selector);
+        state.resume.store(config.resume, Ordering::Relaxed);
+        let core_state = state.clone();
 
-        task::spawn(async move {
+        let core = task::spawn(async move {
             let duration = Duration::from_millis(HEARTBEAT_INTERVAL_MS);
             let timer_stream = timer::interval(duration, TunnelMsg::Heartbeat);
-            let mut msg_stream = timer_stream.merge(receivers);
-
-            loop {
-                ucp_tunnel_core_task(
-                    tid,
-                    server_addr.clone(),
-                    key.clone(),
-                    &mut msg_stream,
-                    core_sender.clone(),
-                )
-                .await;
+            let mut msg_stream = prioritized(priority_receiver, timer_stream.merge(receivers));
+            // The ports outlive a broken session when they resume with
+            // it, see resume.
+            let mut port_hub = PortHub::new(tid);
+
+            // A broken session resumes with its ticket, when asked for.
+            let mut ticket: Option<ResumeTicket> = None;
+            // Sessions that didn't come up wait longer and longer before
+            // the next, and once a session was up, the attempts since.
+            let mut backoff = Backoff::default();
+            let mut reconnects: Option<u32> = None;
+
+            while !core_state.is_closed() {
+                let server = core_state.next_server();
+                port_hub.expire_session(&server, &core_state);
+                let resumed = ticket.is_some();
+                let stream =
+                    UcpStream::connect_with_ticket(&server, Some(&key), &config, ticket.as_ref())
+                        .await;
+                let established = match stream {
+                    Ok(stream) => {
+                        let attempts = reconnects.map_or(0, |attempts| attempts + 1);
+                        let reconnected = || {
+                            if reconnects.is_some() {
+                                events::emit_tunnel(|| TunnelEvent::Reconnected {
+                                    tunnel: tid,
+                                    server: server.clone(),
+                                    attempts,
+                                    resumed,
+                                });
+                            }
+                        };
+                        let established = ucp_tunnel_core_task(
+                            tid,
+                            &stream,
+                            key.clone(),
+                            &mut msg_stream,
+                            &mut port_hub,
+                            &core_state,
+                            reconnected,
+                        )
+                        .await;
+
+                        ticket = stream.resume_ticket();
+                        established
+                    }
+                    Err(e) => {
+                        error!("tunnel {} connect error: {}", tid, e);
+                        false
+                    }
+                };
+
+                // A broken session goes back to its server to resume,
+                // only sessions that didn't come up fail it.
+                if established {
+                    core_state.selector.report_success(&server);
+                    backoff = Backoff::default();
+                    reconnects = Some(0);
+                } else {
+                    core_state.server_failed();
+                    reconnects = reconnects.map(|attempts| attempts + 1);
+                    wait_reconnect(
+                        &mut msg_stream,
+                        &mut port_hub,
+                        &core_state,
+                        backoff.failed(),
+                    )
+                    .await;
+                }
             }
+            port_hub.end_session(&core_state);
         });
 
         Tunnel {
             id: 1,
             senders: sub_senders,
             main_sender: main_sender,
+            priority_sender,
+            state,
+            core: Some(core),
         }
     }
 }
 
 impl TunnelWritePort {
-    pub async fn write(&mut self, buf: Vec<u8>) {
-        let _ = self.tx.send(TunnelMsg::CSData(self.id, buf)).await;
+    // Stripes the data of the port over ports of other tunnels to the
+    // same server, each a tunnel that can_bond, and puts the data coming
+    // back on them in order on `read_port`, the read port of this one.
+    // Called before the connect, which goes over this port. Members past
+    // MAX_BOND_MEMBERS are closed.
+    pub async fn bond(
+        &mut self,
+        read_port: &mut TunnelReadPort,
+        mut members: Vec<(TunnelWritePort, TunnelReadPort)>,
+    ) {
+        if members.len() >= MAX_BOND_MEMBERS {
+            for (mut write_port, mut member_read_port) in members.split_off(MAX_BOND_MEMBERS - 1) {
+                member_read_port.drain();
+                write_port.close().await;
+            }
+        }
+        if members.is_empty() {
+            return;
+        }
+
+        let key = bond::new_key();
+        let count = members.len() + 1;
+        let data = bond::join_data(&key, 0, count);
+        let _ = self.tx.send(TunnelMsg::CSJoinBond(self.id, data)).await;
+
+        for (index, (mut write_port, member_read_port)) in members.into_iter().enumerate() {
+            let data = bond::join_data(&key, index + 1, count);
+            let _ = write_port
+                .tx
+                .send(TunnelMsg::CSJoinBond(write_port.id, data))
+                .await;
+            self.stripes.push(write_port);
+            read_port.stripes.push(member_read_port);
+        }
+        read_port.reassembly = Some(Reassembly::new(count));
+    }
+
+    // Data larger than a frame is split, see MAX_DATA_FRAME_SIZE.
+    pub async fn write(&mut self, buf: DataBuf) {
+        if buf.len() <= MAX_DATA_FRAME_SIZE {
+            return self.write_chunk(buf).await;
+        }
+
+        for chunk in buf.chunks(MAX_DATA_FRAME_SIZE) {
+            self.write_chunk(DataBuf::from(chunk)).await;
+        }
+    }
+
+    // The data of a bond goes over its members in turn.
+    async fn write_chunk(&mut self, buf: DataBuf) {
+        if self.stripes.is_empty() {
+            return self.write_frame(None, buf).await;
+        }
+
+        let seq = self.seq;
+        self.seq += 1;
+        match (seq % (self.stripes.len() as u64 + 1)) as usize {
+            0 => self.write_frame(Some(seq), buf).await,
+            member => self.stripes[member - 1].write_frame(Some(seq), buf).await,
+        }
+    }
+
+    // Waits while the tunnel has more than the limit of the port queued,
+    // see backpressure::QueueLimits, and while the port is out of credit.
+    async fn write_frame(&mut self, seq: Option<u64>, buf: DataBuf) {
+        self.state.queue.wait(self.queue_limit).await;
+        self.window.take(buf.len()).await;
+        self.state.queue.push(buf.len());
+        let msg = match seq {
+            Some(seq) => TunnelMsg::CSStripedData(self.id, seq, buf),
+            None => TunnelMsg::CSData(self.id, buf),
+        };
+        let _ = self.tx.send(msg).await;
+    }
+
+    pub fn set_queue_limit(&mut self, bytes: usize) {
+        self.queue_limit = bytes;
+        for port in self.stripes.iter_mut() {
+            port.queue_limit = bytes;
+        }
+    }
+
+    // The tunnel writes the messages of interactive ports before those
+    // of bulk ones. Set once the port connected, so they can't pass its
+    // open and connect, and on the read port too.
+    pub fn set_class(&mut self, class: PortClass) {
+        if class == PortClass::Interactive {
+            self.tx = self.priority_tx.clone();
+            for port in self.stripes.iter_mut() {
+                port.tx = port.priority_tx.clone();
+            }
+        }
     }
 
     pub async fn connect(&mut self, buf: Vec<u8>) {
@@ -164,24 +838,49 @@ impl TunnelWritePort {
     }
 
     pub async fn shutdown_write(&mut self) {
-        let _ = self.tx.send(TunnelMsg::CSShutdownWrite(self.id)).await;
+        self.send_all(TunnelMsg::CSShutdownWrite).await;
     }
 
     pub async fn close(&mut self) {
-        let _ = self.tx.send(TunnelMsg::CSClosePort(self.id)).await;
+        self.send_all(TunnelMsg::CSClosePort).await;
     }
 
     pub async fn drop(&mut self) {
-        let _ = self.tx.send(TunnelMsg::TunnelPortHalfDrop(self.id)).await;
+        self.send_all(TunnelMsg::TunnelPortHalfDrop).await;
+    }
+
+    // The message goes to the port and the other members of its bond.
+    async fn send_all(&mut self, msg: fn(u32) -> TunnelMsg) {
+        let _ = self.tx.send(msg(self.id)).await;
+        for port in self.stripes.iter_mut() {
+            let _ = port.tx.send(msg(port.id)).await;
+        }
     }
 }
 
 impl TunnelReadPort {
     pub fn drain(&mut self) {
         self.rx = None;
+        for port in self.stripes.iter_mut() {
+            port.rx = None;
+        }
+    }
+
+    // See TunnelWritePort::set_class.
+    pub fn set_class(&mut self, class: PortClass) {
+        if class == PortClass::Interactive {
+            self.tx = self.priority_tx.clone();
+            for port in self.stripes.iter_mut() {
+                port.tx = port.priority_tx.clone();
+            }
+        }
     }
 
     pub async fn read(&mut self) -> TunnelPortMsg {
+        if self.reassembly.is_some() {
+            return self.read_striped().await;
+        }
+
         match self.rx {
             Some(ref mut receiver) => match receiver.next().await {
                 Some(msg) => msg,
@@ -192,12 +891,104 @@ impl TunnelReadPort {
         }
     }
 
+    // The data of a bond in order, whichever member it came on. The other
+    // messages of the members pass as they are, but for the shutdown,
+    // which passes once every member shut down and all data passed.
+    async fn read_striped(&mut self) -> TunnelPortMsg {
+        loop {
+            let reassembly = self.reassembly.as_mut().unwrap();
+            if let Some((member, buf)) = reassembly.pop() {
+                self.last = member;
+                return TunnelPortMsg::Data(buf);
+            }
+
+            if reassembly.finished() {
+                return TunnelPortMsg::ShutdownWrite;
+            }
+
+            let reassembly = &*reassembly;
+            let (members, reads): (Vec<usize>, Vec<_>) = once(&mut self.rx)
+                .chain(self.stripes.iter_mut().map(|port| &mut port.rx))
+                .enumerate()
+                .filter(|(member, _)| !reassembly.is_shut(*member))
+                .filter_map(|(member, rx)| rx.as_mut().map(|rx| (member, rx.next())))
+                .unzip();
+            if reads.is_empty() {
+                return TunnelPortMsg::ClosePort;
+            }
+
+            let (msg, index, _) = select_all(reads).await;
+            let reassembly = self.reassembly.as_mut().unwrap();
+            match msg {
+                Some(TunnelPortMsg::StripedData(seq, buf)) => {
+                    if !reassembly.push(seq, members[index], buf) {
+                        return TunnelPortMsg::ClosePort;
+                    }
+                }
+                Some(TunnelPortMsg::ShutdownWrite) => reassembly.shut(members[index]),
+                Some(msg) => return msg,
+                None => return TunnelPortMsg::ClosePort,
+            }
+        }
+    }
+
+    // The data read was written to the socket, the server may send as
+    // much more once a step of the window came together. The member of
+    // a bond it came on is granted it.
+    pub async fn consumed(&mut self, bytes: usize) {
+        match self.last {
+            0 => self.grant(bytes).await,
+            member => self.stripes[member - 1].grant(bytes).await,
+        }
+    }
+
+    async fn grant(&mut self, bytes: usize) {
+        self.consumed += bytes;
+        if self.consumed >= PORT_WINDOW_STEP {
+            let granted = self.consumed as u32;
+            self.consumed = 0;
+            let _ = self.tx.send(TunnelMsg::CSWindow(self.id, granted)).await;
+        }
+    }
+
     pub async fn close(&mut self) {
-        let _ = self.tx.send(TunnelMsg::CSClosePort(self.id)).await;
+        self.send_all(TunnelMsg::CSClosePort).await;
     }
 
     pub async fn drop(&mut self) {
-        let _ = self.tx.send(TunnelMsg::TunnelPortHalfDrop(self.id)).await;
+        self.send_all(TunnelMsg::TunnelPortHalfDrop).await;
+    }
+
+    async fn send_all(&mut self, msg: fn(u32) -> TunnelMsg) {
+        let _ = self.tx.send(msg(self.id)).await;
+        for port in self.stripes.iter_mut() {
+            let _ = port.tx.send(msg(port.id)).await;
+        }
+    }
+}
+
+impl TunnelUdpPort {
+    // The host is an address or a name the server resolves.
+    pub fn send_to(&mut self, host: &str, port: u16, payload: &[u8]) {
+        if let Some(data) = pack_udp_datagram(host.as_bytes(), port, payload) {
+            let _ = self.tx.try_send(TunnelMsg::CSUdpData(self.id, data));
+        }
+    }
+
+    // The next datagram with the address it came from, None once the
+    // port closed.
+    pub async fn recv(&mut self) -> Option<(String, u16, Vec<u8>)> {
+        loop {
+            let data = self.rx.next().await?;
+            if let Some((host, port, payload)) = unpack_udp_datagram(&data) {
+                let host = String::from_utf8_lossy(host).into_owned();
+                return Some((host, port, payload.to_vec()));
+            }
+        }
+    }
+
+    pub async fn close(&mut self) {
+        let _ = self.tx.send(TunnelMsg::CSCloseUdp(self.id)).await;
     }
 }
 
@@ -205,30 +996,137 @@ struct Port {
     host: String,
     port: u16,
     count: u32,
+    uploaded: u64,
+    downloaded: u64,
     tx: Sender<TunnelPortMsg>,
+    window: Arc<PortWindow>,
+
+    opened: Instant,
+    // Since the connect was sent, until the server answers it.
+    connecting: Option<Instant>,
+    connected: bool,
+    // Kept while the tunnel may resume its ports, see resume.
+    transfer: Option<Transfer>,
+    last_upload: Option<Instant>,
+    last_download: Option<Instant>,
+    // Totals and time of the previous sample, for the current rates.
+    sampled: (u64, u64, Instant),
+    stall_logged: bool,
+}
+
+impl Port {
+    // Sent data since it last received any, and nothing arrived since.
+    fn stalled(&self, now: Instant) -> Option<Duration> {
+        let last_upload = self.last_upload?;
+        let waiting = match self.last_download {
+            Some(last_download) => last_upload > last_download,
+            None => true,
+        };
+
+        let quiet = now - last_upload;
+        if waiting && quiet.as_millis() >= PORT_STALL_TIMEOUT_MS {
+            Some(quiet)
+        } else {
+            None
+        }
+    }
+}
+
+fn bytes_per_sec(bytes: u64, duration: Duration) -> u64 {
+    (bytes as f64 / duration.as_secs_f64().max(0.001)) as u64
+}
+
+// The session the ports of a tunnel may resume in on the server it came
+// from, see resume, since when its ports wait for the next connection,
+// and while the server hasn't answered RESUME, the messages of the ports
+// held for after.
+#[derive(Default)]
+struct Session {
+    server: String,
+    token: Option<Vec<u8>>,
+    broken: Option<Instant>,
+    resuming: bool,
+    held: VecDeque<TunnelMsg>,
+}
+
+// The tunnel id, its ports, its UDP ports and its session.
+struct PortHub(
+    u32,
+    HashMap<u32, Port>,
+    HashMap<u32, Sender<Vec<u8>>>,
+    Session,
+);
+
+impl TunnelMsg {
+    // The port a message of the ports is about.
+    fn port_id(&self) -> Option<u32> {
+        match *self {
+            TunnelMsg::CSOpenPort(id, _, _)
+            | TunnelMsg::CSConnect(id, _)
+            | TunnelMsg::CSConnectDN(id, _, _)
+            | TunnelMsg::CSShutdownWrite(id)
+            | TunnelMsg::CSClosePort(id)
+            | TunnelMsg::CSData(id, _)
+            | TunnelMsg::CSWindow(id, _)
+            | TunnelMsg::CSJoinBond(id, _)
+            | TunnelMsg::CSStripedData(id, _, _)
+            | TunnelMsg::TunnelPortHalfDrop(id) => Some(id),
+            _ => None,
+        }
+    }
 }
 
-struct PortHub(u32, HashMap<u32, Port>);
+// A message of the ports with no connection to go on: ports opening learn
+// the tunnel is reconnecting, data leaves the queue.
+fn drop_msg(msg: TunnelMsg, queue: &TunnelQueue) {
+    match msg {
+        TunnelMsg::CSOpenPort(_, mut tx, _) => {
+            let _ = tx.try_send(TunnelPortMsg::TunnelReconnecting);
+        }
+
+        TunnelMsg::CSData(_, buf) | TunnelMsg::CSStripedData(_, _, buf) => queue.pop(buf.len()),
+
+        _ => {}
+    }
+}
 
 impl PortHub {
     fn new(id: u32) -> Self {
-        PortHub(id, HashMap::new())
+        PortHub(id, HashMap::new(), HashMap::new(), Session::default())
     }
 
     fn get_id(&self) -> u32 {
         self.0
     }
 
-    fn add_port(&mut self, id: u32, tx: Sender<TunnelPortMsg>) {
+    fn is_empty(&self) -> bool {
+        self.1.is_empty() && self.2.is_empty()
+    }
+
+    fn add_port(&mut self, id: u32, tx: Sender<TunnelPortMsg>, window: Arc<PortWindow>) {
         self.1.insert(
             id,
             Port {
                 host: String::new(),
                 port: 0,
                 count: 2,
+                uploaded: 0,
+                downloaded: 0,
                 tx: tx,
+                window,
+                opened: Instant::now(),
+                connecting: None,
+                connected: false,
+                transfer: self.3.token.as_ref().map(|_| Transfer::default()),
+                last_upload: None,
+                last_download: None,
+                sampled: (0, 0, Instant::now()),
+                stall_logged: false,
             },
         );
+
+        let tunnel = self.get_id();
+        events::emit(|| PortEvent::Opened { tunnel, id });
     }
 
     fn update_port(&mut self, id: u32, host: String, port: u16) {
@@ -238,6 +1136,41 @@ impl PortHub {
         }
     }
 
+    fn connecting(&mut self, id: u32) {
+        if let Some(value) = self.1.get_mut(&id) {
+            value.connecting = Some(Instant::now());
+        }
+    }
+
+    // Closes the ports whose connect went unanswered for `timeout`, as
+    // when the server is too old to give up on them. Their owners are
+    // told, the ids are for telling the server.
+    fn reap(&mut self, timeout: Duration) -> Vec<u32> {
+        let reaped: Vec<u32> = self
+            .1
+            .iter()
+            .filter(|(_, port)| {
+                port.connecting
+                    .is_some_and(|since| since.elapsed() >= timeout)
+            })
+            .map(|(&id, _)| id)
+            .collect();
+
+        for &id in reaped.iter() {
+            if let Some(value) = self.1.get_mut(&id) {
+                info!(
+                    "{}.{}: connect {}:{} unanswered, closing it",
+                    self.0, id, value.host, value.port
+                );
+                let _ = value.tx.try_send(TunnelPortMsg::ClosePort);
+            }
+            self.remove_port(id, CloseReason::TimedOut);
+            REAPED_PORTS.fetch_add(1, Ordering::Relaxed);
+        }
+
+        reaped
+    }
+
     fn drop_port_half(&mut self, id: u32) {
         let self_id = self.get_id();
 
@@ -248,52 +1181,242 @@ impl PortHub {
                     "{}.{}: drop tunnel port {}:{}",
                     self_id, id, value.host, value.port
                 );
-                self.1.remove(&id);
+                self.remove_port(id, CloseReason::Finished);
             }
         } else {
             info!("{}.{}: drop unknown tunnel port", self.get_id(), id);
         }
     }
 
+    // The connection broke, the ports learn it before their channels
+    // close.
     fn clear_ports(&mut self) {
-        self.1.clear();
+        let ids: Vec<u32> = self.1.keys().cloned().collect();
+        for id in ids {
+            if let Some(value) = self.1.get_mut(&id) {
+                let _ = value.tx.try_send(TunnelPortMsg::TunnelReconnecting);
+            }
+            self.remove_port(id, CloseReason::TunnelBroken);
+        }
+        self.2.clear();
     }
 
-    fn client_close_port(&mut self, id: u32) {
-        match self.1.get(&id) {
-            Some(value) => {
-                info!(
-                    "{}.{}: client close {}:{}",
-                    self.get_id(),
-                    id,
-                    value.host,
-                    value.port
-                );
-                self.1.remove(&id);
+    fn transfer(&mut self, id: u32) -> Option<&mut Transfer> {
+        self.1.get_mut(&id)?.transfer.as_mut()
+    }
+
+    // The connection broke, the ports that may resume wait for the next
+    // one, within their credit, and the others learn it.
+    fn park_ports(&mut self, state: &TunnelState) {
+        if self.3.token.is_none() {
+            return self.end_session(state);
+        }
+
+        let ids: Vec<u32> = self
+            .1
+            .iter()
+            .filter(|(_, port)| !port.connected || port.transfer.is_none())
+            .map(|(&id, _)| id)
+            .collect();
+        for id in ids {
+            if let Some(value) = self.1.get_mut(&id) {
+                let _ = value.tx.try_send(TunnelPortMsg::TunnelReconnecting);
             }
+            self.remove_port(id, CloseReason::TunnelBroken);
+        }
+        self.2.clear();
+        if self.1.is_empty() {
+            return self.end_session(state);
+        }
 
-            None => {
-                info!("{}.{}: client close unknown server", self.get_id(), id);
+        // Ports opened while it resumed learn the tunnel broke.
+        for msg in mem::take(&mut self.3.held) {
+            if self.holds(&msg) {
+                self.3.held.push_back(msg);
+            } else {
+                drop_msg(msg, &state.queue);
             }
         }
+
+        info!("{}: {} ports wait to resume", self.0, self.1.len());
+        self.3.broken.get_or_insert_with(Instant::now);
+        self.3.resuming = false;
+        state.window.store(true, Ordering::Relaxed);
     }
 
-    fn server_close_port(&mut self, id: u32) {
-        match self.1.get(&id) {
-            Some(value) => {
-                info!(
-                    "{}.{}: server close {}:{}",
-                    self.get_id(),
-                    id,
-                    value.host,
-                    value.port
-                );
-                self.1.remove(&id);
+    // The ports can't resume on a connection to `server`, as it is another
+    // server or has closed them by now.
+    #[cfg(feature = "ucp")]
+    fn expire_session(&mut self, server: &str, state: &TunnelState) {
+        let timeout = Duration::from_millis(resume::SESSION_PARK_TIMEOUT_MS);
+        if let Some(broken) = self.3.broken {
+            if self.3.server != server || broken.elapsed() >= timeout {
+                info!("{}: ports not resumed, closing them", self.0);
+                self.end_session(state);
             }
+        }
+    }
 
-            None => {
-                info!("{}.{}: server close unknown client", self.get_id(), id);
-            }
+    // No port resumes, those waiting learn the tunnel broke.
+    fn end_session(&mut self, state: &TunnelState) {
+        for msg in mem::take(&mut self.3).held {
+            drop_msg(msg, &state.queue);
+        }
+        self.clear_ports();
+    }
+
+    // Messages of the ports waiting to resume wait with them.
+    fn holds(&self, msg: &TunnelMsg) -> bool {
+        msg.port_id().is_some_and(|id| self.1.contains_key(&id))
+    }
+
+    // The session and where its ports are, see resume.
+    fn resume_data(&self) -> Vec<u8> {
+        let ports: Vec<PortOffsets> = self
+            .1
+            .iter()
+            .filter_map(|(&id, port)| Some(port.transfer.as_ref()?.offsets(id)))
+            .collect();
+        let mut data = self.3.token.clone().unwrap_or_default();
+        data.extend(resume::pack_offsets(&ports));
+        data
+    }
+
+    fn remove_port(&mut self, id: u32, reason: CloseReason) {
+        let tunnel = self.get_id();
+
+        if let Some(value) = self.1.remove(&id) {
+            value.window.close();
+            let age = value.opened.elapsed();
+            if age.as_millis() >= HEARTBEAT_INTERVAL_MS as u128 {
+                info!(
+                    "{}.{}: {}:{} uploaded {} bytes at {}B/s, downloaded {} bytes at {}B/s in {}s",
+                    tunnel,
+                    id,
+                    value.host,
+                    value.port,
+                    value.uploaded,
+                    bytes_per_sec(value.uploaded, age),
+                    value.downloaded,
+                    bytes_per_sec(value.downloaded, age),
+                    age.as_secs()
+                );
+            }
+
+            events::emit(|| PortEvent::Closed {
+                tunnel,
+                id,
+                host: value.host,
+                port: value.port,
+                reason,
+                uploaded: value.uploaded,
+                downloaded: value.downloaded,
+            });
+        }
+    }
+
+    fn client_send_data(&mut self, id: u32, len: usize) {
+        if let Some(value) = self.1.get_mut(&id) {
+            value.uploaded += len as u64;
+            value.last_upload = Some(Instant::now());
+        }
+    }
+
+    fn server_window(&mut self, id: u32, bytes: u32) {
+        if let Some(value) = self.1.get_mut(&id) {
+            value.window.grant(bytes as usize);
+            if let Some(transfer) = value.transfer.as_mut() {
+                transfer.granted_by_peer(bytes);
+            }
+        }
+    }
+
+    // Rates since the previous sample, and stalls logged once each. While
+    // the tunnel is heard from a stall waits on the destination.
+    fn sample(&mut self, tunnel_heard: bool) -> Vec<PortStats> {
+        let now = Instant::now();
+        let tid = self.get_id();
+        let mut stats = Vec::new();
+
+        for (&id, value) in self.1.iter_mut() {
+            let (uploaded, downloaded, time) = value.sampled;
+            let interval = now - time;
+            let age = now - value.opened;
+            let stalled = value.stalled(now);
+
+            if let (Some(stalled), false) = (stalled, value.stall_logged) {
+                info!(
+                    "{}.{}: {}:{} stalled {}s, {}",
+                    tid,
+                    id,
+                    value.host,
+                    value.port,
+                    stalled.as_secs(),
+                    if tunnel_heard {
+                        "waiting on the destination"
+                    } else {
+                        "tunnel silent"
+                    }
+                );
+            }
+            value.stall_logged = stalled.is_some();
+
+            stats.push(PortStats {
+                id,
+                host: value.host.clone(),
+                port: value.port,
+                age,
+                uploaded: value.uploaded,
+                downloaded: value.downloaded,
+                upload_rate: bytes_per_sec(value.uploaded - uploaded, interval),
+                download_rate: bytes_per_sec(value.downloaded - downloaded, interval),
+                average_upload_rate: bytes_per_sec(value.uploaded, age),
+                average_download_rate: bytes_per_sec(value.downloaded, age),
+                stalled,
+            });
+
+            value.sampled = (value.uploaded, value.downloaded, now);
+        }
+
+        stats.sort_by_key(|s| s.id);
+        stats
+    }
+
+    fn client_close_port(&mut self, id: u32) {
+        match self.1.get(&id) {
+            Some(value) => {
+                info!(
+                    "{}.{}: client close {}:{}",
+                    self.get_id(),
+                    id,
+                    value.host,
+                    value.port
+                );
+                self.remove_port(id, CloseReason::ClientClosed);
+            }
+
+            None => {
+                info!("{}.{}: client close unknown server", self.get_id(), id);
+            }
+        }
+    }
+
+    fn server_close_port(&mut self, id: u32) {
+        match self.1.get(&id) {
+            Some(value) => {
+                info!(
+                    "{}.{}: server close {}:{}",
+                    self.get_id(),
+                    id,
+                    value.host,
+                    value.port
+                );
+                self.remove_port(id, CloseReason::ServerClosed);
+            }
+
+            None => {
+                info!("{}.{}: server close unknown client", self.get_id(), id);
+            }
         }
     }
 
@@ -329,6 +1452,9 @@ impl PortHub {
                     value.host,
                     value.port
                 );
+                if let Some(transfer) = self.transfer(id) {
+                    transfer.peer_shut_down();
+                }
                 self.try_send_msg(id, TunnelPortMsg::ShutdownWrite).await;
             }
 
@@ -343,15 +1469,24 @@ impl PortHub {
     }
 
     async fn connect_ok(&mut self, id: u32, buf: Vec<u8>) {
-        match self.1.get(&id) {
+        let tunnel = self.get_id();
+        match self.1.get_mut(&id) {
             Some(value) => {
+                value.connecting = None;
+                value.connected = true;
                 info!(
                     "{}.{}: connect {}:{} ok",
-                    self.get_id(),
-                    id,
-                    value.host,
-                    value.port
+                    tunnel, id, value.host, value.port
                 );
+
+                let (host, port) = (value.host.clone(), value.port);
+                events::emit(|| PortEvent::Connected {
+                    tunnel,
+                    id,
+                    host,
+                    port,
+                });
+
                 self.try_send_msg(id, TunnelPortMsg::ConnectOk(buf)).await;
             }
 
@@ -361,8 +1496,37 @@ impl PortHub {
         }
     }
 
-    async fn server_send_data(&mut self, id: u32, buf: Vec<u8>) {
-        self.try_send_msg(id, TunnelPortMsg::Data(buf)).await;
+    async fn server_send_data(&mut self, id: u32, seq: Option<u64>, buf: DataBuf) {
+        let tid = self.get_id();
+
+        if let Some(value) = self.1.get_mut(&id) {
+            value.downloaded += buf.len() as u64;
+            value.last_download = Some(Instant::now());
+            if let (Some(transfer), None) = (value.transfer.as_mut(), seq) {
+                transfer.receive(buf.len());
+            }
+
+            if value.stall_logged {
+                value.stall_logged = false;
+                info!("{}.{}: {}:{} resumed", tid, id, value.host, value.port);
+            }
+        }
+
+        let msg = match seq {
+            Some(seq) => TunnelPortMsg::StripedData(seq, buf),
+            None => TunnelPortMsg::Data(buf),
+        };
+        self.try_send_msg(id, msg).await;
+    }
+
+    fn server_send_udp(&mut self, id: u32, data: Vec<u8>) {
+        if let Some(tx) = self.2.get_mut(&id) {
+            if let Err(e) = tx.try_send(data) {
+                if e.is_disconnected() {
+                    self.2.remove(&id);
+                }
+            }
+        }
     }
 
     async fn try_send_msg(&mut self, id: u32, msg: TunnelPortMsg) {
@@ -374,72 +1538,215 @@ impl PortHub {
                     "{}.{}: send msg to the channel of {}:{} occur error",
                     self_id, id, value.host, value.port
                 );
-                self.1.remove(&id);
+                self.remove_port(id, CloseReason::Error);
             }
         }
     }
 }
 
-async fn tcp_tunnel_core_task<S: Stream<Item = TunnelMsg> + Unpin>(
+// Whether the connection came up, `connected` is called with the server
+// once it did.
+async fn tcp_tunnel_core_task<S: Stream<Item = TunnelMsg> + Unpin, F: FnOnce(String)>(
     tid: u32,
-    server_addr: String,
     key: Vec<u8>,
     msg_stream: &mut S,
-    core_tx: Sender<TunnelMsg>,
-) {
+    port_hub: &mut PortHub,
+    state: &TunnelState,
+    connected: F,
+) -> bool {
+    let server_addr = state.next_server();
     let stream = match TcpStream::connect(&server_addr).await {
         Ok(stream) => stream,
 
-        Err(_) => {
-            task::sleep(Duration::from_millis(1000)).await;
-            return;
+        Err(e) => {
+            error!("tunnel {} connect {} error: {}", tid, server_addr, e);
+            state.server_failed();
+            return false;
         }
     };
 
-    let mut port_hub = PortHub::new(tid);
+    state.selector.report_success(&server_addr);
+    state.set_connected(true);
+    *state.features.lock().unwrap() = Some(TunnelFeatures::tcp());
+    connected(server_addr);
+
+    let (core_tx, replies) = channel(1000);
     let (reader, writer) = &mut (&stream, &stream);
     let r = async {
-        let _ = process_tunnel_read(key.clone(), core_tx, reader).await;
+        let _ = process_tunnel_read(key.clone(), core_tx, state, reader).await;
         let _ = stream.shutdown(Shutdown::Both);
     };
     let w = async {
-        let _ = process_tunnel_write(key.clone(), msg_stream, &mut port_hub, writer).await;
+        let _ = process_tunnel_write(
+            tid,
+            key.clone(),
+            msg_stream,
+            replies,
+            port_hub,
+            state,
+            writer,
+        )
+        .await;
         let _ = stream.shutdown(Shutdown::Both);
     };
-    let _ = r.join(w).await;
+    // A blackholed connection neither fails nor answers, and its writes
+    // may be stuck with the heartbeats behind them.
+    let k = async {
+        loop {
+            task::sleep(Duration::from_millis(HEARTBEAT_INTERVAL_MS)).await;
+            let limit = MISSED_HEARTBEATS.load(Ordering::Relaxed);
+            let missed = state.missed_heartbeats();
+            if limit > 0 && missed > limit {
+                let server = state.server.lock().unwrap().clone();
+                info!(
+                    "Tcp tunnel {} to {} dead, {} heartbeats unanswered",
+                    tid, server, missed
+                );
+                events::emit_tunnel(|| TunnelEvent::Unresponsive {
+                    tunnel: tid,
+                    server,
+                    missed,
+                });
+                break;
+            }
+        }
+    };
+    let rw = async {
+        let _ = r.join(w).await;
+    };
+    rw.race(k).await;
 
-    info!("Tcp tunnel {} broken", tid);
-    port_hub.clear_ports();
+    if state.server_version.lock().unwrap().is_none() {
+        error!(
+            "Tcp tunnel {} broken before the server answered, its key may differ",
+            tid
+        );
+    } else {
+        info!("Tcp tunnel {} broken", tid);
+    }
+    state.server_failed();
+    state.set_connected(false);
+    port_hub.park_ports(state);
+    true
 }
 
-async fn ucp_tunnel_core_task<S: Stream<Item = TunnelMsg> + Unpin>(
-    tid: u32,
-    server_addr: String,
-    key: Vec<u8>,
+// Waits `delay` before the next connection. Ports opened meanwhile are
+// told the tunnel is reconnecting rather than held until it is up, and
+// what the ports of the broken connection still sent is dropped, unless
+// they wait to resume.
+async fn wait_reconnect<S: Stream<Item = TunnelMsg> + Unpin>(
     msg_stream: &mut S,
-    core_tx: Sender<TunnelMsg>,
+    port_hub: &mut PortHub,
+    state: &TunnelState,
+    delay: Duration,
 ) {
-    let stream = UcpStream::connect(&server_addr).await;
+    let deadline = Instant::now() + delay;
 
-    let mut port_hub = PortHub::new(tid);
-    let (reader, writer) = &mut (&stream, &stream);
+    while !state.is_closed() {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+
+        match future::timeout(deadline - now, msg_stream.next()).await {
+            Ok(Some(TunnelMsg::CloseTunnel)) | Ok(None) | Err(_) => break,
+
+            Ok(Some(msg)) if port_hub.holds(&msg) => port_hub.3.held.push_back(msg),
+
+            Ok(Some(msg)) => drop_msg(msg, &state.queue),
+        }
+    }
+}
+
+#[cfg(feature = "ucp")]
+// Whether the session came up, `established` is called once it did.
+async fn ucp_tunnel_core_task<S: Stream<Item = TunnelMsg> + Unpin, F: FnOnce()>(
+    tid: u32,
+    stream: &UcpStream,
+    key: Vec<u8>,
+    msg_stream: &mut S,
+    port_hub: &mut PortHub,
+    state: &TunnelState,
+    established: F,
+) -> bool {
+    state.quality.store(MAX_TUNNEL_QUALITY, Ordering::Relaxed);
+    state.set_connected(true);
+
+    let (core_tx, replies) = channel(1000);
+    let (reader, writer) = &mut (stream, stream);
     let r = async {
-        let _ = process_tunnel_read(key.clone(), core_tx, reader).await;
+        let _ = process_tunnel_read(key.clone(), core_tx, state, reader).await;
         stream.shutdown();
     };
     let w = async {
-        let _ = process_tunnel_write(key.clone(), msg_stream, &mut port_hub, writer).await;
-        stream.shutdown();
+        let _ = process_tunnel_write(
+            tid,
+            key.clone(),
+            msg_stream,
+            replies,
+            port_hub,
+            state,
+            writer,
+        )
+        .await;
+        stream.close().await;
+    };
+    let q = async {
+        let mut last = stream.stats();
+        let mut on_established = Some(established);
+        let mut established = false;
+
+        while stream.alive() {
+            task::sleep(Duration::from_millis(QUALITY_SAMPLE_INTERVAL_MS)).await;
+
+            // The features are known once the server answered.
+            if !established && stream.is_established() {
+                established = true;
+                let features = TunnelFeatures::ucp(stream.capabilities());
+                info!("Ucp tunnel {} runs {}", tid, features);
+                *state.features.lock().unwrap() = Some(features);
+                if let Some(on_established) = on_established.take() {
+                    on_established();
+                }
+            }
+
+            let stats = stream.stats();
+            let sample = stats.since(&last);
+            let score = ucp_quality(&sample);
+            if score < DEGRADED_TUNNEL_QUALITY {
+                info!(
+                    "Ucp tunnel {} degraded, loss {}‰, retransmission {}‰, {} rounds crowded by resends",
+                    tid,
+                    sample.loss_rate(),
+                    sample.retransmission_ratio(),
+                    sample.crowded_rounds
+                );
+            }
+
+            state.quality.store(score, Ordering::Relaxed);
+            last = stats;
+        }
+
+        established
     };
-    let _ = r.join(w).await;
+    let (_, established) = r.join(w).join(q).await;
 
     info!("Ucp tunnel {} broken", tid);
-    port_hub.clear_ports();
+    state.set_connected(false);
+    port_hub.park_ports(state);
+    established
+}
+
+#[cfg(feature = "ucp")]
+fn ucp_quality(stats: &UcpStats) -> u32 {
+    let penalty = stats.loss_rate() + stats.retransmission_ratio() / 2;
+    MAX_TUNNEL_QUALITY.saturating_sub(penalty)
 }
 
 async fn process_tunnel_read<R: Read + Unpin>(
     key: Vec<u8>,
     mut core_tx: Sender<TunnelMsg>,
+    state: &TunnelState,
     stream: &mut R,
 ) -> std::io::Result<()> {
     let mut ctr = vec![0; CTR_SIZE];
@@ -453,72 +1760,350 @@ async fn process_tunnel_read<R: Read + Unpin>(
         let op = op[0];
 
         if op == sc::HEARTBEAT_RSP {
+            state.heartbeat_answered();
             let _ = core_tx.send(TunnelMsg::SCHeartbeat).await;
             continue;
         }
 
+        if op == sc::DRAINING {
+            let _ = core_tx.send(TunnelMsg::SCDraining).await;
+            continue;
+        }
+
         let mut id = [0u8; 4];
         stream.read_exact(&mut id).await?;
-        let id = u32::from_be(unsafe { *(id.as_ptr() as *const u32) });
+        let id = u32::from_be_bytes(id);
 
         match op {
             sc::CLOSE_PORT => {
                 let _ = core_tx.send(TunnelMsg::SCClosePort(id)).await;
             }
 
+            sc::WINDOW => {
+                let mut bytes = [0u8; 4];
+                stream.read_exact(&mut bytes).await?;
+                let bytes = u32::from_be_bytes(bytes);
+                let _ = core_tx.send(TunnelMsg::SCWindow(id, bytes)).await;
+            }
+
             sc::SHUTDOWN_WRITE => {
                 let _ = core_tx.send(TunnelMsg::SCShutdownWrite(id)).await;
             }
 
-            sc::CONNECT_OK | sc::DATA => {
+            sc::CLOSE_UDP => {
+                let _ = core_tx.send(TunnelMsg::SCCloseUdp(id)).await;
+            }
+
+            sc::STRIPED_DATA => {
+                let mut seq = [0u8; 8];
+                stream.read_exact(&mut seq).await?;
+                let seq = u64::from_be_bytes(seq);
+
                 let mut len = [0u8; 4];
                 stream.read_exact(&mut len).await?;
-                let len = u32::from_be(unsafe { *(len.as_ptr() as *const u32) });
+                let len = data_frame_len(len)?;
+
+                let mut data = DataBuf::new(len);
+                stream.read_exact(&mut data).await?;
 
-                let mut buf = vec![0; len as usize];
+                decryptor.decrypt_in_place(&mut data);
+                let _ = core_tx.send(TunnelMsg::SCStripedData(id, seq, data)).await;
+            }
+
+            sc::DATA_LZ4 => {
+                let mut len = [0u8; 4];
+                stream.read_exact(&mut len).await?;
+                let len = data_frame_len(len)?;
+
+                let mut buf = vec![0; len];
+                stream.read_exact(&mut buf).await?;
+
+                let data = compress::decompress(&decryptor.decrypt(&buf)).ok_or_else(|| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, "bad compressed frame")
+                })?;
+                let _ = core_tx.send(TunnelMsg::SCData(id, data.into())).await;
+            }
+
+            sc::DATA => {
+                let mut len = [0u8; 4];
+                stream.read_exact(&mut len).await?;
+                let len = data_frame_len(len)?;
+
+                let mut data = DataBuf::new(len);
+                stream.read_exact(&mut data).await?;
+
+                decryptor.decrypt_in_place(&mut data);
+                let _ = core_tx.send(TunnelMsg::SCData(id, data)).await;
+            }
+
+            sc::CONNECT_OK
+            | sc::HELLO
+            | sc::REFUSED
+            | sc::UDP_DATA
+            | sc::STATS
+            | sc::SESSION
+            | sc::RESUMED => {
+                let mut len = [0u8; 4];
+                stream.read_exact(&mut len).await?;
+                let len = frame_len(len)?;
+
+                let mut buf = vec![0; len];
                 stream.read_exact(&mut buf).await?;
 
                 let data = decryptor.decrypt(&buf);
 
-                if op == sc::CONNECT_OK {
-                    let _ = core_tx.send(TunnelMsg::SCConnectOk(id, data)).await;
-                } else {
-                    let _ = core_tx.send(TunnelMsg::SCData(id, data)).await;
-                }
+                let msg = match op {
+                    sc::CONNECT_OK => TunnelMsg::SCConnectOk(id, data),
+                    sc::HELLO => TunnelMsg::SCHello(data),
+                    sc::REFUSED => TunnelMsg::SCRefused(data),
+                    sc::STATS => TunnelMsg::SCStats(data),
+                    sc::SESSION => TunnelMsg::SCSession(data),
+                    sc::RESUMED => TunnelMsg::SCResumed(data),
+                    _ => TunnelMsg::SCUdpData(id, data),
+                };
+                let _ = core_tx.send(msg).await;
             }
 
-            _ => break,
+            // Nothing after it can be framed right.
+            _ => {
+                let server = state.server.lock().unwrap().clone();
+                error!(
+                    "server {} sent unknown frame {}, it speaks a newer protocol",
+                    server, op
+                );
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "unknown frame",
+                ));
+            }
         }
     }
-
-    Ok(())
 }
 
 async fn process_tunnel_write<W: Write + Unpin, S: Stream<Item = TunnelMsg> + Unpin>(
+    tid: u32,
     key: Vec<u8>,
     msg_stream: &mut S,
+    replies: Receiver<TunnelMsg>,
     port_hub: &mut PortHub,
+    state: &TunnelState,
     stream: &mut W,
 ) -> std::io::Result<()> {
     let mut encryptor = Cryptor::new(&key);
     let mut alive_time = Instant::now();
+    let mut msg_stream = msg_stream.merge(replies);
 
     stream.write_all(encryptor.ctr_as_slice()).await?;
     stream.write_all(&encryptor.encrypt(&VERIFY_DATA)).await?;
+    let mut extensions = vec![EXTENSION_WINDOW];
+    if compress_asked() {
+        extensions.push(EXTENSION_LZ4);
+    }
+    if state.resume.load(Ordering::Relaxed) {
+        extensions.push(EXTENSION_RESUME);
+    }
+    let version = encryptor.encrypt(&hello_data(&extensions));
+    stream.write_all(&pack_cs_hello_msg(&version)).await?;
+    port_hub.3.resuming = port_hub.3.broken.is_some();
 
     loop {
-        match msg_stream.next().await {
+        // The ports wait for the server to tell where they are, and then
+        // go on with what they sent meanwhile.
+        let msg = if port_hub.3.resuming || port_hub.3.held.is_empty() {
+            msg_stream.next().await
+        } else {
+            port_hub.3.held.pop_front()
+        };
+        let msg = match msg {
+            Some(msg) if port_hub.3.resuming && msg.port_id().is_some() => {
+                port_hub.3.held.push_back(msg);
+                continue;
+            }
+            msg => msg,
+        };
+
+        match msg {
             Some(TunnelMsg::Heartbeat) => {
                 let duration = Instant::now() - alive_time;
-                if duration.as_millis() > ALIVE_TIMEOUT_TIME_MS {
+                if duration.as_millis() > ALIVE_TIMEOUT_TIME_MS || state.is_closed() {
                     break;
                 }
 
+                if state.is_retired(port_hub) {
+                    info!("tunnel {} retired", tid);
+                    state.close();
+                    break;
+                }
+
+                let heard = duration.as_millis() < PORT_STALL_TIMEOUT_MS;
+                *state.port_stats.lock().unwrap() = port_hub.sample(heard);
+
+                state.heartbeat_sent();
                 stream.write_all(&pack_cs_heartbeat_msg()).await?;
+                // Servers from before would take it for a port.
+                if state.stats.load(Ordering::Relaxed) {
+                    stream.write_all(&pack_cs_stats_msg()).await?;
+                }
+
+                let timeout = PORT_CONNECT_TIMEOUT_MS.load(Ordering::Relaxed);
+                for id in port_hub.reap(Duration::from_millis(timeout)) {
+                    stream.write_all(&pack_cs_close_port_msg(id)).await?;
+                }
+            }
+
+            Some(TunnelMsg::SCDraining) => {
+                alive_time = Instant::now();
+                state.server_draining(tid);
+            }
+
+            Some(TunnelMsg::SCHello(buf)) => {
+                let version = peer_version(&buf);
+                let protocol = peer_protocol(&buf);
+                info!(
+                    "tunnel {} server runs {}, protocol {}",
+                    tid, version, protocol
+                );
+                *state.server_version.lock().unwrap() = Some(version);
+                if let Some(reason) = protocol_mismatch(protocol) {
+                    error!("tunnel {} refuses its server, {}", tid, reason);
+                    break;
+                }
+                let window = peer_extension(&buf, EXTENSION_WINDOW);
+                state.window.store(window, Ordering::Relaxed);
+                let compress = compress_asked() && peer_extension(&buf, EXTENSION_LZ4);
+                state.compress.store(compress, Ordering::Relaxed);
+                if compress {
+                    info!("tunnel {} compresses data", tid);
+                }
+                let udp = peer_extension(&buf, EXTENSION_UDP);
+                state.udp.store(udp, Ordering::Relaxed);
+                let bond = peer_extension(&buf, EXTENSION_BOND);
+                state.bond.store(bond, Ordering::Relaxed);
+                let closing = peer_extension(&buf, EXTENSION_CLOSING);
+                state.closing.store(closing, Ordering::Relaxed);
+                let stats = peer_extension(&buf, EXTENSION_STATS);
+                state.stats.store(stats, Ordering::Relaxed);
+
+                // The ports of the broken connection resume, see resume,
+                // unless the server no longer keeps ports.
+                if port_hub.3.resuming {
+                    if peer_extension(&buf, EXTENSION_RESUME) {
+                        let data = encryptor.encrypt(&port_hub.resume_data());
+                        stream.write_all(&pack_cs_resume_msg(&data)).await?;
+                    } else {
+                        port_hub.end_session(state);
+                    }
+                }
+            }
+
+            // Ports opened from now on may resume, see resume.
+            Some(TunnelMsg::SCSession(buf)) => {
+                if !port_hub.3.resuming {
+                    port_hub.3.server = state.server.lock().unwrap().clone();
+                    port_hub.3.token = Some(buf);
+                }
+            }
+
+            Some(TunnelMsg::SCResumed(buf)) => {
+                alive_time = Instant::now();
+                resume_ports(tid, &buf, port_hub, &mut encryptor, stream).await?;
+            }
+
+            Some(TunnelMsg::SCStats(buf)) => {
+                alive_time = Instant::now();
+                match TunnelStats::decode(&buf) {
+                    Some(stats) => *state.remote_stats.lock().unwrap() = Some(stats),
+                    None => error!("tunnel {} got stats it can't read", tid),
+                }
+            }
+
+            Some(TunnelMsg::SCRefused(buf)) => {
+                let server = state.server.lock().unwrap().clone();
+                let reason = String::from_utf8_lossy(&buf);
+                error!("tunnel {} refused by server {}, {}", tid, server, reason);
+                break;
+            }
+
+            // Servers from before would take it for a port.
+            Some(TunnelMsg::CSClosing) => {
+                if state.closing.load(Ordering::Relaxed) {
+                    stream.write_all(&pack_cs_closing_msg()).await?;
+                }
             }
 
+            // Servers from before would take it for data.
+            Some(TunnelMsg::CSWindow(id, bytes)) => {
+                if state.window.load(Ordering::Relaxed) {
+                    if let Some(transfer) = port_hub.transfer(id) {
+                        transfer.grant(bytes);
+                    }
+                    stream.write_all(&pack_cs_window_msg(id, bytes)).await?;
+                }
+            }
+
+            // Servers from before would misread them. A bond doesn't
+            // resume.
+            Some(TunnelMsg::CSJoinBond(id, buf)) => {
+                if let Some(value) = port_hub.1.get_mut(&id) {
+                    value.transfer = None;
+                }
+                if state.bond.load(Ordering::Relaxed) {
+                    let data = encryptor.encrypt(&buf);
+                    stream.write_all(&pack_cs_join_bond_msg(id, &data)).await?;
+                }
+            }
+
+            Some(TunnelMsg::CSStripedData(id, seq, buf)) => {
+                state.queue.pop(buf.len());
+                if state.bond.load(Ordering::Relaxed) {
+                    state
+                        .uploaded
+                        .fetch_add(buf.len() as u64, Ordering::Relaxed);
+                    port_hub.client_send_data(id, buf.len());
+                    stream
+                        .write_all(&pack_cs_striped_data_msg(id, seq, &buf, &mut encryptor))
+                        .await?;
+                }
+            }
+
+            // Asked for on the connection before, which the port ended
+            // with, if this server doesn't relay UDP.
+            Some(TunnelMsg::CSOpenUdp(id, tx)) => {
+                if state.udp.load(Ordering::Relaxed) {
+                    port_hub.2.insert(id, tx);
+                    stream.write_all(&pack_cs_open_udp_msg(id)).await?;
+                }
+            }
+
+            Some(TunnelMsg::CloseTunnel) => break,
+
             Some(msg) => {
-                process_tunnel_msg(msg, &mut alive_time, port_hub, &mut encryptor, stream).await?;
+                match msg {
+                    TunnelMsg::CSData(_, ref buf) => {
+                        state.queue.pop(buf.len());
+                        state
+                            .uploaded
+                            .fetch_add(buf.len() as u64, Ordering::Relaxed);
+                    }
+                    TunnelMsg::SCData(_, ref buf) | TunnelMsg::SCStripedData(_, _, ref buf) => {
+                        state
+                            .downloaded
+                            .fetch_add(buf.len() as u64, Ordering::Relaxed);
+                    }
+                    _ => {}
+                }
+
+                let compress = state.compress.load(Ordering::Relaxed);
+                process_tunnel_msg(
+                    msg,
+                    compress,
+                    &mut alive_time,
+                    port_hub,
+                    &mut encryptor,
+                    stream,
+                )
+                .await?;
+                state.open_ports.store(port_hub.1.len(), Ordering::Relaxed);
             }
 
             None => break,
@@ -528,20 +2113,92 @@ async fn process_tunnel_write<W: Write + Unpin, S: Stream<Item = TunnelMsg> + Un
     Ok(())
 }
 
+// Takes where the ports are at the server, sends again what it didn't
+// receive, see resume, and closes the ports it no longer has, or that
+// can't be where it says.
+async fn resume_ports<W: Write + Unpin>(
+    tid: u32,
+    data: &[u8],
+    port_hub: &mut PortHub,
+    encryptor: &mut Cryptor,
+    stream: &mut W,
+) -> std::io::Result<()> {
+    let (token, offsets) = if data.len() >= SESSION_TOKEN_SIZE {
+        let offsets = resume::parse_offsets(&data[SESSION_TOKEN_SIZE..]);
+        (
+            Some(data[..SESSION_TOKEN_SIZE].to_vec()),
+            offsets.unwrap_or_default(),
+        )
+    } else {
+        (None, Vec::new())
+    };
+
+    let mut resumed: Vec<PortOffsets> = Vec::new();
+    let ids: Vec<u32> = port_hub.1.keys().cloned().collect();
+    for id in ids {
+        let peer = offsets.iter().find(|peer| peer.id == id);
+        let value = port_hub.1.get_mut(&id).unwrap();
+        match peer.and_then(|peer| Some((peer, value.transfer.as_mut()?.resume(peer)?))) {
+            Some((peer, credit)) => {
+                value.window.grant(credit);
+                resumed.push(*peer);
+            }
+            None => {
+                let _ = value.tx.try_send(TunnelPortMsg::TunnelReconnecting);
+                port_hub.remove_port(id, CloseReason::TunnelBroken);
+                if peer.is_some() {
+                    stream.write_all(&pack_cs_close_port_msg(id)).await?;
+                }
+            }
+        }
+    }
+    info!("tunnel {} resumed {} ports", tid, resumed.len());
+
+    let session = &mut port_hub.3;
+    session.token = token;
+    session.broken = None;
+    session.resuming = false;
+    for peer in resumed.iter() {
+        if let Some(transfer) = port_hub.transfer(peer.id) {
+            for buf in transfer.unacked() {
+                stream
+                    .write_all(&pack_cs_data_msg(peer.id, buf, encryptor))
+                    .await?;
+            }
+            if transfer.shutdown_lost(peer) {
+                stream
+                    .write_all(&pack_cs_shutdown_write_msg(peer.id))
+                    .await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
 async fn process_tunnel_msg<W: Write + Unpin>(
     msg: TunnelMsg,
+    compress: bool,
     alive_time: &mut Instant,
     port_hub: &mut PortHub,
     encryptor: &mut Cryptor,
     stream: &mut W,
 ) -> std::io::Result<()> {
     match msg {
-        TunnelMsg::CSOpenPort(id, tx) => {
-            port_hub.add_port(id, tx);
+        TunnelMsg::CSOpenPort(id, tx, window) => {
+            port_hub.add_port(id, tx, window);
             stream.write_all(&pack_cs_open_port_msg(id)).await?;
         }
 
         TunnelMsg::CSConnect(id, buf) => {
+            if let Some(addr) = from_utf8(&buf)
+                .ok()
+                .and_then(|s| s.parse::<SocketAddr>().ok())
+            {
+                port_hub.update_port(id, addr.ip().to_string(), addr.port());
+            }
+            port_hub.connecting(id);
+
             let data = encryptor.encrypt(&buf);
             stream.write_all(&pack_cs_connect_msg(id, &data)).await?;
         }
@@ -550,6 +2207,7 @@ async fn process_tunnel_msg<W: Write + Unpin>(
             let host = String::from_utf8(buf.clone()).unwrap_or(String::new());
             info!("{}.{}: connecting {}:{}", port_hub.get_id(), id, host, port);
             port_hub.update_port(id, host, port);
+            port_hub.connecting(id);
 
             let data = encryptor.encrypt(&buf);
             let packed_buffer = pack_cs_connect_domain_msg(id, &data, port);
@@ -558,12 +2216,29 @@ async fn process_tunnel_msg<W: Write + Unpin>(
 
         TunnelMsg::CSShutdownWrite(id) => {
             port_hub.client_shutdown(id);
+            if let Some(transfer) = port_hub.transfer(id) {
+                transfer.shut_down();
+            }
             stream.write_all(&pack_cs_shutdown_write_msg(id)).await?;
         }
 
         TunnelMsg::CSData(id, buf) => {
-            let data = encryptor.encrypt(&buf);
-            stream.write_all(&pack_cs_data_msg(id, &data)).await?;
+            port_hub.client_send_data(id, buf.len());
+            if let Some(transfer) = port_hub.transfer(id) {
+                transfer.send(&buf);
+            }
+            match compress.then(|| compress::compress(&buf)).flatten() {
+                Some(compressed) => {
+                    stream
+                        .write_all(&pack_cs_data_lz4_msg(id, &compressed, encryptor))
+                        .await?;
+                }
+                None => {
+                    stream
+                        .write_all(&pack_cs_data_msg(id, &buf, encryptor))
+                        .await?;
+                }
+            }
         }
 
         TunnelMsg::CSClosePort(id) => {
@@ -571,6 +2246,15 @@ async fn process_tunnel_msg<W: Write + Unpin>(
             stream.write_all(&pack_cs_close_port_msg(id)).await?;
         }
 
+        TunnelMsg::CSUdpData(id, buf) if port_hub.2.contains_key(&id) => {
+            let data = encryptor.encrypt(&buf);
+            stream.write_all(&pack_cs_udp_data_msg(id, &data)).await?;
+        }
+
+        TunnelMsg::CSCloseUdp(id) if port_hub.2.remove(&id).is_some() => {
+            stream.write_all(&pack_cs_close_udp_msg(id)).await?;
+        }
+
         TunnelMsg::SCHeartbeat => {
             *alive_time = Instant::now();
         }
@@ -592,7 +2276,27 @@ async fn process_tunnel_msg<W: Write + Unpin>(
 
         TunnelMsg::SCData(id, buf) => {
             *alive_time = Instant::now();
-            port_hub.server_send_data(id, buf).await;
+            port_hub.server_send_data(id, None, buf).await;
+        }
+
+        TunnelMsg::SCStripedData(id, seq, buf) => {
+            *alive_time = Instant::now();
+            port_hub.server_send_data(id, Some(seq), buf).await;
+        }
+
+        TunnelMsg::SCWindow(id, bytes) => {
+            *alive_time = Instant::now();
+            port_hub.server_window(id, bytes);
+        }
+
+        TunnelMsg::SCUdpData(id, buf) => {
+            *alive_time = Instant::now();
+            port_hub.server_send_udp(id, buf);
+        }
+
+        TunnelMsg::SCCloseUdp(id) => {
+            *alive_time = Instant::now();
+            port_hub.2.remove(&id);
         }
 
         TunnelMsg::TunnelPortHalfDrop(id) => {
@@ -604,3 +2308,305 @@ async fn process_tunnel_msg<W: Write + Unpin>(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use async_std::io::{ReadExt, WriteExt};
+
+    use super::*;
+    use crate::backpressure::PORT_WINDOW;
+    use crate::duplex::{duplex, PipeReader, PipeWriter};
+
+    const KEY: &[u8] = b"tunnel core test key";
+
+    // A tunnel whose core runs one connection over `stream`, ending as
+    // a broken connection does when either direction fails.
+    fn tunnel(stream: (PipeReader, PipeWriter)) -> Tunnel {
+        reconnecting_tunnel(vec![stream], false)
+    }
+
+    // A tunnel whose core runs a connection over each of `streams` in
+    // turn, asking the server to keep the ports when `resume`.
+    fn reconnecting_tunnel(streams: Vec<(PipeReader, PipeWriter)>, resume: bool) -> Tunnel {
+        let (main_sender, sub_senders, receivers) = channel_bus(1, 1000);
+        let (priority_sender, priority_receiver) = channel(1000);
+        let selector = ServerSelector::new(vec!["server:1".to_string()], KEY.to_vec());
+        let state = TunnelState::new(selector);
+        state.resume.store(resume, Ordering::Relaxed);
+        let core_state = state.clone();
+
+        let core = task::spawn(async move {
+            let mut msg_stream = prioritized(priority_receiver, receivers);
+            let mut port_hub = PortHub::new(1);
+            for (mut reader, mut writer) in streams {
+                let (core_tx, replies) = channel(1000);
+                let r = process_tunnel_read(KEY.to_vec(), core_tx, &core_state, &mut reader);
+                let w = process_tunnel_write(
+                    1,
+                    KEY.to_vec(),
+                    &mut msg_stream,
+                    replies,
+                    &mut port_hub,
+                    &core_state,
+                    &mut writer,
+                );
+                let _ = r.race(w).await;
+                core_state.set_connected(false);
+                port_hub.park_ports(&core_state);
+            }
+            port_hub.end_session(&core_state);
+        });
+
+        Tunnel {
+            id: 1,
+            senders: sub_senders,
+            main_sender,
+            priority_sender,
+            state,
+            core: Some(core),
+        }
+    }
+
+    // The server end of a connection, writing and reading frames itself.
+    struct Server {
+        reader: PipeReader,
+        writer: PipeWriter,
+        decryptor: Cryptor,
+        encryptor: Cryptor,
+    }
+
+    impl Server {
+        // Takes the handshake of the client and answers its HELLO with
+        // `extensions`.
+        async fn accept(stream: (PipeReader, PipeWriter), extensions: &[&str]) -> Server {
+            let (mut reader, mut writer) = stream;
+            let mut ctr = vec![0; CTR_SIZE];
+            reader.read_exact(&mut ctr).await.unwrap();
+            let mut decryptor = Cryptor::with_ctr(KEY, ctr);
+            let mut verify = [0; 8];
+            reader.read_exact(&mut verify).await.unwrap();
+            assert_eq!(decryptor.decrypt(&verify), VERIFY_DATA);
+
+            let encryptor = Cryptor::new(KEY);
+            writer.write_all(encryptor.ctr_as_slice()).await.unwrap();
+
+            let mut server = Server {
+                reader,
+                writer,
+                decryptor,
+                encryptor,
+            };
+            assert_eq!(server.frame().await.0, cs::HELLO);
+            let hello = server.encryptor.encrypt(&hello_data(extensions));
+            server.send(&pack_sc_hello_msg(&hello)).await;
+            server
+        }
+
+        async fn send(&mut self, frame: &[u8]) {
+            self.writer.write_all(frame).await.unwrap();
+        }
+
+        async fn send_data(&mut self, id: u32, data: &[u8]) {
+            let frame = pack_sc_data_msg(id, data, &mut self.encryptor);
+            self.send(&frame).await;
+        }
+
+        async fn send_encrypted(&mut self, pack: fn(&[u8]) -> Vec<u8>, data: &[u8]) {
+            let data = self.encryptor.encrypt(data);
+            self.send(&pack(&data)).await;
+        }
+
+        // Names a session for the ports, see resume, and gives the client
+        // the time to take it before they open.
+        async fn session(&mut self) -> Vec<u8> {
+            let token = resume::new_token().to_vec();
+            self.send_encrypted(pack_sc_session_msg, &token).await;
+            task::sleep(Duration::from_millis(100)).await;
+            token
+        }
+
+        async fn u32(&mut self) -> u32 {
+            let mut buf = [0u8; 4];
+            self.reader.read_exact(&mut buf).await.unwrap();
+            u32::from_be_bytes(buf)
+        }
+
+        // The next frame of the client, its data decrypted, and the bytes
+        // granted as the data of a WINDOW.
+        async fn frame(&mut self) -> (u8, u32, Vec<u8>) {
+            let mut op = [0u8; 1];
+            self.reader.read_exact(&mut op).await.unwrap();
+            let id = self.u32().await;
+
+            let data = match op[0] {
+                cs::CONNECT | cs::DATA | cs::HELLO | cs::RESUME => {
+                    let mut data = vec![0; self.u32().await as usize];
+                    self.reader.read_exact(&mut data).await.unwrap();
+                    self.decryptor.decrypt(&data)
+                }
+                cs::WINDOW => self.u32().await.to_be_bytes().to_vec(),
+                _ => Vec::new(),
+            };
+            (op[0], id, data)
+        }
+    }
+
+    // A port of the tunnel, connected once the server answered.
+    async fn connected_port(
+        tunnel: &mut Tunnel,
+        server: &mut Server,
+    ) -> (TunnelWritePort, TunnelReadPort) {
+        let (mut write_port, mut read_port) = tunnel.open_port().await;
+        assert_eq!(server.frame().await, (cs::OPEN_PORT, 1, Vec::new()));
+
+        write_port.connect(b"10.0.0.1:80".to_vec()).await;
+        let (op, id, addr) = server.frame().await;
+        assert_eq!((op, id, &addr[..]), (cs::CONNECT, 1, &b"10.0.0.1:80"[..]));
+
+        let bound = server.encryptor.encrypt(b"10.0.0.2:4000");
+        server.send(&pack_sc_connect_ok_msg(1, &bound)).await;
+        assert!(
+            matches!(read_port.read().await, TunnelPortMsg::ConnectOk(addr) if addr == b"10.0.0.2:4000")
+        );
+
+        (write_port, read_port)
+    }
+
+    // The session with port 1 at `received` bytes, as RESUME and RESUMED
+    // carry it.
+    fn resume_data(token: &[u8], received: u64) -> Vec<u8> {
+        let offsets = PortOffsets {
+            id: 1,
+            received,
+            granted: 0,
+            shut: false,
+        };
+        let mut data = token.to_vec();
+        data.extend(resume::pack_offsets(&[offsets]));
+        data
+    }
+
+    #[test]
+    fn port_lifecycle() {
+        task::block_on(async {
+            let (client, server) = duplex(7);
+            let mut tunnel = tunnel(client);
+            let mut server = Server::accept(server, &[]).await;
+            let (mut write_port, mut read_port) = connected_port(&mut tunnel, &mut server).await;
+
+            write_port.write(DataBuf::from(&b"request"[..])).await;
+            let (op, id, data) = server.frame().await;
+            assert_eq!((op, id, &data[..]), (cs::DATA, 1, &b"request"[..]));
+
+            server.send_data(1, b"response").await;
+            assert!(
+                matches!(read_port.read().await, TunnelPortMsg::Data(data) if &data[..] == b"response")
+            );
+
+            write_port.close().await;
+            assert_eq!(server.frame().await, (cs::CLOSE_PORT, 1, Vec::new()));
+            assert!(matches!(read_port.read().await, TunnelPortMsg::ClosePort));
+        });
+    }
+
+    #[test]
+    fn port_stalls_without_credit() {
+        task::block_on(async {
+            let (client, server) = duplex(1500);
+            let mut tunnel = tunnel(client);
+            let mut server = Server::accept(server, &[EXTENSION_WINDOW]).await;
+            let (mut write_port, mut read_port) = connected_port(&mut tunnel, &mut server).await;
+
+            let writer = task::spawn(async move {
+                let data = vec![7u8; PORT_WINDOW + 1000];
+                write_port.write(DataBuf::from(&data[..])).await;
+                write_port
+            });
+
+            let mut received = 0;
+            while received < PORT_WINDOW {
+                let (op, _, data) = server.frame().await;
+                assert_eq!(op, cs::DATA);
+                received += data.len();
+            }
+            assert_eq!(received, PORT_WINDOW);
+
+            let stalled = future::timeout(Duration::from_millis(200), server.frame()).await;
+            assert!(stalled.is_err());
+
+            server.send(&pack_sc_window_msg(1, 1000)).await;
+            let (op, _, data) = server.frame().await;
+            assert_eq!((op, data.len()), (cs::DATA, 1000));
+            writer.await;
+
+            // The other way the client grants credit once a step of it
+            // was written out.
+            let data = vec![7u8; MAX_DATA_FRAME_SIZE];
+            for _ in 0..PORT_WINDOW_STEP / MAX_DATA_FRAME_SIZE {
+                server.send_data(1, &data).await;
+                match read_port.read().await {
+                    TunnelPortMsg::Data(data) => read_port.consumed(data.len()).await,
+                    _ => panic!("no data"),
+                }
+            }
+            let granted = (PORT_WINDOW_STEP as u32).to_be_bytes().to_vec();
+            assert_eq!(server.frame().await, (cs::WINDOW, 1, granted));
+        });
+    }
+
+    #[test]
+    fn port_resumes() {
+        task::block_on(async {
+            let extensions = [EXTENSION_WINDOW, EXTENSION_RESUME];
+            let (first, broken) = duplex(7);
+            let (second, next) = duplex(7);
+            let mut tunnel = reconnecting_tunnel(vec![first, second], true);
+            let mut server = Server::accept(broken, &extensions).await;
+            let token = server.session().await;
+            let (mut write_port, mut read_port) = connected_port(&mut tunnel, &mut server).await;
+
+            for data in [&b"0123"[..], b"4567"] {
+                write_port.write(DataBuf::from(data)).await;
+                assert_eq!(server.frame().await.0, cs::DATA);
+            }
+            server.send_data(1, b"abcdef").await;
+            assert!(matches!(read_port.read().await, TunnelPortMsg::Data(_)));
+
+            // The port goes on while the connection is down, and the
+            // server only got the first data.
+            drop(server);
+            write_port.write(DataBuf::from(&b"89"[..])).await;
+            server = Server::accept(next, &extensions).await;
+            let resume = resume_data(&token, 6);
+            assert_eq!(server.frame().await, (cs::RESUME, 0, resume));
+
+            let resumed = resume_data(&token, 4);
+            server.send_encrypted(pack_sc_resumed_msg, &resumed).await;
+            assert_eq!(server.frame().await, (cs::DATA, 1, b"4567".to_vec()));
+            assert_eq!(server.frame().await, (cs::DATA, 1, b"89".to_vec()));
+
+            server.send_data(1, b"gh").await;
+            assert!(
+                matches!(read_port.read().await, TunnelPortMsg::Data(data) if &data[..] == b"gh")
+            );
+        });
+    }
+
+    #[test]
+    fn read_broken_mid_frame() {
+        task::block_on(async {
+            let (client, server) = duplex(3);
+            let mut tunnel = tunnel(client);
+            let mut server = Server::accept(server, &[]).await;
+            let (_write_port, mut read_port) = connected_port(&mut tunnel, &mut server).await;
+
+            // The header and half of the data get through.
+            server.writer.break_after(9 + 5);
+            server.send_data(1, b"0123456789").await;
+            assert!(matches!(
+                read_port.read().await,
+                TunnelPortMsg::TunnelReconnecting
+            ));
+        });
+    }
+}
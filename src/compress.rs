@@ -0,0 +1,58 @@
+// LZ4 for the data frames of a tunnel, used once both ends announced it,
+// see protocol::EXTENSION_LZ4. A frame is only sent compressed when it
+// shrinks, so compressed and plain frames mix on one tunnel.
+#[cfg(feature = "compression")]
+use lz4_flex::block;
+
+#[cfg(feature = "compression")]
+use super::protocol::MAX_DATA_FRAME_SIZE;
+
+// Frames smaller than this are sent as they are, LZ4 gains nothing on
+// them.
+pub const MIN_COMPRESS_SIZE: usize = 256;
+
+// Built with LZ4, the end can announce it.
+pub fn available() -> bool {
+    cfg!(feature = "compression")
+}
+
+// The frame compressed, or None when it is too small or doesn't shrink.
+#[cfg(feature = "compression")]
+pub fn compress(data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < MIN_COMPRESS_SIZE {
+        return None;
+    }
+
+    let compressed = block::compress_prepend_size(data);
+    if compressed.len() < data.len() {
+        Some(compressed)
+    } else {
+        None
+    }
+}
+
+#[cfg(not(feature = "compression"))]
+pub fn compress(_data: &[u8]) -> Option<Vec<u8>> {
+    None
+}
+
+#[cfg(feature = "compression")]
+pub fn decompress(data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < 4 {
+        return None;
+    }
+
+    // A compressed frame names its size, anything larger than a data
+    // frame is refused rather than allocated.
+    let size = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+    if size > MAX_DATA_FRAME_SIZE {
+        return None;
+    }
+
+    block::decompress_size_prepended(data).ok()
+}
+
+#[cfg(not(feature = "compression"))]
+pub fn decompress(_data: &[u8]) -> Option<Vec<u8>> {
+    None
+}
@@ -1,7 +1,7 @@
 use crypto::blockmodes::CtrMode;
 use crypto::blowfish::Blowfish;
 use crypto::buffer::{BufferResult, ReadBuffer, RefReadBuffer, RefWriteBuffer, WriteBuffer};
-use crypto::symmetriccipher::{Decryptor, Encryptor};
+use crypto::symmetriccipher::{Decryptor, Encryptor, SynchronousStreamCipher};
 use rand;
 use std::vec::Vec;
 
@@ -31,6 +31,10 @@ impl Cryptor {
         }
     }
 
+    pub fn name() -> &'static str {
+        "blowfish-ctr"
+    }
+
     pub fn key_size_range() -> (usize, usize) {
         (4, 56)
     }
@@ -71,6 +75,26 @@ impl Cryptor {
         result
     }
 
+    // CTR encrypts and decrypts alike, and keeps the size of the data, so
+    // the data path does both in its own buffers.
+    pub fn encrypt_in_place(&mut self, data: &mut [u8]) {
+        self.process_in_place(data);
+    }
+
+    pub fn decrypt_in_place(&mut self, data: &mut [u8]) {
+        self.process_in_place(data);
+    }
+
+    fn process_in_place(&mut self, data: &mut [u8]) {
+        let mut buffer = [0; 2048];
+
+        for chunk in data.chunks_mut(buffer.len()) {
+            let input = &mut buffer[..chunk.len()];
+            input.copy_from_slice(chunk);
+            SynchronousStreamCipher::process(&mut self.cryptor, input, chunk);
+        }
+    }
+
     pub fn decrypt(&mut self, data: &[u8]) -> Vec<u8> {
         let mut result = Vec::<u8>::new();
         let mut read_buffer = RefReadBuffer::new(data);
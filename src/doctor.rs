@@ -0,0 +1,212 @@
+use std::io::ErrorKind;
+use std::net::ToSocketAddrs;
+#[cfg(feature = "ucp")]
+use std::time::Duration;
+use std::time::Instant;
+use std::vec::Vec;
+
+#[cfg(feature = "ucp")]
+use async_std::task;
+
+use super::cryptor::Cryptor;
+use super::selector::probe_server;
+#[cfg(feature = "ucp")]
+use super::ucp::UcpStream;
+
+const CRYPTO_BENCHMARK_BYTES: usize = 16 * 1024 * 1024;
+const CRYPTO_BENCHMARK_CHUNK: usize = 1024;
+#[cfg(feature = "ucp")]
+const UDP_HANDSHAKE_TIMEOUT_MS: u64 = 5000;
+#[cfg(feature = "ucp")]
+const UDP_MTU_SEARCH_MS: u64 = 3000;
+#[cfg(feature = "ucp")]
+const UDP_MAX_PACKET_SIZE: usize = 9000;
+
+pub struct Check {
+    pub section: String,
+    pub name: &'static str,
+    pub ok: bool,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(section: &str, name: &'static str, ok: bool, detail: String) -> Check {
+        Check {
+            section: section.to_string(),
+            name,
+            ok,
+            detail,
+        }
+    }
+}
+
+// Self-tests of the local environment and of every server, with `ucp`
+// the UDP path is tested as well.
+pub async fn run(server_addrs: &[String], key: &[u8], ucp: bool) -> Vec<Check> {
+    let mut checks = vec![
+        Check::new(
+            "environment",
+            "version",
+            true,
+            env!("CARGO_PKG_VERSION").to_string(),
+        ),
+        Check::new(
+            "environment",
+            "platform",
+            true,
+            format!("{} {}", std::env::consts::OS, std::env::consts::ARCH),
+        ),
+        Check::new("environment", "features", true, features().join(", ")),
+        check_crypto(key),
+    ];
+
+    for addr in server_addrs.iter() {
+        let resolved = match addr.to_socket_addrs() {
+            Ok(mut addrs) => addrs.next(),
+            Err(_) => None,
+        };
+
+        let resolved = match resolved {
+            Some(resolved) => resolved.to_string(),
+            None => {
+                checks.push(Check::new(addr, "resolve", false, "failed".to_string()));
+                continue;
+            }
+        };
+
+        checks.push(Check::new(addr, "resolve", true, resolved.clone()));
+        checks.push(check_tcp(addr, &resolved, key).await);
+
+        if ucp {
+            checks.extend(check_udp(addr, &resolved, key).await);
+        }
+    }
+
+    checks
+}
+
+// One line per check, grouped by section, and a summary line.
+pub fn report(checks: &[Check]) -> String {
+    let mut report = String::new();
+    let mut section = "";
+
+    for check in checks.iter() {
+        if check.section != section {
+            section = &check.section;
+            report.push_str(&format!("{}\n", section));
+        }
+
+        let status = if check.ok { "ok" } else { "FAIL" };
+        report.push_str(&format!(
+            "  {:<10} {:<4} {}\n",
+            check.name, status, check.detail
+        ));
+    }
+
+    let failed = checks.iter().filter(|c| !c.ok).count();
+    report.push_str(&format!("{} checks, {} failed\n", checks.len(), failed));
+    report
+}
+
+fn features() -> Vec<&'static str> {
+    let mut features = Vec::new();
+    if cfg!(feature = "ucp") {
+        features.push("ucp");
+    }
+    if cfg!(feature = "local-time") {
+        features.push("local-time");
+    }
+    features
+}
+
+// Throughput of the tunnel cipher in the chunk size ports read with.
+fn check_crypto(key: &[u8]) -> Check {
+    let (min, max) = Cryptor::key_size_range();
+    if key.len() < min || key.len() > max {
+        let detail = format!("key length must in range [{}, {}]", min, max);
+        return Check::new("environment", "crypto", false, detail);
+    }
+
+    let mut cryptor = Cryptor::new(key);
+    let chunk = vec![0u8; CRYPTO_BENCHMARK_CHUNK];
+    let start = Instant::now();
+
+    for _ in 0..CRYPTO_BENCHMARK_BYTES / CRYPTO_BENCHMARK_CHUNK {
+        cryptor.encrypt(&chunk);
+    }
+
+    let secs = start.elapsed().as_secs_f64().max(f64::EPSILON);
+    let mbps = CRYPTO_BENCHMARK_BYTES as f64 / secs / (1024.0 * 1024.0);
+    Check::new(
+        "environment",
+        "crypto",
+        true,
+        format!("tunnel cipher {:.0} MiB/s", mbps),
+    )
+}
+
+async fn check_tcp(section: &str, addr: &str, key: &[u8]) -> Check {
+    match probe_server(addr, key).await {
+        Ok((rtt, draining)) => {
+            let mut detail = format!("rtt {}ms, key accepted", rtt.as_millis());
+            if draining {
+                detail.push_str(", draining");
+            }
+            Check::new(section, "tcp", true, detail)
+        }
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+            let detail = "closed by the server, is the key right?";
+            Check::new(section, "tcp", false, detail.to_string())
+        }
+        Err(e) => Check::new(section, "tcp", false, e.to_string()),
+    }
+}
+
+// A UCP handshake, then the packet size path MTU discovery reaches
+// within a few seconds.
+#[cfg(feature = "ucp")]
+async fn check_udp(section: &str, addr: &str, key: &[u8]) -> Vec<Check> {
+    let stream = match UcpStream::connect_authenticated(addr, 0, key).await {
+        Ok(stream) => stream,
+        Err(e) => return vec![Check::new(section, "udp", false, e.to_string())],
+    };
+    stream.set_max_packet_size(UDP_MAX_PACKET_SIZE);
+
+    let start = Instant::now();
+    let timeout = Duration::from_millis(UDP_HANDSHAKE_TIMEOUT_MS);
+    while !stream.is_established() && start.elapsed() < timeout {
+        task::sleep(Duration::from_millis(10)).await;
+    }
+
+    if !stream.is_established() {
+        stream.shutdown();
+        let detail = "no handshake, is ucp enabled on the server and UDP allowed?";
+        return vec![Check::new(section, "udp", false, detail.to_string())];
+    }
+
+    let rtt = start.elapsed();
+    task::sleep(Duration::from_millis(UDP_MTU_SEARCH_MS)).await;
+    let stats = stream.stats();
+    stream.close().await;
+
+    vec![
+        Check::new(
+            section,
+            "udp",
+            true,
+            format!("handshake in {}ms, key accepted", rtt.as_millis()),
+        ),
+        Check::new(
+            section,
+            "mtu",
+            true,
+            format!("ucp packets of {} bytes pass", stats.packet_size),
+        ),
+    ]
+}
+
+#[cfg(not(feature = "ucp"))]
+async fn check_udp(section: &str, _addr: &str, _key: &[u8]) -> Vec<Check> {
+    let detail = "built without ucp".to_string();
+    vec![Check::new(section, "udp", false, detail)]
+}
@@ -0,0 +1,122 @@
+// An in-memory connection for tests of the tunnel cores, which read and
+// write any async stream. What one end writes the other reads, at most a
+// chunk at a time so frames arrive split, and a read can be made to fail
+// after some bytes, as a connection breaking in the middle of a frame.
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use async_std::io::{Read, Write};
+
+struct Pipe {
+    buf: VecDeque<u8>,
+    chunk: usize,
+    // Bytes left to read before reads fail, see PipeWriter::break_after.
+    breaks_after: Option<usize>,
+    closed: bool,
+    waker: Option<Waker>,
+}
+
+pub struct PipeReader(Arc<Mutex<Pipe>>);
+
+pub struct PipeWriter(Arc<Mutex<Pipe>>);
+
+// One direction of a connection, read `chunk` bytes at a time at most.
+pub fn pipe(chunk: usize) -> (PipeWriter, PipeReader) {
+    let pipe = Arc::new(Mutex::new(Pipe {
+        buf: VecDeque::new(),
+        chunk: chunk.max(1),
+        breaks_after: None,
+        closed: false,
+        waker: None,
+    }));
+    (PipeWriter(pipe.clone()), PipeReader(pipe))
+}
+
+// Both ends of a connection, each reading what the other writes.
+pub fn duplex(chunk: usize) -> ((PipeReader, PipeWriter), (PipeReader, PipeWriter)) {
+    let (a_writer, b_reader) = pipe(chunk);
+    let (b_writer, a_reader) = pipe(chunk);
+    ((a_reader, a_writer), (b_reader, b_writer))
+}
+
+impl Read for PipeReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut pipe = self.0.lock().unwrap();
+        if pipe.breaks_after == Some(0) {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+
+        if pipe.buf.is_empty() {
+            if pipe.closed {
+                return Poll::Ready(Ok(0));
+            }
+            pipe.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let mut size = buf.len().min(pipe.buf.len()).min(pipe.chunk);
+        if let Some(left) = pipe.breaks_after {
+            size = size.min(left);
+            pipe.breaks_after = Some(left - size);
+        }
+        for (byte, read) in buf.iter_mut().zip(pipe.buf.drain(..size)) {
+            *byte = read;
+        }
+        Poll::Ready(Ok(size))
+    }
+}
+
+impl PipeWriter {
+    // Reads at the other end fail as a reset connection once `bytes`
+    // more were read.
+    pub fn break_after(&self, bytes: usize) {
+        self.0.lock().unwrap().breaks_after = Some(bytes);
+    }
+
+    fn close(&self) {
+        let mut pipe = self.0.lock().unwrap();
+        pipe.closed = true;
+        if let Some(waker) = pipe.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl Write for PipeWriter {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut pipe = self.0.lock().unwrap();
+        if pipe.closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+
+        pipe.buf.extend(buf);
+        if let Some(waker) = pipe.waker.take() {
+            waker.wake();
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        self.close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+// The other end reads to the end of what was written, then sees it
+// closed.
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
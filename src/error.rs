@@ -0,0 +1,63 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+// Errors of the library, each with enough context for the binaries to
+// say what went wrong and for embedders to tell them apart.
+#[derive(Debug)]
+pub enum Error {
+    // An I/O error, with what was being done when it happened.
+    Io { context: String, source: io::Error },
+    // An address that doesn't parse, with what it was given as.
+    InvalidAddress(String),
+    // A destination a peer asked for that can't be connected to.
+    InvalidDestination(String),
+    // A SOCKS5 request the client doesn't serve, with why.
+    Socks5(&'static str),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn io<C: Into<String>>(context: C, source: io::Error) -> Error {
+        Error::Io {
+            context: context.into(),
+            source,
+        }
+    }
+
+    // The kind of the underlying I/O error, if there is one.
+    pub fn io_kind(&self) -> Option<io::ErrorKind> {
+        match self {
+            Error::Io { source, .. } => Some(source.kind()),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io { context, source } if context.is_empty() => write!(f, "{}", source),
+            Error::Io { context, source } => write!(f, "{}: {}", context, source),
+            Error::InvalidAddress(addr) => write!(f, "invalid address {:?}", addr),
+            Error::InvalidDestination(dest) => write!(f, "invalid destination {:?}", dest),
+            Error::Socks5(reason) => write!(f, "socks5 request refused: {}", reason),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(source: io::Error) -> Error {
+        Error::io(String::new(), source)
+    }
+}
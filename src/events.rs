@@ -0,0 +1,118 @@
+use std::sync::RwLock;
+
+use futures::channel::mpsc::UnboundedSender;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloseReason {
+    ClientClosed,
+    ServerClosed,
+    // Both directions were shut down cleanly.
+    Finished,
+    TunnelBroken,
+    Error,
+    // Closed by the tunnel for connecting or idling too long.
+    TimedOut,
+}
+
+// Ports are identified by the tunnel id and the port id within the tunnel.
+// Byte totals count payload towards the destination as uploaded and payload
+// from the destination as downloaded, on both the client and the server.
+#[derive(Clone, Debug)]
+pub enum PortEvent {
+    Opened {
+        tunnel: u32,
+        id: u32,
+    },
+    Connected {
+        tunnel: u32,
+        id: u32,
+        host: String,
+        port: u16,
+    },
+    Closed {
+        tunnel: u32,
+        id: u32,
+        host: String,
+        port: u16,
+        reason: CloseReason,
+        uploaded: u64,
+        downloaded: u64,
+    },
+}
+
+// Events of a tunnel as a whole, on the client.
+#[derive(Clone, Debug)]
+pub enum TunnelEvent {
+    // A broken tunnel is up again, `attempts` sessions after the last one
+    // that came up. With `resumed` the session offered a resume ticket.
+    Reconnected {
+        tunnel: u32,
+        server: String,
+        attempts: u32,
+        resumed: bool,
+    },
+    // The server of a TCP tunnel answered no heartbeat for `missed`
+    // intervals, the tunnel reconnects, see client::set_missed_heartbeats.
+    Unresponsive {
+        tunnel: u32,
+        server: String,
+        missed: u32,
+    },
+}
+
+pub trait EventHandler: Send + Sync {
+    fn port_event(&self, event: &PortEvent);
+
+    fn tunnel_event(&self, _event: &TunnelEvent) {}
+}
+
+impl<F: Fn(&PortEvent) + Send + Sync> EventHandler for F {
+    fn port_event(&self, event: &PortEvent) {
+        self(event)
+    }
+}
+
+// Forwards events into a channel, for consumers that prefer a stream.
+pub struct ChannelHandler(UnboundedSender<PortEvent>);
+
+impl ChannelHandler {
+    pub fn new(sender: UnboundedSender<PortEvent>) -> ChannelHandler {
+        ChannelHandler(sender)
+    }
+}
+
+impl EventHandler for ChannelHandler {
+    fn port_event(&self, event: &PortEvent) {
+        let _ = self.0.unbounded_send(event.clone());
+    }
+}
+
+static HANDLERS: RwLock<Vec<Box<dyn EventHandler>>> = RwLock::new(Vec::new());
+
+// Handlers run inline in the tunnel core task, so they must return quickly.
+pub fn add_handler(handler: Box<dyn EventHandler>) {
+    HANDLERS.write().unwrap().push(handler);
+}
+
+// The event is only built when a handler is installed.
+pub(crate) fn emit<F: FnOnce() -> PortEvent>(event: F) {
+    let handlers = HANDLERS.read().unwrap();
+
+    if !handlers.is_empty() {
+        let event = event();
+        for handler in handlers.iter() {
+            handler.port_event(&event);
+        }
+    }
+}
+
+pub(crate) fn emit_tunnel<F: FnOnce() -> TunnelEvent>(event: F) {
+    let handlers = HANDLERS.read().unwrap();
+
+    if !handlers.is_empty() {
+        let event = event();
+        for handler in handlers.iter() {
+            handler.tunnel_event(&event);
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use std::fmt;
+use std::vec::Vec;
+
+use super::cryptor::Cryptor;
+#[cfg(feature = "ucp")]
+use super::ucp::UcpCapabilities;
+
+// What an established tunnel runs with, so operators can check in the
+// status that a rollout took effect on every tunnel.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TunnelFeatures {
+    pub transport: &'static str,
+    pub cipher: &'static str,
+    #[cfg(feature = "ucp")]
+    pub ucp: Option<UcpCapabilities>,
+}
+
+impl TunnelFeatures {
+    pub fn tcp() -> TunnelFeatures {
+        TunnelFeatures {
+            transport: "tcp",
+            cipher: Cryptor::name(),
+            #[cfg(feature = "ucp")]
+            ucp: None,
+        }
+    }
+
+    #[cfg(feature = "ucp")]
+    pub fn ucp(capabilities: UcpCapabilities) -> TunnelFeatures {
+        TunnelFeatures {
+            transport: "ucp",
+            cipher: Cryptor::name(),
+            ucp: Some(capabilities),
+        }
+    }
+
+    // The transport, with the protocol version for ucp, the cipher, then
+    // each feature the session negotiated.
+    pub fn names(&self) -> Vec<String> {
+        let names = vec![self.transport.to_string(), self.cipher.to_string()];
+        #[cfg(feature = "ucp")]
+        let names = match self.ucp {
+            Some(ucp) => ucp_names(names, &ucp),
+            None => names,
+        };
+        names
+    }
+}
+
+#[cfg(feature = "ucp")]
+fn ucp_names(mut names: Vec<String>, ucp: &UcpCapabilities) -> Vec<String> {
+    names[0] = format!("{}/{}", names[0], ucp.version);
+    if ucp.encrypted {
+        names.push("chacha20".to_string());
+    }
+    if ucp.keyed_check {
+        names.push("blake2s".to_string());
+    }
+    if ucp.fec_group != 0 {
+        names.push(format!("fec{}", ucp.fec_group));
+    }
+
+    let flags = [
+        ("channels", ucp.channels),
+        ("datagrams", ucp.datagrams),
+        ("resume", ucp.resume),
+        ("probes", ucp.probes),
+        ("loss-reports", ucp.loss_reports),
+    ];
+    names.extend(
+        flags
+            .iter()
+            .filter(|(_, on)| *on)
+            .map(|(name, _)| name.to_string()),
+    );
+    names
+}
+
+impl fmt::Display for TunnelFeatures {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.names().join(" "))
+    }
+}
@@ -0,0 +1,175 @@
+use std::io::{BufRead, BufReader, Read};
+use std::net::IpAddr;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use async_std::task;
+
+pub const DEFAULT_HOOK_TIMEOUT_MS: u64 = 1000;
+const HOOK_POLL_INTERVAL_MS: u64 = 5;
+const MAX_VERDICT_SIZE: u64 = 1024;
+
+static DENIED_CONNECTS: AtomicUsize = AtomicUsize::new(0);
+
+// What the hook decided for a destination.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    Deny,
+    Rewrite(String, u16),
+}
+
+// A command run through `sh -c` before the server connects a port, with
+// the client address and the destination in STUNNEL_CLIENT, STUNNEL_HOST
+// and STUNNEL_PORT. The first line it prints is `allow`, `deny` or
+// `rewrite host:port`, its stdout is closed after that line. Anything
+// else, a failed command or one still running at the timeout denies the
+// destination.
+pub struct ConnectHook {
+    pub command: String,
+    pub timeout: Duration,
+}
+
+impl ConnectHook {
+    pub fn new(command: String) -> ConnectHook {
+        ConnectHook {
+            command,
+            timeout: Duration::from_millis(DEFAULT_HOOK_TIMEOUT_MS),
+        }
+    }
+
+    // A process is started for every port, in a blocking task so slow
+    // hooks hold up only the port they decide on.
+    pub async fn check(&self, client: IpAddr, host: &str, port: u16) -> Verdict {
+        let command = self.command.clone();
+        let timeout = self.timeout;
+        let host = host.to_string();
+
+        let verdict = task::spawn_blocking(move || run(&command, timeout, client, &host, port))
+            .await
+            .unwrap_or_else(|e| {
+                error!("connect hook failed: {}", e);
+                Verdict::Deny
+            });
+
+        if verdict == Verdict::Deny {
+            DENIED_CONNECTS.fetch_add(1, Ordering::Relaxed);
+        }
+
+        verdict
+    }
+}
+
+// Destinations the connect hook denied.
+pub fn denied_connect_count() -> usize {
+    DENIED_CONNECTS.load(Ordering::Relaxed)
+}
+
+fn run(
+    command: &str,
+    timeout: Duration,
+    client: IpAddr,
+    host: &str,
+    port: u16,
+) -> Result<Verdict, String> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("STUNNEL_CLIENT", client.to_string())
+        .env("STUNNEL_HOST", host)
+        .env("STUNNEL_PORT", port.to_string())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| e.to_string())?;
+
+    // The verdict line is read on a thread of its own while waiting, and
+    // the pipe closed after it, so neither more output nor a process left
+    // behind holding the pipe keeps the port past the deadline.
+    let stdout = child.stdout.take().ok_or("no stdout")?;
+    let (sender, verdict) = mpsc::channel();
+    thread::spawn(move || {
+        let mut line = String::new();
+        let read = BufReader::new(stdout.take(MAX_VERDICT_SIZE)).read_line(&mut line);
+        let _ = sender.send(read.map(|_| line).map_err(|e| e.to_string()));
+    });
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        match child.try_wait().map_err(|e| e.to_string())? {
+            Some(status) => break status,
+            None if Instant::now() >= deadline => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!("timed out after {}ms", timeout.as_millis()));
+            }
+            None => thread::sleep(Duration::from_millis(HOOK_POLL_INTERVAL_MS)),
+        }
+    };
+
+    if !status.success() {
+        return Err(format!("exited with {}", status));
+    }
+
+    let line = verdict
+        .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        .map_err(|_| format!("no verdict after {}ms", timeout.as_millis()))??;
+    parse_verdict(line.trim())
+}
+
+fn parse_verdict(line: &str) -> Result<Verdict, String> {
+    let mut words = line.splitn(2, ' ');
+    match (words.next(), words.next()) {
+        (Some("allow"), None) => Ok(Verdict::Allow),
+        (Some("deny"), None) => Ok(Verdict::Deny),
+        (Some("rewrite"), Some(dest)) => {
+            let (host, port) = dest
+                .trim()
+                .rsplit_once(':')
+                .ok_or_else(|| format!("rewrite without port: {:?}", line))?;
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+            let port = port
+                .parse()
+                .map_err(|_| format!("rewrite with invalid port: {:?}", line))?;
+
+            if host.is_empty() {
+                return Err(format!("rewrite without host: {:?}", line));
+            }
+            Ok(Verdict::Rewrite(host.to_string(), port))
+        }
+        _ => Err(format!("unknown verdict {:?}", line)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_for(command: &str) -> Result<Verdict, String> {
+        let client = IpAddr::from([127, 0, 0, 1]);
+        run(
+            command,
+            Duration::from_millis(500),
+            client,
+            "example.com",
+            80,
+        )
+    }
+
+    #[test]
+    fn verdict_ahead_of_a_lingering_process() {
+        let start = Instant::now();
+        assert_eq!(run_for("echo allow; sleep 5 &"), Ok(Verdict::Allow));
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn no_verdict_line_times_out() {
+        let start = Instant::now();
+        assert!(run_for("printf allow; sleep 5 &").is_err());
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,161 @@
+use std::net::IpAddr;
+use std::str::from_utf8;
+
+const MAX_HOSTNAME_LEN: usize = 253;
+const MAX_LABEL_LEN: usize = 63;
+const ACE_PREFIX: &str = "xn--";
+
+// Punycode parameters from RFC 3492.
+const BASE: u32 = 36;
+const TMIN: u32 = 1;
+const TMAX: u32 = 26;
+const SKEW: u32 = 38;
+const DAMP: u32 = 700;
+const INITIAL_BIAS: u32 = 72;
+const INITIAL_N: u32 = 128;
+
+// Lowercases the name, drops a trailing dot and punycode encodes
+// internationalized labels, returns None for names no resolver would
+// accept. IP literals are passed through, IPv6 without brackets.
+pub fn canonicalize(name: &[u8]) -> Option<Vec<u8>> {
+    let name = from_utf8(name).ok()?;
+
+    let literal = name
+        .strip_prefix('[')
+        .and_then(|name| name.strip_suffix(']'))
+        .unwrap_or(name);
+    if literal.parse::<IpAddr>().is_ok() {
+        return Some(literal.as_bytes().to_vec());
+    }
+
+    let mut labels: Vec<&str> = name.split(is_label_separator).collect();
+    if labels.len() > 1 && labels.last() == Some(&"") {
+        labels.pop();
+    }
+
+    let mut host = String::new();
+    for label in labels {
+        if !host.is_empty() {
+            host.push('.');
+        }
+        host.push_str(&canonical_label(label)?);
+    }
+
+    if host.is_empty() || host.len() > MAX_HOSTNAME_LEN {
+        return None;
+    }
+
+    Some(host.into_bytes())
+}
+
+// Full stops that IDNA treats like the ASCII one.
+fn is_label_separator(c: char) -> bool {
+    matches!(c, '.' | '\u{3002}' | '\u{ff0e}' | '\u{ff61}')
+}
+
+fn canonical_label(label: &str) -> Option<String> {
+    let label: Vec<char> = label.chars().flat_map(char::to_lowercase).collect();
+
+    let label = if label.iter().all(char::is_ascii) {
+        label.into_iter().collect()
+    } else {
+        let mut ace = ACE_PREFIX.to_string();
+        ace.push_str(&punycode(&label)?);
+        ace
+    };
+
+    // Underscores are not valid in hostnames, but common in service names.
+    let valid = !label.is_empty()
+        && label.len() <= MAX_LABEL_LEN
+        && !label.starts_with('-')
+        && !label.ends_with('-')
+        && label
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+
+    if valid {
+        Some(label)
+    } else {
+        None
+    }
+}
+
+fn punycode(input: &[char]) -> Option<String> {
+    let mut output: String = input.iter().filter(|c| c.is_ascii()).collect();
+    let basic = output.len() as u32;
+    if basic > 0 {
+        output.push('-');
+    }
+
+    let mut n = INITIAL_N;
+    let mut delta: u32 = 0;
+    let mut bias = INITIAL_BIAS;
+    let mut handled = basic;
+
+    while (handled as usize) < input.len() {
+        let m = input.iter().map(|&c| c as u32).filter(|&c| c >= n).min()?;
+
+        delta = delta.checked_add((m - n).checked_mul(handled + 1)?)?;
+        n = m;
+
+        for c in input.iter().map(|&c| c as u32) {
+            if c < n {
+                delta = delta.checked_add(1)?;
+            }
+
+            if c == n {
+                let mut q = delta;
+                let mut k = BASE;
+
+                loop {
+                    let t = if k <= bias {
+                        TMIN
+                    } else if k >= bias + TMAX {
+                        TMAX
+                    } else {
+                        k - bias
+                    };
+
+                    if q < t {
+                        break;
+                    }
+
+                    output.push(punycode_digit(t + (q - t) % (BASE - t)));
+                    q = (q - t) / (BASE - t);
+                    k += BASE;
+                }
+
+                output.push(punycode_digit(q));
+                bias = adapt(delta, handled + 1, handled == basic);
+                delta = 0;
+                handled += 1;
+            }
+        }
+
+        delta += 1;
+        n += 1;
+    }
+
+    Some(output)
+}
+
+fn punycode_digit(d: u32) -> char {
+    if d < 26 {
+        (b'a' + d as u8) as char
+    } else {
+        (b'0' + (d - 26) as u8) as char
+    }
+}
+
+fn adapt(delta: u32, points: u32, first: bool) -> u32 {
+    let mut delta = if first { delta / DAMP } else { delta / 2 };
+    delta += delta / points;
+
+    let mut k = 0;
+    while delta > ((BASE - TMIN) * TMAX) / 2 {
+        delta /= BASE - TMIN;
+        k += BASE;
+    }
+
+    k + (BASE - TMIN + 1) * delta / (delta + SKEW)
+}
@@ -1,25 +1,55 @@
 #[macro_use]
 extern crate log;
 extern crate async_std;
+#[cfg(feature = "local-time")]
 extern crate chrono;
+#[cfg(feature = "ucp")]
 extern crate crc;
+#[cfg(feature = "ucp")]
 extern crate crossbeam_utils;
 extern crate crypto;
 extern crate futures;
 extern crate futures_timer;
+#[cfg(any(
+    all(feature = "ucp", target_os = "linux"),
+    all(feature = "signals", unix)
+))]
+extern crate libc;
+#[cfg(feature = "compression")]
+extern crate lz4_flex;
 extern crate rand;
 
+pub mod admin;
+pub mod backpressure;
+pub mod balance;
+pub mod bond;
+pub mod buffer;
 pub mod client;
+pub mod compress;
 pub mod cryptor;
+pub mod doctor;
+#[cfg(test)]
+mod duplex;
+pub mod error;
+pub mod events;
+pub mod features;
+pub mod hook;
+pub mod hostname;
+pub mod listener;
 pub mod logger;
+pub mod resume;
+pub mod selector;
 pub mod server;
+pub mod shutdown;
 pub mod socks5;
+pub mod stats;
 pub mod timer;
+#[cfg(feature = "ucp")]
 pub mod ucp;
 
 mod util {
     use futures::channel::mpsc::{channel, Receiver, Sender};
-    use futures::stream::SelectAll;
+    use futures::stream::{self, PollNext, SelectAll, Stream};
     use std::vec::Vec;
 
     pub type Receivers<T> = SelectAll<Receiver<T>>;
@@ -44,27 +74,80 @@ mod util {
         buffer: usize,
     ) -> (MainSender<T>, SubSenders<T>, Receivers<T>) {
         let (main_sender, main_receiver) = channel(buffer);
+        let (sub_senders, mut receivers) = sub_channels(bus_num, buffer);
+        receivers.push(main_receiver);
+
+        (main_sender, sub_senders, receivers)
+    }
+
+    // The channels of a bus without its main one.
+    pub fn sub_channels<T>(bus_num: usize, buffer: usize) -> (SubSenders<T>, Receivers<T>) {
         let mut receivers = Receivers::new();
         let mut sub_senders = SubSenders(Vec::new(), 0);
 
-        receivers.push(main_receiver);
         for _ in 0..bus_num {
             let (sender, receiver) = channel(buffer);
             sub_senders.0.push(sender);
             receivers.push(receiver);
         }
 
-        (main_sender, sub_senders, receivers)
+        (sub_senders, receivers)
+    }
+
+    // Messages of `priority` before any of `rest`, for the ports of the
+    // interactive class, see backpressure::PortClass.
+    pub fn prioritized<T, P: Stream<Item = T> + Unpin, S: Stream<Item = T> + Unpin>(
+        priority: P,
+        rest: S,
+    ) -> impl Stream<Item = T> + Unpin {
+        stream::select_with_strategy(priority, rest, |_: &mut ()| PollNext::Left)
     }
 }
 
 mod protocol {
     use std::vec::Vec;
 
+    use super::buffer::DataBuf;
+    use super::cryptor::Cryptor;
+
     pub const VERIFY_DATA: [u8; 8] = [0xF0u8, 0xEF, 0xE, 0x2, 0xAE, 0xBC, 0x8C, 0x78];
     pub const HEARTBEAT_INTERVAL_MS: u64 = 5000;
     pub const ALIVE_TIMEOUT_TIME_MS: u128 = 60000;
 
+    // Each end names its software once the tunnel is up, the client
+    // first so servers from before see a message for a port that doesn't
+    // exist, and servers only answer clients that sent theirs.
+    pub const SOFTWARE_VERSION: &str = concat!("stunnel/", env!("CARGO_PKG_VERSION"));
+    const MAX_PEER_VERSION_SIZE: usize = 64;
+
+    // The frames every end of a protocol version speaks, named in HELLO
+    // as `protocol=N`, the rest is negotiated as extensions. Ends from
+    // before name none and count as 0. An end refuses peers below its
+    // minimum, raised only once no release in use speaks less.
+    pub const PROTOCOL_VERSION: u32 = 1;
+    pub const MIN_PROTOCOL_VERSION: u32 = 0;
+    const PROTOCOL_PREFIX: &str = "protocol=";
+
+    // Ports write their data in frames of at most this much, so one
+    // large write doesn't hold the frames of the other ports behind it.
+    // Larger data frames, or ones decompressing to more, are refused.
+    pub const MAX_DATA_FRAME_SIZE: usize = 16 * 1024;
+    // Frames naming a larger length are refused rather than allocated.
+    // Ends from before write data as they read it, 1024 bytes at a time,
+    // and UDP datagrams whole, which stay well below it.
+    pub const MAX_FRAME_SIZE: usize = 256 * 1024;
+
+    // Extensions of the protocol an end speaks, named after its version
+    // in HELLO. Ends from before show them as part of the version, and
+    // are sent none of their messages.
+    pub const EXTENSION_WINDOW: &str = "window";
+    pub const EXTENSION_LZ4: &str = "lz4";
+    pub const EXTENSION_UDP: &str = "udp";
+    pub const EXTENSION_BOND: &str = "bond";
+    pub const EXTENSION_CLOSING: &str = "closing";
+    pub const EXTENSION_STATS: &str = "stats";
+    pub const EXTENSION_RESUME: &str = "resume";
+
     pub mod cs {
         pub const OPEN_PORT: u8 = 1;
         pub const CLOSE_PORT: u8 = 2;
@@ -73,6 +156,17 @@ mod protocol {
         pub const CONNECT_DOMAIN_NAME: u8 = 6;
         pub const DATA: u8 = 7;
         pub const HEARTBEAT: u8 = 8;
+        pub const HELLO: u8 = 9;
+        pub const WINDOW: u8 = 10;
+        pub const DATA_LZ4: u8 = 11;
+        pub const OPEN_UDP: u8 = 12;
+        pub const UDP_DATA: u8 = 13;
+        pub const CLOSE_UDP: u8 = 14;
+        pub const JOIN_BOND: u8 = 15;
+        pub const STRIPED_DATA: u8 = 16;
+        pub const CLOSING: u8 = 17;
+        pub const STATS: u8 = 18;
+        pub const RESUME: u8 = 19;
     }
 
     pub mod sc {
@@ -81,22 +175,52 @@ mod protocol {
         pub const CONNECT_OK: u8 = 4;
         pub const DATA: u8 = 5;
         pub const HEARTBEAT_RSP: u8 = 6;
+        pub const DRAINING: u8 = 7;
+        pub const HELLO: u8 = 8;
+        pub const WINDOW: u8 = 9;
+        pub const DATA_LZ4: u8 = 10;
+        pub const UDP_DATA: u8 = 11;
+        pub const CLOSE_UDP: u8 = 12;
+        pub const STRIPED_DATA: u8 = 13;
+        pub const REFUSED: u8 = 14;
+        pub const STATS: u8 = 15;
+        pub const SESSION: u8 = 16;
+        pub const RESUMED: u8 = 17;
     }
 
     fn write_cmd_id_len(buf: &mut [u8], cmd: u8, id: u32, len: u32) {
         buf[0] = cmd;
-        unsafe {
-            *(buf.as_ptr().offset(1) as *mut u32) = id.to_be();
-            *(buf.as_ptr().offset(5) as *mut u32) = len.to_be();
+        buf[1..5].copy_from_slice(&id.to_be_bytes());
+        buf[5..9].copy_from_slice(&len.to_be_bytes());
+    }
+
+    // The length field of a received frame.
+    pub fn frame_len(len: [u8; 4]) -> std::io::Result<usize> {
+        bounded_frame_len(len, MAX_FRAME_SIZE)
+    }
+
+    // The length field of a received data frame, which the other end
+    // splits at MAX_DATA_FRAME_SIZE.
+    pub fn data_frame_len(len: [u8; 4]) -> std::io::Result<usize> {
+        bounded_frame_len(len, MAX_DATA_FRAME_SIZE)
+    }
+
+    fn bounded_frame_len(len: [u8; 4], limit: usize) -> std::io::Result<usize> {
+        let len = u32::from_be_bytes(len) as usize;
+        if len > limit {
+            error!("refused a frame of {} bytes, more than {}", len, limit);
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "frame too large",
+            ));
         }
+        Ok(len)
     }
 
     fn pack_cmd_id_msg(cmd: u8, id: u32) -> [u8; 5] {
         let mut buf = [0u8; 5];
         buf[0] = cmd;
-        unsafe {
-            *(buf.as_ptr().offset(1) as *mut u32) = id.to_be();
-        }
+        buf[1..].copy_from_slice(&id.to_be_bytes());
         buf
     }
 
@@ -110,6 +234,39 @@ mod protocol {
         buf
     }
 
+    // Data of a port is copied once, into a pooled frame, and encrypted
+    // there.
+    fn pack_cmd_id_data_frame(cmd: u8, id: u32, data: &[u8], encryptor: &mut Cryptor) -> DataBuf {
+        let mut buf = DataBuf::new(9 + data.len());
+        let len = data.len() as u32;
+
+        write_cmd_id_len(&mut buf, cmd, id, len);
+        buf[9..].copy_from_slice(data);
+        encryptor.encrypt_in_place(&mut buf[9..]);
+
+        buf
+    }
+
+    // Data of a bonded port, the sequence number follows the id, see
+    // bond.
+    fn pack_cmd_id_seq_data_frame(
+        cmd: u8,
+        id: u32,
+        seq: u64,
+        data: &[u8],
+        encryptor: &mut Cryptor,
+    ) -> DataBuf {
+        let mut buf = DataBuf::new(17 + data.len());
+        buf[0] = cmd;
+        buf[1..5].copy_from_slice(&id.to_be_bytes());
+        buf[5..13].copy_from_slice(&seq.to_be_bytes());
+        buf[13..17].copy_from_slice(&(data.len() as u32).to_be_bytes());
+        buf[17..].copy_from_slice(data);
+        encryptor.encrypt_in_place(&mut buf[17..]);
+
+        buf
+    }
+
     pub fn pack_cs_open_port_msg(id: u32) -> [u8; 5] {
         pack_cmd_id_msg(cs::OPEN_PORT, id)
     }
@@ -125,11 +282,7 @@ mod protocol {
 
         write_cmd_id_len(&mut buf, cs::CONNECT_DOMAIN_NAME, id, len);
         buf[9..buf_len - 2].copy_from_slice(domain);
-
-        unsafe {
-            let offset = (buf_len - 2) as isize;
-            *(buf.as_ptr().offset(offset) as *mut u16) = port.to_be();
-        }
+        buf[buf_len - 2..].copy_from_slice(&port.to_be_bytes());
 
         buf
     }
@@ -138,8 +291,38 @@ mod protocol {
         pack_cmd_id_msg(cs::SHUTDOWN_WRITE, id)
     }
 
-    pub fn pack_cs_data_msg(id: u32, data: &[u8]) -> Vec<u8> {
-        pack_cmd_id_data_msg(cs::DATA, id, data)
+    pub fn pack_cs_data_msg(id: u32, data: &[u8], encryptor: &mut Cryptor) -> DataBuf {
+        pack_cmd_id_data_frame(cs::DATA, id, data, encryptor)
+    }
+
+    // Data compressed, see compress.
+    pub fn pack_cs_data_lz4_msg(id: u32, data: &[u8], encryptor: &mut Cryptor) -> DataBuf {
+        pack_cmd_id_data_frame(cs::DATA_LZ4, id, data, encryptor)
+    }
+
+    pub fn pack_cs_open_udp_msg(id: u32) -> [u8; 5] {
+        pack_cmd_id_msg(cs::OPEN_UDP, id)
+    }
+
+    pub fn pack_cs_udp_data_msg(id: u32, data: &[u8]) -> Vec<u8> {
+        pack_cmd_id_data_msg(cs::UDP_DATA, id, data)
+    }
+
+    pub fn pack_cs_close_udp_msg(id: u32) -> [u8; 5] {
+        pack_cmd_id_msg(cs::CLOSE_UDP, id)
+    }
+
+    pub fn pack_cs_join_bond_msg(id: u32, data: &[u8]) -> Vec<u8> {
+        pack_cmd_id_data_msg(cs::JOIN_BOND, id, data)
+    }
+
+    pub fn pack_cs_striped_data_msg(
+        id: u32,
+        seq: u64,
+        data: &[u8],
+        encryptor: &mut Cryptor,
+    ) -> DataBuf {
+        pack_cmd_id_seq_data_frame(cs::STRIPED_DATA, id, seq, data, encryptor)
     }
 
     pub fn pack_cs_close_port_msg(id: u32) -> [u8; 5] {
@@ -151,6 +334,32 @@ mod protocol {
         buf
     }
 
+    pub fn pack_cs_closing_msg() -> [u8; 1] {
+        [cs::CLOSING]
+    }
+
+    pub fn pack_cs_stats_msg() -> [u8; 1] {
+        [cs::STATS]
+    }
+
+    pub fn pack_cs_hello_msg(data: &[u8]) -> Vec<u8> {
+        pack_cmd_id_data_msg(cs::HELLO, 0, data)
+    }
+
+    // The session the ports of a broken tunnel resume, with where they
+    // are, see resume.
+    pub fn pack_cs_resume_msg(data: &[u8]) -> Vec<u8> {
+        pack_cmd_id_data_msg(cs::RESUME, 0, data)
+    }
+
+    // Grants a port of the server `bytes` more to send, see
+    // backpressure::PortWindow. The length field carries them.
+    pub fn pack_cs_window_msg(id: u32, bytes: u32) -> [u8; 9] {
+        let mut buf = [0u8; 9];
+        write_cmd_id_len(&mut buf, cs::WINDOW, id, bytes);
+        buf
+    }
+
     pub fn pack_sc_close_port_msg(id: u32) -> [u8; 5] {
         pack_cmd_id_msg(sc::CLOSE_PORT, id)
     }
@@ -163,12 +372,143 @@ mod protocol {
         pack_cmd_id_data_msg(sc::CONNECT_OK, id, data)
     }
 
-    pub fn pack_sc_data_msg(id: u32, data: &[u8]) -> Vec<u8> {
-        pack_cmd_id_data_msg(sc::DATA, id, data)
+    pub fn pack_sc_data_msg(id: u32, data: &[u8], encryptor: &mut Cryptor) -> DataBuf {
+        pack_cmd_id_data_frame(sc::DATA, id, data, encryptor)
+    }
+
+    pub fn pack_sc_data_lz4_msg(id: u32, data: &[u8], encryptor: &mut Cryptor) -> DataBuf {
+        pack_cmd_id_data_frame(sc::DATA_LZ4, id, data, encryptor)
+    }
+
+    pub fn pack_sc_udp_data_msg(id: u32, data: &[u8]) -> Vec<u8> {
+        pack_cmd_id_data_msg(sc::UDP_DATA, id, data)
+    }
+
+    pub fn pack_sc_close_udp_msg(id: u32) -> [u8; 5] {
+        pack_cmd_id_msg(sc::CLOSE_UDP, id)
+    }
+
+    pub fn pack_sc_striped_data_msg(
+        id: u32,
+        seq: u64,
+        data: &[u8],
+        encryptor: &mut Cryptor,
+    ) -> DataBuf {
+        pack_cmd_id_seq_data_frame(sc::STRIPED_DATA, id, seq, data, encryptor)
     }
 
     pub fn pack_sc_heartbeat_rsp_msg() -> [u8; 1] {
         let buf = [sc::HEARTBEAT_RSP];
         buf
     }
+
+    pub fn pack_sc_draining_msg() -> [u8; 1] {
+        [sc::DRAINING]
+    }
+
+    pub fn pack_sc_hello_msg(data: &[u8]) -> Vec<u8> {
+        pack_cmd_id_data_msg(sc::HELLO, 0, data)
+    }
+
+    // The server's view of the tunnel, see stats::TunnelStats.
+    pub fn pack_sc_stats_msg(data: &[u8]) -> Vec<u8> {
+        pack_cmd_id_data_msg(sc::STATS, 0, data)
+    }
+
+    // Why the server closes the tunnel, answering HELLO.
+    pub fn pack_sc_refused_msg(data: &[u8]) -> Vec<u8> {
+        pack_cmd_id_data_msg(sc::REFUSED, 0, data)
+    }
+
+    pub fn pack_sc_window_msg(id: u32, bytes: u32) -> [u8; 9] {
+        let mut buf = [0u8; 9];
+        write_cmd_id_len(&mut buf, sc::WINDOW, id, bytes);
+        buf
+    }
+
+    // The session the ports of the tunnel may resume in, answering HELLO.
+    pub fn pack_sc_session_msg(data: &[u8]) -> Vec<u8> {
+        pack_cmd_id_data_msg(sc::SESSION, 0, data)
+    }
+
+    // The ports that resumed with where they are, answering RESUME.
+    pub fn pack_sc_resumed_msg(data: &[u8]) -> Vec<u8> {
+        pack_cmd_id_data_msg(sc::RESUMED, 0, data)
+    }
+
+    // The data of a UDP frame, a datagram with the address it goes to or
+    // came from: the length of the host, the host, a name or an address,
+    // the port, then the payload.
+    pub fn pack_udp_datagram(host: &[u8], port: u16, payload: &[u8]) -> Option<Vec<u8>> {
+        if host.is_empty() || host.len() > u8::MAX as usize {
+            return None;
+        }
+
+        let mut data = Vec::with_capacity(3 + host.len() + payload.len());
+        data.push(host.len() as u8);
+        data.extend_from_slice(host);
+        data.extend_from_slice(&port.to_be_bytes());
+        data.extend_from_slice(payload);
+        Some(data)
+    }
+
+    pub fn unpack_udp_datagram(data: &[u8]) -> Option<(&[u8], u16, &[u8])> {
+        let size = *data.first()? as usize;
+        if size == 0 || data.len() < 3 + size {
+            return None;
+        }
+
+        let host = &data[1..1 + size];
+        let port = u16::from_be_bytes([data[1 + size], data[2 + size]]);
+        Some((host, port, &data[3 + size..]))
+    }
+
+    // What an end sends in HELLO, its version, protocol and extensions.
+    pub fn hello_data(extensions: &[&str]) -> Vec<u8> {
+        let mut data = format!(
+            "{} {}{}",
+            SOFTWARE_VERSION, PROTOCOL_PREFIX, PROTOCOL_VERSION
+        );
+        for extension in extensions {
+            data.push(' ');
+            data.push_str(extension);
+        }
+        data.into_bytes()
+    }
+
+    // The version of the other end, up to its extensions, goes to logs
+    // and status, so only a short run of printable ASCII is kept.
+    pub fn peer_version(data: &[u8]) -> String {
+        data.iter()
+            .take_while(|&&c| c != b' ')
+            .take(MAX_PEER_VERSION_SIZE)
+            .map(|&c| if c.is_ascii_graphic() { c as char } else { '?' })
+            .collect()
+    }
+
+    pub fn peer_extension(data: &[u8], name: &str) -> bool {
+        data.split(|&c| c == b' ')
+            .skip(1)
+            .any(|extension| extension == name.as_bytes())
+    }
+
+    pub fn peer_protocol(data: &[u8]) -> u32 {
+        data.split(|&c| c == b' ')
+            .skip(1)
+            .find_map(|extension| extension.strip_prefix(PROTOCOL_PREFIX.as_bytes()))
+            .and_then(|version| std::str::from_utf8(version).ok()?.parse().ok())
+            .unwrap_or(0)
+    }
+
+    // Why the peer's protocol is too old, None when it can be spoken to.
+    pub fn protocol_mismatch(peer: u32) -> Option<String> {
+        if (MIN_PROTOCOL_VERSION..).contains(&peer) {
+            return None;
+        }
+
+        Some(format!(
+            "peer speaks protocol {}, {} needs at least {}",
+            peer, SOFTWARE_VERSION, MIN_PROTOCOL_VERSION
+        ))
+    }
 }
@@ -0,0 +1,210 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use async_std::net::TcpListener;
+use async_std::task;
+
+const BIND_RETRY_MIN_MS: u64 = 1000;
+const BIND_RETRY_MAX_MS: u64 = 30000;
+const ACCEPT_RETRY_MIN_MS: u64 = 10;
+const ACCEPT_RETRY_MAX_MS: u64 = 1000;
+const ACCEPT_WARNING_INTERVAL_SECS: u64 = 10;
+
+// EMFILE and ENFILE, the same on Linux, the BSDs and macOS.
+const EMFILE: i32 = 24;
+const ENFILE: i32 = 23;
+
+static LISTENERS: Mutex<BTreeMap<&'static str, ListenerStatus>> = Mutex::new(BTreeMap::new());
+static FD_EXHAUSTIONS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone)]
+pub struct ListenerStatus {
+    pub name: &'static str,
+    pub addr: String,
+    pub bound: bool,
+    // The last bind error, kept while retrying.
+    pub error: Option<String>,
+    pub attempts: u32,
+}
+
+// Retry schedule of a bind or a tunnel reconnect, doubling from 1s up
+// to 30s.
+pub struct Backoff {
+    delay: Duration,
+    next: Instant,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            delay: Duration::from_millis(BIND_RETRY_MIN_MS),
+            next: Instant::now(),
+        }
+    }
+}
+
+impl Backoff {
+    pub fn is_due(&self) -> bool {
+        Instant::now() >= self.next
+    }
+
+    // Schedules the next attempt and returns how far away it is.
+    pub fn failed(&mut self) -> Duration {
+        let delay = self.delay;
+        self.next = Instant::now() + delay;
+        self.delay = (delay * 2).min(Duration::from_millis(BIND_RETRY_MAX_MS));
+        delay
+    }
+}
+
+// Paces an accept loop after errors. An accept failing for want of file
+// descriptors fails again at once until some are closed, so it waits,
+// doubling from 10ms up to 1s, and warns at most every 10 seconds.
+pub struct AcceptBackoff {
+    delay: Duration,
+    warned: Option<Instant>,
+    failures: u64,
+}
+
+impl Default for AcceptBackoff {
+    fn default() -> Self {
+        AcceptBackoff {
+            delay: Duration::from_millis(ACCEPT_RETRY_MIN_MS),
+            warned: None,
+            failures: 0,
+        }
+    }
+}
+
+impl AcceptBackoff {
+    pub fn accepted(&mut self) {
+        self.delay = Duration::from_millis(ACCEPT_RETRY_MIN_MS);
+    }
+
+    // Returns how long to wait before the next accept.
+    pub fn failed(&mut self, name: &str, e: &io::Error) -> Duration {
+        if is_fd_exhaustion(e) {
+            FD_EXHAUSTIONS.fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.failures += 1;
+        let due = self.warned.is_none_or(|warned| {
+            warned.elapsed() >= Duration::from_secs(ACCEPT_WARNING_INTERVAL_SECS)
+        });
+        if due {
+            error!(
+                "{} accept error: {}, {} failures since the last warning",
+                name, e, self.failures
+            );
+            self.warned = Some(Instant::now());
+            self.failures = 0;
+        }
+
+        let delay = self.delay;
+        self.delay = (delay * 2).min(Duration::from_millis(ACCEPT_RETRY_MAX_MS));
+        delay
+    }
+}
+
+pub fn is_fd_exhaustion(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(EMFILE) | Some(ENFILE))
+}
+
+// Accepts that failed for want of file descriptors.
+pub fn fd_exhaustion_count() -> u64 {
+    FD_EXHAUSTIONS.load(Ordering::Relaxed)
+}
+
+// The file descriptors the process has open and may have open, where
+// /proc tells.
+pub fn fd_usage() -> (Option<u64>, Option<u64>) {
+    let open = fs::read_dir("/proc/self/fd")
+        .ok()
+        .map(|entries| entries.count() as u64);
+    let limit = fs::read_to_string("/proc/self/limits")
+        .ok()
+        .and_then(|limits| {
+            limits
+                .lines()
+                .find(|line| line.starts_with("Max open files"))
+                .and_then(|line| line.split_whitespace().nth(3))
+                .and_then(|soft| soft.parse().ok())
+        });
+
+    (open, limit)
+}
+
+// Records the outcome of binding listener `name` on `addr`, see status.
+pub fn report<T, E: Display>(name: &'static str, addr: &str, result: &Result<T, E>) {
+    let mut listeners = LISTENERS.lock().unwrap();
+    let status = listeners.entry(name).or_insert(ListenerStatus {
+        name,
+        addr: String::new(),
+        bound: false,
+        error: None,
+        attempts: 0,
+    });
+
+    if status.addr != addr {
+        status.addr = addr.to_string();
+        status.attempts = 0;
+    }
+
+    status.attempts += 1;
+    match result {
+        Ok(_) => {
+            status.bound = true;
+            status.error = None;
+            info!("{} listening on {}", name, addr);
+        }
+        Err(e) => {
+            status.bound = false;
+            status.error = Some(e.to_string());
+        }
+    }
+}
+
+// Binds with `bind` until it succeeds, an address in use or not yet
+// assigned to the host doesn't stop the process.
+pub async fn bind<T, E, F, Fut>(name: &'static str, addr: &str, bind: F) -> T
+where
+    E: Display,
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut backoff = Backoff::default();
+    loop {
+        let result = bind().await;
+        report(name, addr, &result);
+
+        match result {
+            Ok(listener) => return listener,
+            Err(e) => {
+                let delay = backoff.failed();
+                error!(
+                    "{} bind {} error: {}, retrying in {}s",
+                    name,
+                    addr,
+                    e,
+                    delay.as_secs()
+                );
+                task::sleep(delay).await;
+            }
+        }
+    }
+}
+
+pub async fn bind_tcp(name: &'static str, addr: &str) -> TcpListener {
+    bind(name, addr, || TcpListener::bind(addr)).await
+}
+
+// Every listener bound or being bound, by name.
+pub fn status() -> Vec<ListenerStatus> {
+    LISTENERS.lock().unwrap().values().cloned().collect()
+}
@@ -1,3 +1,4 @@
+#[cfg(feature = "local-time")]
 use chrono::prelude::*;
 use log::{self, Level, LevelFilter, Metadata, Record, SetLoggerError};
 use std::collections::vec_deque::VecDeque;
@@ -20,12 +21,10 @@ impl log::Log for ChannelLogger {
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
             let mut data = Vec::new();
-            let datetime = Local::now();
-
             let _ = write!(
                 &mut data,
                 "[{}][{}][{}:{}] - {}\n",
-                datetime.format("%F %T%.6f").to_string(),
+                timestamp(),
                 record.level(),
                 record.file().unwrap(),
                 record.line().unwrap(),
@@ -42,6 +41,22 @@ impl log::Log for ChannelLogger {
     fn flush(&self) {}
 }
 
+#[cfg(feature = "local-time")]
+fn timestamp() -> String {
+    Local::now().format("%F %T%.6f").to_string()
+}
+
+// Without chrono there is no timezone database, log seconds since epoch.
+#[cfg(not(feature = "local-time"))]
+fn timestamp() -> String {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format!("{}.{:06}", now.as_secs(), now.subsec_micros())
+}
+
 fn log_thread_func(
     msg_queue: Arc<(Mutex<VecDeque<Vec<u8>>>, Condvar)>,
     log_path: String,
@@ -0,0 +1,257 @@
+// A tunnel asking for it with EXTENSION_RESUME keeps its ports when its
+// connection breaks, and resumes them on the next one. The server names
+// a session for the ports and keeps them for a while once the connection
+// broke. Each end keeps what its ports sent until the other end grants
+// it, which it does once the data is written to the socket, and counts
+// what they received. On the next connection the client sends the
+// session with where its ports are, the server answers with where its
+// ports are, and each end sends again what the other didn't receive, so
+// every byte arrives once and in order.
+use std::collections::VecDeque;
+
+use rand::random;
+
+use super::buffer::DataBuf;
+
+pub const SESSION_TOKEN_SIZE: usize = 16;
+// How long the server keeps the ports of a broken tunnel, and the client
+// tries to resume them.
+pub const SESSION_PARK_TIMEOUT_MS: u64 = 60000;
+const PORT_OFFSETS_SIZE: usize = 21;
+
+pub fn new_token() -> [u8; SESSION_TOKEN_SIZE] {
+    random()
+}
+
+// Where a port is as one end tells the other: the data it received, the
+// credit it granted for it, and whether the other end shut down writing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PortOffsets {
+    pub id: u32,
+    pub received: u64,
+    pub granted: u64,
+    pub shut: bool,
+}
+
+// The data of RESUME after the session, and of RESUMED after the session
+// the ports continue in.
+pub fn pack_offsets(ports: &[PortOffsets]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(ports.len() * PORT_OFFSETS_SIZE);
+    for port in ports {
+        data.extend_from_slice(&port.id.to_be_bytes());
+        data.extend_from_slice(&port.received.to_be_bytes());
+        data.extend_from_slice(&port.granted.to_be_bytes());
+        data.push(port.shut as u8);
+    }
+    data
+}
+
+pub fn parse_offsets(data: &[u8]) -> Option<Vec<PortOffsets>> {
+    let ports = data.chunks_exact(PORT_OFFSETS_SIZE);
+    if !ports.remainder().is_empty() {
+        return None;
+    }
+
+    let read_u64 = |bytes: &[u8]| {
+        let mut value = [0u8; 8];
+        value.copy_from_slice(bytes);
+        u64::from_be_bytes(value)
+    };
+    let ports = ports
+        .map(|port| PortOffsets {
+            id: u32::from_be_bytes([port[0], port[1], port[2], port[3]]),
+            received: read_u64(&port[4..12]),
+            granted: read_u64(&port[12..20]),
+            shut: port[20] != 0,
+        })
+        .collect();
+    Some(ports)
+}
+
+// What a port sent and received over the connections of its tunnel. The
+// data sent from offset `acked` on isn't granted yet, and so may not have
+// arrived. The credit granted bounds it, see backpressure::PortWindow.
+#[derive(Default)]
+pub struct Transfer {
+    unacked: VecDeque<DataBuf>,
+    acked: u64,
+    sent: u64,
+    // The credit the other end granted, and this end granted it.
+    grants: u64,
+    received: u64,
+    granted: u64,
+    shut_sent: bool,
+    shut_received: bool,
+}
+
+impl Transfer {
+    pub fn send(&mut self, buf: &DataBuf) {
+        self.sent += buf.len() as u64;
+        self.unacked.push_back(buf.clone());
+    }
+
+    pub fn shut_down(&mut self) {
+        self.shut_sent = true;
+    }
+
+    // The other end wrote out that much more of what it received.
+    pub fn granted_by_peer(&mut self, bytes: u32) {
+        self.grants += bytes as u64;
+        self.ack(self.grants);
+    }
+
+    pub fn receive(&mut self, bytes: usize) {
+        self.received += bytes as u64;
+    }
+
+    pub fn peer_shut_down(&mut self) {
+        self.shut_received = true;
+    }
+
+    pub fn grant(&mut self, bytes: u32) {
+        self.granted += bytes as u64;
+    }
+
+    pub fn offsets(&self, id: u32) -> PortOffsets {
+        PortOffsets {
+            id,
+            received: self.received,
+            granted: self.granted,
+            shut: self.shut_received,
+        }
+    }
+
+    // Takes where the other end is: what it received isn't sent again,
+    // and the credit it granted that never arrived is returned for the
+    // window. None when it can't be right, as the other end would have
+    // received data never sent, or lost data it granted.
+    pub fn resume(&mut self, peer: &PortOffsets) -> Option<usize> {
+        if peer.received < self.acked || peer.received > self.sent {
+            return None;
+        }
+        if peer.granted < self.grants || peer.granted > peer.received {
+            return None;
+        }
+
+        self.ack(peer.received);
+        let credit = peer.granted - self.grants;
+        self.grants = peer.granted;
+        Some(credit as usize)
+    }
+
+    // What the other end is sent again once resumed, in order.
+    pub fn unacked(&self) -> impl Iterator<Item = &DataBuf> {
+        self.unacked.iter()
+    }
+
+    // The shutdown is sent again once resumed, when the other end didn't
+    // get it.
+    pub fn shutdown_lost(&self, peer: &PortOffsets) -> bool {
+        self.shut_sent && !peer.shut
+    }
+
+    fn ack(&mut self, offset: u64) {
+        while let Some(buf) = self.unacked.front() {
+            let end = self.acked + buf.len() as u64;
+            if end > offset {
+                // Frames arrive whole, but the count is all that is known.
+                if offset > self.acked {
+                    let rest = DataBuf::from(&buf[(offset - self.acked) as usize..]);
+                    self.unacked[0] = rest;
+                    self.acked = offset;
+                }
+                break;
+            }
+
+            self.acked = end;
+            self.unacked.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sent(transfer: &Transfer) -> Vec<u8> {
+        transfer.unacked().flat_map(|buf| buf.to_vec()).collect()
+    }
+
+    #[test]
+    fn offsets_round_trip() {
+        let ports = vec![
+            PortOffsets {
+                id: 3,
+                received: 1 << 40,
+                granted: 4096,
+                shut: true,
+            },
+            PortOffsets {
+                id: 9,
+                received: 0,
+                granted: 0,
+                shut: false,
+            },
+        ];
+        let data = pack_offsets(&ports);
+        assert_eq!(parse_offsets(&data), Some(ports));
+        assert_eq!(parse_offsets(&data[1..]), None);
+    }
+
+    #[test]
+    fn grants_drop_what_arrived() {
+        let mut transfer = Transfer::default();
+        transfer.send(&DataBuf::from(&b"0123"[..]));
+        transfer.send(&DataBuf::from(&b"4567"[..]));
+
+        transfer.granted_by_peer(4);
+        assert_eq!(sent(&transfer), b"4567");
+        transfer.granted_by_peer(2);
+        assert_eq!(sent(&transfer), b"67");
+    }
+
+    #[test]
+    fn resume_sends_what_the_peer_missed() {
+        let mut transfer = Transfer::default();
+        for data in [&b"0123"[..], b"4567", b"89"] {
+            transfer.send(&DataBuf::from(data));
+        }
+        transfer.granted_by_peer(4);
+        transfer.shut_down();
+
+        // The peer received past the grants that arrived, and granted
+        // more than arrived.
+        let peer = PortOffsets {
+            id: 1,
+            received: 8,
+            granted: 6,
+            shut: false,
+        };
+        assert_eq!(transfer.resume(&peer), Some(2));
+        assert_eq!(sent(&transfer), b"89");
+        assert!(transfer.shutdown_lost(&peer));
+    }
+
+    #[test]
+    fn resume_refuses_impossible_offsets() {
+        let mut transfer = Transfer::default();
+        transfer.send(&DataBuf::from(&b"0123"[..]));
+        transfer.granted_by_peer(2);
+
+        let mut peer = PortOffsets {
+            id: 1,
+            received: 5,
+            granted: 2,
+            shut: false,
+        };
+        assert_eq!(transfer.resume(&peer), None);
+        peer.received = 1;
+        assert_eq!(transfer.resume(&peer), None);
+        peer.received = 3;
+        peer.granted = 1;
+        assert_eq!(transfer.resume(&peer), None);
+        peer.granted = 2;
+        assert_eq!(transfer.resume(&peer), Some(0));
+        assert_eq!(sent(&transfer), b"3");
+    }
+}
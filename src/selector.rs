@@ -0,0 +1,248 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+
+use async_std::io;
+use async_std::net::TcpStream;
+use async_std::prelude::*;
+use async_std::task;
+
+use super::cryptor::*;
+use super::protocol::*;
+
+const PROBE_TIMEOUT_MS: u64 = 5000;
+pub const PROBE_INTERVAL_MS: u64 = 60000;
+// A server a tunnel failed on is passed over for a second, doubling with
+// each failure in a row up to this.
+const MAX_FAILED_MS: u64 = 30000;
+
+// The rtt of the last probe that reached the server, and the failures
+// of connections to it since the last that came up.
+struct ServerState {
+    addr: String,
+    rtt: Option<Duration>,
+    reachable: bool,
+    draining: bool,
+    failures: u32,
+    failed_until: Option<Instant>,
+}
+
+pub struct ServerSelector {
+    key: Vec<u8>,
+    servers: Mutex<Vec<ServerState>>,
+    failback: AtomicBool,
+}
+
+impl ServerState {
+    fn new(addr: String) -> ServerState {
+        ServerState {
+            addr,
+            rtt: None,
+            reachable: true,
+            draining: false,
+            failures: 0,
+            failed_until: None,
+        }
+    }
+
+    fn is_usable(&self, now: Instant) -> bool {
+        self.reachable && !self.draining && self.failed_until.is_none_or(|until| now >= until)
+    }
+}
+
+impl ServerSelector {
+    pub fn new(addrs: Vec<String>, key: Vec<u8>) -> Arc<ServerSelector> {
+        let servers = addrs.into_iter().map(ServerState::new).collect();
+
+        Arc::new(ServerSelector {
+            key,
+            servers: Mutex::new(servers),
+            failback: AtomicBool::new(false),
+        })
+    }
+
+    // Prefers the servers in the order they are configured over the
+    // lowest latency, so tunnels go back to the first once it is usable
+    // again, see should_fail_back.
+    pub fn set_failback(&self, enabled: bool) {
+        self.failback.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn server_count(&self) -> usize {
+        self.servers.lock().unwrap().len()
+    }
+
+    // Lowest-latency healthy server, the first configured one when
+    // no probe has succeeded yet, or with failback the first healthy one.
+    // Servers a tunnel just failed on come next, the one passed over the
+    // shortest first, and draining servers are the last resort.
+    pub fn best(&self) -> String {
+        let now = Instant::now();
+        let servers = self.servers.lock().unwrap();
+
+        let healthy = if self.failback.load(Ordering::Relaxed) {
+            servers.iter().find(|s| s.is_usable(now))
+        } else {
+            servers
+                .iter()
+                .filter(|s| s.rtt.is_some() && s.is_usable(now))
+                .min_by_key(|s| s.rtt.unwrap())
+                .or(servers.iter().find(|s| s.is_usable(now)))
+        };
+
+        healthy
+            .or(servers
+                .iter()
+                .filter(|s| !s.draining)
+                .min_by_key(|s| s.failed_until))
+            .or(servers.first())
+            .map(|s| s.addr.clone())
+            .unwrap_or_default()
+    }
+
+    // A connection to the server failed, or broke, so the next ones go to
+    // another server for a while.
+    pub fn report_failure(&self, addr: &str) {
+        let mut servers = self.servers.lock().unwrap();
+        if let Some(s) = servers.iter_mut().find(|s| s.addr == addr) {
+            s.failures += 1;
+            let millis = (1000u64 << (s.failures - 1).min(5)).min(MAX_FAILED_MS);
+            s.failed_until = Some(Instant::now() + Duration::from_millis(millis));
+            info!(
+                "server {} failed {} times in a row, passed over for {}ms",
+                addr, s.failures, millis
+            );
+        }
+    }
+
+    pub fn report_success(&self, addr: &str) {
+        let mut servers = self.servers.lock().unwrap();
+        if let Some(s) = servers.iter_mut().find(|s| s.addr == addr) {
+            s.failures = 0;
+            s.failed_until = None;
+        }
+    }
+
+    // With failback, a tunnel to the server moves to the one before it
+    // that is healthy again.
+    pub fn should_fail_back(&self, addr: &str) -> bool {
+        if !self.failback.load(Ordering::Relaxed) {
+            return false;
+        }
+
+        let now = Instant::now();
+        let servers = self.servers.lock().unwrap();
+        servers
+            .iter()
+            .take_while(|s| s.addr != addr)
+            .any(|s| s.is_usable(now))
+    }
+
+    // Servers kept from the old list keep their probe results.
+    pub fn set_servers(&self, addrs: Vec<String>) {
+        let mut servers = self.servers.lock().unwrap();
+        let mut old_servers = std::mem::take(&mut *servers);
+
+        *servers = addrs
+            .into_iter()
+            .map(
+                |addr| match old_servers.iter().position(|s| s.addr == addr) {
+                    Some(i) => old_servers.swap_remove(i),
+                    None => ServerState::new(addr),
+                },
+            )
+            .collect();
+    }
+
+    pub fn has_server(&self, addr: &str) -> bool {
+        let servers = self.servers.lock().unwrap();
+        servers.iter().any(|s| s.addr == addr)
+    }
+
+    // Until the next probe tells otherwise.
+    pub fn set_draining(&self, addr: &str) {
+        let mut servers = self.servers.lock().unwrap();
+        if let Some(s) = servers.iter_mut().find(|s| s.addr == addr) {
+            s.draining = true;
+        }
+    }
+
+    // Address, probed rtt, whether the server is draining and the
+    // failures of connections to it in a row.
+    pub fn status(&self) -> Vec<(String, Option<Duration>, bool, u32)> {
+        let servers = self.servers.lock().unwrap();
+        servers
+            .iter()
+            .map(|s| (s.addr.clone(), s.rtt, s.draining, s.failures))
+            .collect()
+    }
+
+    pub async fn probe_all(&self) {
+        let addrs: Vec<String> = self.status().into_iter().map(|s| s.0).collect();
+
+        for addr in addrs.iter() {
+            let result = probe_server(addr, &self.key).await.ok();
+
+            match result {
+                Some((rtt, false)) => info!("probe server {} rtt {}ms", addr, rtt.as_millis()),
+                Some((rtt, true)) => {
+                    info!("probe server {} rtt {}ms, draining", addr, rtt.as_millis())
+                }
+                None => info!("probe server {} unreachable", addr),
+            }
+
+            let mut servers = self.servers.lock().unwrap();
+            if let Some(s) = servers.iter_mut().find(|s| &s.addr == addr) {
+                s.rtt = result.map(|(rtt, _)| rtt);
+                s.reachable = result.is_some();
+                s.draining = result.is_some_and(|(_, draining)| draining);
+            }
+        }
+
+        info!("selected server {}", self.best());
+    }
+
+    pub fn start_probing(selector: Arc<ServerSelector>, interval: Duration) {
+        task::spawn(async move {
+            loop {
+                task::sleep(interval).await;
+                if selector.server_count() > 1 {
+                    selector.probe_all().await;
+                }
+            }
+        });
+    }
+}
+
+// Round trip of TCP connect plus a tunnel handshake answered by a
+// heartbeat response, which also verifies the server accepts our key.
+// A draining server announces it ahead of the response.
+pub async fn probe_server(addr: &str, key: &[u8]) -> std::io::Result<(Duration, bool)> {
+    let start = Instant::now();
+    let timeout = Duration::from_millis(PROBE_TIMEOUT_MS);
+
+    io::timeout(timeout, async {
+        let mut stream = TcpStream::connect(addr).await?;
+        let mut encryptor = Cryptor::new(key);
+
+        stream.write_all(encryptor.ctr_as_slice()).await?;
+        stream.write_all(&encryptor.encrypt(&VERIFY_DATA)).await?;
+        stream.write_all(&pack_cs_heartbeat_msg()).await?;
+
+        let mut buf = vec![0; CTR_SIZE + 1];
+        stream.read_exact(&mut buf).await?;
+
+        let draining = buf[CTR_SIZE] == sc::DRAINING;
+        if draining {
+            stream.read_exact(&mut buf[CTR_SIZE..]).await?;
+        }
+
+        if buf[CTR_SIZE] != sc::HEARTBEAT_RSP {
+            return Err(std::io::Error::from(std::io::ErrorKind::InvalidData));
+        }
+
+        Ok((Instant::now() - start, draining))
+    })
+    .await
+}
@@ -1,36 +1,98 @@
-use std::collections::HashMap;
-use std::net::Shutdown;
+use std::collections::{BTreeMap, HashMap};
+use std::iter::once;
+use std::mem;
+use std::net::{IpAddr, Shutdown, SocketAddr};
 use std::str::from_utf8;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use std::vec::Vec;
 
-use async_std::io::{Read, Write};
-use async_std::net::TcpStream;
+use async_std::future;
+use async_std::io::{self, Read, Write};
+use async_std::net::{TcpStream, ToSocketAddrs, UdpSocket};
 use async_std::prelude::*;
 use async_std::task;
 
 use futures::channel::mpsc::{channel, Receiver, Sender};
+use futures::future::select_all;
 use futures::sink::SinkExt;
 
+use super::backpressure::{PortClass, PortWindow, QueueLimits, TunnelQueue, PORT_WINDOW_STEP};
+use super::bond::{self, Reassembly, BOND_JOIN_TIMEOUT_MS};
+use super::buffer::DataBuf;
+use super::compress;
 use super::cryptor::*;
+use super::error::{Error, Result};
+use super::events::{self, CloseReason, PortEvent};
+use super::features::TunnelFeatures;
+use super::hook::{ConnectHook, Verdict};
 use super::protocol::*;
-use super::timer;
+use super::resume::{self, PortOffsets, Transfer, SESSION_PARK_TIMEOUT_MS, SESSION_TOKEN_SIZE};
+use super::stats::TunnelStats;
+use super::timer::{self, Watchdog};
+#[cfg(feature = "ucp")]
 use super::ucp::UcpStream;
 use super::util::*;
 
+pub const DEFAULT_HANDSHAKE_TIMEOUT_MS: u64 = 10000;
+// The first frame of an idle client may be its heartbeat, a shorter
+// handshake timeout would drop it.
+pub const MIN_HANDSHAKE_TIMEOUT_MS: u64 = HEARTBEAT_INTERVAL_MS;
+pub const DEFAULT_PORT_IDLE_TIMEOUT_MS: u64 = 300000;
+pub const DEFAULT_PORT_CONNECT_TIMEOUT_MS: u64 = 20000;
+pub const DEFAULT_OPEN_RATE: f64 = 10.0;
+const MAX_OPEN_BUDGETS: usize = 4096;
+const SHED_IDLE_TIMEOUT_MS: u64 = 30000;
+const SHED_DURATION_MS: u64 = 10000;
+// Destinations a UDP port remembers resolved, it forgets them all when
+// it reaches this.
+const MAX_UDP_DESTINATIONS: usize = 256;
+const MAX_UDP_DATAGRAM: usize = 65536;
+const MAX_PENDING_BONDS: usize = 1024;
+
+static HANDSHAKE_TIMEOUTS: AtomicUsize = AtomicUsize::new(0);
+static REAPED_PORTS: AtomicUsize = AtomicUsize::new(0);
+static THROTTLED_OPENS: AtomicUsize = AtomicUsize::new(0);
+static NEXT_TUNNEL_ID: AtomicU32 = AtomicU32::new(0);
+static DRAINING: AtomicBool = AtomicBool::new(false);
+static OPEN_BUDGETS: Mutex<BTreeMap<IpAddr, OpenBudget>> = Mutex::new(BTreeMap::new());
+static SHED_UNTIL: Mutex<Option<Instant>> = Mutex::new(None);
+static CLIENT_VERSIONS: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+static TUNNEL_FEATURES: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+static BONDS: Mutex<BTreeMap<Vec<u8>, PendingBond>> = Mutex::new(BTreeMap::new());
+static PARKED_TUNNELS: Mutex<BTreeMap<Vec<u8>, ParkedTunnel>> = Mutex::new(BTreeMap::new());
+
+// Clients from before they named their software.
+const UNKNOWN_CLIENT_VERSION: &str = "unknown";
+
 #[derive(Clone)]
 enum TunnelMsg {
     CSHeartbeat,
+    CSClosing,
+    CSStats,
     CSOpenPort(u32),
     CSClosePort(u32),
     CSShutdownWrite(u32),
     CSConnectDN(u32, Vec<u8>, u16),
-    CSData(u8, u32, Vec<u8>),
+    CSData(u8, u32, DataBuf),
+    CSHello(Vec<u8>),
+    CSResume(Vec<u8>),
+    CSWindow(u32, u32),
+    CSOpenUdp(u32),
+    CSUdpData(u32, Vec<u8>),
+    CSCloseUdp(u32),
+    CSJoinBond(u32, Vec<u8>),
+    CSStripedData(u32, u64, DataBuf),
 
     SCClosePort(u32),
     SCShutdownWrite(u32),
     SCConnectOk(u32, Vec<u8>),
-    SCData(u32, Vec<u8>),
+    SCData(u32, DataBuf),
+    SCWindow(u32, u32),
+    SCUdpData(u32, Vec<u8>),
+    SCCloseUdp(u32),
+    SCStripedData(u32, u64, DataBuf),
 
     TunnelPortHalfDrop(u32),
     Heartbeat,
@@ -39,76 +101,424 @@ enum TunnelMsg {
 
 enum TunnelPortMsg {
     ConnectDN(Vec<u8>, u16),
-    Data(u8, Vec<u8>),
+    Data(u8, DataBuf),
     ShutdownWrite,
     ClosePort,
+    JoinBond(Vec<u8>),
+    StripedData(u64, DataBuf),
+}
+
+#[derive(Clone)]
+pub struct TunnelConfig {
+    pub handshake_timeout: Duration,
+    pub port_idle_timeout: Duration,
+    // How long a port may take from its open to connecting, see
+    // PortHub::reap.
+    pub port_connect_timeout: Duration,
+    // Ports a client address may open at once, 0 for no limit, and the
+    // ports per second refilling it.
+    pub open_burst: u32,
+    pub open_rate: f64,
+    pub queue_limits: QueueLimits,
+    // Decides where each port may connect, see ConnectHook.
+    pub connect_hook: Option<Arc<ConnectHook>>,
+    // Whether clients may open UDP ports, see udp_port_task.
+    pub udp_relay: bool,
+}
+
+// Token bucket of port opens, shared by the tunnels of an address.
+struct OpenBudget {
+    tokens: f64,
+    time: Instant,
+    throttled: bool,
 }
 
 pub struct TcpTunnel;
+#[cfg(feature = "ucp")]
 pub struct UcpTunnel;
 
 struct TunnelWritePort {
     id: u32,
     tx: Sender<TunnelMsg>,
+    priority_tx: Sender<TunnelMsg>,
+    queue: Arc<TunnelQueue>,
+    window: Arc<PortWindow>,
+    queue_limit: usize,
+    // The other members of its bond, see bond, and the sequence number
+    // of the next data.
+    stripes: Vec<TunnelWritePort>,
+    seq: u64,
 }
 
 struct TunnelReadPort {
     id: u32,
     tx: Sender<TunnelMsg>,
+    priority_tx: Sender<TunnelMsg>,
     rx: Option<Receiver<TunnelPortMsg>>,
+    // Bytes written to the socket and not granted to the client yet.
+    consumed: usize,
+    // The other members of its bond, the data they received out of
+    // order, and the member the last data came on.
+    stripes: Vec<TunnelReadPort>,
+    reassembly: Option<Reassembly>,
+    last: usize,
+}
+
+// The members of a bond that joined so far.
+struct PendingBond {
+    client: IpAddr,
+    time: Instant,
+    members: Vec<Option<(TunnelReadPort, TunnelWritePort)>>,
+}
+
+enum Joined {
+    Waiting,
+    Complete(Vec<(TunnelReadPort, TunnelWritePort)>),
+    Refused(TunnelWritePort),
+}
+
+// The messages of the ports of a tunnel, the interactive ones apart, and
+// the senders new ports get. A tunnel resuming ports takes over their
+// receivers, see resume_ports.
+struct PortBus {
+    senders: SubSenders<TunnelMsg>,
+    priority_sender: Sender<TunnelMsg>,
+    receivers: Receivers<TunnelMsg>,
+    priority: Receivers<TunnelMsg>,
+}
+
+// The ports of a broken tunnel waiting for their client to resume them,
+// with what a PortHub shares with them, see park_ports.
+struct ParkedTunnel {
+    time: Instant,
+    id: u32,
+    ports: HashMap<u32, Port>,
+    queue: Arc<TunnelQueue>,
+    window: Arc<AtomicBool>,
+    receivers: Receivers<TunnelMsg>,
+    priority: Receivers<TunnelMsg>,
 }
 
 struct Port {
+    host: String,
+    port: u16,
     count: u32,
+    uploaded: u64,
+    downloaded: u64,
     tx: Sender<TunnelPortMsg>,
+    window: Arc<PortWindow>,
+    opened: Instant,
+    // Since the port connected or last carried data, None while it
+    // connects.
+    active: Option<Instant>,
+    // A member of a bond, which times out as a whole, see join_bond.
+    bonded: bool,
+    // Kept while the tunnel may resume its ports, see resume.
+    transfer: Option<Transfer>,
 }
 
-struct PortHub(HashMap<u32, Port>);
+// The tunnel id, its ports, the client address, the software the client
+// named, see client_versions, the data its ports queued, the features
+// of its transport, see tunnel_features, whether the client grants the
+// ports credit, see backpressure::PortWindow, whether it takes data
+// frames compressed, see compress, its UDP ports, and the session its
+// ports may resume in, see resume.
+struct PortHub(
+    u32,
+    HashMap<u32, Port>,
+    IpAddr,
+    String,
+    Arc<TunnelQueue>,
+    String,
+    Arc<AtomicBool>,
+    bool,
+    HashMap<u32, Sender<Vec<u8>>>,
+    Option<Vec<u8>>,
+);
+
+impl Default for TunnelConfig {
+    fn default() -> Self {
+        TunnelConfig {
+            handshake_timeout: Duration::from_millis(DEFAULT_HANDSHAKE_TIMEOUT_MS),
+            port_idle_timeout: Duration::from_millis(DEFAULT_PORT_IDLE_TIMEOUT_MS),
+            port_connect_timeout: Duration::from_millis(DEFAULT_PORT_CONNECT_TIMEOUT_MS),
+            open_burst: 0,
+            open_rate: DEFAULT_OPEN_RATE,
+            queue_limits: QueueLimits::default(),
+            connect_hook: None,
+            udp_relay: true,
+        }
+    }
+}
 
 impl TcpTunnel {
-    pub fn new(key: Vec<u8>, stream: TcpStream) {
+    pub fn new(key: Vec<u8>, stream: TcpStream, config: TunnelConfig) {
         task::spawn(async move {
-            tcp_tunnel_core_task(key, stream).await;
+            tcp_tunnel_core_task(key, stream, config).await;
         });
     }
 }
 
+#[cfg(feature = "ucp")]
 impl UcpTunnel {
-    pub fn new(key: Vec<u8>, stream: UcpStream) {
+    pub fn new(key: Vec<u8>, stream: UcpStream, config: TunnelConfig) {
         task::spawn(async move {
-            ucp_tunnel_core_task(key, stream).await;
+            ucp_tunnel_core_task(key, stream, config).await;
         });
     }
 }
 
+pub fn handshake_timeout_count() -> usize {
+    HANDSHAKE_TIMEOUTS.load(Ordering::Relaxed)
+}
+
+// Ports the tunnels closed for connecting or idling too long.
+pub fn reaped_port_count() -> usize {
+    REAPED_PORTS.load(Ordering::Relaxed)
+}
+
+// Port opens refused because their client address ran out of budget.
+pub fn throttled_open_count() -> usize {
+    THROTTLED_OPENS.load(Ordering::Relaxed)
+}
+
+fn take_open_budget(client: IpAddr, config: &TunnelConfig) -> bool {
+    if config.open_burst == 0 {
+        return true;
+    }
+
+    let burst = config.open_burst as f64;
+    let now = Instant::now();
+    let refill = |budget: &OpenBudget| {
+        let tokens = budget.tokens + config.open_rate * (now - budget.time).as_secs_f64();
+        tokens.min(burst)
+    };
+
+    let mut budgets = OPEN_BUDGETS.lock().unwrap();
+    if budgets.len() >= MAX_OPEN_BUDGETS {
+        // A full budget is the same as none.
+        budgets.retain(|_, budget| refill(budget) < burst);
+    }
+
+    let budget = budgets.entry(client).or_insert(OpenBudget {
+        tokens: burst,
+        time: now,
+        throttled: false,
+    });
+    budget.tokens = refill(budget);
+    budget.time = now;
+
+    if budget.tokens >= 1.0 {
+        budget.tokens -= 1.0;
+        budget.throttled = false;
+        return true;
+    }
+
+    THROTTLED_OPENS.fetch_add(1, Ordering::Relaxed);
+    if !budget.throttled {
+        budget.throttled = true;
+        error!("{} opens ports too fast, refusing new ones", client);
+    }
+    false
+}
+
+// Open tunnels by the software version of their client.
+pub fn client_versions() -> Vec<(String, u64)> {
+    CLIENT_VERSIONS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(version, count)| (version.clone(), *count))
+        .collect()
+}
+
+// Open tunnels by the features of their transport, each set named as
+// TunnelFeatures shows it.
+pub fn tunnel_features() -> Vec<(String, u64)> {
+    TUNNEL_FEATURES
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(features, count)| (features.clone(), *count))
+        .collect()
+}
+
+fn count_client_version(version: &str, added: bool) {
+    count_tunnel(&CLIENT_VERSIONS, version, added);
+}
+
+fn count_tunnel(counts: &Mutex<BTreeMap<String, u64>>, key: &str, added: bool) {
+    let mut counts = counts.lock().unwrap();
+    let count = counts.entry(key.to_string()).or_insert(0);
+    if added {
+        *count += 1;
+    } else {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(key);
+        }
+    }
+}
+
+// Tells clients, along with each heartbeat response, to open new ports
+// on another server. Existing ports and new ones keep being served.
+pub fn start_draining() {
+    if !DRAINING.swap(true, Ordering::Relaxed) {
+        info!("draining, asking clients to move to other servers");
+    }
+}
+
+pub fn is_draining() -> bool {
+    DRAINING.load(Ordering::Relaxed)
+}
+
+// For a while after accepts failed for want of file descriptors, ports
+// idle for 30 seconds close as if their idle timeout had passed, so new
+// tunnels and ports get the descriptors instead.
+pub fn shed_idle_ports() {
+    let now = Instant::now();
+    let mut until = SHED_UNTIL.lock().unwrap();
+    if until.is_none_or(|until| now >= until) {
+        error!(
+            "out of file descriptors, closing ports idle for {}s",
+            SHED_IDLE_TIMEOUT_MS / 1000
+        );
+    }
+    *until = Some(now + Duration::from_millis(SHED_DURATION_MS));
+}
+
+pub fn is_shedding() -> bool {
+    SHED_UNTIL
+        .lock()
+        .unwrap()
+        .is_some_and(|until| Instant::now() < until)
+}
+
+// Ports check this at least as often as shedding needs.
+fn port_check_period(watchdog: &Watchdog) -> Duration {
+    watchdog
+        .period()
+        .min(Duration::from_millis(SHED_IDLE_TIMEOUT_MS))
+}
+
+fn port_expired(watchdog: &Watchdog) -> bool {
+    watchdog.expired()
+        || is_shedding() && watchdog.idle() >= Duration::from_millis(SHED_IDLE_TIMEOUT_MS)
+}
+
+impl Drop for PortHub {
+    fn drop(&mut self) {
+        count_client_version(&self.3, false);
+        count_tunnel(&TUNNEL_FEATURES, &self.5, false);
+        self.4.close();
+    }
+}
+
 impl TunnelWritePort {
+    // The tunnel writes the messages of interactive ports before those
+    // of bulk ones. Set before the connect is answered, the port sends
+    // nothing before, and on the read port too.
+    fn set_class(&mut self, class: PortClass) {
+        if class == PortClass::Interactive {
+            self.tx = self.priority_tx.clone();
+            for port in self.stripes.iter_mut() {
+                port.tx = port.priority_tx.clone();
+            }
+        }
+    }
+
+    fn set_queue_limit(&mut self, bytes: usize) {
+        self.queue_limit = bytes;
+        for port in self.stripes.iter_mut() {
+            port.queue_limit = bytes;
+        }
+    }
+
     async fn connect_ok(&mut self, buf: Vec<u8>) {
         let _ = self.tx.send(TunnelMsg::SCConnectOk(self.id, buf)).await;
     }
 
-    async fn write(&mut self, buf: Vec<u8>) {
-        let _ = self.tx.send(TunnelMsg::SCData(self.id, buf)).await;
+    // Data larger than a frame is split, see MAX_DATA_FRAME_SIZE.
+    async fn write(&mut self, buf: DataBuf) {
+        if buf.len() <= MAX_DATA_FRAME_SIZE {
+            return self.write_chunk(buf).await;
+        }
+
+        for chunk in buf.chunks(MAX_DATA_FRAME_SIZE) {
+            self.write_chunk(DataBuf::from(chunk)).await;
+        }
+    }
+
+    // The data of a bond goes over its members in turn.
+    async fn write_chunk(&mut self, buf: DataBuf) {
+        if self.stripes.is_empty() {
+            return self.write_frame(None, buf).await;
+        }
+
+        let seq = self.seq;
+        self.seq += 1;
+        match (seq % (self.stripes.len() as u64 + 1)) as usize {
+            0 => self.write_frame(Some(seq), buf).await,
+            member => self.stripes[member - 1].write_frame(Some(seq), buf).await,
+        }
+    }
+
+    // Waits while the tunnel has more than the limit of the port queued,
+    // and while the port is out of credit.
+    async fn write_frame(&mut self, seq: Option<u64>, buf: DataBuf) {
+        self.queue.wait(self.queue_limit).await;
+        self.window.take(buf.len()).await;
+        self.queue.push(buf.len());
+        let msg = match seq {
+            Some(seq) => TunnelMsg::SCStripedData(self.id, seq, buf),
+            None => TunnelMsg::SCData(self.id, buf),
+        };
+        let _ = self.tx.send(msg).await;
     }
 
     async fn shutdown_write(&mut self) {
-        let _ = self.tx.send(TunnelMsg::SCShutdownWrite(self.id)).await;
+        self.send_all(TunnelMsg::SCShutdownWrite).await;
     }
 
     async fn close(&mut self) {
-        let _ = self.tx.send(TunnelMsg::SCClosePort(self.id)).await;
+        self.send_all(TunnelMsg::SCClosePort).await;
     }
 
     async fn drop(&mut self) {
-        let _ = self.tx.send(TunnelMsg::TunnelPortHalfDrop(self.id)).await;
+        self.send_all(TunnelMsg::TunnelPortHalfDrop).await;
+    }
+
+    // The message goes to the port and the other members of its bond.
+    async fn send_all(&mut self, msg: fn(u32) -> TunnelMsg) {
+        let _ = self.tx.send(msg(self.id)).await;
+        for port in self.stripes.iter_mut() {
+            let _ = port.tx.send(msg(port.id)).await;
+        }
     }
 }
 
 impl TunnelReadPort {
     fn drain(&mut self) {
         self.rx = None;
+        for port in self.stripes.iter_mut() {
+            port.rx = None;
+        }
+    }
+
+    fn set_class(&mut self, class: PortClass) {
+        if class == PortClass::Interactive {
+            self.tx = self.priority_tx.clone();
+            for port in self.stripes.iter_mut() {
+                port.tx = port.priority_tx.clone();
+            }
+        }
     }
 
     async fn read(&mut self) -> TunnelPortMsg {
+        if self.reassembly.is_some() {
+            return self.read_striped().await;
+        }
+
         match self.rx {
             Some(ref mut receiver) => match receiver.next().await {
                 Some(msg) => msg,
@@ -119,71 +529,409 @@ impl TunnelReadPort {
         }
     }
 
+    // The data of a bond in order, whichever member it came on. The other
+    // messages of the members pass as they are, but for the shutdown,
+    // which passes once every member shut down and all data passed.
+    async fn read_striped(&mut self) -> TunnelPortMsg {
+        loop {
+            let reassembly = self.reassembly.as_mut().unwrap();
+            if let Some((member, buf)) = reassembly.pop() {
+                self.last = member;
+                return TunnelPortMsg::Data(cs::DATA, buf);
+            }
+
+            if reassembly.finished() {
+                return TunnelPortMsg::ShutdownWrite;
+            }
+
+            let reassembly = &*reassembly;
+            let (members, reads): (Vec<usize>, Vec<_>) = once(&mut self.rx)
+                .chain(self.stripes.iter_mut().map(|port| &mut port.rx))
+                .enumerate()
+                .filter(|(member, _)| !reassembly.is_shut(*member))
+                .filter_map(|(member, rx)| rx.as_mut().map(|rx| (member, rx.next())))
+                .unzip();
+            if reads.is_empty() {
+                return TunnelPortMsg::ClosePort;
+            }
+
+            let (msg, index, _) = select_all(reads).await;
+            let reassembly = self.reassembly.as_mut().unwrap();
+            match msg {
+                Some(TunnelPortMsg::StripedData(seq, buf)) => {
+                    if !reassembly.push(seq, members[index], buf) {
+                        return TunnelPortMsg::ClosePort;
+                    }
+                }
+                Some(TunnelPortMsg::ShutdownWrite) => reassembly.shut(members[index]),
+                Some(msg) => return msg,
+                None => return TunnelPortMsg::ClosePort,
+            }
+        }
+    }
+
+    // The data read was written to the socket, the client may send as
+    // much more once a step of the window came together. The member of
+    // a bond it came on is granted it.
+    async fn consumed(&mut self, bytes: usize) {
+        match self.last {
+            0 => self.grant(bytes).await,
+            member => self.stripes[member - 1].grant(bytes).await,
+        }
+    }
+
+    async fn grant(&mut self, bytes: usize) {
+        self.consumed += bytes;
+        if self.consumed >= PORT_WINDOW_STEP {
+            let granted = self.consumed as u32;
+            self.consumed = 0;
+            let _ = self.tx.send(TunnelMsg::SCWindow(self.id, granted)).await;
+        }
+    }
+
     async fn close(&mut self) {
-        let _ = self.tx.send(TunnelMsg::SCClosePort(self.id)).await;
+        self.send_all(TunnelMsg::SCClosePort).await;
     }
 
     async fn drop(&mut self) {
-        let _ = self.tx.send(TunnelMsg::TunnelPortHalfDrop(self.id)).await;
+        self.send_all(TunnelMsg::TunnelPortHalfDrop).await;
+    }
+
+    async fn send_all(&mut self, msg: fn(u32) -> TunnelMsg) {
+        let _ = self.tx.send(msg(self.id)).await;
+        for port in self.stripes.iter_mut() {
+            let _ = port.tx.send(msg(port.id)).await;
+        }
     }
 }
 
-impl PortHub {
+impl PortBus {
     fn new() -> Self {
-        PortHub(HashMap::new())
+        let (senders, receivers) = sub_channels(10, 1000);
+        let (priority_sender, priority_receiver) = channel(1000);
+        PortBus {
+            senders,
+            priority_sender,
+            receivers,
+            priority: once(priority_receiver).collect(),
+        }
     }
+}
 
-    fn add_port(&mut self, id: u32, tx: Sender<TunnelPortMsg>) {
-        self.0.insert(id, Port { count: 2, tx: tx });
+impl Port {
+    // Connected on its own, and tracked since it opened.
+    fn can_resume(&self) -> bool {
+        self.active.is_some() && !self.bonded && self.transfer.is_some()
+    }
+}
+
+fn port_closed(tunnel: u32, id: u32, port: Port, reason: CloseReason) {
+    port.window.close();
+    events::emit(|| PortEvent::Closed {
+        tunnel,
+        id,
+        host: port.host,
+        port: port.port,
+        reason,
+        uploaded: port.uploaded,
+        downloaded: port.downloaded,
+    });
+}
+
+impl PortHub {
+    fn new(client: IpAddr, features: TunnelFeatures) -> Self {
+        let features = features.to_string();
+        count_client_version(UNKNOWN_CLIENT_VERSION, true);
+        count_tunnel(&TUNNEL_FEATURES, &features, true);
+        PortHub(
+            NEXT_TUNNEL_ID.fetch_add(1, Ordering::Relaxed),
+            HashMap::new(),
+            client,
+            UNKNOWN_CLIENT_VERSION.to_string(),
+            Arc::new(TunnelQueue::default()),
+            features,
+            Arc::new(AtomicBool::new(false)),
+            false,
+            HashMap::new(),
+            None,
+        )
+    }
+
+    fn set_client_version(&mut self, version: String) {
+        info!(
+            "tunnel {} from {} runs {} over {}",
+            self.0, self.2, version, self.5
+        );
+        count_client_version(&self.3, false);
+        count_client_version(&version, true);
+        self.3 = version;
+    }
+
+    fn add_port(&mut self, id: u32, tx: Sender<TunnelPortMsg>, window: Arc<PortWindow>) {
+        self.1.insert(
+            id,
+            Port {
+                host: String::new(),
+                port: 0,
+                count: 2,
+                uploaded: 0,
+                downloaded: 0,
+                tx,
+                window,
+                opened: Instant::now(),
+                active: None,
+                bonded: false,
+                transfer: self.9.as_ref().map(|_| Transfer::default()),
+            },
+        );
+
+        let tunnel = self.0;
+        events::emit(|| PortEvent::Opened { tunnel, id });
+    }
+
+    fn update_port(&mut self, id: u32, host: String, port: u16) {
+        if let Some(value) = self.1.get_mut(&id) {
+            value.host = host;
+            value.port = port;
+        }
     }
 
     fn drop_port_half(&mut self, id: u32) {
-        if let Some(value) = self.0.get_mut(&id) {
+        if let Some(value) = self.1.get_mut(&id) {
             value.count -= 1;
             if value.count == 0 {
-                self.0.remove(&id);
+                self.remove_port(id, CloseReason::Finished);
             }
         };
     }
 
     fn clear_ports(&mut self) {
-        self.0.clear();
+        let ids: Vec<u32> = self.1.keys().cloned().collect();
+        for id in ids {
+            self.remove_port(id, CloseReason::TunnelBroken);
+        }
+        self.8.clear();
+    }
+
+    fn remove_port(&mut self, id: u32, reason: CloseReason) {
+        if let Some(value) = self.1.remove(&id) {
+            port_closed(self.0, id, value, reason);
+        }
+    }
+
+    fn transfer(&mut self, id: u32) -> Option<&mut Transfer> {
+        self.1.get_mut(&id)?.transfer.as_mut()
+    }
+
+    // The ports that may resume leave with the session, the others are
+    // closed.
+    fn park(&mut self, bus: PortBus) -> Option<(Vec<u8>, ParkedTunnel)> {
+        let token = match self.9.take() {
+            Some(token) => token,
+            None => {
+                self.clear_ports();
+                return None;
+            }
+        };
+
+        let ids: Vec<u32> = self
+            .1
+            .iter()
+            .filter(|(_, port)| !port.can_resume())
+            .map(|(&id, _)| id)
+            .collect();
+        for id in ids {
+            self.remove_port(id, CloseReason::TunnelBroken);
+        }
+        self.8.clear();
+        if self.1.is_empty() {
+            return None;
+        }
+
+        let parked = ParkedTunnel {
+            time: Instant::now(),
+            id: self.0,
+            ports: mem::take(&mut self.1),
+            queue: mem::take(&mut self.4),
+            window: mem::take(&mut self.6),
+            receivers: bus.receivers,
+            priority: bus.priority,
+        };
+        Some((token, parked))
+    }
+
+    // Takes over the ports of a session, on a connection that has none
+    // of its own yet.
+    fn adopt(&mut self, token: Vec<u8>, parked: ParkedTunnel, bus: &mut PortBus) {
+        bus.receivers.extend(parked.receivers);
+        bus.priority.extend(parked.priority);
+        parked
+            .window
+            .store(self.6.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.0 = parked.id;
+        self.1 = parked.ports;
+        self.4 = parked.queue;
+        self.6 = parked.window;
+        self.9 = Some(token);
     }
 
     fn client_close_port(&mut self, id: u32) {
-        self.0.remove(&id);
+        self.remove_port(id, CloseReason::ClientClosed);
     }
 
     fn server_close_port(&mut self, id: u32) {
-        self.0.remove(&id);
+        self.remove_port(id, CloseReason::ServerClosed);
+    }
+
+    fn connect_ok(&mut self, id: u32) {
+        if let Some(value) = self.1.get_mut(&id) {
+            value.active = Some(Instant::now());
+            let tunnel = self.0;
+            let (host, port) = (value.host.clone(), value.port);
+            events::emit(|| PortEvent::Connected {
+                tunnel,
+                id,
+                host,
+                port,
+            });
+        }
+    }
+
+    fn server_send_data(&mut self, id: u32, len: usize) {
+        if let Some(value) = self.1.get_mut(&id) {
+            value.downloaded += len as u64;
+            value.active = Some(Instant::now());
+        }
+    }
+
+    // A bond doesn't resume.
+    fn join_bond(&mut self, id: u32) {
+        if let Some(value) = self.1.get_mut(&id) {
+            value.bonded = true;
+            value.transfer = None;
+        }
+    }
+
+    // Closes ports still connecting after `connect_timeout`, as when the
+    // client never says where to or the destination doesn't answer, and
+    // ports idle for longer than their task should have let them. Their
+    // tasks are told, the ids are for telling the client.
+    fn reap(&mut self, connect_timeout: Duration, idle_timeout: Duration) -> Vec<u32> {
+        let now = Instant::now();
+        let idle_timeout = idle_timeout + Duration::from_millis(HEARTBEAT_INTERVAL_MS);
+        let reaped: Vec<(u32, bool)> = self
+            .1
+            .iter()
+            .filter(|(_, port)| !port.bonded)
+            .filter_map(|(&id, port)| match port.active {
+                None if now - port.opened >= connect_timeout => Some((id, false)),
+                Some(active) if now - active >= idle_timeout => Some((id, true)),
+                _ => None,
+            })
+            .collect();
+
+        for &(id, idle) in reaped.iter() {
+            if let Some(port) = self.1.get_mut(&id) {
+                info!(
+                    "tunnel {}.{} from {}: {}:{} {}, closing it",
+                    self.0,
+                    id,
+                    self.2,
+                    port.host,
+                    port.port,
+                    if idle { "idle" } else { "not connected" }
+                );
+                let _ = port.tx.try_send(TunnelPortMsg::ClosePort);
+            }
+            self.remove_port(id, CloseReason::TimedOut);
+            REAPED_PORTS.fetch_add(1, Ordering::Relaxed);
+        }
+
+        reaped.into_iter().map(|(id, _)| id).collect()
+    }
+
+    fn client_window(&mut self, id: u32, bytes: u32) {
+        if let Some(value) = self.1.get_mut(&id) {
+            value.window.grant(bytes as usize);
+            if let Some(transfer) = value.transfer.as_mut() {
+                transfer.granted_by_peer(bytes);
+            }
+        }
     }
 
     async fn connect(&mut self, id: u32, domain: Vec<u8>, port: u16) {
+        let host = String::from_utf8(domain.clone()).unwrap_or_default();
+        self.update_port(id, host, port);
+
         self.try_send_msg(id, TunnelPortMsg::ConnectDN(domain, port))
             .await;
     }
 
-    async fn client_send_data(&mut self, id: u32, op: u8, buf: Vec<u8>) {
+    async fn client_send_data(&mut self, id: u32, op: u8, buf: DataBuf) {
+        if op == cs::CONNECT {
+            if let Some(addr) = from_utf8(&buf)
+                .ok()
+                .and_then(|s| s.parse::<SocketAddr>().ok())
+            {
+                self.update_port(id, addr.ip().to_string(), addr.port());
+            }
+        } else if let Some(value) = self.1.get_mut(&id) {
+            value.uploaded += buf.len() as u64;
+            value.active = Some(Instant::now());
+            if let Some(transfer) = value.transfer.as_mut() {
+                transfer.receive(buf.len());
+            }
+        }
+
         self.try_send_msg(id, TunnelPortMsg::Data(op, buf)).await;
     }
 
+    async fn client_send_striped(&mut self, id: u32, seq: u64, buf: DataBuf) {
+        if let Some(value) = self.1.get_mut(&id) {
+            value.uploaded += buf.len() as u64;
+            value.active = Some(Instant::now());
+        }
+
+        self.try_send_msg(id, TunnelPortMsg::StripedData(seq, buf))
+            .await;
+    }
+
     async fn client_shutdown(&mut self, id: u32) {
+        if let Some(transfer) = self.transfer(id) {
+            transfer.peer_shut_down();
+        }
         self.try_send_msg(id, TunnelPortMsg::ShutdownWrite).await;
     }
 
+    // A datagram the UDP port has no room for is dropped, as the network
+    // would.
+    fn client_send_udp(&mut self, id: u32, data: Vec<u8>) {
+        if let Some(tx) = self.8.get_mut(&id) {
+            if let Err(e) = tx.try_send(data) {
+                if e.is_disconnected() {
+                    self.8.remove(&id);
+                }
+            }
+        }
+    }
+
     async fn try_send_msg(&mut self, id: u32, msg: TunnelPortMsg) {
-        if let Some(value) = self.0.get_mut(&id) {
+        if let Some(value) = self.1.get_mut(&id) {
             if value.tx.send(msg).await.is_err() {
-                self.0.remove(&id);
+                self.remove_port(id, CloseReason::Error);
             }
         }
     }
 }
 
-async fn tunnel_port_write(stream: &mut &TcpStream, mut write_port: TunnelWritePort) {
+async fn tunnel_port_write(
+    stream: &mut &TcpStream,
+    mut write_port: TunnelWritePort,
+    watchdog: &Watchdog,
+) {
     loop {
-        let mut buf = vec![0; 1024];
-        match stream.read(&mut buf).await {
+        let mut buf = DataBuf::new(1024);
+        match io::timeout(port_check_period(watchdog), stream.read(&mut buf)).await {
             Ok(0) => {
                 let _ = stream.shutdown(Shutdown::Read);
                 write_port.shutdown_write().await;
@@ -192,10 +940,20 @@ async fn tunnel_port_write(stream: &mut &TcpStream, mut write_port: TunnelWriteP
             }
 
             Ok(n) => {
+                watchdog.feed();
                 buf.truncate(n);
-                write_port.write(buf).await;
+                if future::timeout(watchdog.period(), write_port.write(buf))
+                    .await
+                    .is_err()
+                {
+                    let _ = stream.shutdown(Shutdown::Both);
+                    write_port.close().await;
+                    break;
+                }
             }
 
+            Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut && !port_expired(watchdog) => {}
+
             Err(_) => {
                 let _ = stream.shutdown(Shutdown::Both);
                 write_port.close().await;
@@ -205,25 +963,36 @@ async fn tunnel_port_write(stream: &mut &TcpStream, mut write_port: TunnelWriteP
     }
 }
 
-async fn tunnel_port_read(stream: &mut &TcpStream, mut read_port: TunnelReadPort) {
+async fn tunnel_port_read(
+    stream: &mut &TcpStream,
+    mut read_port: TunnelReadPort,
+    watchdog: &Watchdog,
+) {
     loop {
-        match read_port.read().await {
-            TunnelPortMsg::Data(cs::DATA, buf) => {
-                if stream.write_all(&buf).await.is_err() {
+        match future::timeout(port_check_period(watchdog), read_port.read()).await {
+            Ok(TunnelPortMsg::Data(cs::DATA, buf)) => {
+                watchdog.feed();
+                if io::timeout(watchdog.period(), stream.write_all(&buf))
+                    .await
+                    .is_err()
+                {
                     let _ = stream.shutdown(Shutdown::Both);
                     read_port.drain();
                     read_port.close().await;
                     break;
                 }
+                read_port.consumed(buf.len()).await;
             }
 
-            TunnelPortMsg::ShutdownWrite => {
+            Ok(TunnelPortMsg::ShutdownWrite) => {
                 let _ = stream.shutdown(Shutdown::Write);
                 read_port.drain();
                 read_port.drop().await;
                 break;
             }
 
+            Err(_) if !port_expired(watchdog) => {}
+
             _ => {
                 let _ = stream.shutdown(Shutdown::Both);
                 read_port.drain();
@@ -234,26 +1003,121 @@ async fn tunnel_port_read(stream: &mut &TcpStream, mut read_port: TunnelReadPort
     }
 }
 
-async fn tunnel_port_task(mut read_port: TunnelReadPort, mut write_port: TunnelWritePort) {
-    let stream = match read_port.read().await {
-        TunnelPortMsg::Data(cs::CONNECT, buf) => {
-            TcpStream::connect(from_utf8(&buf).unwrap()).await.ok()
+// Connects to the address, or the host and port, a client asked for.
+async fn connect_destination(host: &[u8], port: Option<u16>) -> Result<TcpStream> {
+    let host = from_utf8(host)
+        .map_err(|_| Error::InvalidDestination(String::from_utf8_lossy(host).into_owned()))?;
+    let result = match port {
+        Some(port) => TcpStream::connect((host, port)).await,
+        None => TcpStream::connect(host).await,
+    };
+
+    result.map_err(|e| match port {
+        Some(port) => Error::io(format!("connect {}:{}", host, port), e),
+        None => Error::io(format!("connect {}", host), e),
+    })
+}
+
+// The destination the hook allows, rewritten if it says so, or None when
+// it denies it. An address is split into its host and port for the hook.
+async fn check_destination(
+    hook: &ConnectHook,
+    client: IpAddr,
+    host: Vec<u8>,
+    port: Option<u16>,
+) -> Result<Option<(Vec<u8>, Option<u16>)>> {
+    let text = from_utf8(&host)
+        .map_err(|_| Error::InvalidDestination(String::from_utf8_lossy(&host).into_owned()))?;
+    let (name, number) = match port {
+        Some(port) => (text.to_string(), port),
+        None => {
+            let addr = text
+                .parse::<SocketAddr>()
+                .map_err(|_| Error::InvalidDestination(text.to_string()))?;
+            (addr.ip().to_string(), addr.port())
         }
+    };
 
-        TunnelPortMsg::ConnectDN(domain_name, port) => {
-            TcpStream::connect((from_utf8(&domain_name).unwrap(), port))
-                .await
-                .ok()
+    match hook.check(client, &name, number).await {
+        Verdict::Allow => Ok(Some((host, port))),
+        Verdict::Deny => {
+            info!("connect hook denied {} to {}:{}", client, name, number);
+            Ok(None)
         }
+        Verdict::Rewrite(new_name, new_number) => {
+            info!(
+                "connect hook rewrote {}:{} to {}:{} for {}",
+                name, number, new_name, new_number, client
+            );
+            Ok(Some((new_name.into_bytes(), Some(new_number))))
+        }
+    }
+}
 
-        _ => None,
+async fn tunnel_port_task(
+    mut read_port: TunnelReadPort,
+    mut write_port: TunnelWritePort,
+    client: IpAddr,
+    idle_timeout: Duration,
+    connect_timeout: Duration,
+    queue_limits: QueueLimits,
+    hook: Option<Arc<ConnectHook>>,
+) {
+    let (host, port) = loop {
+        match read_port.read().await {
+            TunnelPortMsg::Data(cs::CONNECT, buf) => break (buf.to_vec(), None),
+            TunnelPortMsg::ConnectDN(domain_name, port) => break (domain_name, Some(port)),
+            // The connect follows on the first member once all joined.
+            TunnelPortMsg::JoinBond(data) if read_port.reassembly.is_none() => {
+                match join_bond(read_port, write_port, client, &data).await {
+                    Some((bond_read_port, bond_write_port)) => {
+                        read_port = bond_read_port;
+                        write_port = bond_write_port;
+                    }
+                    None => return,
+                }
+            }
+            _ => return write_port.close().await,
+        }
+    };
+
+    let destination = match hook {
+        Some(hook) => check_destination(&hook, client, host, port).await,
+        None => Ok(Some((host, port))),
+    };
+
+    // The tunnel closes the port once it timed out, the connect is
+    // given up with it.
+    let stream = match destination {
+        Ok(Some((host, port))) => {
+            match future::timeout(connect_timeout, connect_destination(&host, port)).await {
+                Ok(stream) => stream,
+                Err(_) => return write_port.close().await,
+            }
+        }
+        Ok(None) => return write_port.close().await,
+        Err(e) => Err(e),
     };
 
     let stream = match stream {
-        Some(s) => s,
-        None => return write_port.close().await,
+        Ok(s) => s,
+        Err(e @ Error::InvalidDestination(_)) => {
+            error!("{}", e);
+            return write_port.close().await;
+        }
+        Err(e) => {
+            info!("{}", e);
+            return write_port.close().await;
+        }
     };
 
+    if let Ok(addr) = stream.peer_addr() {
+        let class = PortClass::of_port(addr.port());
+        write_port.set_queue_limit(queue_limits.limit(class));
+        write_port.set_class(class);
+        read_port.set_class(class);
+    }
+
     match stream.local_addr() {
         Ok(addr) => {
             let mut buf = Vec::new();
@@ -266,83 +1130,436 @@ async fn tunnel_port_task(mut read_port: TunnelReadPort, mut write_port: TunnelW
         }
     }
 
+    // Shared by both directions, see Watchdog.
+    let watchdog = Watchdog::new(idle_timeout);
     let (reader, writer) = &mut (&stream, &stream);
-    let w = tunnel_port_write(reader, write_port);
-    let r = tunnel_port_read(writer, read_port);
+    let w = tunnel_port_write(reader, write_port, &watchdog);
+    let r = tunnel_port_read(writer, read_port, &watchdog);
     let _ = r.join(w).await;
 }
 
-async fn tcp_tunnel_core_task(key: Vec<u8>, stream: TcpStream) {
-    let (mut main_sender, sub_senders, receivers) = channel_bus(10, 1000);
+// The ports of a bond completed by this member, as the first member
+// with the others as its stripes. None while the bond waits for the
+// rest of its members, or when the member was refused.
+async fn join_bond(
+    read_port: TunnelReadPort,
+    write_port: TunnelWritePort,
+    client: IpAddr,
+    data: &[u8],
+) -> Option<(TunnelReadPort, TunnelWritePort)> {
+    let (joined, expired) = park_bond_member(read_port, write_port, client, data);
+    for (_, mut write_port) in expired {
+        write_port.close().await;
+    }
+
+    let members = match joined {
+        Joined::Waiting => return None,
+        Joined::Refused(mut write_port) => {
+            write_port.close().await;
+            return None;
+        }
+        Joined::Complete(members) => members,
+    };
+
+    let count = members.len();
+    let mut members = members.into_iter();
+    let (mut read_port, mut write_port) = members.next()?;
+    for (stripe_read_port, stripe_write_port) in members {
+        read_port.stripes.push(stripe_read_port);
+        write_port.stripes.push(stripe_write_port);
+    }
+    read_port.reassembly = Some(Reassembly::new(count));
+
+    info!("bond of {} ports from {}", count, client);
+    Some((read_port, write_port))
+}
+
+// Keeps the member until the rest of its bond joined, the member to
+// complete it gets them all. The members of bonds that waited too long
+// are handed back to be closed.
+fn park_bond_member(
+    read_port: TunnelReadPort,
+    write_port: TunnelWritePort,
+    client: IpAddr,
+    data: &[u8],
+) -> (Joined, Vec<(TunnelReadPort, TunnelWritePort)>) {
+    let (key, index, count) = match bond::parse_join(data) {
+        Some(join) => join,
+        None => return (Joined::Refused(write_port), Vec::new()),
+    };
+
+    let now = Instant::now();
+    let timeout = Duration::from_millis(BOND_JOIN_TIMEOUT_MS);
+    let mut bonds = BONDS.lock().unwrap();
+    let expired_keys: Vec<Vec<u8>> = bonds
+        .iter()
+        .filter(|(_, bond)| now - bond.time >= timeout)
+        .map(|(key, _)| key.clone())
+        .collect();
+    let expired = expired_keys
+        .iter()
+        .filter_map(|key| bonds.remove(key))
+        .flat_map(|bond| bond.members.into_iter().flatten())
+        .collect();
+
+    if !bonds.contains_key(key) && bonds.len() >= MAX_PENDING_BONDS {
+        return (Joined::Refused(write_port), expired);
+    }
+
+    let bond = bonds.entry(key.to_vec()).or_insert_with(|| PendingBond {
+        client,
+        time: now,
+        members: (0..count).map(|_| None).collect(),
+    });
+    if bond.client != client || bond.members.len() != count || bond.members[index].is_some() {
+        return (Joined::Refused(write_port), expired);
+    }
+
+    bond.members[index] = Some((read_port, write_port));
+    if bond.members.iter().any(Option::is_none) {
+        return (Joined::Waiting, expired);
+    }
+
+    let members = bonds.remove(key).unwrap().members;
+    (
+        Joined::Complete(members.into_iter().flatten().collect()),
+        expired,
+    )
+}
+
+// Relays the datagrams of a UDP port of the client from a socket of its
+// own, until the client closes the port or it idles for the port idle
+// timeout. Replies come back with the address they came from. A
+// destination is resolved, and checked by the hook, when the port first
+// sends to it.
+async fn udp_port_task(
+    id: u32,
+    mut rx: Receiver<Vec<u8>>,
+    mut tx: Sender<TunnelMsg>,
+    client: IpAddr,
+    idle_timeout: Duration,
+    hook: Option<Arc<ConnectHook>>,
+) {
+    let socket = match bind_udp_socket().await {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("{}", e);
+            let _ = tx.send(TunnelMsg::SCCloseUdp(id)).await;
+            return;
+        }
+    };
+    let mapped = socket.local_addr().is_ok_and(|addr| addr.is_ipv6());
+
+    let watchdog = Watchdog::new(idle_timeout);
+    let outgoing = async {
+        let mut destinations: HashMap<(Vec<u8>, u16), Option<SocketAddr>> = HashMap::new();
+        loop {
+            let data = match future::timeout(port_check_period(&watchdog), rx.next()).await {
+                Ok(Some(data)) => data,
+                Err(_) if !port_expired(&watchdog) => continue,
+                _ => break,
+            };
 
-    let mut port_hub = PortHub::new();
+            let (host, port, payload) = match unpack_udp_datagram(&data) {
+                Some(datagram) => datagram,
+                None => continue,
+            };
+            watchdog.feed();
+
+            let key = (host.to_vec(), port);
+            let addr = match destinations.get(&key) {
+                Some(addr) => *addr,
+                None => {
+                    let addr = resolve_udp_destination(hook.as_deref(), client, host, port).await;
+                    if destinations.len() >= MAX_UDP_DESTINATIONS {
+                        destinations.clear();
+                    }
+                    destinations.insert(key, addr);
+                    addr
+                }
+            };
+
+            if let Some(addr) = addr {
+                let addr = match addr {
+                    SocketAddr::V4(v4) if mapped => {
+                        SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port())
+                    }
+                    addr => addr,
+                };
+                let _ = socket.send_to(payload, addr).await;
+            }
+        }
+    };
+    let incoming = async {
+        let mut buf = vec![0; MAX_UDP_DATAGRAM];
+        let mut tx = tx.clone();
+        loop {
+            let (size, from) =
+                match io::timeout(port_check_period(&watchdog), socket.recv_from(&mut buf)).await {
+                    Ok(received) => received,
+                    Err(ref e)
+                        if e.kind() == std::io::ErrorKind::TimedOut && !port_expired(&watchdog) =>
+                    {
+                        continue
+                    }
+                    Err(_) => break,
+                };
+            watchdog.feed();
+
+            let ip = match from.ip() {
+                IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(IpAddr::V6(v6), IpAddr::V4),
+                ip => ip,
+            };
+            let host = ip.to_string();
+            if let Some(data) = pack_udp_datagram(host.as_bytes(), from.port(), &buf[..size]) {
+                if tx.send(TunnelMsg::SCUdpData(id, data)).await.is_err() {
+                    break;
+                }
+            }
+        }
+    };
+    outgoing.race(incoming).await;
+
+    let _ = tx.send(TunnelMsg::SCCloseUdp(id)).await;
+}
+
+async fn bind_udp_socket() -> Result<UdpSocket> {
+    match UdpSocket::bind("[::]:0").await {
+        Ok(socket) => Ok(socket),
+        Err(_) => UdpSocket::bind("0.0.0.0:0")
+            .await
+            .map_err(|e| Error::io("udp bind".to_string(), e)),
+    }
+}
+
+// Where a UDP port sends to the host and port, or None when it can't be
+// resolved or the hook denies it.
+async fn resolve_udp_destination(
+    hook: Option<&ConnectHook>,
+    client: IpAddr,
+    host: &[u8],
+    port: u16,
+) -> Option<SocketAddr> {
+    let (host, port) = match hook {
+        Some(hook) => match check_destination(hook, client, host.to_vec(), Some(port)).await {
+            Ok(Some((host, port))) => (host, port?),
+            _ => return None,
+        },
+        None => (host.to_vec(), port),
+    };
+
+    let host = from_utf8(&host).ok()?;
+    match (host, port).to_socket_addrs().await {
+        Ok(mut addrs) => addrs.next(),
+        Err(e) => {
+            info!("udp resolve {}:{}: {}", host, port, e);
+            None
+        }
+    }
+}
+
+// The ports of a broken tunnel that may resume wait for their client,
+// see resume, the others are closed.
+fn park_ports(port_hub: &mut PortHub, bus: PortBus) {
+    let (token, parked) = match port_hub.park(bus) {
+        Some(parked) => parked,
+        None => return,
+    };
+
+    info!(
+        "tunnel {} from {} broken, {} ports wait to resume",
+        parked.id,
+        port_hub.2,
+        parked.ports.len()
+    );
+    PARKED_TUNNELS.lock().unwrap().insert(token, parked);
+    task::spawn(async {
+        task::sleep(Duration::from_millis(SESSION_PARK_TIMEOUT_MS)).await;
+        expire_parked_tunnels();
+    });
+}
+
+fn expire_parked_tunnels() {
+    let timeout = Duration::from_millis(SESSION_PARK_TIMEOUT_MS);
+    let expired: Vec<ParkedTunnel> = {
+        let mut parked = PARKED_TUNNELS.lock().unwrap();
+        let tokens: Vec<Vec<u8>> = parked
+            .iter()
+            .filter(|(_, tunnel)| tunnel.time.elapsed() >= timeout)
+            .map(|(token, _)| token.clone())
+            .collect();
+        tokens
+            .iter()
+            .filter_map(|token| parked.remove(token))
+            .collect()
+    };
+
+    for tunnel in expired {
+        info!(
+            "tunnel {} not resumed, closing its {} ports",
+            tunnel.id,
+            tunnel.ports.len()
+        );
+        tunnel.queue.close();
+        for (id, port) in tunnel.ports {
+            port_closed(tunnel.id, id, port, CloseReason::TunnelBroken);
+        }
+    }
+}
+
+async fn tcp_tunnel_core_task(key: Vec<u8>, stream: TcpStream, config: TunnelConfig) {
+    let (mut main_sender, requests) = channel(1000);
+    let mut bus = PortBus::new();
+
+    let client = match stream.peer_addr() {
+        Ok(addr) => addr.ip(),
+        Err(_) => return,
+    };
+    let mut port_hub = PortHub::new(client, TunnelFeatures::tcp());
     let (reader, writer) = &mut (&stream, &stream);
     let r = async {
-        let _ = process_tunnel_read(key.clone(), &mut main_sender, reader).await;
+        let handshake_timeout = config.handshake_timeout;
+        let _ = process_tunnel_read(
+            key.clone(),
+            client,
+            handshake_timeout,
+            &mut main_sender,
+            reader,
+        )
+        .await;
         let _ = main_sender.send(TunnelMsg::CloseTunnel).await;
         let _ = stream.shutdown(Shutdown::Both);
     };
     let w = async {
-        let _ =
-            process_tunnel_write(key.clone(), sub_senders, receivers, &mut port_hub, writer).await;
+        let _ = process_tunnel_write(
+            key.clone(),
+            &config,
+            requests,
+            &mut bus,
+            &mut port_hub,
+            writer,
+        )
+        .await;
         let _ = stream.shutdown(Shutdown::Both);
     };
     let _ = r.join(w).await;
 
-    port_hub.clear_ports();
+    park_ports(&mut port_hub, bus);
 }
 
-async fn ucp_tunnel_core_task(key: Vec<u8>, stream: UcpStream) {
-    let (mut main_sender, sub_senders, receivers) = channel_bus(10, 1000);
+#[cfg(feature = "ucp")]
+async fn ucp_tunnel_core_task(key: Vec<u8>, stream: UcpStream, config: TunnelConfig) {
+    let (mut main_sender, requests) = channel(1000);
+    let mut bus = PortBus::new();
 
-    let mut port_hub = PortHub::new();
+    let features = TunnelFeatures::ucp(stream.capabilities());
+    let client = stream.remote_addr().ip();
+    let mut port_hub = PortHub::new(client, features);
     let (reader, writer) = &mut (&stream, &stream);
     let r = async {
-        let _ = process_tunnel_read(key.clone(), &mut main_sender, reader).await;
+        let handshake_timeout = config.handshake_timeout;
+        let _ = process_tunnel_read(
+            key.clone(),
+            client,
+            handshake_timeout,
+            &mut main_sender,
+            reader,
+        )
+        .await;
         let _ = main_sender.send(TunnelMsg::CloseTunnel).await;
         stream.shutdown();
     };
     let w = async {
-        let _ =
-            process_tunnel_write(key.clone(), sub_senders, receivers, &mut port_hub, writer).await;
+        let _ = process_tunnel_write(
+            key.clone(),
+            &config,
+            requests,
+            &mut bus,
+            &mut port_hub,
+            writer,
+        )
+        .await;
         stream.shutdown();
     };
     let _ = r.join(w).await;
 
-    port_hub.clear_ports();
+    park_ports(&mut port_hub, bus);
 }
 
 async fn process_tunnel_read<R: Read + Unpin>(
     key: Vec<u8>,
+    client: IpAddr,
+    handshake_timeout: Duration,
     sender: &mut MainSender<TunnelMsg>,
     stream: &mut R,
 ) -> std::io::Result<()> {
-    let mut ctr = vec![0; Cryptor::ctr_size()];
-    stream.read_exact(&mut ctr).await?;
+    // The handshake and the first frame must arrive before the deadline,
+    // otherwise the connection is holding resources without using them.
+    let handshake = io::timeout(handshake_timeout, async {
+        let mut ctr = vec![0; Cryptor::ctr_size()];
+        stream.read_exact(&mut ctr).await?;
 
-    let mut decryptor = Cryptor::with_ctr(&key, ctr);
+        let mut decryptor = Cryptor::with_ctr(&key, ctr);
 
-    let mut buf = vec![0; VERIFY_DATA.len()];
-    stream.read_exact(&mut buf).await?;
+        let mut buf = vec![0; VERIFY_DATA.len()];
+        stream.read_exact(&mut buf).await?;
 
-    let data = decryptor.decrypt(&buf);
-    if &data != &VERIFY_DATA {
-        return Err(std::io::Error::from(std::io::ErrorKind::InvalidInput));
-    }
+        let data = decryptor.decrypt(&buf);
+        if &data != &VERIFY_DATA {
+            error!("tunnel from {} failed to verify, its key differs", client);
+            return Err(std::io::Error::from(std::io::ErrorKind::InvalidInput));
+        }
 
-    loop {
         let mut op = [0u8; 1];
         stream.read_exact(&mut op).await?;
-        let op = op[0];
+
+        Ok((decryptor, op[0]))
+    })
+    .await;
+
+    let (mut decryptor, first_op) = match handshake {
+        Ok(result) => result,
+
+        Err(e) => {
+            if e.kind() == std::io::ErrorKind::TimedOut {
+                let count = HANDSHAKE_TIMEOUTS.fetch_add(1, Ordering::Relaxed) + 1;
+                error!("tunnel handshake timeout, total timeouts: {}", count);
+            }
+            return Err(e);
+        }
+    };
+
+    let mut first_op = Some(first_op);
+
+    loop {
+        let op = match first_op.take() {
+            Some(op) => op,
+
+            None => {
+                let mut op = [0u8; 1];
+                stream.read_exact(&mut op).await?;
+                op[0]
+            }
+        };
 
         if op == cs::HEARTBEAT {
             let _ = sender.send(TunnelMsg::CSHeartbeat).await;
             continue;
         }
 
+        if op == cs::CLOSING {
+            let _ = sender.send(TunnelMsg::CSClosing).await;
+            continue;
+        }
+
+        if op == cs::STATS {
+            let _ = sender.send(TunnelMsg::CSStats).await;
+            continue;
+        }
+
         let mut id = [0u8; 4];
         stream.read_exact(&mut id).await?;
-        let id = u32::from_be(unsafe { *(id.as_ptr() as *const u32) });
+        let id = u32::from_be_bytes(id);
 
         match op {
             cs::OPEN_PORT => {
@@ -360,39 +1577,143 @@ async fn process_tunnel_read<R: Read + Unpin>(
             cs::CONNECT_DOMAIN_NAME => {
                 let mut len = [0u8; 4];
                 stream.read_exact(&mut len).await?;
-                let len = u32::from_be(unsafe { *(len.as_ptr() as *const u32) });
+                let len = frame_len(len)?;
 
-                let mut buf = vec![0; len as usize];
+                let mut buf = vec![0; len];
                 stream.read_exact(&mut buf).await?;
+                if buf.len() < 2 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "connect without port",
+                    ));
+                }
 
-                let pos = (len - 2) as usize;
+                let pos = buf.len() - 2;
                 let domain_name = decryptor.decrypt(&buf[0..pos]);
-                let port = u16::from_be(unsafe { *(buf[pos..].as_ptr() as *const u16) });
+                let port = u16::from_be_bytes([buf[pos], buf[pos + 1]]);
 
                 let _ = sender
                     .send(TunnelMsg::CSConnectDN(id, domain_name, port))
                     .await;
             }
 
-            _ => {
+            cs::DATA_LZ4 => {
+                let mut len = [0u8; 4];
+                stream.read_exact(&mut len).await?;
+                let len = data_frame_len(len)?;
+
+                let mut buf = vec![0; len];
+                stream.read_exact(&mut buf).await?;
+
+                let data = compress::decompress(&decryptor.decrypt(&buf)).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "bad compressed frame")
+                })?;
+                let _ = sender
+                    .send(TunnelMsg::CSData(cs::DATA, id, data.into()))
+                    .await;
+            }
+
+            cs::JOIN_BOND => {
+                let mut len = [0u8; 4];
+                stream.read_exact(&mut len).await?;
+                let len = frame_len(len)?;
+
+                let mut buf = vec![0; len];
+                stream.read_exact(&mut buf).await?;
+
+                let data = decryptor.decrypt(&buf);
+                let _ = sender.send(TunnelMsg::CSJoinBond(id, data)).await;
+            }
+
+            cs::STRIPED_DATA => {
+                let mut seq = [0u8; 8];
+                stream.read_exact(&mut seq).await?;
+                let seq = u64::from_be_bytes(seq);
+
                 let mut len = [0u8; 4];
                 stream.read_exact(&mut len).await?;
-                let len = u32::from_be(unsafe { *(len.as_ptr() as *const u32) });
+                let len = data_frame_len(len)?;
 
-                let mut buf = vec![0; len as usize];
+                let mut data = DataBuf::new(len);
+                stream.read_exact(&mut data).await?;
+
+                decryptor.decrypt_in_place(&mut data);
+                let _ = sender.send(TunnelMsg::CSStripedData(id, seq, data)).await;
+            }
+
+            cs::OPEN_UDP => {
+                let _ = sender.send(TunnelMsg::CSOpenUdp(id)).await;
+            }
+
+            cs::CLOSE_UDP => {
+                let _ = sender.send(TunnelMsg::CSCloseUdp(id)).await;
+            }
+
+            cs::UDP_DATA => {
+                let mut len = [0u8; 4];
+                stream.read_exact(&mut len).await?;
+                let len = frame_len(len)?;
+
+                let mut buf = vec![0; len];
                 stream.read_exact(&mut buf).await?;
 
                 let data = decryptor.decrypt(&buf);
+                let _ = sender.send(TunnelMsg::CSUdpData(id, data)).await;
+            }
+
+            cs::WINDOW => {
+                let mut bytes = [0u8; 4];
+                stream.read_exact(&mut bytes).await?;
+                let bytes = u32::from_be_bytes(bytes);
+                let _ = sender.send(TunnelMsg::CSWindow(id, bytes)).await;
+            }
+
+            cs::HELLO | cs::RESUME => {
+                let mut len = [0u8; 4];
+                stream.read_exact(&mut len).await?;
+                let len = frame_len(len)?;
+
+                let mut buf = vec![0; len];
+                stream.read_exact(&mut buf).await?;
+
+                let data = decryptor.decrypt(&buf);
+                let msg = if op == cs::HELLO {
+                    TunnelMsg::CSHello(data)
+                } else {
+                    TunnelMsg::CSResume(data)
+                };
+                let _ = sender.send(msg).await;
+            }
+
+            cs::CONNECT | cs::DATA => {
+                let mut len = [0u8; 4];
+                stream.read_exact(&mut len).await?;
+                let len = data_frame_len(len)?;
+
+                let mut data = DataBuf::new(len);
+                stream.read_exact(&mut data).await?;
+
+                decryptor.decrypt_in_place(&mut data);
                 let _ = sender.send(TunnelMsg::CSData(op, id, data)).await;
             }
+
+            // Nothing after it can be framed right.
+            _ => {
+                error!(
+                    "tunnel from {} sent unknown frame {}, the client speaks a newer protocol",
+                    client, op
+                );
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown frame"));
+            }
         }
     }
 }
 
 async fn process_tunnel_write<W: Write + Unpin>(
     key: Vec<u8>,
-    mut senders: SubSenders<TunnelMsg>,
-    receivers: Receivers<TunnelMsg>,
+    config: &TunnelConfig,
+    mut requests: Receiver<TunnelMsg>,
+    bus: &mut PortBus,
     port_hub: &mut PortHub,
     stream: &mut W,
 ) -> std::io::Result<()> {
@@ -400,26 +1721,87 @@ async fn process_tunnel_write<W: Write + Unpin>(
     let mut encryptor = Cryptor::new(&key);
 
     let duration = Duration::from_millis(HEARTBEAT_INTERVAL_MS);
-    let timer_stream = timer::interval(duration, TunnelMsg::Heartbeat);
-    let mut msg_stream = timer_stream.merge(receivers);
+    let mut timer_stream = timer::interval(duration, TunnelMsg::Heartbeat);
 
     stream.write_all(encryptor.ctr_as_slice()).await?;
+    let mut closing = false;
+    let (mut uploaded, mut downloaded) = (0u64, 0u64);
 
     loop {
-        match msg_stream.next().await {
+        // The receivers of the ports grow when they resume, see
+        // resume_ports.
+        let rest = (&mut timer_stream)
+            .merge(&mut requests)
+            .merge(&mut bus.receivers);
+        let msg = prioritized(&mut bus.priority, rest).next().await;
+        match msg {
             Some(TunnelMsg::Heartbeat) => {
                 let duration = Instant::now() - alive_time;
                 if duration.as_millis() > ALIVE_TIMEOUT_TIME_MS {
                     break;
                 }
+
+                if closing && port_hub.1.is_empty() && port_hub.8.is_empty() {
+                    info!("tunnel {} from {} closed", port_hub.0, port_hub.2);
+                    break;
+                }
+
+                for id in port_hub.reap(config.port_connect_timeout, config.port_idle_timeout) {
+                    stream.write_all(&pack_sc_close_port_msg(id)).await?;
+                }
+            }
+
+            // The client opens no more ports, and closes the tunnel once
+            // the open ones have finished.
+            Some(TunnelMsg::CSClosing) => {
+                alive_time = Instant::now();
+                closing = true;
+                info!(
+                    "tunnel {} from {} closing, {} ports open",
+                    port_hub.0,
+                    port_hub.2,
+                    port_hub.1.len()
+                );
+            }
+
+            Some(TunnelMsg::CSStats) => {
+                alive_time = Instant::now();
+                let stats = TunnelStats {
+                    open_ports: port_hub.1.len() as u64,
+                    uploaded,
+                    downloaded,
+                    queued_frames: port_hub.4.frames() as u64,
+                    queued_bytes: port_hub.4.queued() as u64,
+                };
+                let data = encryptor.encrypt(&stats.encode());
+                stream.write_all(&pack_sc_stats_msg(&data)).await?;
+            }
+
+            Some(TunnelMsg::CSResume(buf)) => {
+                alive_time = Instant::now();
+                resume_ports(&buf, bus, port_hub, &mut encryptor, stream).await?;
             }
 
             Some(TunnelMsg::CloseTunnel) => break,
 
             Some(msg) => {
+                match msg {
+                    TunnelMsg::SCData(_, ref buf) | TunnelMsg::SCStripedData(_, _, ref buf) => {
+                        port_hub.4.pop(buf.len());
+                        downloaded += buf.len() as u64;
+                    }
+                    TunnelMsg::CSData(cs::DATA, _, ref buf)
+                    | TunnelMsg::CSStripedData(_, _, ref buf) => {
+                        uploaded += buf.len() as u64;
+                    }
+                    _ => {}
+                }
+
                 process_tunnel_msg(
                     msg,
-                    &mut senders,
+                    config,
+                    &mut bus.senders,
+                    &bus.priority_sender,
                     &mut alive_time,
                     port_hub,
                     &mut encryptor,
@@ -435,9 +1817,81 @@ async fn process_tunnel_write<W: Write + Unpin>(
     Ok(())
 }
 
+// Takes over the ports of the session the client resumes, tells it where
+// they are and sends again what it didn't receive, see resume. The ports
+// the client no longer has, or that can't be where it says, are closed.
+async fn resume_ports<W: Write + Unpin>(
+    data: &[u8],
+    bus: &mut PortBus,
+    port_hub: &mut PortHub,
+    encryptor: &mut Cryptor,
+    stream: &mut W,
+) -> std::io::Result<()> {
+    let token = &data[..data.len().min(SESSION_TOKEN_SIZE)];
+    let offsets = resume::parse_offsets(&data[token.len()..]).unwrap_or_default();
+    let parked = if port_hub.1.is_empty() {
+        PARKED_TUNNELS.lock().unwrap().remove(token)
+    } else {
+        None
+    };
+
+    let mut resumed: Vec<PortOffsets> = Vec::new();
+    if let Some(parked) = parked {
+        port_hub.adopt(token.to_vec(), parked, bus);
+        let ids: Vec<u32> = port_hub.1.keys().cloned().collect();
+        for id in ids {
+            let peer = offsets.iter().find(|peer| peer.id == id);
+            let port = port_hub.1.get_mut(&id).unwrap();
+            let credit = peer.and_then(|peer| port.transfer.as_mut()?.resume(peer));
+            match (peer, credit) {
+                (Some(peer), Some(credit)) => {
+                    port.window.grant(credit);
+                    resumed.push(*peer);
+                }
+                _ => port_hub.remove_port(id, CloseReason::TunnelBroken),
+            }
+        }
+    }
+
+    let mut answer = port_hub.9.clone().unwrap_or_default();
+    let ports: Vec<PortOffsets> = resumed
+        .iter()
+        .filter_map(|peer| Some(port_hub.transfer(peer.id)?.offsets(peer.id)))
+        .collect();
+    answer.extend(resume::pack_offsets(&ports));
+    let data = encryptor.encrypt(&answer);
+    stream.write_all(&pack_sc_resumed_msg(&data)).await?;
+    info!(
+        "tunnel {} from {} resumed {} ports",
+        port_hub.0,
+        port_hub.2,
+        resumed.len()
+    );
+
+    for peer in resumed.iter() {
+        if let Some(transfer) = port_hub.transfer(peer.id) {
+            for buf in transfer.unacked() {
+                stream
+                    .write_all(&pack_sc_data_msg(peer.id, buf, encryptor))
+                    .await?;
+            }
+            if transfer.shutdown_lost(peer) {
+                stream
+                    .write_all(&pack_sc_shutdown_write_msg(peer.id))
+                    .await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn process_tunnel_msg<W: Write + Unpin>(
     msg: TunnelMsg,
+    config: &TunnelConfig,
     senders: &mut SubSenders<TunnelMsg>,
+    priority_sender: &Sender<TunnelMsg>,
     alive_time: &mut Instant,
     port_hub: &mut PortHub,
     encryptor: &mut Cryptor,
@@ -446,29 +1900,63 @@ async fn process_tunnel_msg<W: Write + Unpin>(
     match msg {
         TunnelMsg::CSHeartbeat => {
             *alive_time = Instant::now();
+            if is_draining() {
+                stream.write_all(&pack_sc_draining_msg()).await?;
+            }
             stream.write_all(&pack_sc_heartbeat_rsp_msg()).await?;
         }
 
         TunnelMsg::CSOpenPort(id) => {
             *alive_time = Instant::now();
+            if !take_open_budget(port_hub.2, config) {
+                stream.write_all(&pack_sc_close_port_msg(id)).await?;
+                return Ok(());
+            }
+
             let (tx, rx) = channel(1000);
-            port_hub.add_port(id, tx);
+            let window = Arc::new(PortWindow::new(port_hub.6.clone()));
+            port_hub.add_port(id, tx, window.clone());
 
             let sender = senders.get_one_sender();
 
             let read_port = TunnelReadPort {
                 id: id,
                 tx: sender.clone(),
+                priority_tx: priority_sender.clone(),
                 rx: Some(rx),
+                consumed: 0,
+                stripes: Vec::new(),
+                reassembly: None,
+                last: 0,
             };
 
             let write_port = TunnelWritePort {
                 id: id,
                 tx: sender.clone(),
+                priority_tx: priority_sender.clone(),
+                queue: port_hub.4.clone(),
+                window,
+                queue_limit: config.queue_limits.bulk,
+                stripes: Vec::new(),
+                seq: 0,
             };
 
+            let client = port_hub.2;
+            let idle_timeout = config.port_idle_timeout;
+            let connect_timeout = config.port_connect_timeout;
+            let queue_limits = config.queue_limits;
+            let hook = config.connect_hook.clone();
             task::spawn(async move {
-                tunnel_port_task(read_port, write_port).await;
+                tunnel_port_task(
+                    read_port,
+                    write_port,
+                    client,
+                    idle_timeout,
+                    connect_timeout,
+                    queue_limits,
+                    hook,
+                )
+                .await;
             });
         }
 
@@ -492,23 +1980,153 @@ async fn process_tunnel_msg<W: Write + Unpin>(
             port_hub.client_send_data(id, op, buf).await;
         }
 
+        TunnelMsg::CSHello(buf) => {
+            port_hub.set_client_version(peer_version(&buf));
+            if let Some(reason) = protocol_mismatch(peer_protocol(&buf)) {
+                error!(
+                    "tunnel {} from {} refused, {}",
+                    port_hub.0, port_hub.2, reason
+                );
+                let data = encryptor.encrypt(reason.as_bytes());
+                stream.write_all(&pack_sc_refused_msg(&data)).await?;
+                return Err(io::Error::new(io::ErrorKind::InvalidData, reason));
+            }
+
+            let window = peer_extension(&buf, EXTENSION_WINDOW);
+            port_hub.6.store(window, Ordering::Relaxed);
+            port_hub.7 = compress::available() && peer_extension(&buf, EXTENSION_LZ4);
+
+            let mut extensions = vec![
+                EXTENSION_WINDOW,
+                EXTENSION_BOND,
+                EXTENSION_CLOSING,
+                EXTENSION_STATS,
+                EXTENSION_RESUME,
+            ];
+            if compress::available() {
+                extensions.push(EXTENSION_LZ4);
+            }
+            if config.udp_relay {
+                extensions.push(EXTENSION_UDP);
+            }
+            let data = encryptor.encrypt(&hello_data(&extensions));
+            stream.write_all(&pack_sc_hello_msg(&data)).await?;
+
+            // The ports resume from the grants, see resume.
+            if window && peer_extension(&buf, EXTENSION_RESUME) {
+                let token = resume::new_token().to_vec();
+                let data = encryptor.encrypt(&token);
+                stream.write_all(&pack_sc_session_msg(&data)).await?;
+                port_hub.9 = Some(token);
+            }
+        }
+
+        TunnelMsg::CSWindow(id, bytes) => {
+            *alive_time = Instant::now();
+            port_hub.client_window(id, bytes);
+        }
+
+        TunnelMsg::CSOpenUdp(id) => {
+            *alive_time = Instant::now();
+            if !config.udp_relay || !take_open_budget(port_hub.2, config) {
+                stream.write_all(&pack_sc_close_udp_msg(id)).await?;
+                return Ok(());
+            }
+
+            let (tx, rx) = channel(1000);
+            port_hub.8.insert(id, tx);
+
+            let sender = senders.get_one_sender();
+            let client = port_hub.2;
+            let idle_timeout = config.port_idle_timeout;
+            let hook = config.connect_hook.clone();
+            task::spawn(async move {
+                udp_port_task(id, rx, sender, client, idle_timeout, hook).await;
+            });
+        }
+
+        TunnelMsg::CSJoinBond(id, buf) => {
+            *alive_time = Instant::now();
+            port_hub.join_bond(id);
+            port_hub
+                .try_send_msg(id, TunnelPortMsg::JoinBond(buf))
+                .await;
+        }
+
+        TunnelMsg::CSStripedData(id, seq, buf) => {
+            *alive_time = Instant::now();
+            port_hub.client_send_striped(id, seq, buf).await;
+        }
+
+        TunnelMsg::CSUdpData(id, buf) => {
+            *alive_time = Instant::now();
+            port_hub.client_send_udp(id, buf);
+        }
+
+        TunnelMsg::CSCloseUdp(id) => {
+            *alive_time = Instant::now();
+            port_hub.8.remove(&id);
+        }
+
+        TunnelMsg::SCStripedData(id, seq, buf) => {
+            port_hub.server_send_data(id, buf.len());
+            stream
+                .write_all(&pack_sc_striped_data_msg(id, seq, &buf, encryptor))
+                .await?;
+        }
+
+        TunnelMsg::SCUdpData(id, buf) => {
+            let data = encryptor.encrypt(&buf);
+            stream.write_all(&pack_sc_udp_data_msg(id, &data)).await?;
+        }
+
+        TunnelMsg::SCCloseUdp(id) if port_hub.8.remove(&id).is_some() => {
+            stream.write_all(&pack_sc_close_udp_msg(id)).await?;
+        }
+
         TunnelMsg::SCClosePort(id) => {
             port_hub.server_close_port(id);
             stream.write_all(&pack_sc_close_port_msg(id)).await?;
         }
 
         TunnelMsg::SCShutdownWrite(id) => {
+            if let Some(transfer) = port_hub.transfer(id) {
+                transfer.shut_down();
+            }
             stream.write_all(&pack_sc_shutdown_write_msg(id)).await?;
         }
 
         TunnelMsg::SCConnectOk(id, buf) => {
+            port_hub.connect_ok(id);
             let data = encryptor.encrypt(&buf);
             stream.write_all(&pack_sc_connect_ok_msg(id, &data)).await?;
         }
 
         TunnelMsg::SCData(id, buf) => {
-            let data = encryptor.encrypt(&buf);
-            stream.write_all(&pack_sc_data_msg(id, &data)).await?;
+            port_hub.server_send_data(id, buf.len());
+            if let Some(transfer) = port_hub.transfer(id) {
+                transfer.send(&buf);
+            }
+            match port_hub.7.then(|| compress::compress(&buf)).flatten() {
+                Some(compressed) => {
+                    stream
+                        .write_all(&pack_sc_data_lz4_msg(id, &compressed, encryptor))
+                        .await?;
+                }
+                None => {
+                    stream
+                        .write_all(&pack_sc_data_msg(id, &buf, encryptor))
+                        .await?;
+                }
+            }
+        }
+
+        // Clients from before would break the tunnel on it.
+        TunnelMsg::SCWindow(id, bytes) if port_hub.6.load(Ordering::Relaxed) => {
+            if let Some(transfer) = port_hub.transfer(id) {
+                transfer.grant(bytes);
+            }
+            stream.write_all(&pack_sc_window_msg(id, bytes)).await?;
         }
 
         TunnelMsg::TunnelPortHalfDrop(id) => {
@@ -520,3 +2138,245 @@ async fn process_tunnel_msg<W: Write + Unpin>(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use async_std::io::{ReadExt, WriteExt};
+    use async_std::net::TcpListener;
+
+    use super::*;
+    use crate::duplex::{duplex, PipeReader, PipeWriter};
+
+    const KEY: &[u8] = b"tunnel core test key";
+
+    // The core of a tunnel from a client on `stream`, as
+    // tcp_tunnel_core_task runs it over a connection.
+    fn tunnel(stream: (PipeReader, PipeWriter)) -> task::JoinHandle<()> {
+        task::spawn(async move {
+            let (mut reader, mut writer) = stream;
+            let (mut main_sender, requests) = channel(1000);
+            let mut bus = PortBus::new();
+            let client = IpAddr::from([127, 0, 0, 1]);
+            let config = TunnelConfig::default();
+            let mut port_hub = PortHub::new(client, TunnelFeatures::tcp());
+            let r = async {
+                let timeout = config.handshake_timeout;
+                let _ = process_tunnel_read(
+                    KEY.to_vec(),
+                    client,
+                    timeout,
+                    &mut main_sender,
+                    &mut reader,
+                )
+                .await;
+                let _ = main_sender.send(TunnelMsg::CloseTunnel).await;
+            };
+            let w = process_tunnel_write(
+                KEY.to_vec(),
+                &config,
+                requests,
+                &mut bus,
+                &mut port_hub,
+                &mut writer,
+            );
+            let _ = r.join(w).await;
+            park_ports(&mut port_hub, bus);
+        })
+    }
+
+    // The client end of a connection, writing and reading frames itself.
+    struct Client {
+        reader: PipeReader,
+        writer: PipeWriter,
+        decryptor: Cryptor,
+        encryptor: Cryptor,
+    }
+
+    impl Client {
+        async fn connect(stream: (PipeReader, PipeWriter)) -> Client {
+            Client::connect_with(stream, &[]).await
+        }
+
+        // Makes the handshake and exchanges HELLO with the server, asking
+        // for `extensions`.
+        async fn connect_with(stream: (PipeReader, PipeWriter), extensions: &[&str]) -> Client {
+            let (mut reader, mut writer) = stream;
+            let mut encryptor = Cryptor::new(KEY);
+            writer.write_all(encryptor.ctr_as_slice()).await.unwrap();
+            let verify = encryptor.encrypt(&VERIFY_DATA);
+            writer.write_all(&verify).await.unwrap();
+
+            let mut ctr = vec![0; CTR_SIZE];
+            reader.read_exact(&mut ctr).await.unwrap();
+            let decryptor = Cryptor::with_ctr(KEY, ctr);
+
+            let mut client = Client {
+                reader,
+                writer,
+                decryptor,
+                encryptor,
+            };
+            let hello = client.encryptor.encrypt(&hello_data(extensions));
+            client.send(&pack_cs_hello_msg(&hello)).await;
+            assert_eq!(client.frame().await.0, sc::HELLO);
+            client
+        }
+
+        async fn send(&mut self, frame: &[u8]) {
+            self.writer.write_all(frame).await.unwrap();
+        }
+
+        async fn send_data(&mut self, id: u32, data: &[u8]) {
+            let frame = pack_cs_data_msg(id, data, &mut self.encryptor);
+            self.send(&frame).await;
+        }
+
+        async fn send_encrypted(&mut self, pack: fn(&[u8]) -> Vec<u8>, data: &[u8]) {
+            let data = self.encryptor.encrypt(data);
+            self.send(&pack(&data)).await;
+        }
+
+        async fn u32(&mut self) -> u32 {
+            let mut buf = [0u8; 4];
+            self.reader.read_exact(&mut buf).await.unwrap();
+            u32::from_be_bytes(buf)
+        }
+
+        // The next frame of the server, its data decrypted, and the bytes
+        // granted as the data of a WINDOW.
+        async fn frame(&mut self) -> (u8, u32, Vec<u8>) {
+            let mut op = [0u8; 1];
+            self.reader.read_exact(&mut op).await.unwrap();
+            let id = self.u32().await;
+
+            let data = match op[0] {
+                sc::CONNECT_OK | sc::DATA | sc::HELLO | sc::SESSION | sc::RESUMED => {
+                    let mut data = vec![0; self.u32().await as usize];
+                    self.reader.read_exact(&mut data).await.unwrap();
+                    self.decryptor.decrypt(&data)
+                }
+                sc::WINDOW => self.u32().await.to_be_bytes().to_vec(),
+                _ => Vec::new(),
+            };
+            (op[0], id, data)
+        }
+    }
+
+    // A port connected to a destination, which is accepted on `listener`.
+    async fn connected_port(client: &mut Client, listener: &TcpListener) -> TcpStream {
+        client.send(&pack_cs_open_port_msg(1)).await;
+        let addr = listener.local_addr().unwrap().to_string();
+        let connect = client.encryptor.encrypt(addr.as_bytes());
+        client.send(&pack_cs_connect_msg(1, &connect)).await;
+
+        let (destination, _) = listener.accept().await.unwrap();
+        let (op, id, bound) = client.frame().await;
+        assert_eq!((op, id), (sc::CONNECT_OK, 1));
+        assert_eq!(
+            from_utf8(&bound).unwrap(),
+            destination.peer_addr().unwrap().to_string()
+        );
+        destination
+    }
+
+    // The session with port 1 at `received` bytes, as RESUME and RESUMED
+    // carry it.
+    fn resume_data(token: &[u8], received: u64) -> Vec<u8> {
+        let offsets = PortOffsets {
+            id: 1,
+            received,
+            granted: 0,
+            shut: false,
+        };
+        let mut data = token.to_vec();
+        data.extend(resume::pack_offsets(&[offsets]));
+        data
+    }
+
+    #[test]
+    fn port_lifecycle() {
+        task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let (client, server) = duplex(7);
+            let _tunnel = tunnel(server);
+            let mut client = Client::connect(client).await;
+            let mut destination = connected_port(&mut client, &listener).await;
+
+            client.send_data(1, b"request").await;
+            let mut request = [0u8; 7];
+            destination.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request, b"request");
+
+            destination.write_all(b"response").await.unwrap();
+            let (op, id, data) = client.frame().await;
+            assert_eq!((op, id, &data[..]), (sc::DATA, 1, &b"response"[..]));
+
+            client.send(&pack_cs_close_port_msg(1)).await;
+            let mut rest = Vec::new();
+            destination.read_to_end(&mut rest).await.unwrap();
+            assert!(rest.is_empty());
+        });
+    }
+
+    #[test]
+    fn port_resumes() {
+        task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let extensions = [EXTENSION_WINDOW, EXTENSION_RESUME];
+            let (stream, server) = duplex(7);
+            let broken = tunnel(server);
+            let mut client = Client::connect_with(stream, &extensions).await;
+            let (op, _, token) = client.frame().await;
+            assert_eq!((op, token.len()), (sc::SESSION, SESSION_TOKEN_SIZE));
+            let mut destination = connected_port(&mut client, &listener).await;
+
+            client.send_data(1, b"0123").await;
+            let mut request = [0u8; 4];
+            destination.read_exact(&mut request).await.unwrap();
+            destination.write_all(b"abcdef").await.unwrap();
+            let (op, _, data) = client.frame().await;
+            assert_eq!((op, &data[..]), (sc::DATA, &b"abcdef"[..]));
+
+            // The next data is on its way when the connection breaks.
+            destination.write_all(b"ghij").await.unwrap();
+            client.reader.read_exact(&mut [0u8; 1]).await.unwrap();
+            drop(client);
+            broken.await;
+
+            let (stream, server) = duplex(7);
+            let _tunnel = tunnel(server);
+            client = Client::connect_with(stream, &extensions).await;
+            assert_eq!(client.frame().await.0, sc::SESSION);
+            let resume = resume_data(&token, 6);
+            client.send_encrypted(pack_cs_resume_msg, &resume).await;
+
+            let resumed = resume_data(&token, 4);
+            assert_eq!(client.frame().await, (sc::RESUMED, 0, resumed));
+            assert_eq!(client.frame().await, (sc::DATA, 1, b"ghij".to_vec()));
+
+            client.send_data(1, b"4567").await;
+            destination.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request, b"4567");
+        });
+    }
+
+    #[test]
+    fn read_broken_mid_frame() {
+        task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let (client, server) = duplex(3);
+            let tunnel = tunnel(server);
+            let mut client = Client::connect(client).await;
+            let mut destination = connected_port(&mut client, &listener).await;
+
+            // The header and half of the data get through, none of it
+            // reaches the destination, which is closed with the tunnel.
+            client.writer.break_after(9 + 5);
+            client.send_data(1, b"0123456789").await;
+            tunnel.await;
+            let mut rest = Vec::new();
+            destination.read_to_end(&mut rest).await.unwrap();
+            assert!(rest.is_empty());
+        });
+    }
+}
@@ -0,0 +1,37 @@
+// A graceful shutdown, asked for by SIGINT, SIGTERM or the admin
+// command. The client stops taking connections and lets the open ports
+// finish before it exits, a second signal exits at once.
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+pub fn request() {
+    if !REQUESTED.swap(true, Ordering::Relaxed) {
+        info!("shutdown requested");
+    }
+}
+
+pub fn is_requested() -> bool {
+    REQUESTED.load(Ordering::Relaxed)
+}
+
+#[cfg(all(feature = "signals", unix))]
+extern "C" fn on_signal(_: libc::c_int) {
+    // Only what is safe in a signal handler.
+    if REQUESTED.swap(true, Ordering::Relaxed) {
+        unsafe { libc::_exit(1) };
+    }
+}
+
+#[cfg(all(feature = "signals", unix))]
+pub fn handle_signals() {
+    let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+// Signals keep ending the process at once.
+#[cfg(not(all(feature = "signals", unix)))]
+pub fn handle_signals() {}
@@ -2,6 +2,8 @@ use async_std::net::TcpStream;
 use async_std::prelude::*;
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 
+use super::error::{Error, Result};
+
 const VER: u8 = 5;
 const RSV: u8 = 0;
 
@@ -19,77 +21,92 @@ const REP_FAILURE: u8 = 1;
 pub enum Destination {
     Address(SocketAddr),
     DomainName(Vec<u8>, u16),
-    Unknown,
 }
 
-pub async fn handshake(stream: &mut TcpStream) -> std::io::Result<Destination> {
+pub async fn handshake(stream: &mut TcpStream) -> Result<Destination> {
     let mut buf = [0u8; 2];
-    stream.read_exact(&mut buf).await?;
+    stream.read_exact(&mut buf).await.map_err(handshake_error)?;
 
     if buf[0] != VER {
-        choose_method(stream, METHOD_NO_ACCEPT).await?;
-        return Ok(Destination::Unknown);
+        choose_method(stream, METHOD_NO_ACCEPT)
+            .await
+            .map_err(handshake_error)?;
+        return Err(Error::Socks5("unsupported version"));
     }
 
     let mut methods = vec![0; buf[1] as usize];
-    stream.read_exact(&mut methods).await?;
+    stream
+        .read_exact(&mut methods)
+        .await
+        .map_err(handshake_error)?;
 
     if !methods.into_iter().any(|method| method == METHOD_NO_AUTH) {
-        choose_method(stream, METHOD_NO_ACCEPT).await?;
-        return Ok(Destination::Unknown);
+        choose_method(stream, METHOD_NO_ACCEPT)
+            .await
+            .map_err(handshake_error)?;
+        return Err(Error::Socks5("authentication required"));
     }
 
-    choose_method(stream, METHOD_NO_AUTH).await?;
+    choose_method(stream, METHOD_NO_AUTH)
+        .await
+        .map_err(handshake_error)?;
 
     let mut buf = [0u8; 4];
-    stream.read_exact(&mut buf).await?;
+    stream.read_exact(&mut buf).await.map_err(handshake_error)?;
 
     if buf[1] != CMD_CONNECT {
-        return Ok(Destination::Unknown);
+        return Err(Error::Socks5("unsupported command"));
     }
 
     let destination = match buf[3] {
         ATYP_IPV4 => {
             let mut ipv4_addr = [0u8; 6];
-            stream.read_exact(&mut ipv4_addr).await?;
+            stream
+                .read_exact(&mut ipv4_addr)
+                .await
+                .map_err(handshake_error)?;
 
-            let port = unsafe { *(ipv4_addr.as_ptr().offset(4) as *const u16) };
             Destination::Address(SocketAddr::V4(SocketAddrV4::new(
-                Ipv4Addr::new(ipv4_addr[3], ipv4_addr[2], ipv4_addr[1], ipv4_addr[0]),
-                u16::from_be(port),
+                Ipv4Addr::new(ipv4_addr[0], ipv4_addr[1], ipv4_addr[2], ipv4_addr[3]),
+                u16::from_be_bytes([ipv4_addr[4], ipv4_addr[5]]),
             )))
         }
 
         ATYP_DOMAINNAME => {
             let mut len = [0u8; 1];
-            stream.read_exact(&mut len).await?;
+            stream.read_exact(&mut len).await.map_err(handshake_error)?;
 
             let len = len[0] as usize;
             let mut buf = vec![0u8; len + 2];
-            stream.read_exact(&mut buf).await?;
+            stream.read_exact(&mut buf).await.map_err(handshake_error)?;
 
-            let port = unsafe { *(buf.as_ptr().offset(len as isize) as *const u16) };
+            let port = u16::from_be_bytes([buf[len], buf[len + 1]]);
             buf.truncate(len);
-            Destination::DomainName(buf, u16::from_be(port))
+            Destination::DomainName(buf, port)
         }
 
-        ATYP_IPV6 => Destination::Unknown,
-        _ => Destination::Unknown,
+        ATYP_IPV6 => return Err(Error::Socks5("ipv6 destination")),
+        _ => return Err(Error::Socks5("unknown address type")),
     };
 
     Ok(destination)
 }
 
-pub async fn destination_unreached(stream: &mut TcpStream) -> std::io::Result<()> {
+pub async fn destination_unreached(stream: &mut TcpStream) -> Result<()> {
     let bind_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
-    destination_result(stream, bind_addr, REP_FAILURE).await
+    destination_result(stream, bind_addr, REP_FAILURE)
+        .await
+        .map_err(|e| Error::io("socks5 reply", e))
 }
 
-pub async fn destination_connected(
-    stream: &mut TcpStream,
-    bind_addr: SocketAddr,
-) -> std::io::Result<()> {
-    destination_result(stream, bind_addr, REP_SUCCESS).await
+pub async fn destination_connected(stream: &mut TcpStream, bind_addr: SocketAddr) -> Result<()> {
+    destination_result(stream, bind_addr, REP_SUCCESS)
+        .await
+        .map_err(|e| Error::io("socks5 reply", e))
+}
+
+fn handshake_error(e: std::io::Error) -> Error {
+    Error::io("socks5 handshake", e)
 }
 
 async fn choose_method(stream: &mut TcpStream, method: u8) -> std::io::Result<()> {
@@ -110,10 +127,8 @@ async fn destination_result(
             buf[1] = rsp;
             buf[2] = RSV;
             buf[3] = ATYP_IPV4;
-            unsafe {
-                *(buf.as_ptr().offset(4) as *mut u32) = u32::from(ipv4.ip().clone()).to_be();
-                *(buf.as_ptr().offset(8) as *mut u16) = ipv4.port().to_be();
-            }
+            buf[4..8].copy_from_slice(&ipv4.ip().octets());
+            buf[8..].copy_from_slice(&ipv4.port().to_be_bytes());
 
             stream.write_all(&buf).await?
         }
@@ -125,10 +140,8 @@ async fn destination_result(
             buf[1] = rsp;
             buf[2] = RSV;
             buf[3] = ATYP_IPV6;
-            unsafe {
-                *(buf.as_ptr().offset(4) as *mut u128) = u128::from(ipv6.ip().clone()).to_be();
-                *(buf.as_ptr().offset(20) as *mut u16) = ipv6.port().to_be();
-            }
+            buf[4..20].copy_from_slice(&ipv6.ip().octets());
+            buf[20..].copy_from_slice(&ipv6.port().to_be_bytes());
 
             stream.write_all(&buf).await?
         }
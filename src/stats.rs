@@ -0,0 +1,57 @@
+// What a tunnel is doing as a whole, as one end sees it. Clients ask
+// servers that announce protocol::EXTENSION_STATS for their view with
+// each heartbeat, see client::Tunnel::remote_stats.
+
+// Counting the payload of data frames towards the destination as
+// uploaded and from it as downloaded, over the connection, and the data
+// frames the ports queued that the connection didn't take yet.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TunnelStats {
+    pub open_ports: u64,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub queued_frames: u64,
+    pub queued_bytes: u64,
+}
+
+const FIELDS: usize = 5;
+
+impl TunnelStats {
+    fn fields(&self) -> [u64; FIELDS] {
+        [
+            self.open_ports,
+            self.uploaded,
+            self.downloaded,
+            self.queued_frames,
+            self.queued_bytes,
+        ]
+    }
+
+    // Big-endian u64s in the order of the fields. Fields added later go
+    // at the end, and ends from before ignore them.
+    pub fn encode(&self) -> Vec<u8> {
+        self.fields()
+            .iter()
+            .flat_map(|field| field.to_be_bytes())
+            .collect()
+    }
+
+    pub fn decode(data: &[u8]) -> Option<TunnelStats> {
+        if data.len() < FIELDS * 8 {
+            return None;
+        }
+
+        let field = |i: usize| {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&data[i * 8..i * 8 + 8]);
+            u64::from_be_bytes(bytes)
+        };
+        Some(TunnelStats {
+            open_ports: field(0),
+            uploaded: field(1),
+            downloaded: field(2),
+            queued_frames: field(3),
+            queued_bytes: field(4),
+        })
+    }
+}
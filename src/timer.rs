@@ -1,5 +1,6 @@
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::task::{Context, Poll};
 use std::time::{Duration, Instant};
 
@@ -59,3 +60,45 @@ fn next_interval(prev: Instant, now: Instant, interval: Duration) -> Instant {
     );
     prev + interval * (mult as u32)
 }
+
+// The idle deadline of a port, fed by both of its directions: the port
+// is idle once neither moved data for the period, so a download isn't
+// cut for the lack of uploads. A single write stalled for the period
+// fails the port whatever the other direction does.
+pub struct Watchdog {
+    period: Duration,
+    start: Instant,
+    active: AtomicU64,
+}
+
+impl Watchdog {
+    pub fn new(period: Duration) -> Watchdog {
+        Watchdog {
+            period,
+            start: Instant::now(),
+            active: AtomicU64::new(0),
+        }
+    }
+
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    pub fn feed(&self) {
+        let elapsed = (Instant::now() - self.start).as_millis() as u64;
+        self.active.store(elapsed, Ordering::Relaxed);
+    }
+
+    // Time since the last feed.
+    pub fn idle(&self) -> Duration {
+        let elapsed = (Instant::now() - self.start).as_millis() as u64;
+        let active = self.active.load(Ordering::Relaxed);
+        Duration::from_millis(elapsed - active)
+    }
+
+    pub fn expired(&self) -> bool {
+        let elapsed = (Instant::now() - self.start).as_millis() as u64;
+        let active = self.active.load(Ordering::Relaxed);
+        elapsed - active >= self.period.as_millis() as u64
+    }
+}
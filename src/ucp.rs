@@ -1,42 +1,185 @@
+use async_std::future;
 use async_std::io::{self, Read, Write};
-use async_std::net::UdpSocket;
+use async_std::net::{ToSocketAddrs, UdpSocket};
 use async_std::task;
 use crc::crc32;
 use crossbeam_utils::Backoff;
+use futures::channel::mpsc::{self, Receiver, Sender};
+use futures::StreamExt;
 use rand::random;
 use std::cell::Cell;
 use std::cmp::min;
-use std::collections::{HashMap, VecDeque};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, VecDeque};
 use std::io::{Error, ErrorKind};
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::str::FromStr;
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll, Waker};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 use std::vec::Vec;
 
+use super::error;
+
+use self::auth::{AuthKey, ReplayWindow, SynGuard, AUTH_TRAILER_SIZE};
+use self::batch::{Datagram, MAX_BATCH};
+use self::budget::RecvBudget;
+use self::channel::{
+    Channel, ChannelMap, Control, CHANNEL_HEADER_SIZE, CONTROL_CHANNEL, CONTROL_MESSAGE_SIZE,
+    MAX_CHANNELS,
+};
+use self::cipher::PacketCipher;
+use self::congestion::{CongestionAlgorithm, CongestionControl};
+use self::cookie::{CookieJar, COOKIE_SIZE};
+use self::fec::{FecCache, FecGroup, FEC_HEADER_SIZE, MAX_FEC_GROUP};
+use self::pool::PacketBuf;
+use self::rendezvous::Role;
+use self::sim::SimNetwork;
+use self::ticket::{Grant, TicketBook, TICKET_LIFETIME_SECS, TICKET_SIZE};
+use self::trace::{Direction, PacketRecord, PacketTracer};
+use self::transport::Transport;
+use self::wire::{Reader, Writer};
+
+mod auth;
+mod batch;
+mod budget;
+mod channel;
+mod cipher;
+pub mod congestion;
+mod cookie;
+mod ecn;
+mod fec;
+mod pool;
+pub mod rendezvous;
+mod serial;
+pub mod sim;
+mod sockopt;
+mod ticket;
+pub mod trace;
+mod transport;
+mod wire;
+
 const CMD_SYN: u8 = 128;
 const CMD_SYN_ACK: u8 = 129;
 const CMD_ACK: u8 = 130;
 const CMD_DATA: u8 = 131;
 const CMD_HEARTBEAT: u8 = 132;
 const CMD_HEARTBEAT_ACK: u8 = 133;
+const CMD_SACK: u8 = 134;
+const CMD_FRAG: u8 = 135;
+const CMD_FEC: u8 = 136;
+const CMD_MTU_PROBE: u8 = 137;
+const CMD_MTU_PROBE_ACK: u8 = 138;
+const CMD_FIN: u8 = 139;
+const CMD_FIN_ACK: u8 = 140;
+const CMD_PATH_CHALLENGE: u8 = 141;
+const CMD_PATH_RESPONSE: u8 = 142;
+const CMD_UDATA: u8 = 143;
+const CMD_PROBE: u8 = 144;
+const CMD_SYN_COOKIE: u8 = 145;
+const CMD_RESET: u8 = 146;
 const UCP_PACKET_META_SIZE: usize = 29;
+// Offset of cmd, which stays readable in handshake packets
+const UCP_PACKET_CMD_OFFSET: usize = 28;
 const DEFAULT_WINDOW: u32 = 512;
+const DEFAULT_RECV_BUFFER: usize = 4 * 1024 * 1024;
+// Memory of received packets a stream, and all streams of a listener,
+// may hold before packets above a gap are dropped.
+const DEFAULT_RECV_MEMORY: usize = 16 * 1024 * 1024;
+const DEFAULT_MAX_RECV_MEMORY: usize = 256 * 1024 * 1024;
+// Sessions a listener keeps at most, and of them those still in the
+// handshake before SYNs are answered with a cookie.
+const DEFAULT_MAX_SESSIONS: usize = 4096;
+const DEFAULT_SYN_BACKLOG: usize = 256;
+const DEFAULT_SYN_TIMEOUT: u32 = 5000;
+const DEFAULT_SYN_MAX_AGE: u32 = 120;
+const WORKER_QUEUE_SIZE: usize = 1024;
+const MIN_WINDOW: u32 = 1;
 const DEFAULT_RTO: u32 = 100;
-const HEARTBEAT_INTERVAL_MILLIS: u128 = 2500;
-const UCP_STREAM_BROKEN_MILLIS: u128 = 20000;
-const SKIP_RESEND_TIMES: u32 = 2;
+const DEFAULT_MIN_RTO: u32 = 30;
+const DEFAULT_MAX_RTO: u32 = 10000;
+// Resolution of the output loop
+const CLOCK_GRANULARITY: u32 = 10;
+const DEFAULT_HEARTBEAT_INTERVAL: u32 = 2500;
+// How late the server's heartbeat may be before an adaptive client takes
+// the NAT to have dropped the mapping, on top of the RTO, and how much
+// the interval grows each time one arrived in time.
+const NAT_PROBE_MARGIN_MILLIS: u32 = 1000;
+const NAT_PROBE_GROWTH: f64 = 1.5;
+const DEFAULT_BROKEN_TIMEOUT: u32 = 20000;
+const DEFAULT_FAST_RESEND_THRESHOLD: u32 = 3;
+const DEFAULT_MAX_XMIT: u32 = 0;
+// Doublings of the RTO a packet that keeps timing out waits at most.
+const MAX_PACKET_BACKOFF: u32 = 16;
+const UCP_CLOSE_TIMEOUT_MILLIS: u128 = 5000;
+// Resets a listener sends a second at most, and how far behind the
+// stream's counter the counter a reset echoes may be.
+const MAX_RESETS_PER_SEC: u32 = 64;
+const RESET_COUNTER_WINDOW: u32 = 65536;
+// How long a connect to a host name waits for the handshake with one of
+// its addresses before also trying the next, as RFC 8305 suggests.
+const HAPPY_EYEBALLS_DELAY_MILLIS: u64 = 250;
+// Output rounds are at least a tick apart so writes and acks batch, and an
+// idle stream waits up to the longer tick unless something wakes it.
+const OUTPUT_TICK_MILLIS: u64 = 10;
+const IDLE_OUTPUT_TICK_MILLIS: u64 = 100;
+const MAX_SACK_BLOCKS: usize = 32;
+const MIN_STALE_TIMERS: usize = 64;
+const DEFAULT_ACK_EVERY: u32 = 1;
+const DEFAULT_ACK_DELAY: u32 = 0;
+const UCP_PACKET_SIZE_STEPS: [usize; 5] = [1400, 1200, 1000, 800, 576];
+const UCP_MAX_PACKET_SIZE: usize = 9000;
+const BLACKHOLE_TIMEOUTS: u32 = 4;
+const PMTU_PROBE_ATTEMPTS: u32 = 3;
+const PMTU_SEARCH_PRECISION: usize = 16;
+const PMTU_RAISE_INTERVAL_MILLIS: u128 = 600000;
+const DEFAULT_PACING_GAIN: f64 = 2.0;
+const PACING_MIN_BURST: f64 = 4.0;
+const PACING_MAX_BURST_MILLIS: f64 = 20.0;
+const RATE_LIMIT_BURST_MILLIS: f64 = 50.0;
+// Version of the wire protocol, raised with every change a peer has to
+// know about. It takes the top byte of the features word, which peers
+// from before mask off, so they count as version 0.
+pub const PROTOCOL_VERSION: u32 = 4;
+const VERSION_SHIFT: u32 = 24;
+// Clients from this version start the counters of their datagrams at
+// their clock, see auth::SynGuard.
+const DATED_SYN_VERSION: u32 = 4;
+// Bits of the features word in SYN and SYN_ACK
+const FEATURE_CHANNELS: u32 = 1;
+const FEATURE_DATAGRAMS: u32 = 2;
+const FEATURE_RESUME: u32 = 4;
+const FEATURE_PROBES: u32 = 8;
+const FEATURE_KEYED_CHECK: u32 = 16;
+const FEATURE_LOSS_REPORTS: u32 = 32;
+const SUPPORTED_FEATURES: u32 = FEATURE_CHANNELS
+    | FEATURE_DATAGRAMS
+    | FEATURE_RESUME
+    | FEATURE_PROBES
+    | FEATURE_KEYED_CHECK
+    | FEATURE_LOSS_REPORTS;
+// Datagrams waiting to be sent or read, the oldest gives way.
+const MAX_QUEUED_DATAGRAMS: usize = 256;
+
+const PROBE_INTERVAL_MILLIS: u128 = 1000;
+const PROBE_BASE_DELAY_EXPIRE: u32 = 30000;
 
 #[derive(Clone)]
 struct UcpPacket {
-    buf: [u8; 1400],
+    buf: PacketBuf,
     size: usize,
     payload: u16,
     read_pos: usize,
-    skip_times: u32,
+    dup_acks: u32,
+    fast_resent: bool,
+    timeouts: u32,
+    auth_counter: u32,
+    // Arrived with congestion experienced, and with a frame check
+    // verified under the session's key, not part of the packet.
+    ce: bool,
+    checked: bool,
 
     session_id: u32,
     timestamp: u32,
@@ -48,13 +191,23 @@ struct UcpPacket {
 }
 
 impl UcpPacket {
+    // Large enough for any datagram, see shrink.
     fn new() -> UcpPacket {
+        UcpPacket::with_size(UCP_MAX_PACKET_SIZE)
+    }
+
+    fn with_size(size: usize) -> UcpPacket {
         UcpPacket {
-            buf: [0; 1400],
+            buf: PacketBuf::new(size),
             size: 0,
             payload: 0,
             read_pos: 0,
-            skip_times: 0,
+            dup_acks: 0,
+            fast_resent: false,
+            timeouts: 0,
+            auth_counter: 0,
+            ce: false,
+            checked: false,
             session_id: 0,
             timestamp: 0,
             window: 0,
@@ -65,6 +218,24 @@ impl UcpPacket {
         }
     }
 
+    // Checks and strips the authentication trailer, which has to happen
+    // before parse.
+    fn unseal(&mut self, auth_key: Option<&AuthKey>) -> bool {
+        let auth_key = match auth_key {
+            Some(auth_key) => auth_key,
+            None => return true,
+        };
+
+        match auth_key.open(&self.buf[..self.size]) {
+            Some(counter) => {
+                self.auth_counter = counter;
+                self.size -= AUTH_TRAILER_SIZE;
+                true
+            }
+            None => false,
+        }
+    }
+
     fn parse(&mut self) -> bool {
         if !self.is_legal() {
             return false;
@@ -73,81 +244,81 @@ impl UcpPacket {
         self.payload = (self.size - UCP_PACKET_META_SIZE) as u16;
         self.read_pos = UCP_PACKET_META_SIZE;
 
-        let mut offset = 4;
-        self.session_id = self.parse_u32(&mut offset);
-        self.timestamp = self.parse_u32(&mut offset);
-        self.window = self.parse_u32(&mut offset);
-        self.xmit = self.parse_u32(&mut offset);
-        self.una = self.parse_u32(&mut offset);
-        self.seq = self.parse_u32(&mut offset);
-        self.cmd = self.parse_u8(&mut offset);
-
-        self.cmd >= CMD_SYN && self.cmd <= CMD_HEARTBEAT_ACK
-    }
-
-    fn pack(&mut self) {
-        let mut offset = 4;
-        let session_id = self.session_id;
-        let timestamp = self.timestamp;
-        let window = self.window;
-        let xmit = self.xmit;
-        let una = self.una;
-        let seq = self.seq;
-        let cmd = self.cmd;
-
-        self.write_u32(&mut offset, session_id);
-        self.write_u32(&mut offset, timestamp);
-        self.write_u32(&mut offset, window);
-        self.write_u32(&mut offset, xmit);
-        self.write_u32(&mut offset, una);
-        self.write_u32(&mut offset, seq);
-        self.write_u8(&mut offset, cmd);
-
-        offset = 0;
-        self.size = self.payload as usize + UCP_PACKET_META_SIZE;
-
-        let digest = crc32::checksum_ieee(&self.buf[4..self.size]);
-        self.write_u32(&mut offset, digest);
+        self.parse_header().is_some() && self.cmd >= CMD_SYN && self.cmd <= CMD_RESET
     }
 
-    fn packed_buffer(&self) -> &[u8] {
-        &self.buf[..self.size]
+    fn parse_header(&mut self) -> Option<()> {
+        let mut header = Reader::new(&self.buf[4..self.size]);
+        let session_id = header.u32()?;
+        let timestamp = header.u32()?;
+        let window = header.u32()?;
+        let xmit = header.u32()?;
+        let una = header.u32()?;
+        let seq = header.u32()?;
+        let cmd = header.u8()?;
+
+        self.session_id = session_id;
+        self.timestamp = timestamp;
+        self.window = window;
+        self.xmit = xmit;
+        self.una = una;
+        self.seq = seq;
+        self.cmd = cmd;
+        Some(())
     }
 
-    fn parse_u32(&self, offset: &mut isize) -> u32 {
-        let u = unsafe { *(self.buf.as_ptr().offset(*offset) as *const u32) };
+    // The frame check is a CRC32, or a keyed check once the session
+    // negotiated one.
+    fn pack(&mut self, check_key: Option<&AuthKey>) {
+        let mut header = Writer::new(&mut self.buf[4..UCP_PACKET_META_SIZE]);
+        header.u32(self.session_id);
+        header.u32(self.timestamp);
+        header.u32(self.window);
+        header.u32(self.xmit);
+        header.u32(self.una);
+        header.u32(self.seq);
+        header.u8(self.cmd);
 
-        *offset += 4;
-        u32::from_be(u)
-    }
+        self.size = self.payload as usize + UCP_PACKET_META_SIZE;
 
-    fn parse_u8(&self, offset: &mut isize) -> u8 {
-        let u = self.buf[*offset as usize];
-        *offset += 1;
-        u
+        let data = &self.buf[4..self.size];
+        let digest = match check_key {
+            Some(key) => key.check(data),
+            None => crc32::checksum_ieee(data),
+        };
+        Writer::new(&mut self.buf[..4]).u32(digest);
     }
 
-    fn write_u32(&mut self, offset: &mut isize, u: u32) {
-        unsafe {
-            *(self.buf.as_ptr().offset(*offset) as *mut u32) = u.to_be();
-        }
+    // Bytes the packet takes while queued.
+    fn memory(&self) -> usize {
+        std::mem::size_of::<UcpPacket>() + self.buf.capacity()
+    }
 
-        *offset += 4;
+    // Releases the room a received datagram didn't use.
+    fn shrink(&mut self) {
+        self.buf.fit(self.size);
     }
 
-    fn write_u8(&mut self, offset: &mut isize, u: u8) {
-        self.buf[*offset as usize] = u;
-        *offset += 1;
+    fn packed_buffer(&self) -> &[u8] {
+        &self.buf[..self.size]
     }
 
     fn is_legal(&self) -> bool {
-        self.size >= UCP_PACKET_META_SIZE && self.is_crc32_correct()
+        self.size >= UCP_PACKET_META_SIZE && (self.checked || self.is_crc32_correct())
+    }
+
+    fn is_keyed_check_correct(&self, key: &AuthKey) -> bool {
+        let digest = Reader::new(&self.buf[..self.size]).u32();
+        digest == Some(key.check(&self.buf[4..self.size]))
     }
 
     fn is_crc32_correct(&self) -> bool {
-        let mut offset = 0;
-        let digest = self.parse_u32(&mut offset);
-        crc32::checksum_ieee(&self.buf[4..self.size]) == digest
+        let digest = Reader::new(&self.buf[..self.size]).u32();
+        digest == Some(crc32::checksum_ieee(&self.buf[4..self.size]))
+    }
+
+    fn packet_size(&self) -> usize {
+        self.payload as usize + UCP_PACKET_META_SIZE
     }
 
     fn is_syn(&self) -> bool {
@@ -158,46 +329,49 @@ impl UcpPacket {
         self.buf.len() - self.payload as usize - UCP_PACKET_META_SIZE
     }
 
-    fn payload_offset(&self) -> isize {
-        (self.payload as usize + UCP_PACKET_META_SIZE) as isize
+    fn payload_offset(&self) -> usize {
+        self.payload as usize + UCP_PACKET_META_SIZE
+    }
+
+    // The payload written so far, also before the packet is packed.
+    fn payload_data(&self) -> &[u8] {
+        &self.buf[UCP_PACKET_META_SIZE..self.payload_offset()]
     }
 
     fn payload_write_u32(&mut self, u: u32) -> bool {
-        if self.remaining_load() >= 4 {
-            let mut offset = self.payload_offset();
-            self.write_u32(&mut offset, u);
-            self.payload += 4;
-            true
-        } else {
-            false
-        }
+        self.payload_write_slice(&u.to_be_bytes())
     }
 
     fn payload_write_slice(&mut self, buf: &[u8]) -> bool {
-        if self.remaining_load() >= buf.len() {
-            let offset = self.payload_offset() as usize;
-            let end = offset + buf.len();
-            self.buf[offset..end].copy_from_slice(buf);
+        let offset = self.payload_offset();
+        let written = Writer::new(&mut self.buf[offset..]).bytes(buf);
+        if written {
             self.payload += buf.len() as u16;
-            true
-        } else {
-            false
         }
+        written
     }
 
     fn payload_remaining(&self) -> usize {
         self.size - self.read_pos
     }
 
-    fn payload_read_u32(&mut self) -> u32 {
-        if self.read_pos + 4 > self.size {
-            panic!("Out of range when read u32 from {}", self.read_pos);
-        }
+    // Callers check the payload size first, a read past the end of a
+    // malformed packet gives 0 rather than failing.
+    fn payload_read<T: Default>(&mut self, read: fn(&mut Reader) -> Option<T>) -> T {
+        let payload = self.buf.get(self.read_pos..self.size).unwrap_or(&[]);
+        let mut reader = Reader::new(payload);
+        let value = read(&mut reader);
+
+        self.read_pos += reader.position();
+        value.unwrap_or_default()
+    }
+
+    fn payload_read_u16(&mut self) -> u16 {
+        self.payload_read(|reader| reader.u16())
+    }
 
-        let mut offset = self.read_pos as isize;
-        let u = self.parse_u32(&mut offset);
-        self.read_pos = offset as usize;
-        u
+    fn payload_read_u32(&mut self) -> u32 {
+        self.payload_read(|reader| reader.u32())
     }
 
     fn payload_read_slice(&mut self, buf: &mut [u8]) -> usize {
@@ -214,41 +388,489 @@ impl UcpPacket {
 }
 
 type UcpPacketQueue = VecDeque<Box<UcpPacket>>;
+#[derive(Clone, Copy)]
+struct MtuProbe {
+    size: usize,
+    timestamp: u32,
+    attempts: u32,
+}
+
+// Token sent to a new address of the peer, the session moves there once
+// it is echoed back from that address.
+#[derive(Clone, Copy)]
+struct PathChallenge {
+    addr: SocketAddr,
+    token: u32,
+    time: Instant,
+}
+
+// What a client learned of how long its NAT keeps the session's mapping
+// while idle, see UcpConfig::adaptive_heartbeat. The server is asked to
+// heartbeat after `interval` of idle time, 0 until the handshake agreed
+// on a base interval.
+#[derive(Clone, Copy, Default)]
+struct NatProbe {
+    interval: u32,
+    // The longest interval the server's heartbeat arrived after.
+    held: u32,
+    // The search ended, by reaching the limit or by a late heartbeat.
+    settled: bool,
+    // The server has been told the interval.
+    told: bool,
+}
+
+// Intervals NATs held the mapping for, by server, so the next session
+// to the server starts with what the last one learned.
+static NAT_INTERVALS: Mutex<BTreeMap<SocketAddr, u32>> = Mutex::new(BTreeMap::new());
+
+// Resend deadlines in stream milliseconds of sent packets by seq, with
+// the transmission they were set for, see resend_packets.
+type ResendTimers = BinaryHeap<Reverse<(u64, u32, u32)>>;
+
+// Partly received data by seq, with the byte ranges received so far
+type FragmentMap = HashMap<u32, (Vec<u8>, Vec<(usize, usize)>)>;
+
+#[derive(Clone, Copy, Default)]
+pub struct UcpStats {
+    pub sent_packets: u64,
+    pub resent_packets: u64,
+    pub lost_packets: u64,
+    pub fast_resent_packets: u64,
+    pub fec_recovered_packets: u64,
+    // Ack packets sent, and output rounds whose resends took the pacing
+    // budget while new data waited. Each round resends first, then acks,
+    // then sends new data with what is left of the budget.
+    pub ack_packets: u64,
+    pub crowded_rounds: u64,
+    // Congestion experienced marks the peer echoed back.
+    pub ecn_marks: u64,
+    pub rto: u32,
+    pub srtt: u32,
+    pub cwnd: u32,
+    pub packet_size: usize,
+    // From the probes of the peer, when negotiated: bytes per second of
+    // our packets reaching it, and how many milliseconds they queue on
+    // the way beyond the shortest one-way delay seen. The last is the
+    // same for the peer's packets reaching us.
+    pub delivery_rate: u64,
+    pub queue_delay: u32,
+    pub recv_queue_delay: u32,
+    // Packets dropped unacked as they didn't fit the memory budgets.
+    pub recv_dropped_packets: u64,
+    // With loss reports negotiated: packets missing below the highest
+    // one the peer received from us, loss on the way there, and the same
+    // for the peer's packets reaching us, loss on the way back.
+    pub peer_recv_holes: u32,
+    pub recv_holes: u32,
+}
+
+impl UcpStats {
+    // Permille of sent data packets which needed at least one resend
+    pub fn loss_rate(&self) -> u32 {
+        (self.lost_packets * 1000 / self.sent_packets.max(1)) as u32
+    }
+
+    // Permille of resends relative to first transmissions
+    pub fn retransmission_ratio(&self) -> u32 {
+        (self.resent_packets * 1000 / self.sent_packets.max(1)) as u32
+    }
+
+    pub fn since(&self, earlier: &UcpStats) -> UcpStats {
+        UcpStats {
+            sent_packets: self.sent_packets - earlier.sent_packets,
+            resent_packets: self.resent_packets - earlier.resent_packets,
+            lost_packets: self.lost_packets - earlier.lost_packets,
+            fast_resent_packets: self.fast_resent_packets - earlier.fast_resent_packets,
+            fec_recovered_packets: self.fec_recovered_packets - earlier.fec_recovered_packets,
+            ack_packets: self.ack_packets - earlier.ack_packets,
+            crowded_rounds: self.crowded_rounds - earlier.crowded_rounds,
+            ecn_marks: self.ecn_marks - earlier.ecn_marks,
+            rto: self.rto,
+            srtt: self.srtt,
+            cwnd: self.cwnd,
+            packet_size: self.packet_size,
+            delivery_rate: self.delivery_rate,
+            queue_delay: self.queue_delay,
+            recv_queue_delay: self.recv_queue_delay,
+            recv_dropped_packets: self.recv_dropped_packets - earlier.recv_dropped_packets,
+            peer_recv_holes: self.peer_recv_holes,
+            recv_holes: self.recv_holes,
+        }
+    }
+}
+
+// What a session negotiated. The version is the lower of the two ends,
+// new wire changes are used only with peers of a version that has them.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct UcpCapabilities {
+    pub version: u32,
+    pub fec_group: u32,
+    pub encrypted: bool,
+    pub channels: bool,
+    pub datagrams: bool,
+    pub resume: bool,
+    pub probes: bool,
+    pub keyed_check: bool,
+    pub loss_reports: bool,
+}
+
+// Tuning of a stream, times are in milliseconds and windows in packets.
+// The listener applies its config to every accepted stream.
+#[derive(Clone, Debug)]
+pub struct UcpConfig {
+    pub window: u32,
+    pub recv_buffer: usize,
+    // Bytes of received packets a stream holds at most, and all streams
+    // of a listener together.
+    pub recv_memory: usize,
+    pub max_recv_memory: usize,
+    pub initial_rto: u32,
+    pub min_rto: u32,
+    pub max_rto: u32,
+    pub heartbeat_interval: u32,
+    // A client asks the server to heartbeat after longer and longer idle
+    // times while its heartbeats arrive, and settles on the longest the
+    // NAT keeps the mapping for, up to half the broken timeout.
+    pub adaptive_heartbeat: bool,
+    pub broken_timeout: u32,
+    pub fast_resend_threshold: u32,
+    // Resends of a packet after which the stream is broken, 0 for no
+    // limit.
+    pub max_xmit: u32,
+    pub pacing_gain: f64,
+    pub ack_every: u32,
+    pub ack_delay: u32,
+    pub max_packet_size: usize,
+    pub congestion: CongestionAlgorithm,
+    // FEC group size a client asks for, and the largest a server grants.
+    pub fec_group: u32,
+    pub max_fec_group: u32,
+    // Asked for by a client, needs a key.
+    pub encrypt: bool,
+    // Sends packets as ECN capable, marks are echoed either way.
+    pub ecn: bool,
+    // Options of the UDP socket: the DSCP packets are marked with, the
+    // kernel's buffers in bytes, 0 for its default, whether datagrams are
+    // sent with the don't fragment bit for path MTU discovery, and the
+    // network device the socket is bound to.
+    pub dscp: u8,
+    pub socket_send_buffer: usize,
+    pub socket_recv_buffer: usize,
+    pub dont_fragment: bool,
+    pub device: Option<String>,
+    // Sends the packets of an output round and receives what is queued
+    // with one syscall where the platform has one, see batch::send_all.
+    pub batch_io: bool,
+    // Asked for by a client, see UcpStream::channel.
+    pub channels: bool,
+    // Asked for by a client, see UcpStream::send_datagram.
+    pub datagrams: bool,
+    // Asked for by a client, see UcpStream::resume_ticket.
+    pub resume: bool,
+    // Asked for by a client, see UcpStats::delivery_rate.
+    pub probes: bool,
+    // Asked for by a client with a key, a keyed check in place of the
+    // CRC32 of every packet after the handshake.
+    pub keyed_check: bool,
+    // Asked for by a client, see UcpStats::recv_holes.
+    pub loss_reports: bool,
+    // Bytes per second of data packets the stream sends at most, 0 for
+    // no limit, see UcpStream::set_max_send_rate.
+    pub max_send_rate: u64,
+    // Sessions a listener keeps, handshakes it has open at most and how
+    // long each may take.
+    pub max_sessions: usize,
+    pub syn_backlog: usize,
+    pub syn_timeout: u32,
+    // Seconds a SYN from a client with a key may be old by the
+    // listener's clock, 0 takes SYNs of any age, see auth::SynGuard.
+    pub syn_max_age: u32,
+    // Tasks a listener hands the packets of accepted sessions to, by
+    // session id, so they are processed on more than one core. 0 keeps
+    // them on the task calling incoming.
+    pub workers: usize,
+    // A file every packet header is appended to, for debugging, see
+    // trace::FileTracer.
+    pub trace: Option<String>,
+}
+
+impl Default for UcpConfig {
+    fn default() -> Self {
+        UcpConfig {
+            window: DEFAULT_WINDOW,
+            recv_buffer: DEFAULT_RECV_BUFFER,
+            recv_memory: DEFAULT_RECV_MEMORY,
+            max_recv_memory: DEFAULT_MAX_RECV_MEMORY,
+            initial_rto: DEFAULT_RTO,
+            min_rto: DEFAULT_MIN_RTO,
+            max_rto: DEFAULT_MAX_RTO,
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+            adaptive_heartbeat: false,
+            broken_timeout: DEFAULT_BROKEN_TIMEOUT,
+            fast_resend_threshold: DEFAULT_FAST_RESEND_THRESHOLD,
+            max_xmit: DEFAULT_MAX_XMIT,
+            pacing_gain: DEFAULT_PACING_GAIN,
+            ack_every: DEFAULT_ACK_EVERY,
+            ack_delay: DEFAULT_ACK_DELAY,
+            max_packet_size: UCP_PACKET_SIZE_STEPS[0],
+            congestion: CongestionAlgorithm::default(),
+            fec_group: 0,
+            max_fec_group: MAX_FEC_GROUP,
+            encrypt: false,
+            ecn: false,
+            dscp: 0,
+            socket_send_buffer: 0,
+            socket_recv_buffer: 0,
+            dont_fragment: true,
+            device: None,
+            batch_io: true,
+            channels: false,
+            datagrams: false,
+            resume: false,
+            probes: true,
+            keyed_check: true,
+            loss_reports: true,
+            max_send_rate: 0,
+            max_sessions: DEFAULT_MAX_SESSIONS,
+            syn_backlog: DEFAULT_SYN_BACKLOG,
+            syn_timeout: DEFAULT_SYN_TIMEOUT,
+            syn_max_age: DEFAULT_SYN_MAX_AGE,
+            workers: 0,
+            trace: None,
+        }
+    }
+}
+
+impl UcpConfig {
+    // Sets a tunable from a `name=value` option.
+    pub fn set(&mut self, option: &str) -> Result<(), String> {
+        let (name, value) = option
+            .split_once('=')
+            .ok_or_else(|| format!("ucp option {} is not name=value", option))?;
+
+        match name {
+            "window" => self.window = parse_option(name, value)?,
+            "recv-buffer" => self.recv_buffer = parse_option(name, value)?,
+            "recv-memory" => self.recv_memory = parse_option(name, value)?,
+            "max-recv-memory" => self.max_recv_memory = parse_option(name, value)?,
+            "rto" => self.initial_rto = parse_option(name, value)?,
+            "min-rto" => self.min_rto = parse_option(name, value)?,
+            "max-rto" => self.max_rto = parse_option(name, value)?,
+            "heartbeat" => self.heartbeat_interval = parse_option(name, value)?,
+            "adaptive-heartbeat" => self.adaptive_heartbeat = parse_option(name, value)?,
+            "timeout" => self.broken_timeout = parse_option(name, value)?,
+            "fast-resend" => self.fast_resend_threshold = parse_option(name, value)?,
+            "max-xmit" => self.max_xmit = parse_option(name, value)?,
+            "pacing-gain" => self.pacing_gain = parse_option(name, value)?,
+            "ack-every" => self.ack_every = parse_option(name, value)?,
+            "ack-delay" => self.ack_delay = parse_option(name, value)?,
+            "ecn" => self.ecn = parse_option(name, value)?,
+            "dscp" => {
+                self.dscp = parse_option(name, value)?;
+                if self.dscp > 63 {
+                    return Err(format!("invalid value for ucp option dscp: {}", value));
+                }
+            }
+            "socket-send-buffer" => self.socket_send_buffer = parse_option(name, value)?,
+            "socket-recv-buffer" => self.socket_recv_buffer = parse_option(name, value)?,
+            "dont-fragment" => self.dont_fragment = parse_option(name, value)?,
+            "device" => self.device = Some(value.to_string()).filter(|device| !device.is_empty()),
+            "batch-io" => self.batch_io = parse_option(name, value)?,
+            "channels" => self.channels = parse_option(name, value)?,
+            "datagrams" => self.datagrams = parse_option(name, value)?,
+            "resume" => self.resume = parse_option(name, value)?,
+            "probes" => self.probes = parse_option(name, value)?,
+            "keyed-check" => self.keyed_check = parse_option(name, value)?,
+            "loss-reports" => self.loss_reports = parse_option(name, value)?,
+            "max-send-rate" => self.max_send_rate = parse_option(name, value)?,
+            "max-sessions" => self.max_sessions = parse_option(name, value)?,
+            "syn-backlog" => self.syn_backlog = parse_option(name, value)?,
+            "syn-timeout" => self.syn_timeout = parse_option(name, value)?,
+            "syn-max-age" => self.syn_max_age = parse_option(name, value)?,
+            "workers" => self.workers = parse_option(name, value)?,
+            "trace" => self.trace = Some(value.to_string()).filter(|path| !path.is_empty()),
+            _ => return Err(format!("unknown ucp option {}", name)),
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_option<T: FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value for ucp option {}: {}", name, value))
+}
 
 #[derive(Clone, Copy)]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
 enum UcpState {
     NONE,
     ACCEPTING,
     CONNECTING,
     ESTABLISHED,
+    // FIN sent after the send buffer drained, waiting for its FIN_ACK.
+    FIN_WAIT,
+    // Either end finished, remaining data can still be read.
+    CLOSED,
 }
 
 struct InnerStream {
     lock: AtomicUsize,
     alive: AtomicBool,
-    socket: Arc<UdpSocket>,
-    remote_addr: SocketAddr,
+    socket: Arc<Transport>,
+    // Datagrams of the output round in progress, with batch_io.
+    batch_io: Cell<bool>,
+    outbox: Cell<Option<Vec<Datagram>>>,
+    tracer: Cell<Option<Arc<dyn PacketTracer>>>,
+    remote_addr: Cell<SocketAddr>,
+    path_challenge: Cell<Option<PathChallenge>>,
+    auth_key: Option<Arc<AuthKey>>,
+    auth_counter: Cell<u32>,
+    replay_window: Cell<ReplayWindow>,
+    cipher: Cell<Option<PacketCipher>>,
+    cipher_salt: Cell<u32>,
     initial_time: Instant,
     alive_time: Cell<Instant>,
-    heartbeat: Cell<Instant>,
+    last_send: Cell<Instant>,
+    heartbeat_interval: Cell<u32>,
+    broken_timeout: Cell<u32>,
+    nat_probe: Cell<Option<NatProbe>>,
     state: Cell<UcpState>,
+    fin_time: Cell<Option<Instant>>,
 
     send_queue: Cell<UcpPacketQueue>,
+    resend_timers: Cell<ResendTimers>,
+    fast_resends: Cell<Vec<u32>>,
     recv_queue: Cell<UcpPacketQueue>,
     send_buffer: Cell<UcpPacketQueue>,
 
     read_waker: Cell<Option<Waker>>,
     write_waker: Cell<Option<Waker>>,
+    // Something for the next output round, see UcpStream::send.
+    output_due: Cell<bool>,
+    output_waker: Cell<Option<Waker>>,
+    // Waits for the handshake to finish or fail, see UcpStream::race.
+    state_waker: Cell<Option<Waker>>,
 
     ack_list: Cell<Vec<(u32, u32)>>,
+    ack_every: Cell<u32>,
+    ack_delay: Cell<u32>,
+    ack_time: Cell<Option<u32>>,
+    ack_now: Cell<bool>,
     session_id: Cell<u32>,
     local_window: Cell<u32>,
+    recv_window: Cell<u32>,
+    recv_buffer: Cell<usize>,
+    recv_bytes: Cell<usize>,
+    recv_memory: Cell<usize>,
+    recv_memory_limit: Cell<usize>,
+    recv_dropped_packets: Cell<u64>,
+    recv_budget: Option<Arc<RecvBudget>>,
+    window_update: Cell<bool>,
     remote_window: Cell<u32>,
     seq: Cell<u32>,
     una: Cell<u32>,
     rto: Cell<u32>,
     srtt: Cell<u32>,
     rttvar: Cell<u32>,
+    rtt_sampled: Cell<bool>,
+    min_rto: Cell<u32>,
+    max_rto: Cell<u32>,
+    congestion: Cell<Box<dyn CongestionControl>>,
+    pacing_gain: Cell<f64>,
+    pacing_credit: Cell<f64>,
+    pacing_time: Cell<u32>,
+    max_send_rate: Cell<u64>,
+    send_tokens: Cell<f64>,
+    send_tokens_time: Cell<u32>,
+
+    sent_packets: Cell<u64>,
+    resent_packets: Cell<u64>,
+    lost_packets: Cell<u64>,
+    fast_resent_packets: Cell<u64>,
+    fast_resend_threshold: Cell<u32>,
+    max_xmit: Cell<u32>,
+    ack_packets: Cell<u64>,
+    crowded_rounds: Cell<u64>,
+    // Packets resent in the output round in progress.
+    round_resends: Cell<usize>,
+
+    packet_size: Cell<usize>,
+    last_large_ack: Cell<u32>,
+    fragments: Cell<FragmentMap>,
+
+    // Path MTU search between a packet size known to pass and the
+    // smallest one known to fail.
+    max_packet_size: Cell<usize>,
+    pmtu_low: Cell<usize>,
+    pmtu_high: Cell<usize>,
+    pmtu_search_time: Cell<Instant>,
+    mtu_probe: Cell<Option<MtuProbe>>,
+
+    // Negotiated parity group size, 0 when FEC is off
+    fec_group: Cell<u32>,
+    max_fec_group: Cell<u32>,
+    fec_encoder: Cell<Option<FecGroup>>,
+    fec_cache: Cell<FecCache>,
+    fec_recovered_packets: Cell<u64>,
+
+    // Congestion experienced packets received, echoed in every ack, and
+    // the peer's count last echoed to us.
+    ce_received: Cell<u32>,
+    ce_echoed: Cell<u32>,
+    ecn_marks: Cell<u64>,
+
+    // Logical channels, once negotiated, and the ones the peer opened
+    // waiting to be accepted.
+    channels: Cell<bool>,
+    channel_map: Cell<ChannelMap>,
+    new_channels: Cell<VecDeque<u32>>,
+    accept_waker: Cell<Option<Waker>>,
+
+    // Features and FEC group size a client asked for in its SYN.
+    features_asked: Cell<u32>,
+    syn_payload: Cell<u16>,
+    features: Cell<u32>,
+    peer_version: Cell<u32>,
+    fec_asked: Cell<u32>,
+
+    // A listener's tickets, the grant a client resumes with until the
+    // server confirms it, and the ticket a client got for its next
+    // session.
+    tickets: Option<Arc<TicketBook>>,
+    resuming: Cell<Option<Grant>>,
+    resume_ticket: Cell<Option<ResumeTicket>>,
+
+    // Unreliable datagrams, once negotiated, waiting to be sent on the
+    // next tick and to be read.
+    datagrams: Cell<bool>,
+    udata_send_queue: Cell<UcpPacketQueue>,
+    udata_recv_queue: Cell<VecDeque<Vec<u8>>>,
+    udata_waker: Cell<Option<Waker>>,
+
+    // Probes, once negotiated: bytes received from the peer so far, the
+    // shortest one-way delay from the peer seen lately, the peer's last
+    // probe, and what the probes tell.
+    probes: Cell<bool>,
+    probe_time: Cell<Instant>,
+    received_bytes: Cell<u32>,
+    base_delay: Cell<Option<(i64, u32)>>,
+    last_probe: Cell<Option<(u32, u32)>>,
+    delivery_rate: Cell<u64>,
+    queue_delay: Cell<u32>,
+    recv_queue_delay: Cell<u32>,
+
+    // Keyed frame checks, once negotiated, and whether the peer sent one
+    // already, after which its packets with a CRC32 are dropped.
+    keyed_check: Cell<bool>,
+    peer_keyed: Cell<bool>,
+
+    // Loss reports, once negotiated: the highest data packet received,
+    // and the packets missing below the highest the peer received.
+    loss_reports: Cell<bool>,
+    recv_highest: Cell<Option<u32>>,
+    peer_recv_holes: Cell<u32>,
 }
 
 unsafe impl Send for InnerStream {}
@@ -265,47 +887,173 @@ impl Drop for Lock<'_> {
     }
 }
 
+// Packets are handed around boxed, as they are stored in the queues.
+#[allow(clippy::boxed_local)]
 impl InnerStream {
-    fn new(socket: Arc<UdpSocket>, remote_addr: SocketAddr) -> Self {
+    fn new(
+        socket: Arc<Transport>,
+        remote_addr: SocketAddr,
+        auth_key: Option<Arc<AuthKey>>,
+        tickets: Option<Arc<TicketBook>>,
+        recv_budget: Option<Arc<RecvBudget>>,
+    ) -> Self {
         InnerStream {
             lock: AtomicUsize::new(0),
             alive: AtomicBool::new(true),
             socket: socket,
-            remote_addr: remote_addr,
+            batch_io: Cell::new(true),
+            outbox: Cell::new(None),
+            tracer: Cell::new(None),
+            remote_addr: Cell::new(remote_addr),
+            path_challenge: Cell::new(None),
+            auth_key,
+            auth_counter: Cell::new(auth::unix_time()),
+            replay_window: Cell::new(ReplayWindow::default()),
+            cipher: Cell::new(None),
+            cipher_salt: Cell::new(0),
             initial_time: Instant::now(),
             alive_time: Cell::new(Instant::now()),
-            heartbeat: Cell::new(Instant::now()),
+            last_send: Cell::new(Instant::now()),
+            heartbeat_interval: Cell::new(DEFAULT_HEARTBEAT_INTERVAL),
+            nat_probe: Cell::new(None),
+            broken_timeout: Cell::new(DEFAULT_BROKEN_TIMEOUT),
             state: Cell::new(UcpState::NONE),
+            fin_time: Cell::new(None),
 
             send_queue: Cell::new(UcpPacketQueue::new()),
+            resend_timers: Cell::new(ResendTimers::new()),
+            fast_resends: Cell::new(Vec::new()),
             recv_queue: Cell::new(UcpPacketQueue::new()),
             send_buffer: Cell::new(UcpPacketQueue::new()),
 
             read_waker: Cell::new(None),
             write_waker: Cell::new(None),
+            output_due: Cell::new(false),
+            output_waker: Cell::new(None),
+            state_waker: Cell::new(None),
 
             ack_list: Cell::new(Vec::new()),
+            ack_every: Cell::new(DEFAULT_ACK_EVERY),
+            ack_delay: Cell::new(DEFAULT_ACK_DELAY),
+            ack_time: Cell::new(None),
+            ack_now: Cell::new(false),
             session_id: Cell::new(0),
             local_window: Cell::new(DEFAULT_WINDOW),
+            recv_window: Cell::new(DEFAULT_WINDOW),
+            recv_buffer: Cell::new(DEFAULT_RECV_BUFFER),
+            recv_bytes: Cell::new(0),
+            recv_memory: Cell::new(0),
+            recv_memory_limit: Cell::new(DEFAULT_RECV_MEMORY),
+            recv_dropped_packets: Cell::new(0),
+            recv_budget,
+            window_update: Cell::new(false),
             remote_window: Cell::new(DEFAULT_WINDOW),
             seq: Cell::new(0),
             una: Cell::new(0),
             rto: Cell::new(DEFAULT_RTO),
             srtt: Cell::new(0),
             rttvar: Cell::new(0),
+            rtt_sampled: Cell::new(false),
+            min_rto: Cell::new(DEFAULT_MIN_RTO),
+            max_rto: Cell::new(DEFAULT_MAX_RTO),
+            congestion: Cell::new(CongestionAlgorithm::default().build()),
+            pacing_gain: Cell::new(DEFAULT_PACING_GAIN),
+            pacing_credit: Cell::new(PACING_MIN_BURST),
+            pacing_time: Cell::new(0),
+            max_send_rate: Cell::new(0),
+            send_tokens: Cell::new(0.0),
+            send_tokens_time: Cell::new(0),
+
+            sent_packets: Cell::new(0),
+            resent_packets: Cell::new(0),
+            lost_packets: Cell::new(0),
+            fast_resent_packets: Cell::new(0),
+            fast_resend_threshold: Cell::new(DEFAULT_FAST_RESEND_THRESHOLD),
+            max_xmit: Cell::new(DEFAULT_MAX_XMIT),
+            ack_packets: Cell::new(0),
+            crowded_rounds: Cell::new(0),
+            round_resends: Cell::new(0),
+
+            packet_size: Cell::new(UCP_PACKET_SIZE_STEPS[0]),
+            last_large_ack: Cell::new(0),
+            fragments: Cell::new(FragmentMap::new()),
+
+            max_packet_size: Cell::new(UCP_PACKET_SIZE_STEPS[0]),
+            pmtu_low: Cell::new(UCP_PACKET_SIZE_STEPS[0]),
+            pmtu_high: Cell::new(UCP_PACKET_SIZE_STEPS[0] + 1),
+            pmtu_search_time: Cell::new(Instant::now()),
+            mtu_probe: Cell::new(None),
+
+            fec_group: Cell::new(0),
+            max_fec_group: Cell::new(MAX_FEC_GROUP),
+            fec_encoder: Cell::new(None),
+            fec_cache: Cell::new(FecCache::default()),
+            fec_recovered_packets: Cell::new(0),
+
+            ce_received: Cell::new(0),
+            ce_echoed: Cell::new(0),
+            ecn_marks: Cell::new(0),
+
+            channels: Cell::new(false),
+            channel_map: Cell::new(ChannelMap::new()),
+            new_channels: Cell::new(VecDeque::new()),
+            accept_waker: Cell::new(None),
+
+            features_asked: Cell::new(0),
+            syn_payload: Cell::new(0),
+            features: Cell::new(0),
+            peer_version: Cell::new(0),
+            fec_asked: Cell::new(0),
+
+            tickets,
+            resuming: Cell::new(None),
+            resume_ticket: Cell::new(None),
+
+            datagrams: Cell::new(false),
+            udata_send_queue: Cell::new(UcpPacketQueue::new()),
+            udata_recv_queue: Cell::new(VecDeque::new()),
+            udata_waker: Cell::new(None),
+
+            probes: Cell::new(false),
+            probe_time: Cell::new(Instant::now()),
+            received_bytes: Cell::new(0),
+            base_delay: Cell::new(None),
+            last_probe: Cell::new(None),
+            delivery_rate: Cell::new(0),
+            queue_delay: Cell::new(0),
+            recv_queue_delay: Cell::new(0),
+
+            keyed_check: Cell::new(false),
+            peer_keyed: Cell::new(false),
+
+            loss_reports: Cell::new(false),
+            recv_highest: Cell::new(None),
+            peer_recv_holes: Cell::new(0),
         }
     }
 
     async fn input(&self, packet: Box<UcpPacket>, remote_addr: SocketAddr) {
-        if self.remote_addr != remote_addr {
-            error!(
-                "unexpect packet from {}, expect from {}",
-                remote_addr, self.remote_addr
-            );
+        let _l = self.lock();
+        self.trace(Direction::Received, &packet, remote_addr);
+
+        let replay_window = unsafe { &mut *self.replay_window.as_ptr() };
+        // Cookies and resets come from the listener, not the session's
+        // counter.
+        let counted = packet.cmd != CMD_SYN_COOKIE && packet.cmd != CMD_RESET;
+        if self.auth_key.is_some() && counted && !replay_window.accept(packet.auth_counter) {
+            error!("replayed packet from {}", remote_addr);
             return;
         }
 
-        let _l = self.lock();
+        if packet.cmd == CMD_RESET {
+            self.process_reset(packet, remote_addr);
+            return;
+        }
+
+        if self.remote_addr.get() != remote_addr {
+            self.migrating(packet, remote_addr).await;
+            return;
+        }
 
         let state = self.state.get();
         match state {
@@ -320,48 +1068,132 @@ impl InnerStream {
         }
     }
 
+    fn session(&self) -> (u32, SocketAddr) {
+        let _l = self.lock();
+        (self.session_id.get(), self.remote_addr.get())
+    }
+
     async fn output(&self) {
         let _l = self.lock();
 
-        if self.check_if_alive() {
-            self.do_heartbeat().await;
-            self.send_ack_list().await;
-            self.timeout_resend().await;
-            self.send_pending_packets().await;
-        } else {
+        if self.batch_io.get() {
+            self.outbox.set(Some(Vec::new()));
+        }
+
+        self.output_round().await;
+
+        if let Some(datagrams) = self.outbox.take() {
+            self.socket.send_all(&datagrams).await;
+        }
+    }
+
+    async fn output_round(&self) {
+        if !self.check_if_alive() {
             self.die();
+            return;
+        }
+
+        // A stream closed by the peer lives on until its data is read.
+        if self.is_closed() {
+            if !self.is_readable() {
+                self.die();
+            }
+            return;
+        }
+
+        // Resends go first, acks next, and new data takes what is left
+        // of the pacing budget. A heartbeat is only due when none of them
+        // went.
+        self.refill_send_tokens();
+        self.resend_packets().await;
+        if !self.alive() {
+            return;
         }
+        self.send_window_update().await;
+        self.send_ack_list().await;
+        self.do_heartbeat().await;
+        self.send_pending_packets().await;
+        self.send_udata().await;
+        self.send_probe().await;
+        self.probe_path_mtu().await;
+        self.send_fin().await;
     }
 
     fn poll_read(&self, cx: &mut Context, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
         let _l = self.lock();
 
-        if !self.alive() {
+        if !self.alive() && !self.is_closed() {
             return Poll::Ready(Err(Error::from(ErrorKind::Other)));
         }
 
+        if self.channels.get() {
+            return self.poll_channel_read(cx, 0, buf);
+        }
+
         let n = self.recv(buf);
-        if n == 0 {
+        if n > 0 || self.is_closed() {
+            Poll::Ready(Ok(n))
+        } else {
             self.read_waker.set(Some(cx.waker().clone()));
             Poll::Pending
-        } else {
-            Poll::Ready(Ok(n))
         }
     }
 
     fn poll_write(&self, cx: &mut Context, buf: &[u8]) -> Poll<std::io::Result<usize>> {
         let _l = self.lock();
 
-        if !self.alive() {
-            return Poll::Ready(Err(Error::from(ErrorKind::Other)));
+        match self.write_send_buffer(buf) {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                self.write_waker.set(Some(cx.waker().clone()));
+                Poll::Pending
+            }
+            result => Poll::Ready(result),
         }
+    }
 
-        if self.is_send_buffer_overflow() {
-            self.write_waker.set(Some(cx.waker().clone()));
-            Poll::Pending
+    fn try_send(&self, buf: &[u8]) -> std::io::Result<usize> {
+        let _l = self.lock();
+        self.write_send_buffer(buf)
+    }
+
+    fn poll_writable(&self, cx: &mut Context) -> Poll<std::io::Result<()>> {
+        let _l = self.lock();
+
+        match self.send_room() {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                self.write_waker.set(Some(cx.waker().clone()));
+                Poll::Pending
+            }
+            result => Poll::Ready(result),
+        }
+    }
+
+    // The bytes of `buf` the send buffer took, on channel 0 once
+    // channels were granted.
+    fn write_send_buffer(&self, buf: &[u8]) -> std::io::Result<usize> {
+        self.send_room()?;
+
+        if self.channels.get() {
+            self.channel_write(0, buf)
         } else {
-            self.send(buf);
-            Poll::Ready(Ok(buf.len()))
+            Ok(self.send(buf))
+        }
+    }
+
+    // An error once the stream finished, WouldBlock while the send buffer
+    // is full or until the handshake tells whether channels were granted.
+    fn send_room(&self) -> std::io::Result<()> {
+        let finished = matches!(self.state.get(), UcpState::FIN_WAIT | UcpState::CLOSED);
+        if !self.alive() || finished {
+            return Err(Error::from(ErrorKind::Other));
+        }
+
+        let connecting = matches!(self.state.get(), UcpState::CONNECTING);
+        let channels_asked = self.features_asked.get() & FEATURE_CHANNELS != 0;
+        if self.is_send_buffer_overflow() || (connecting && channels_asked) {
+            Err(Error::from(ErrorKind::WouldBlock))
+        } else {
+            Ok(())
         }
     }
 
@@ -370,12 +1202,229 @@ impl InnerStream {
         self.die();
     }
 
+    fn configure(&self, config: &UcpConfig) {
+        let _l = self.lock();
+        self.recv_window.set(config.window.max(MIN_WINDOW));
+        self.recv_buffer.set(config.recv_buffer);
+        self.recv_memory_limit.set(config.recv_memory);
+        self.min_rto.set(config.min_rto.max(1));
+        self.max_rto.set(config.max_rto.max(config.min_rto));
+        self.rto.set(self.clamp_rto(config.initial_rto));
+        self.heartbeat_interval
+            .set(config.heartbeat_interval.max(1));
+        self.broken_timeout.set(config.broken_timeout);
+        self.fast_resend_threshold.set(config.fast_resend_threshold);
+        self.max_xmit.set(config.max_xmit);
+        self.batch_io.set(config.batch_io);
+        self.pacing_gain.set(config.pacing_gain);
+        self.max_send_rate.set(config.max_send_rate);
+        self.ack_every.set(config.ack_every.max(1));
+        self.ack_delay.set(config.ack_delay);
+        self.max_fec_group.set(config.max_fec_group);
+        self.congestion.set(config.congestion.build());
+        self.limit_packet_size(config.max_packet_size);
+        self.update_local_window();
+
+        if let Some(ref path) = config.trace {
+            match trace::file_tracer(path) {
+                Ok(tracer) => self.tracer.set(Some(tracer)),
+                Err(e) => error!("open ucp trace {} error: {}", path, e),
+            }
+        }
+    }
+
+    fn set_tracer(&self, tracer: Option<Arc<dyn PacketTracer>>) {
+        let _l = self.lock();
+        self.tracer.set(tracer);
+    }
+
+    fn trace(&self, direction: Direction, packet: &UcpPacket, remote_addr: SocketAddr) {
+        let tracer = unsafe { &*self.tracer.as_ptr() };
+        if let Some(tracer) = tracer {
+            tracer.packet(&PacketRecord {
+                time: SystemTime::now(),
+                direction,
+                remote_addr,
+                session_id: packet.session_id,
+                cmd: packet.cmd,
+                seq: packet.seq,
+                una: packet.una,
+                xmit: packet.xmit,
+                window: packet.window,
+                timestamp: packet.timestamp,
+                payload: packet.payload,
+            });
+        }
+    }
+
+    fn set_recv_window(&self, window: u32) {
+        let _l = self.lock();
+        self.recv_window.set(window.max(MIN_WINDOW));
+        self.update_local_window();
+    }
+
+    fn set_recv_buffer(&self, bytes: usize) {
+        let _l = self.lock();
+        self.recv_buffer.set(bytes);
+        self.update_local_window();
+    }
+
+    fn set_fast_resend_threshold(&self, threshold: u32) {
+        let _l = self.lock();
+        self.fast_resend_threshold.set(threshold);
+    }
+
+    fn set_max_xmit(&self, max_xmit: u32) {
+        let _l = self.lock();
+        self.max_xmit.set(max_xmit);
+    }
+
+    fn set_rto_bounds(&self, min_rto: u32, max_rto: u32) {
+        let _l = self.lock();
+        self.min_rto.set(min_rto.max(1));
+        self.max_rto.set(max_rto.max(min_rto));
+        self.rto.set(self.clamp_rto(self.rto.get()));
+    }
+
+    fn set_max_packet_size(&self, size: usize) {
+        let _l = self.lock();
+        self.limit_packet_size(size);
+    }
+
+    fn limit_packet_size(&self, size: usize) {
+        let size = size.clamp(
+            UCP_PACKET_SIZE_STEPS[UCP_PACKET_SIZE_STEPS.len() - 1],
+            UCP_MAX_PACKET_SIZE,
+        );
+
+        self.max_packet_size.set(size);
+        if self.packet_size.get() > size {
+            self.packet_size.set(size);
+            self.resegment_send_buffer();
+        }
+
+        self.restart_pmtu_search(size + 1);
+    }
+
+    fn set_pacing_gain(&self, gain: f64) {
+        let _l = self.lock();
+        self.pacing_gain.set(gain);
+    }
+
+    fn set_delayed_ack(&self, every: u32, delay: u32) {
+        let _l = self.lock();
+        self.ack_every.set(every.max(1));
+        self.ack_delay.set(delay);
+    }
+
+    // Sends FIN once all written data has been acknowledged, returns
+    // true when there is nothing left to wait for.
+    fn finish(&self) -> bool {
+        let _l = self.lock();
+        let send_queue = unsafe { &*self.send_queue.as_ptr() };
+        let send_buffer = unsafe { &*self.send_buffer.as_ptr() };
+
+        match self.state.get() {
+            UcpState::ESTABLISHED => {
+                if send_queue.is_empty() && send_buffer.is_empty() {
+                    self.state.set(UcpState::FIN_WAIT);
+                    self.wake_output();
+                }
+                false
+            }
+            UcpState::FIN_WAIT => false,
+            _ => true,
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        matches!(self.state.get(), UcpState::CLOSED)
+    }
+
+    fn is_established(&self) -> bool {
+        let _l = self.lock();
+        !matches!(
+            self.state.get(),
+            UcpState::NONE | UcpState::ACCEPTING | UcpState::CONNECTING
+        )
+    }
+
+    // Ready with whether the handshake finished, once it did or the
+    // stream died.
+    fn poll_settled(&self, cx: &mut Context) -> Poll<bool> {
+        self.state_waker.set(Some(cx.waker().clone()));
+        if self.is_established() {
+            Poll::Ready(true)
+        } else if !self.alive() {
+            Poll::Ready(false)
+        } else {
+            Poll::Pending
+        }
+    }
+
+    fn wake_state(&self) {
+        if let Some(w) = self.state_waker.take() {
+            w.wake();
+        }
+    }
+
+    fn is_readable(&self) -> bool {
+        if self.channels.get() {
+            let channel_map = unsafe { &*self.channel_map.as_ptr() };
+            return channel_map
+                .values()
+                .any(|channel| channel.readable_len() > 0);
+        }
+
+        let recv_queue = unsafe { &*self.recv_queue.as_ptr() };
+        recv_queue
+            .front()
+            .is_some_and(|packet| serial::before(packet.seq, self.una.get()))
+    }
+
+    fn stats(&self) -> UcpStats {
+        let _l = self.lock();
+
+        UcpStats {
+            sent_packets: self.sent_packets.get(),
+            resent_packets: self.resent_packets.get(),
+            lost_packets: self.lost_packets.get(),
+            fast_resent_packets: self.fast_resent_packets.get(),
+            fec_recovered_packets: self.fec_recovered_packets.get(),
+            ack_packets: self.ack_packets.get(),
+            crowded_rounds: self.crowded_rounds.get(),
+            ecn_marks: self.ecn_marks.get(),
+            rto: self.rto.get(),
+            srtt: self.srtt.get(),
+            cwnd: unsafe { &*self.congestion.as_ptr() }.window(),
+            packet_size: self.packet_size.get(),
+            delivery_rate: self.delivery_rate.get(),
+            queue_delay: self.queue_delay.get(),
+            recv_queue_delay: self.recv_queue_delay.get(),
+            recv_dropped_packets: self.recv_dropped_packets.get(),
+            peer_recv_holes: self.peer_recv_holes.get(),
+            recv_holes: self.recv_holes(),
+        }
+    }
+
+    fn set_congestion_control(&self, congestion: Box<dyn CongestionControl>) {
+        let _l = self.lock();
+        info!(
+            "ucp session {} congestion control {}",
+            self.session_id.get(),
+            congestion.name()
+        );
+        self.congestion.set(congestion);
+    }
+
     fn alive(&self) -> bool {
         self.alive.load(Ordering::Relaxed)
     }
 
     fn die(&self) {
         self.alive.store(false, Ordering::Relaxed);
+        self.wake_output();
+        self.wake_state();
 
         if let Some(w) = self.read_waker.take() {
             w.wake()
@@ -384,6 +1433,12 @@ impl InnerStream {
         if let Some(w) = self.write_waker.take() {
             w.wake()
         }
+
+        self.wake_channels();
+
+        if let Some(w) = self.udata_waker.take() {
+            w.wake()
+        }
     }
 
     fn lock(&self) -> Lock<'_> {
@@ -405,7 +1460,7 @@ impl InnerStream {
 
         while size < buf.len() && !recv_queue.is_empty() {
             if let Some(packet) = recv_queue.front_mut() {
-                let diff = (packet.seq - una) as i32;
+                let diff = serial::diff(packet.seq, una);
                 if diff >= 0 {
                     break;
                 }
@@ -419,20 +1474,54 @@ impl InnerStream {
                 .unwrap();
 
             if no_remain_payload {
-                recv_queue.pop_front();
+                if let Some(packet) = recv_queue.pop_front() {
+                    self.release_recv_memory(&packet);
+                }
             }
         }
 
+        self.recv_bytes.set(self.recv_bytes.get() - size);
+        self.update_local_window();
         size
     }
 
-    fn send(&self, buf: &[u8]) {
+    // Advertise the part of the receive window not yet occupied by
+    // packets the application hasn't read, so a slow reader slows
+    // down the sender. The receive buffer bounds it in bytes, counted
+    // in full packets of the current size.
+    fn update_local_window(&self) {
+        let recv_queue = unsafe { &*self.recv_queue.as_ptr() };
+        let load = self.data_load().max(1);
+        let buffered = (self.recv_buffer.get() / load).min(u32::MAX as usize) as u32;
+        let capacity = min(self.recv_window.get(), buffered).max(MIN_WINDOW);
+
+        let free_packets = (capacity as usize).saturating_sub(recv_queue.len());
+        let free_bytes = self.recv_buffer.get().saturating_sub(self.recv_bytes.get());
+        let window = (min(free_packets, free_bytes / load) as u32).max(MIN_WINDOW);
+
+        let old_window = self.local_window.get();
+        if old_window < capacity / 2 && window >= capacity / 2 {
+            self.window_update.set(true);
+            self.wake_output();
+        }
+
+        self.local_window.set(window);
+    }
+
+    // Fills the send buffer up to the peer's window, returns the bytes
+    // taken.
+    fn send(&self, buf: &[u8]) -> usize {
         let mut pos = 0;
         let send_buffer = unsafe { &mut *self.send_buffer.as_ptr() };
 
         if let Some(packet) = send_buffer.back_mut() {
             if packet.cmd == CMD_DATA {
-                let remain = min(packet.remaining_load(), buf.len());
+                let limit = self.data_load() + UCP_PACKET_META_SIZE;
+                let load = min(
+                    limit - min(packet.packet_size(), limit),
+                    packet.remaining_load(),
+                );
+                let remain = min(load, buf.len());
                 if remain > 0 {
                     packet.payload_write_slice(&buf[0..remain]);
                 }
@@ -441,16 +1530,20 @@ impl InnerStream {
             }
         }
 
-        if pos < buf.len() {
-            self.make_packet_send(&buf[pos..]);
+        let packets = (self.remote_window.get() as usize).saturating_sub(send_buffer.len());
+        let end = min(buf.len(), pos + packets * self.data_load());
+        if pos < end {
+            self.make_packet_send(&buf[pos..end]);
         }
+
+        end
     }
 
     fn try_wake_reader(&self) {
         let recv_queue = unsafe { &*self.recv_queue.as_ptr() };
 
         if let Some(packet) = recv_queue.front() {
-            let diff = (packet.seq - self.una.get()) as i32;
+            let diff = serial::diff(packet.seq, self.una.get());
             if diff < 0 {
                 if let Some(w) = self.read_waker.take() {
                     w.wake();
@@ -464,6 +1557,13 @@ impl InnerStream {
             if let Some(w) = self.write_waker.take() {
                 w.wake();
             }
+
+            let channel_map = unsafe { &mut *self.channel_map.as_ptr() };
+            for channel in channel_map.values_mut() {
+                if let Some(w) = channel.write_waker.take() {
+                    w.wake();
+                }
+            }
         }
     }
 
@@ -473,461 +1573,3106 @@ impl InnerStream {
         send_buffer.len() >= remote_window as usize
     }
 
-    fn check_if_alive(&self) -> bool {
-        let now = Instant::now();
-        let interval = (now - self.alive_time.get()).as_millis();
-        let alive = interval < UCP_STREAM_BROKEN_MILLIS;
+    fn poll_read_channel(
+        &self,
+        cx: &mut Context,
+        id: u32,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let _l = self.lock();
 
-        if !alive {
-            error!(
-                "ucp alive timeout, remote address: {}, session: {}",
-                self.remote_addr,
-                self.session_id.get()
-            );
+        if !self.alive() && !self.is_closed() {
+            return Poll::Ready(Err(Error::from(ErrorKind::Other)));
         }
 
-        alive
+        self.poll_channel_read(cx, id, buf)
     }
 
-    async fn do_heartbeat(&self) {
-        let now = Instant::now();
-        let interval = (now - self.heartbeat.get()).as_millis();
-
-        if interval >= HEARTBEAT_INTERVAL_MILLIS {
-            let mut heartbeat = self.new_noseq_packet(CMD_HEARTBEAT);
-            self.send_packet_directly(&mut heartbeat).await;
-            self.heartbeat.set(now);
-        }
-    }
+    fn poll_write_channel(
+        &self,
+        cx: &mut Context,
+        id: u32,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let _l = self.lock();
 
-    async fn send_ack_list(&self) {
-        let ack_list = self.ack_list.take();
-        if ack_list.is_empty() {
-            return;
+        let finished = matches!(self.state.get(), UcpState::FIN_WAIT | UcpState::CLOSED);
+        if !self.alive() || finished {
+            return Poll::Ready(Err(Error::from(ErrorKind::Other)));
         }
 
-        let mut packet = self.new_noseq_packet(CMD_ACK);
+        self.poll_channel_write(cx, id, buf)
+    }
 
-        for &(seq, timestamp) in ack_list.iter() {
-            if packet.remaining_load() < 8 {
-                self.send_packet_directly(&mut packet).await;
-                packet = self.new_noseq_packet(CMD_ACK);
+    // A channel gone after both ends closed it reads as finished.
+    fn poll_channel_read(
+        &self,
+        cx: &mut Context,
+        id: u32,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let channel_map = unsafe { &mut *self.channel_map.as_ptr() };
+        let channel = match channel_map.get_mut(&id) {
+            Some(channel) => channel,
+            None => return Poll::Ready(Ok(0)),
+        };
+
+        let n = channel.read(buf);
+        if n > 0 {
+            self.recv_bytes.set(self.recv_bytes.get() - n);
+            self.update_local_window();
+            if let Some(limit) = channel.window_update() {
+                self.send_control(Control::Window(id, limit));
             }
+            Poll::Ready(Ok(n))
+        } else if channel.is_finished() || self.is_closed() {
+            self.remove_channel_if_done(id);
+            Poll::Ready(Ok(0))
+        } else {
+            if id == 0 {
+                self.read_waker.set(Some(cx.waker().clone()));
+            } else {
+                channel.read_waker = Some(cx.waker().clone());
+            }
+            Poll::Pending
+        }
+    }
+
+    fn poll_channel_write(
+        &self,
+        cx: &mut Context,
+        id: u32,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.channel_write(id, buf) {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                if id == 0 {
+                    self.write_waker.set(Some(cx.waker().clone()));
+                } else {
+                    let channel_map = unsafe { &mut *self.channel_map.as_ptr() };
+                    if let Some(channel) = channel_map.get_mut(&id) {
+                        channel.write_waker = Some(cx.waker().clone());
+                    }
+                }
+                Poll::Pending
+            }
+            result => Poll::Ready(result),
+        }
+    }
+
+    // Writes up to the credit the peer's reader granted the channel.
+    fn channel_write(&self, id: u32, buf: &[u8]) -> std::io::Result<usize> {
+        if !self.open_channel(id, false) {
+            return Err(Error::from(ErrorKind::Other));
+        }
+
+        let channel_map = unsafe { &mut *self.channel_map.as_ptr() };
+        let channel = channel_map.get_mut(&id).unwrap();
+
+        if channel.write_closed {
+            return Err(Error::from(ErrorKind::BrokenPipe));
+        }
+
+        let credit = channel.send_credit();
+        if self.is_send_buffer_overflow() || credit == 0 {
+            return Err(Error::from(ErrorKind::WouldBlock));
+        }
+
+        let n = min(credit, buf.len());
+        channel.note_sent(n);
+        self.channel_send(id, &buf[..n]);
+        Ok(n)
+    }
+
+    // Like send, packets only carry data of one channel.
+    fn channel_send(&self, id: u32, buf: &[u8]) {
+        let mut pos = 0;
+        let send_buffer = unsafe { &mut *self.send_buffer.as_ptr() };
+
+        if let Some(packet) = send_buffer.back_mut() {
+            if packet.cmd == CMD_DATA
+                && packet.payload as usize >= CHANNEL_HEADER_SIZE
+                && Reader::new(packet.payload_data()).u32() == Some(id)
+            {
+                let limit = self.data_load() + UCP_PACKET_META_SIZE;
+                let load = min(
+                    limit - min(packet.packet_size(), limit),
+                    packet.remaining_load(),
+                );
+                let remain = min(load, buf.len());
+                if remain > 0 {
+                    packet.payload_write_slice(&buf[0..remain]);
+                }
+
+                pos = remain;
+            }
+        }
+
+        let channel_map = unsafe { &mut *self.channel_map.as_ptr() };
+        let channel = channel_map.entry(id).or_insert_with(Channel::new);
+        while pos < buf.len() {
+            let mut packet = self.new_packet(CMD_DATA);
+            let size = min(self.data_load() - CHANNEL_HEADER_SIZE, buf.len() - pos);
+
+            packet.payload_write_u32(id);
+            packet.payload_write_u32(channel.send_seq);
+            packet.payload_write_slice(&buf[pos..pos + size]);
+            channel.send_seq = channel.send_seq.wrapping_add(1);
+            self.send_packet(packet);
+
+            pos += size;
+        }
+    }
+
+    // Control messages sent after our FIN would never be delivered.
+    fn send_control(&self, control: Control) {
+        if matches!(
+            self.state.get(),
+            UcpState::ACCEPTING | UcpState::ESTABLISHED
+        ) {
+            self.channel_send(CONTROL_CHANNEL, &control.encode());
+        }
+    }
+
+    // Channels open with the first packet either end sends on them, the
+    // ones the peer opened wait for accept_channel.
+    fn open_channel(&self, id: u32, by_peer: bool) -> bool {
+        let channel_map = unsafe { &mut *self.channel_map.as_ptr() };
+        if !channel_map.contains_key(&id) {
+            if channel_map.len() >= MAX_CHANNELS {
+                error!(
+                    "ucp session {} has too many channels",
+                    self.session_id.get()
+                );
+                return false;
+            }
+
+            channel_map.insert(id, Channel::new());
+            if by_peer && id != 0 && id != CONTROL_CHANNEL {
+                let new_channels = unsafe { &mut *self.new_channels.as_ptr() };
+                new_channels.push_back(id);
+                if let Some(w) = self.accept_waker.take() {
+                    w.wake();
+                }
+            }
+        }
+
+        true
+    }
+
+    fn close_channel(&self, id: u32) {
+        let _l = self.lock();
+
+        let channel_map = unsafe { &mut *self.channel_map.as_ptr() };
+        let end_seq = match channel_map.get_mut(&id) {
+            Some(channel) if !channel.write_closed => {
+                channel.write_closed = true;
+                channel.send_seq
+            }
+            _ => return,
+        };
+
+        self.send_control(Control::Close(id, end_seq));
+        self.remove_channel_if_done(id);
+    }
+
+    fn remove_channel_if_done(&self, id: u32) {
+        let channel_map = unsafe { &mut *self.channel_map.as_ptr() };
+        if channel_map
+            .get(&id)
+            .is_some_and(|channel| channel.is_done())
+        {
+            channel_map.remove(&id);
+        }
+    }
+
+    fn poll_accept_channel(&self, cx: &mut Context) -> Poll<Option<u32>> {
+        let _l = self.lock();
+
+        let new_channels = unsafe { &mut *self.new_channels.as_ptr() };
+        if let Some(id) = new_channels.pop_front() {
+            Poll::Ready(Some(id))
+        } else if !self.alive() || self.is_closed() {
+            Poll::Ready(None)
+        } else {
+            self.accept_waker.set(Some(cx.waker().clone()));
+            Poll::Pending
+        }
+    }
+
+    // Channel 0, the stream itself, is there from the start.
+    fn enable_channels(&self) {
+        self.channels.set(true);
+        self.open_channel(0, false);
+        info!("ucp session {} has channels", self.session_id.get());
+    }
+
+    fn has_channels(&self) -> bool {
+        let _l = self.lock();
+        self.channels.get()
+    }
+
+    fn add_channel(&self, id: u32) -> bool {
+        let _l = self.lock();
+        self.channels.get() && id != CONTROL_CHANNEL && self.open_channel(id, false)
+    }
+
+    fn resume_ticket(&self) -> Option<ResumeTicket> {
+        let _l = self.lock();
+        unsafe { &*self.resume_ticket.as_ptr() }.clone()
+    }
+
+    fn has_datagrams(&self) -> bool {
+        let _l = self.lock();
+        self.datagrams.get()
+    }
+
+    fn queue_udata(&self, buf: &[u8]) -> std::io::Result<()> {
+        let _l = self.lock();
+
+        let finished = matches!(self.state.get(), UcpState::FIN_WAIT | UcpState::CLOSED);
+        if !self.alive() || finished {
+            return Err(Error::from(ErrorKind::NotConnected));
+        }
+
+        if !self.datagrams.get() {
+            return Err(Error::from(ErrorKind::Unsupported));
+        }
+
+        if buf.len() > self.data_load() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "datagram larger than a packet",
+            ));
+        }
+
+        let mut packet = self.new_noseq_packet(CMD_UDATA);
+        packet.payload_write_slice(buf);
+
+        let udata_send_queue = unsafe { &mut *self.udata_send_queue.as_ptr() };
+        udata_send_queue.push_back(packet);
+        self.wake_output();
+        if udata_send_queue.len() > MAX_QUEUED_DATAGRAMS {
+            udata_send_queue.pop_front();
+        }
+
+        Ok(())
+    }
+
+    // Neither the congestion window nor pacing hold datagrams back,
+    // they are sent once and never resent.
+    async fn send_udata(&self) {
+        let udata_send_queue = unsafe { &mut *self.udata_send_queue.as_ptr() };
+        while let Some(mut packet) = udata_send_queue.pop_front() {
+            packet.una = self.una.get();
+            packet.window = self.local_window.get();
+            self.send_datagram(&mut packet, self.remote_addr.get())
+                .await;
+        }
+    }
+
+    fn process_udata(&self, mut packet: Box<UcpPacket>) {
+        if !self.datagrams.get() {
+            return;
+        }
+
+        let mut data = vec![0u8; packet.payload_remaining()];
+        packet.payload_read_slice(&mut data);
+
+        let udata_recv_queue = unsafe { &mut *self.udata_recv_queue.as_ptr() };
+        udata_recv_queue.push_back(data);
+        if udata_recv_queue.len() > MAX_QUEUED_DATAGRAMS {
+            udata_recv_queue.pop_front();
+        }
+
+        if let Some(w) = self.udata_waker.take() {
+            w.wake();
+        }
+    }
+
+    fn poll_recv_udata(&self, cx: &mut Context) -> Poll<Option<Vec<u8>>> {
+        let _l = self.lock();
+
+        let udata_recv_queue = unsafe { &mut *self.udata_recv_queue.as_ptr() };
+        if let Some(data) = udata_recv_queue.pop_front() {
+            Poll::Ready(Some(data))
+        } else if !self.alive() || self.is_closed() {
+            Poll::Ready(None)
+        } else {
+            self.udata_waker.set(Some(cx.waker().clone()));
+            Poll::Pending
+        }
+    }
+
+    // Takes the channel header and data off a packet in session order,
+    // the data is readable as soon as its channel's order allows.
+    fn deliver_to_channel(&self, packet: &mut UcpPacket) {
+        if packet.payload_remaining() < CHANNEL_HEADER_SIZE {
+            error!(
+                "ucp session {} data without channel header",
+                self.session_id.get()
+            );
+            return;
+        }
+
+        let id = packet.payload_read_u32();
+        let seq = packet.payload_read_u32();
+        let data = packet.buf[packet.read_pos..packet.size].to_vec();
+        packet.read_pos = packet.size;
+
+        if !self.open_channel(id, true) {
+            return;
+        }
+
+        let channel_map = unsafe { &mut *self.channel_map.as_ptr() };
+        let channel = channel_map.get_mut(&id).unwrap();
+        let n = channel.receive(seq, data);
+        if id == CONTROL_CHANNEL {
+            self.process_control();
+            return;
+        }
+
+        self.recv_bytes.set(self.recv_bytes.get() + n);
+        if channel.is_readable() {
+            self.wake_channel(id, channel);
+        }
+    }
+
+    // Messages are taken off first, applying them may open channels.
+    fn process_control(&self) {
+        let channel_map = unsafe { &mut *self.channel_map.as_ptr() };
+        let mut messages = Vec::new();
+        if let Some(control) = channel_map.get_mut(&CONTROL_CHANNEL) {
+            let mut buf = [0u8; CONTROL_MESSAGE_SIZE];
+            while control.readable_len() >= CONTROL_MESSAGE_SIZE {
+                control.read(&mut buf);
+                messages.extend(Control::decode(&buf));
+            }
+        }
+
+        for message in messages {
+            match message {
+                Control::Window(id, limit) => {
+                    if let Some(channel) = channel_map.get_mut(&id) {
+                        channel.grant(limit);
+                        self.wake_channel(id, channel);
+                    }
+                }
+                Control::Close(id, end_seq) => {
+                    if self.open_channel(id, true) {
+                        let channel_map = unsafe { &mut *self.channel_map.as_ptr() };
+                        let channel = channel_map.get_mut(&id).unwrap();
+                        channel.set_end(end_seq);
+                        self.wake_channel(id, channel);
+                    }
+                }
+            }
+        }
+    }
+
+    // Channel 0 is the stream itself and shares its wakers.
+    fn wake_channel(&self, id: u32, channel: &mut Channel) {
+        let (read_waker, write_waker) = if id == 0 {
+            (self.read_waker.take(), self.write_waker.take())
+        } else {
+            (channel.read_waker.take(), channel.write_waker.take())
+        };
+
+        if let Some(w) = read_waker {
+            w.wake();
+        }
+
+        if let Some(w) = write_waker {
+            w.wake();
+        }
+    }
+
+    fn wake_channels(&self) {
+        let channel_map = unsafe { &mut *self.channel_map.as_ptr() };
+        for (&id, channel) in channel_map.iter_mut() {
+            self.wake_channel(id, channel);
+        }
+
+        if let Some(w) = self.accept_waker.take() {
+            w.wake();
+        }
+    }
+
+    fn check_if_alive(&self) -> bool {
+        let now = Instant::now();
+        let interval = (now - self.alive_time.get()).as_millis();
+        let alive = interval < self.broken_timeout.get() as u128;
+
+        if !alive {
+            error!(
+                "ucp alive timeout, remote address: {}, session: {}",
+                self.remote_addr.get(),
+                self.session_id.get()
+            );
+        }
+
+        alive
+    }
+
+    // Every second, the bytes received so far and the queueing delay
+    // seen on the packets of the peer. The packet timestamp is the send
+    // time the peer measures one-way delay with.
+    async fn send_probe(&self) {
+        let established = matches!(self.state.get(), UcpState::ESTABLISHED);
+        let elapsed = (Instant::now() - self.probe_time.get()).as_millis();
+        if !self.probes.get() || !established || elapsed < PROBE_INTERVAL_MILLIS {
+            return;
+        }
+
+        self.probe_time.set(Instant::now());
+        let mut probe = self.new_noseq_packet(CMD_PROBE);
+        probe.payload_write_u32(self.received_bytes.get());
+        probe.payload_write_u32(self.recv_queue_delay.get());
+        self.send_packet_directly(&mut probe).await;
+    }
+
+    // Clocks of the two ends aren't synchronized, so the one-way delay
+    // only tells how much longer the packets took than the fastest one
+    // lately, which is the time they queued.
+    fn process_probe(&self, mut packet: Box<UcpPacket>) {
+        if packet.payload != 8 {
+            return;
+        }
+
+        let received = packet.payload_read_u32();
+        let queue_delay = packet.payload_read_u32();
+        let now = self.timestamp();
+
+        let delay = now as i64 - packet.timestamp as i64;
+        let base = match self.base_delay.get() {
+            Some((base, time))
+                if base <= delay && now.wrapping_sub(time) < PROBE_BASE_DELAY_EXPIRE =>
+            {
+                base
+            }
+            _ => {
+                self.base_delay.set(Some((delay, now)));
+                delay
+            }
+        };
+        self.recv_queue_delay.set((delay - base) as u32);
+
+        if let Some((time, bytes)) = self.last_probe.get() {
+            if serial::diff(packet.timestamp, time) <= 0 {
+                return;
+            }
+
+            let elapsed = packet.timestamp.wrapping_sub(time) as u64;
+            self.delivery_rate
+                .set(received.wrapping_sub(bytes) as u64 * 1000 / elapsed);
+        }
+        self.last_probe.set(Some((packet.timestamp, received)));
+        self.queue_delay.set(queue_delay);
+
+        let rate = self.delivery_rate.get() as f64 / 1000.0 / self.packet_size.get() as f64;
+        let congestion = unsafe { &mut *self.congestion.as_ptr() };
+        congestion.on_probe(rate, queue_delay, now, self.srtt.get());
+    }
+
+    async fn send_fin(&self) {
+        if !matches!(self.state.get(), UcpState::FIN_WAIT) {
+            return;
+        }
+
+        let now = Instant::now();
+        let due = match self.fin_time.get() {
+            Some(fin_time) => (now - fin_time).as_millis() >= self.rto.get() as u128,
+            None => true,
+        };
+
+        if due {
+            let mut fin = self.new_noseq_packet(CMD_FIN);
+            self.send_packet_directly(&mut fin).await;
+            self.fin_time.set(Some(now));
+        }
+    }
+
+    // Any packet tells the peer we are alive, so only an idle stream
+    // sends heartbeats.
+    async fn do_heartbeat(&self) {
+        let idle = (Instant::now() - self.last_send.get()).as_millis();
+
+        let established = matches!(self.state.get(), UcpState::ESTABLISHED);
+        if let (Some(probe), true) = (self.nat_probe.get(), established) {
+            return self.do_nat_probe(probe, idle as u32).await;
+        }
+
+        if idle >= self.heartbeat_interval.get() as u128 {
+            let mut heartbeat = self.new_noseq_packet(CMD_HEARTBEAT);
+            self.send_packet_directly(&mut heartbeat).await;
+        }
+    }
+
+    // An adaptive client leaves heartbeats to the server, whose arrival
+    // after the interval asked for shows the NAT mapping held that long.
+    // One that is late means it didn't, the interval goes back to the
+    // longest that held and the heartbeat tells the server.
+    async fn do_nat_probe(&self, mut probe: NatProbe, idle: u32) {
+        let base = self.heartbeat_interval.get();
+        if probe.interval == 0 {
+            let learned = NAT_INTERVALS
+                .lock()
+                .unwrap()
+                .get(&self.remote_addr.get())
+                .copied();
+            probe.interval = learned.unwrap_or(base).min(self.max_nat_interval());
+            probe.settled = learned.is_some();
+        }
+
+        let late = probe.interval + self.rto.get() + NAT_PROBE_MARGIN_MILLIS;
+        if probe.told && idle >= late {
+            probe.interval = probe.held.max(base).min(probe.interval);
+            probe.settled = true;
+            probe.told = false;
+            self.learned_nat_interval(probe.interval);
+            info!(
+                "ucp server {} heartbeat late, nat keeps the mapping for {}ms",
+                self.remote_addr.get(),
+                probe.interval
+            );
+        }
+
+        if !probe.told {
+            let mut heartbeat = self.new_noseq_packet(CMD_HEARTBEAT);
+            heartbeat.payload_write_u32(probe.interval);
+            self.send_packet_directly(&mut heartbeat).await;
+            probe.told = true;
+        }
+
+        self.nat_probe.set(Some(probe));
+    }
+
+    // Arrived after the interval asked for, the mapping held for it.
+    fn nat_probe_held(&self) {
+        let mut probe = match self.nat_probe.get() {
+            Some(probe) if probe.told => probe,
+            _ => return,
+        };
+
+        let idle = (Instant::now() - self.last_send.get()).as_millis() as u32;
+        if idle < probe.interval {
+            return;
+        }
+
+        probe.held = probe.held.max(probe.interval);
+        if !probe.settled {
+            let max = self.max_nat_interval();
+            probe.interval = ((probe.interval as f64 * NAT_PROBE_GROWTH) as u32).min(max);
+            probe.settled = probe.interval == probe.held;
+            probe.told = false;
+            if probe.settled {
+                self.learned_nat_interval(probe.interval);
+            }
+        }
+        self.nat_probe.set(Some(probe));
+    }
+
+    // Heartbeats at half the broken timeout still leave the next one time
+    // to arrive.
+    fn max_nat_interval(&self) -> u32 {
+        (self.broken_timeout.get() / 2).max(self.heartbeat_interval.get())
+    }
+
+    fn learned_nat_interval(&self, interval: u32) {
+        NAT_INTERVALS
+            .lock()
+            .unwrap()
+            .insert(self.remote_addr.get(), interval);
+    }
+
+    // Both ends heartbeat at the shorter interval and give up after the
+    // longer timeout, so neither declares the other broken early.
+    fn agree_liveness(&self, heartbeat_interval: u32, broken_timeout: u32) {
+        let heartbeat_interval = heartbeat_interval.clamp(1, self.heartbeat_interval.get());
+        let broken_timeout = broken_timeout.max(self.broken_timeout.get());
+        self.heartbeat_interval.set(heartbeat_interval);
+        self.broken_timeout.set(broken_timeout);
+    }
+
+    async fn send_window_update(&self) {
+        if self.window_update.take() {
+            let mut packet = self.new_ack_packet();
+            self.send_packet_directly(&mut packet).await;
+        }
+    }
+
+    async fn send_ack_list(&self) {
+        if !self.is_ack_due() {
+            return;
+        }
+
+        let ack_list = self.ack_list.take();
+        self.ack_time.set(None);
+        self.ack_now.set(false);
+
+        let mut packet = self.new_ack_packet();
+
+        for &(seq, timestamp) in ack_list.iter() {
+            if packet.packet_size() + 8 > self.unsealed_size(self.packet_size.get()) {
+                self.send_packet_directly(&mut packet).await;
+                self.ack_packets.set(self.ack_packets.get() + 1);
+                packet = self.new_ack_packet();
+            }
+
+            packet.payload_write_u32(seq);
+            packet.payload_write_u32(timestamp);
+        }
+
+        self.send_packet_directly(&mut packet).await;
+        self.ack_packets.set(self.ack_packets.get() + 1);
+        self.send_sack().await;
+    }
+
+    // Acks wait for `ack_every` packets or `ack_delay` milliseconds,
+    // whichever comes first, unless something arrived out of order.
+    fn is_ack_due(&self) -> bool {
+        let ack_list = unsafe { &*self.ack_list.as_ptr() };
+        if ack_list.is_empty() {
+            return false;
+        }
+
+        let waited = match self.ack_time.get() {
+            Some(time) => self.timestamp().wrapping_sub(time),
+            None => 0,
+        };
+
+        self.ack_now.get()
+            || ack_list.len() as u32 >= self.ack_every.get()
+            || waited >= self.ack_delay.get()
+    }
+
+    fn queue_ack(&self, packet: &UcpPacket, immediately: bool) {
+        let ack_list = unsafe { &mut *self.ack_list.as_ptr() };
+        ack_list.push((packet.seq, packet.timestamp));
+        self.wake_output();
+
+        if self.ack_time.get().is_none() {
+            self.ack_time.set(Some(self.timestamp()));
+        }
+
+        if immediately {
+            self.ack_now.set(true);
+        }
+    }
+
+    // Ranges [start, end) of packets received above una, which lets the
+    // sender skip them when resending.
+    async fn send_sack(&self) {
+        let una = self.una.get();
+        let recv_queue = unsafe { &*self.recv_queue.as_ptr() };
+        let mut blocks: Vec<(u32, u32)> = Vec::new();
+
+        for packet in recv_queue.iter() {
+            if serial::before(packet.seq, una) {
+                continue;
+            }
+
+            let next = packet.seq.wrapping_add(1);
+            match blocks.last_mut() {
+                Some(block) if block.1 == packet.seq => block.1 = next,
+                _ => {
+                    if blocks.len() >= MAX_SACK_BLOCKS {
+                        break;
+                    }
+                    blocks.push((packet.seq, next));
+                }
+            }
+        }
+
+        if blocks.is_empty() {
+            return;
+        }
+
+        let mut packet = self.new_noseq_packet(CMD_SACK);
+        for &(start, end) in blocks.iter() {
+            packet.payload_write_u32(start);
+            packet.payload_write_u32(end);
+        }
+
+        self.send_packet_directly(&mut packet).await;
+    }
+
+    // A packet is resent when its RTO expires, or once per RTO when enough
+    // later packets sent after it were acked, so a single reordered packet
+    // costs at most one extra transmission. Each timeout of a packet
+    // doubles the RTO it waits for, as samples from packets that get
+    // through keep the stream's RTO low, and a packet sent max_xmit times
+    // breaks the stream.
+    //
+    // Only packets whose deadline passed or that reached the fast resend
+    // threshold are looked at. A deadline is set when the packet is sent,
+    // and checked against the RTO of the time it expires.
+    async fn resend_packets(&self) {
+        let now = self.timestamp();
+        let clock = self.clock();
+        let una = self.una.get();
+        let rto = self.rto.get();
+        let max_xmit = self.max_xmit.get();
+        let threshold = self.fast_resend_threshold.get();
+        let congestion = unsafe { &mut *self.congestion.as_ptr() };
+        let limit = congestion.window().max(1) as usize;
+        let mut resend = Vec::new();
+        let mut blackhole = false;
+        let mut timeouts = false;
+
+        {
+            let send_queue = unsafe { &mut *self.send_queue.as_ptr() };
+            let resend_timers = unsafe { &mut *self.resend_timers.as_ptr() };
+            let fast_resends = unsafe { &mut *self.fast_resends.as_ptr() };
+
+            let mut due: Vec<(u32, Option<u32>)> =
+                fast_resends.drain(..).map(|seq| (seq, None)).collect();
+            while let Some(&Reverse((deadline, seq, xmit))) = resend_timers.peek() {
+                if deadline > clock {
+                    break;
+                }
+                resend_timers.pop();
+                due.push((seq, Some(xmit)));
+            }
+
+            // Retransmits are paced by the congestion window, the rest
+            // wait for the next round.
+            let mut due = due.into_iter();
+            while let Some((seq, timer)) = due.next() {
+                let packet = match send_queue.binary_search_by(|p| serial::diff(p.seq, seq).cmp(&0))
+                {
+                    Ok(i) => &mut send_queue[i],
+                    Err(_) => continue,
+                };
+
+                let timeout = match timer {
+                    // Timers of earlier transmissions are stale.
+                    Some(xmit) if xmit != packet.xmit => continue,
+                    Some(xmit) => {
+                        let wait = self.packet_rto(rto, packet.timeouts);
+                        let interval = now.wrapping_sub(packet.timestamp);
+                        if interval < wait {
+                            let deadline = clock + (wait - interval) as u64;
+                            resend_timers.push(Reverse((deadline, seq, xmit)));
+                            continue;
+                        }
+                        true
+                    }
+                    None => false,
+                };
+                let fast = !timeout
+                    && threshold > 0
+                    && !packet.fast_resent
+                    && packet.dup_acks >= threshold;
+                if !timeout && !fast {
+                    continue;
+                }
+
+                if timeout && max_xmit > 0 && packet.xmit >= max_xmit {
+                    error!(
+                        "ucp packet resent {} times, remote address: {}, session: {}",
+                        packet.xmit,
+                        self.remote_addr.get(),
+                        self.session_id.get()
+                    );
+                    self.die();
+                    return;
+                }
+
+                if resend.len() >= limit || !self.take_send_tokens(packet.packet_size()) {
+                    for (seq, timer) in std::iter::once((seq, timer)).chain(due) {
+                        match timer {
+                            Some(xmit) => resend_timers.push(Reverse((clock, seq, xmit))),
+                            None => fast_resends.push(seq),
+                        }
+                    }
+                    break;
+                }
+
+                if fast {
+                    self.fast_resent_packets
+                        .set(self.fast_resent_packets.get() + 1);
+                }
+
+                if timeout {
+                    timeouts = true;
+                    packet.timeouts += 1;
+                    blackhole = blackhole || self.is_blackhole_suspected(packet, now);
+                }
+
+                packet.dup_acks = 0;
+                packet.fast_resent = fast;
+                packet.window = self.local_window.get();
+                packet.una = una;
+                packet.timestamp = now;
+                packet.xmit += 1;
+
+                let deadline = clock + self.packet_rto(rto, packet.timeouts) as u64;
+                resend_timers.push(Reverse((deadline, seq, packet.xmit)));
+
+                if packet.xmit == 1 {
+                    self.lost_packets.set(self.lost_packets.get() + 1);
+                }
+                self.resent_packets.set(self.resent_packets.get() + 1);
+
+                resend.push(packet.clone());
+            }
+
+            // Timers of acked packets go once they outnumber the rest.
+            if resend_timers.len() > send_queue.len() * 2 + MIN_STALE_TIMERS {
+                resend_timers.retain(|&Reverse((_, seq, xmit))| {
+                    send_queue
+                        .binary_search_by(|p| serial::diff(p.seq, seq).cmp(&0))
+                        .is_ok_and(|i| send_queue[i].xmit == xmit)
+                });
+            }
+        }
+
+        // Resends spend the pacing credit new data would.
+        self.round_resends.set(resend.len());
+        if !resend.is_empty() {
+            congestion.on_loss(now, self.srtt.get());
+            let credit = self.pacing_credit.get() - resend.len() as f64;
+            self.pacing_credit.set(credit);
+        }
+
+        if timeouts {
+            self.backoff_rto();
+        }
+
+        if blackhole {
+            self.reduce_packet_size();
+        }
+
+        for packet in resend.iter_mut() {
+            self.send_packet_directly(packet).await;
+        }
+    }
+
+    fn packet_rto(&self, rto: u32, timeouts: u32) -> u32 {
+        let backoff = 1u32 << timeouts.min(MAX_PACKET_BACKOFF);
+        rto.max(self.clamp_rto(rto.saturating_mul(backoff)))
+    }
+
+    // Large packets keep timing out while nothing large was acked for
+    // several RTOs, yet the peer is still heard from: the path drops
+    // datagrams above some size.
+    fn is_blackhole_suspected(&self, packet: &UcpPacket, now: u32) -> bool {
+        let rto = self.base_rto();
+        let heard = (Instant::now() - self.alive_time.get()).as_millis()
+            < self.heartbeat_interval.get() as u128 * 2;
+        let quiet =
+            now.wrapping_sub(self.last_large_ack.get()) > rto.saturating_mul(BLACKHOLE_TIMEOUTS);
+        let smaller = UCP_PACKET_SIZE_STEPS
+            .iter()
+            .any(|&size| size < packet.packet_size());
+
+        packet.timeouts >= BLACKHOLE_TIMEOUTS && heard && quiet && smaller
+    }
+
+    fn reduce_packet_size(&self) {
+        let current = self.packet_size.get();
+        let size = match UCP_PACKET_SIZE_STEPS.iter().find(|&&size| size < current) {
+            Some(&size) => size,
+            None => return,
+        };
+
+        error!(
+            "ucp path mtu blackhole suspected, remote address: {}, session: {}, packet size {} -> {}",
+            self.remote_addr.get(),
+            self.session_id.get(),
+            current,
+            size
+        );
+
+        self.packet_size.set(size);
+        self.restart_pmtu_search(current);
+
+        // The timeouts came from the packet size, not from congestion.
+        if self.rtt_sampled.get() {
+            self.reset_rto();
+        }
+
+        let send_queue = unsafe { &mut *self.send_queue.as_ptr() };
+        for packet in send_queue.iter_mut() {
+            packet.timeouts = 0;
+        }
+
+        self.resegment_send_buffer();
+    }
+
+    // Searches packet sizes between the current one and `high`, which
+    // is known to fail or just above the configured maximum.
+    fn restart_pmtu_search(&self, high: usize) {
+        self.pmtu_low.set(self.packet_size.get());
+        self.pmtu_high.set(high);
+        self.pmtu_search_time.set(Instant::now());
+        self.mtu_probe.set(None);
+    }
+
+    // Binary search with padded probes that are never fragmented, one
+    // at a time. A probe unanswered after several RTOs marks its size as
+    // too large, the search restarts from time to time to notice a path
+    // that got a larger MTU.
+    async fn probe_path_mtu(&self) {
+        if !matches!(self.state.get(), UcpState::ESTABLISHED) {
+            return;
+        }
+
+        let now = self.timestamp();
+        let size = match self.mtu_probe.get() {
+            Some(probe) if now.wrapping_sub(probe.timestamp) < self.rto.get() => return,
+
+            Some(probe) if probe.attempts < PMTU_PROBE_ATTEMPTS => {
+                self.mtu_probe.set(Some(MtuProbe {
+                    size: probe.size,
+                    timestamp: now,
+                    attempts: probe.attempts + 1,
+                }));
+                probe.size
+            }
+
+            probe => {
+                if let Some(probe) = probe {
+                    self.pmtu_high.set(probe.size);
+                    self.mtu_probe.set(None);
+                }
+
+                let searched = self.pmtu_high.get() - self.pmtu_low.get() <= PMTU_SEARCH_PRECISION;
+                if searched {
+                    let elapsed = (Instant::now() - self.pmtu_search_time.get()).as_millis();
+                    if elapsed < PMTU_RAISE_INTERVAL_MILLIS
+                        || self.packet_size.get() >= self.max_packet_size.get()
+                    {
+                        return;
+                    }
+
+                    self.restart_pmtu_search(self.max_packet_size.get() + 1);
+                }
+
+                let size = (self.pmtu_low.get() + self.pmtu_high.get()) / 2;
+                self.mtu_probe.set(Some(MtuProbe {
+                    size,
+                    timestamp: now,
+                    attempts: 1,
+                }));
+                size
+            }
+        };
+
+        let mut probe = self.new_noseq_packet_with_size(CMD_MTU_PROBE, size);
+        probe.payload_write_u32(size as u32);
+        let padding = probe.remaining_load();
+        probe.payload_write_slice(&vec![0; padding]);
+        self.send_packet_directly(&mut probe).await;
+    }
+
+    async fn process_mtu_probe(&self, packet: Box<UcpPacket>) {
+        let mut ack = self.new_noseq_packet(CMD_MTU_PROBE_ACK);
+        ack.payload_write_u32((packet.size + self.auth_overhead()) as u32);
+        self.send_packet_directly(&mut ack).await;
+    }
+
+    fn process_mtu_probe_ack(&self, mut packet: Box<UcpPacket>) {
+        if packet.payload != 4 {
+            return;
+        }
+
+        let size = packet.payload_read_u32() as usize;
+        match self.mtu_probe.get() {
+            Some(probe) if probe.size == size => self.mtu_probe.set(None),
+            _ => return,
+        }
+
+        self.pmtu_low.set(self.pmtu_low.get().max(size));
+        if size > self.packet_size.get() {
+            info!(
+                "ucp path mtu probed, remote address: {}, session: {}, packet size {} -> {}",
+                self.remote_addr.get(),
+                self.session_id.get(),
+                self.packet_size.get(),
+                size
+            );
+            self.packet_size.set(size);
+        }
+    }
+
+    // Packets not sent yet are unknown to the peer, so they can be cut
+    // again with fresh sequence numbers.
+    fn resegment_send_buffer(&self) {
+        let send_buffer = unsafe { &mut *self.send_buffer.as_ptr() };
+        if self.channels.get() || send_buffer.iter().any(|packet| packet.cmd != CMD_DATA) {
+            return;
+        }
+
+        let first_seq = match send_buffer.front() {
+            Some(packet) => packet.seq,
+            None => return,
+        };
+
+        let mut data = Vec::new();
+        for packet in send_buffer.iter() {
+            let start = UCP_PACKET_META_SIZE;
+            data.extend_from_slice(&packet.buf[start..start + packet.payload as usize]);
+        }
+
+        send_buffer.clear();
+        self.seq.set(first_seq.wrapping_sub(1));
+        self.make_packet_send(&data);
+    }
+
+    async fn send_pending_packets(&self) {
+        let now = self.timestamp();
+        let una = self.una.get();
+        let remote_window = self.remote_window.get();
+        let congestion = unsafe { &*self.congestion.as_ptr() };
+        let window = min(remote_window, congestion.window()) as usize;
+        let budget = self.pacing_budget(window);
+        let deadline = self.clock() + self.packet_rto(self.rto.get(), 0) as u64;
+        let mut pending = Vec::new();
+        let mut parities = Vec::new();
+
+        {
+            let send_queue = unsafe { &mut *self.send_queue.as_ptr() };
+            let send_buffer = unsafe { &mut *self.send_buffer.as_ptr() };
+            let resend_timers = unsafe { &mut *self.resend_timers.as_ptr() };
+
+            while send_queue.len() < window && pending.len() < budget {
+                if let Some(q) = send_queue.front() {
+                    if let Some(p) = send_buffer.front() {
+                        let seq_diff = p.seq.wrapping_sub(q.seq) as usize;
+                        if seq_diff >= window {
+                            break;
+                        }
+                    }
+                }
+
+                let size = send_buffer.front().map_or(0, |p| p.packet_size());
+                if size > 0 && !self.take_send_tokens(size) {
+                    break;
+                }
+
+                if let Some(mut packet) = send_buffer.pop_front() {
+                    packet.window = self.local_window.get();
+                    packet.una = una;
+                    packet.timestamp = now;
+                    self.sent_packets.set(self.sent_packets.get() + 1);
+
+                    if let Some(parity) = self.fec_encode(&packet) {
+                        parities.push(parity);
+                    }
+
+                    pending.push(packet.clone());
+                    resend_timers.push(Reverse((deadline, packet.seq, 0)));
+                    send_queue.push_back(packet);
+                } else {
+                    break;
+                }
+            }
+        }
+
+        let waiting = !unsafe { &*self.send_buffer.as_ptr() }.is_empty();
+        if self.round_resends.get() > 0 && pending.len() >= budget && waiting {
+            self.crowded_rounds.set(self.crowded_rounds.get() + 1);
+        }
+
+        for packet in pending.iter_mut() {
+            self.send_packet_directly(packet).await;
+        }
+
+        for packet in parities.iter_mut() {
+            self.spend_send_tokens(packet.packet_size());
+            self.send_packet_directly(packet).await;
+        }
+
+        let credit = self.pacing_credit.get() - pending.len() as f64;
+        self.pacing_credit.set(credit.max(0.0));
+
+        self.try_wake_writer();
+    }
+
+    // Parity packets follow each group of data packets when the peer
+    // agrees to the requested group size.
+    // New packets leave at the estimated bandwidth times the pacing gain
+    // instead of a whole window per tick, the bandwidth being the one
+    // measured by the congestion control or else the window per RTT.
+    // Unused credit is kept for at most a short burst.
+    fn pacing_budget(&self, window: usize) -> usize {
+        let gain = self.pacing_gain.get();
+        if gain <= 0.0 || !self.rtt_sampled.get() {
+            return usize::MAX;
+        }
+
+        let now = self.timestamp();
+        let elapsed = now.wrapping_sub(self.pacing_time.get());
+        self.pacing_time.set(now);
+
+        let congestion = unsafe { &*self.congestion.as_ptr() };
+        let srtt = self.srtt.get().max(1) as f64;
+        let bandwidth = congestion.bandwidth().unwrap_or(window as f64 / srtt);
+
+        // While the peer sees our packets queue for a good part of the
+        // round trip, the gain above 1 only grows the queue.
+        let queueing = self.queue_delay.get() as f64 * 4.0 > srtt;
+        let rate = bandwidth * if queueing { gain.min(1.0) } else { gain };
+
+        let burst = (rate * PACING_MAX_BURST_MILLIS).max(PACING_MIN_BURST);
+        let credit = (self.pacing_credit.get() + rate * elapsed as f64).min(burst);
+        self.pacing_credit.set(credit);
+
+        credit as usize
+    }
+
+    // Tokens of the send rate limit accrue at the rate, up to a short
+    // burst though never less than a packet, and every data packet sent
+    // or resent takes its size. Parity packets may overdraw them.
+    fn refill_send_tokens(&self) {
+        let now = self.timestamp();
+        let elapsed = now.wrapping_sub(self.send_tokens_time.get());
+        self.send_tokens_time.set(now);
+
+        let rate = self.max_send_rate.get() as f64 / 1000.0;
+        if rate <= 0.0 {
+            return;
+        }
+
+        let burst = (rate * RATE_LIMIT_BURST_MILLIS).max(self.packet_size.get() as f64);
+        let tokens = (self.send_tokens.get() + rate * elapsed as f64).min(burst);
+        self.send_tokens.set(tokens);
+    }
+
+    fn take_send_tokens(&self, size: usize) -> bool {
+        if self.max_send_rate.get() == 0 {
+            return true;
+        }
+
+        if self.send_tokens.get() < size as f64 {
+            return false;
+        }
+
+        self.spend_send_tokens(size);
+        true
+    }
+
+    fn spend_send_tokens(&self, size: usize) {
+        if self.max_send_rate.get() > 0 {
+            self.send_tokens.set(self.send_tokens.get() - size as f64);
+        }
+    }
+
+    fn set_max_send_rate(&self, bytes_per_sec: u64) {
+        let _l = self.lock();
+        self.max_send_rate.set(bytes_per_sec);
+    }
+
+    fn connecting(&self, config: &UcpConfig, ticket: Option<&ResumeTicket>) {
+        self.state.set(UcpState::CONNECTING);
+        self.session_id.set(random::<u32>());
+        if config.adaptive_heartbeat {
+            self.nat_probe.set(Some(NatProbe::default()));
+        }
+
+        // The FEC group size, a salt asking for encryption or 0, the
+        // heartbeat interval and broken timeout to agree on, then the
+        // features asked for with our version and a ticket of the last
+        // session.
+        if config.encrypt && self.auth_key.is_some() {
+            self.cipher_salt.set(random::<u32>() | 1);
+        }
+
+        let fec_group = config.fec_group.min(MAX_FEC_GROUP);
+        let mut syn = self.new_packet(CMD_SYN);
+        syn.payload_write_u32(fec_group);
+        syn.payload_write_u32(self.cipher_salt.get());
+        syn.payload_write_u32(self.heartbeat_interval.get());
+        syn.payload_write_u32(self.broken_timeout.get());
+        let mut features = 0;
+        if config.channels {
+            features |= FEATURE_CHANNELS;
+        }
+        if config.datagrams {
+            features |= FEATURE_DATAGRAMS;
+        }
+        if config.resume {
+            features |= FEATURE_RESUME;
+        }
+        if config.probes {
+            features |= FEATURE_PROBES;
+        }
+        if config.keyed_check && self.auth_key.is_some() {
+            features |= FEATURE_KEYED_CHECK;
+        }
+        if config.loss_reports {
+            features |= FEATURE_LOSS_REPORTS;
+        }
+        syn.payload_write_u32(features | PROTOCOL_VERSION << VERSION_SHIFT);
+        self.features_asked.set(features);
+        self.fec_asked.set(fec_group);
+
+        let encrypt = self.cipher_salt.get() != 0;
+        let ticket = ticket.filter(|ticket| {
+            ticket.server == self.remote_addr.get()
+                && ticket.issued.elapsed().as_secs() < TICKET_LIFETIME_SECS
+                && (ticket.features, ticket.fec_group, ticket.encrypt)
+                    == (features, fec_group, encrypt)
+        });
+        if let Some(ticket) = ticket {
+            syn.payload_write_slice(&ticket.ticket);
+            self.resume(Grant::read(&ticket.ticket));
+        }
+
+        self.syn_payload.set(syn.payload);
+        self.send_packet(syn);
+        info!(
+            "{} ucp server {}, session: {}",
+            if ticket.is_some() {
+                "resuming"
+            } else {
+                "connecting"
+            },
+            self.remote_addr.get(),
+            self.session_id.get()
+        );
+    }
+
+    // Starts with what the last session was granted, so data goes out
+    // along with the SYN.
+    fn resume(&self, grant: Grant) {
+        self.resuming.set(Some(grant));
+        if grant.fec_group > 0 {
+            self.enable_fec(grant.fec_group);
+        }
+        if grant.server_salt != 0 {
+            self.enable_cipher(self.cipher_salt.get(), grant.server_salt, true);
+        }
+        self.enable_features(grant.features);
+    }
+
+    fn enable_features(&self, features: u32) {
+        self.features.set(features);
+        if features & FEATURE_CHANNELS != 0 {
+            self.enable_channels();
+        }
+        if features & FEATURE_DATAGRAMS != 0 {
+            self.datagrams.set(true);
+        }
+        if features & FEATURE_PROBES != 0 {
+            self.probes.set(true);
+        }
+        if features & FEATURE_KEYED_CHECK != 0 {
+            self.keyed_check.set(true);
+        }
+        if features & FEATURE_LOSS_REPORTS != 0 {
+            self.loss_reports.set(true);
+        }
+    }
+
+    fn accepting(&self, mut packet: Box<UcpPacket>) {
+        self.state.set(UcpState::ACCEPTING);
+        self.session_id.set(packet.session_id);
+        self.una.set(packet.seq.wrapping_add(1));
+        self.remote_window.set(packet.window);
+
+        let fec_group = if packet.payload >= 4 {
+            packet.payload_read_u32().min(self.max_fec_group.get())
+        } else {
+            0
+        };
+
+        let client_salt = if packet.payload >= 8 {
+            packet.payload_read_u32()
+        } else {
+            0
+        };
+
+        // Clients from before liveness agreement keep our settings.
+        let liveness = packet.payload >= 16;
+        if liveness {
+            let heartbeat_interval = packet.payload_read_u32();
+            let broken_timeout = packet.payload_read_u32();
+            self.agree_liveness(heartbeat_interval, broken_timeout);
+        }
+
+        // Every feature a client asks for is granted, keyed checks only
+        // with a key.
+        let features = if packet.payload >= 20 {
+            let word = packet.payload_read_u32();
+            self.peer_version.set(word >> VERSION_SHIFT);
+            let mut features = word & SUPPORTED_FEATURES;
+            if self.auth_key.is_none() {
+                features &= !FEATURE_KEYED_CHECK;
+            }
+            Some(features)
+        } else {
+            None
+        };
+
+        // A cookie may follow the ticket, see syn_cookie.
+        let rest = (packet.payload as usize).saturating_sub(20);
+        let ticket = if rest == TICKET_SIZE || rest == TICKET_SIZE + COOKIE_SIZE {
+            let mut ticket = vec![0; TICKET_SIZE];
+            packet.payload_read_slice(&mut ticket);
+            Some(ticket)
+        } else {
+            None
+        };
+        self.enable_features(features.unwrap_or(0));
+
+        // A resumed session is established with the SYN, when it is
+        // granted the same as the session the ticket came from.
+        let encrypt = client_salt != 0 && self.auth_key.is_some();
+        let resumed = ticket
+            .and_then(|ticket| self.tickets.as_ref()?.redeem(&ticket))
+            .filter(|grant| {
+                (grant.features, grant.fec_group, grant.server_salt != 0)
+                    == (features.unwrap_or(0), fec_group, encrypt)
+            });
+
+        let server_salt = match resumed {
+            Some(grant) => grant.server_salt,
+            None if encrypt => random::<u32>() | 1,
+            None => 0,
+        };
+
+        let mut syn_ack = self.new_packet(CMD_SYN_ACK);
+        syn_ack.payload_write_u32(packet.seq);
+        syn_ack.payload_write_u32(packet.timestamp);
+        if fec_group > 0 || server_salt != 0 || liveness {
+            syn_ack.payload_write_u32(fec_group);
+        }
+        if fec_group > 0 {
+            self.enable_fec(fec_group);
+        }
+        if server_salt != 0 || liveness {
+            syn_ack.payload_write_u32(server_salt);
+        }
+        if server_salt != 0 {
+            self.enable_cipher(client_salt, server_salt, false);
+        }
+        if liveness {
+            syn_ack.payload_write_u32(self.heartbeat_interval.get());
+            syn_ack.payload_write_u32(self.broken_timeout.get());
+        }
+        if let Some(features) = features {
+            syn_ack.payload_write_u32(features | PROTOCOL_VERSION << VERSION_SHIFT);
+        }
+        if let Some(tickets) = self.tickets.as_ref() {
+            let features = features.unwrap_or(0);
+            if features & FEATURE_RESUME != 0 {
+                // The resumed session gets a salt of its own.
+                syn_ack.payload_write_slice(&tickets.issue(Grant {
+                    features,
+                    fec_group,
+                    server_salt: if encrypt { random::<u32>() | 1 } else { 0 },
+                }));
+            }
+        }
+        self.send_packet(syn_ack);
+
+        if resumed.is_some() {
+            self.state.set(UcpState::ESTABLISHED);
+        }
+        info!(
+            "{} ucp client {}, session: {}, version: {}",
+            if resumed.is_some() {
+                "resumed"
+            } else {
+                "accepting"
+            },
+            self.remote_addr.get(),
+            self.session_id.get(),
+            self.peer_version.get()
+        );
+    }
+
+    async fn processing(&self, packet: Box<UcpPacket>) {
+        if self.session_id.get() != packet.session_id {
+            error!(
+                "unexpect session_id: {}, expect {}",
+                packet.session_id,
+                self.session_id.get()
+            );
+            return;
+        }
+
+        self.alive_time.set(Instant::now());
+        self.remote_window.set(packet.window);
+        let received = self.received_bytes.get();
+        self.received_bytes
+            .set(received.wrapping_add(packet.size as u32));
+
+        if packet.ce {
+            self.ce_received.set(self.ce_received.get().wrapping_add(1));
+            self.ack_now.set(true);
+        }
+
+        let state = self.state.get();
+        match state {
+            UcpState::ACCEPTING => {
+                self.process_state_accepting(packet);
+            }
+            UcpState::CONNECTING => {
+                self.process_state_connecting(packet).await;
+            }
+            UcpState::ESTABLISHED | UcpState::FIN_WAIT => {
+                self.process_state_established(packet).await;
+            }
+            UcpState::CLOSED => {
+                self.process_state_closed(packet).await;
+            }
+            UcpState::NONE => {}
+        }
+    }
+
+    fn process_state_accepting(&self, mut packet: Box<UcpPacket>) {
+        if packet.cmd == CMD_ACK && packet.payload == 8 {
+            let seq = packet.payload_read_u32();
+            let timestamp = packet.payload_read_u32();
+
+            if self.process_an_ack(seq, timestamp) {
+                self.state.set(UcpState::ESTABLISHED);
+                self.wake_state();
+                info!(
+                    "{} established, session: {}",
+                    self.remote_addr.get(),
+                    self.session_id.get()
+                );
+            }
+        }
+    }
+
+    // Packets of the session from another address, after the peer's NAT
+    // mapping changed. The address has to echo a challenge before the
+    // session moves there, other packets from it are dropped meanwhile.
+    async fn migrating(&self, mut packet: Box<UcpPacket>, remote_addr: SocketAddr) {
+        let established = matches!(self.state.get(), UcpState::ESTABLISHED | UcpState::FIN_WAIT);
+
+        if !established || packet.session_id != self.session_id.get() {
+            error!(
+                "unexpect packet from {}, expect from {}",
+                remote_addr,
+                self.remote_addr.get()
+            );
+            return;
+        }
+
+        let challenge = self.path_challenge.get();
+        if packet.cmd == CMD_PATH_RESPONSE {
+            if packet.payload != 4 {
+                return;
+            }
+
+            let token = packet.payload_read_u32();
+            if let Some(challenge) = challenge {
+                if challenge.addr == remote_addr && challenge.token == token {
+                    info!(
+                        "{} migrated to {}, session: {}",
+                        self.remote_addr.get(),
+                        remote_addr,
+                        self.session_id.get()
+                    );
+
+                    self.remote_addr.set(remote_addr);
+                    self.path_challenge.set(None);
+                    self.alive_time.set(Instant::now());
+                }
+            }
+            return;
+        }
+
+        let now = Instant::now();
+        let due = match challenge {
+            Some(challenge) if challenge.addr == remote_addr => {
+                (now - challenge.time).as_millis() >= self.rto.get() as u128
+            }
+            _ => true,
+        };
+
+        if due {
+            let token = random::<u32>();
+            let mut packet = self.new_noseq_packet(CMD_PATH_CHALLENGE);
+            packet.payload_write_u32(token);
+            self.send_datagram(&mut packet, remote_addr).await;
+
+            self.path_challenge.set(Some(PathChallenge {
+                addr: remote_addr,
+                token,
+                time: now,
+            }));
+        }
+    }
+
+    // Our FIN_ACK may have been lost, the peer resends its FIN.
+    // The listener knows no session for a packet of ours, it restarted
+    // or dropped the session, so waiting for the broken timeout is
+    // pointless. With a key the reset has to echo the counter of a packet
+    // sent lately, as the session id of an encrypted one is unreadable
+    // to the listener, which also keeps replayed resets out. Resets of a
+    // connecting stream are ignored, its data may overtake the SYN and
+    // the SYN is resent anyway.
+    fn process_reset(&self, mut packet: Box<UcpPacket>, remote_addr: SocketAddr) {
+        let state = self.state.get();
+        if remote_addr != self.remote_addr.get()
+            || matches!(
+                state,
+                UcpState::NONE | UcpState::CONNECTING | UcpState::CLOSED
+            )
+            || packet.payload != 4
+        {
+            return;
+        }
+
+        let echoed = packet.payload_read_u32();
+        let valid = if self.auth_key.is_some() {
+            let behind = self.auth_counter.get().wrapping_sub(echoed);
+            behind > 0 && behind <= RESET_COUNTER_WINDOW
+        } else {
+            packet.session_id == self.session_id.get()
+        };
+        if !valid {
+            return;
+        }
+
+        error!(
+            "ucp session {} reset by {}",
+            self.session_id.get(),
+            remote_addr
+        );
+        self.state.set(UcpState::CLOSED);
+        self.die();
+    }
+
+    async fn process_state_closed(&self, packet: Box<UcpPacket>) {
+        if packet.cmd == CMD_FIN {
+            let mut fin_ack = self.new_noseq_packet(CMD_FIN_ACK);
+            self.send_packet_directly(&mut fin_ack).await;
+        }
+    }
+
+    async fn process_state_connecting(&self, packet: Box<UcpPacket>) {
+        if packet.cmd == CMD_SYN_COOKIE {
+            self.process_syn_cookie(packet).await;
+        } else {
+            self.process_syn_ack(packet).await;
+        }
+    }
+
+    // The server has too many handshakes open and answered with a cookie
+    // instead of a SYN_ACK, the SYN goes again echoing it.
+    async fn process_syn_cookie(&self, mut packet: Box<UcpPacket>) {
+        if packet.payload as usize != COOKIE_SIZE {
+            return;
+        }
+
+        let mut cookie = [0u8; COOKIE_SIZE];
+        packet.payload_read_slice(&mut cookie);
+
+        let send_queue = unsafe { &mut *self.send_queue.as_ptr() };
+        let syn = match send_queue.iter_mut().find(|p| p.cmd == CMD_SYN) {
+            Some(syn) => syn,
+            None => return,
+        };
+
+        syn.payload = self.syn_payload.get();
+        syn.payload_write_slice(&cookie);
+        let mut syn = syn.clone();
+        self.send_packet_directly(&mut syn).await;
+        info!(
+            "{} sent a syn cookie, session: {}",
+            self.remote_addr.get(),
+            self.session_id.get()
+        );
+    }
+
+    async fn process_state_established(&self, packet: Box<UcpPacket>) {
+        self.process_una(packet.una);
+
+        match packet.cmd {
+            CMD_ACK => {
+                self.process_ack(packet);
+            }
+            CMD_SACK => {
+                self.process_sack(packet);
+            }
+            CMD_FRAG => {
+                self.process_fragment(packet);
+            }
+            CMD_FEC => {
+                self.process_fec(packet);
+            }
+            CMD_MTU_PROBE => {
+                self.process_mtu_probe(packet).await;
+            }
+            CMD_MTU_PROBE_ACK => {
+                self.process_mtu_probe_ack(packet);
+            }
+            CMD_DATA => {
+                self.process_data(packet);
+            }
+            CMD_UDATA => {
+                self.process_udata(packet);
+            }
+            CMD_PROBE => {
+                self.process_probe(packet);
+            }
+            CMD_SYN_ACK => {
+                self.process_syn_ack(packet).await;
+            }
+            CMD_HEARTBEAT => {
+                self.process_heartbeat(packet).await;
+            }
+            CMD_HEARTBEAT_ACK => {
+                self.process_heartbeat_ack();
+            }
+            CMD_FIN => {
+                self.process_fin().await;
+            }
+            CMD_PATH_CHALLENGE => {
+                self.process_path_challenge(packet).await;
+            }
+            CMD_FIN_ACK => {
+                self.process_fin_ack();
+            }
+            _ => {}
+        }
+    }
+
+    fn process_una(&self, una: u32) {
+        let send_queue = unsafe { &mut *self.send_queue.as_ptr() };
+        let mut acked = 0;
+
+        while !send_queue.is_empty() {
+            let diff = send_queue
+                .front()
+                .map(|packet| serial::diff(packet.seq, una))
+                .unwrap();
+
+            if diff < 0 {
+                if let Some(packet) = send_queue.pop_front() {
+                    self.note_acked(&packet);
+                }
+                acked += 1;
+            } else {
+                break;
+            }
+        }
+
+        if acked > 0 {
+            let now = self.timestamp();
+            let srtt = self.srtt.get();
+            let congestion = unsafe { &mut *self.congestion.as_ptr() };
+            congestion.on_ack(acked, srtt, now);
+        }
+    }
+
+    // The ack of the SYN_ACK comes without a loss report.
+    fn process_ack(&self, mut packet: Box<UcpPacket>) {
+        if self.loss_reports.get() && packet.payload >= 12 && packet.payload % 8 == 4 {
+            let ce_count = packet.payload_read_u32();
+            self.process_ecn_echo(ce_count);
+            let highest = packet.payload_read_u32();
+            let holes = packet.payload_read_u32();
+            self.process_loss_report(highest, holes);
+        } else if packet.cmd == CMD_ACK && packet.payload % 8 == 4 {
+            let ce_count = packet.payload_read_u32();
+            self.process_ecn_echo(ce_count);
+        }
+
+        if packet.cmd == CMD_ACK && packet.payload_remaining().is_multiple_of(8) {
+            while packet.payload_remaining() > 0 {
+                let seq = packet.payload_read_u32();
+                let timestamp = packet.payload_read_u32();
+                self.process_an_ack(seq, timestamp);
+            }
+        }
+    }
+
+    // Holes in what we sent that the peer's highest packet can't account
+    // for are lost or still on the way.
+    fn process_loss_report(&self, highest: u32, holes: u32) {
+        if !serial::before(highest, self.seq.get()) {
+            return;
+        }
+
+        self.peer_recv_holes.set(holes);
+
+        let now = self.timestamp();
+        let congestion = unsafe { &mut *self.congestion.as_ptr() };
+        congestion.on_loss_report(holes, now, self.srtt.get());
+    }
+
+    // The count only grows, a reordered ack with an older count is no news.
+    fn process_ecn_echo(&self, ce_count: u32) {
+        let marks = serial::diff(ce_count, self.ce_echoed.get());
+        if marks <= 0 {
+            return;
+        }
+
+        self.ce_echoed.set(ce_count);
+        self.ecn_marks.set(self.ecn_marks.get() + marks as u64);
+
+        let now = self.timestamp();
+        let congestion = unsafe { &mut *self.congestion.as_ptr() };
+        congestion.on_ecn(now, self.srtt.get());
+    }
+
+    fn process_sack(&self, mut packet: Box<UcpPacket>) {
+        if !packet.payload.is_multiple_of(8) {
+            return;
+        }
+
+        let mut blocks = Vec::new();
+        while packet.payload_remaining() > 0 {
+            let start = packet.payload_read_u32();
+            let end = packet.payload_read_u32();
+            blocks.push((start, end));
+        }
+
+        let send_queue = unsafe { &mut *self.send_queue.as_ptr() };
+        let before = send_queue.len();
+
+        send_queue.retain(|p| {
+            let sacked = blocks
+                .iter()
+                .any(|&(start, end)| p.seq.wrapping_sub(start) < end.wrapping_sub(start));
+            if sacked {
+                self.note_acked(p);
+            }
+            !sacked
+        });
+
+        let sacked = (before - send_queue.len()) as u32;
+        if sacked > 0 {
+            let now = self.timestamp();
+            let srtt = self.srtt.get();
+            let congestion = unsafe { &mut *self.congestion.as_ptr() };
+            congestion.on_ack(sacked, srtt, now);
+        }
+    }
+
+    fn note_acked(&self, packet: &UcpPacket) {
+        if packet.packet_size() > UCP_PACKET_SIZE_STEPS[UCP_PACKET_SIZE_STEPS.len() - 1] {
+            self.last_large_ack.set(self.timestamp());
+        }
+    }
+
+    fn process_fragment(&self, mut packet: Box<UcpPacket>) {
+        if packet.payload < 4 {
+            return;
+        }
+
+        let offset = packet.payload_read_u16() as usize;
+        let total = packet.payload_read_u16() as usize;
+        let end = offset + packet.payload_remaining();
+        if end > total || total > UCP_MAX_PACKET_SIZE - UCP_PACKET_META_SIZE {
+            return;
+        }
+
+        // All but the last fragment are as large as the peer's packet
+        // size, which also bounds what we send back on this path. They
+        // are cut at multiples of their load, and no smaller than the
+        // smallest packet size, a malformed one leaves it as it is.
+        let size = packet.size + self.auth_overhead();
+        let load = end - offset;
+        if end < total
+            && load > 0
+            && offset % load == 0
+            && size >= UCP_PACKET_SIZE_STEPS[UCP_PACKET_SIZE_STEPS.len() - 1]
+            && size < self.packet_size.get()
+        {
+            info!(
+                "ucp peer reduced packet size, remote address: {}, session: {}, packet size {} -> {}",
+                self.remote_addr.get(),
+                self.session_id.get(),
+                self.packet_size.get(),
+                size
+            );
+            let current = self.packet_size.get();
+            self.packet_size.set(size);
+            self.restart_pmtu_search(current);
+            self.resegment_send_buffer();
+        }
+
+        let una = self.una.get();
+        if serial::before(packet.seq, una) {
+            self.queue_ack(&packet, true);
+            return;
+        }
+
+        let fragments = unsafe { &mut *self.fragments.as_ptr() };
+        fragments.retain(|&seq, _| !serial::before(seq, una));
+
+        // Fragments cut with different packet sizes carry the same bytes
+        // at the same offsets, so they can be combined.
+        let (data, ranges) = fragments
+            .entry(packet.seq)
+            .or_insert_with(|| (vec![0; total], Vec::new()));
+        if data.len() != total {
+            return;
+        }
+
+        packet.payload_read_slice(&mut data[offset..end]);
+        ranges.push((offset, end));
+        ranges.sort_unstable();
+
+        let mut covered = 0;
+        for &(start, end) in ranges.iter() {
+            if start > covered {
+                return;
+            }
+            covered = covered.max(end);
+        }
+
+        if covered < total {
+            return;
+        }
+
+        let (data, _) = fragments.remove(&packet.seq).unwrap();
+        let mut whole = Box::new(UcpPacket::with_size(data.len() + UCP_PACKET_META_SIZE));
+        whole.payload_write_slice(&data);
+
+        whole.size = whole.packet_size();
+        whole.read_pos = UCP_PACKET_META_SIZE;
+        whole.session_id = packet.session_id;
+        whole.timestamp = packet.timestamp;
+        whole.window = packet.window;
+        whole.xmit = packet.xmit;
+        whole.una = packet.una;
+        whole.seq = packet.seq;
+        whole.cmd = CMD_DATA;
+
+        self.process_data(whole);
+    }
+
+    // A duplicate, a packet above a gap and one that fills a gap are
+    // acked right away, so the sender learns of the loss quickly.
+    fn process_data(&self, mut packet: Box<UcpPacket>) {
+        let una = self.una.get();
+        let una_diff = serial::diff(packet.seq, una);
+        let recv_queue = unsafe { &mut *self.recv_queue.as_ptr() };
+
+        let queued = una_diff < 0 || recv_queue.iter().any(|p| p.seq == packet.seq);
+        if !queued && !self.hold_recv_memory(&packet, una_diff == 0) {
+            return;
+        }
+
+        self.queue_ack(&packet, packet.seq != una);
+        if una_diff < 0 {
+            return;
+        }
 
-            packet.payload_write_u32(seq);
-            packet.payload_write_u32(timestamp);
+        if self
+            .recv_highest
+            .get()
+            .is_none_or(|highest| serial::before(highest, packet.seq))
+        {
+            self.recv_highest.set(Some(packet.seq));
         }
 
-        self.send_packet_directly(&mut packet).await;
+        let mut pos = 0;
+        for i in 0..recv_queue.len() {
+            let seq_diff = serial::diff(packet.seq, recv_queue[i].seq);
+
+            if seq_diff == 0 {
+                return;
+            } else if seq_diff < 0 {
+                break;
+            } else {
+                pos += 1;
+            }
+        }
+
+        if self.fec_group.get() > 0 {
+            let start = UCP_PACKET_META_SIZE;
+            let fec_cache = unsafe { &mut *self.fec_cache.as_ptr() };
+            fec_cache.insert(
+                packet.seq,
+                &packet.buf[start..start + packet.payload as usize],
+            );
+        }
+
+        if self.channels.get() {
+            self.deliver_to_channel(&mut packet);
+        } else {
+            self.recv_bytes
+                .set(self.recv_bytes.get() + packet.payload_remaining());
+        }
+        recv_queue.insert(pos, packet);
+        self.update_local_window();
+
+        for i in pos..recv_queue.len() {
+            let una = self.una.get();
+            if recv_queue[i].seq == una {
+                self.una.set(una.wrapping_add(1));
+            } else {
+                break;
+            }
+        }
+
+        if self.una.get().wrapping_sub(una) > 1 {
+            self.ack_now.set(true);
+        }
+
+        // Channel data was delivered already, the queue only holds what
+        // is above a gap.
+        if self.channels.get() {
+            while recv_queue
+                .front()
+                .is_some_and(|packet| serial::before(packet.seq, self.una.get()))
+            {
+                if let Some(packet) = recv_queue.pop_front() {
+                    self.release_recv_memory(&packet);
+                }
+            }
+            self.update_local_window();
+            return;
+        }
+
+        self.try_wake_reader();
     }
 
-    async fn timeout_resend(&self) {
-        let now = self.timestamp();
-        let una = self.una.get();
-        let rto = self.rto.get();
-        let mut resend = Vec::new();
+    // Queued packets were acked already and the sender let go of them,
+    // so what doesn't fit a budget is the packet arriving. It is dropped
+    // unacked and resent later, and as the packet at una always gets in
+    // and frees memory once read, the packets dropped are the ones
+    // furthest ahead of the reader.
+    fn hold_recv_memory(&self, packet: &UcpPacket, always: bool) -> bool {
+        let memory = packet.memory();
+        let held = self.recv_memory.get() + memory;
+        let fits = always || held <= self.recv_memory_limit.get();
+        let taken = fits
+            && self
+                .recv_budget
+                .as_ref()
+                .is_none_or(|budget| budget.take(memory, always));
+
+        if taken {
+            self.recv_memory.set(held);
+        } else {
+            self.recv_dropped_packets
+                .set(self.recv_dropped_packets.get() + 1);
+            budget::count_recv_drop();
+        }
+
+        taken
+    }
+
+    fn release_recv_memory(&self, packet: &UcpPacket) {
+        let memory = packet.memory();
+        self.recv_memory.set(self.recv_memory.get() - memory);
+        if let Some(budget) = self.recv_budget.as_ref() {
+            budget.give(memory);
+        }
+    }
+
+    async fn process_syn_ack(&self, mut packet: Box<UcpPacket>) {
+        let ticket_size = 28 + TICKET_SIZE as u16;
+        let known_size =
+            matches!(packet.payload, 8 | 12 | 16 | 24 | 28) || packet.payload == ticket_size;
+        if packet.cmd == CMD_SYN_ACK && known_size {
+            let seq = packet.payload_read_u32();
+            let timestamp = packet.payload_read_u32();
+            let fec_group = if packet.payload >= 12 {
+                packet.payload_read_u32().min(MAX_FEC_GROUP)
+            } else {
+                0
+            };
+            let server_salt = if packet.payload >= 16 {
+                packet.payload_read_u32()
+            } else {
+                0
+            };
+            let liveness = if packet.payload >= 24 {
+                Some((packet.payload_read_u32(), packet.payload_read_u32()))
+            } else {
+                None
+            };
+            let features = if packet.payload >= 28 {
+                packet.payload_read_u32()
+            } else {
+                0
+            };
+            let ticket = if packet.payload == ticket_size {
+                let mut ticket = vec![0; TICKET_SIZE];
+                packet.payload_read_slice(&mut ticket);
+                Some(ticket)
+            } else {
+                None
+            };
+
+            // Our ack is the first encrypted packet, unless the session
+            // resumed with the salt the server gave again.
+            let connecting = matches!(self.state.get(), UcpState::CONNECTING);
+            let resumed_salt = self.resuming.get().map(|grant| grant.server_salt);
+            if connecting
+                && server_salt != 0
+                && self.cipher_salt.get() != 0
+                && resumed_salt != Some(server_salt)
+            {
+                self.enable_cipher(self.cipher_salt.get(), server_salt, true);
+            }
 
+            let mut ack = self.new_noseq_packet(CMD_ACK);
+            ack.payload_write_u32(packet.seq);
+            ack.payload_write_u32(packet.timestamp);
+            self.send_packet_directly(&mut ack).await;
+
+            match self.state.get() {
+                UcpState::CONNECTING => {
+                    if self.process_an_ack(seq, timestamp) {
+                        self.state.set(UcpState::ESTABLISHED);
+                        self.wake_state();
+                        self.una.set(packet.seq.wrapping_add(1));
+                        if let Some((heartbeat_interval, broken_timeout)) = liveness {
+                            self.agree_liveness(heartbeat_interval, broken_timeout);
+                        }
+                        self.peer_version.set(features >> VERSION_SHIFT);
+                        let features = features & self.features_asked.get();
+                        match self.resuming.get() {
+                            // Channel and FEC framing of the data sent
+                            // already can't change.
+                            Some(grant)
+                                if (grant.features, grant.fec_group) != (features, fec_group) =>
+                            {
+                                error!(
+                                    "ucp session {} resumed with other grants",
+                                    self.session_id.get()
+                                );
+                                self.die();
+                                return;
+                            }
+                            Some(_) => {}
+                            None => {
+                                if fec_group > 0 {
+                                    self.enable_fec(fec_group);
+                                }
+                                self.enable_features(features);
+                            }
+                        }
+                        if let Some(ticket) = ticket {
+                            self.resume_ticket.set(Some(ResumeTicket {
+                                server: self.remote_addr.get(),
+                                issued: Instant::now(),
+                                features: self.features_asked.get(),
+                                fec_group: self.fec_asked.get(),
+                                encrypt: self.cipher_salt.get() != 0,
+                                ticket,
+                            }));
+                        }
+                        self.try_wake_writer();
+                        info!(
+                            "{} established, session: {}, version: {}",
+                            self.remote_addr.get(),
+                            self.session_id.get(),
+                            self.peer_version.get()
+                        );
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn capabilities(&self) -> UcpCapabilities {
+        let _l = self.lock();
+        let features = self.features.get();
+
+        UcpCapabilities {
+            version: self.peer_version.get().min(PROTOCOL_VERSION),
+            fec_group: self.fec_group.get(),
+            encrypted: unsafe { &*self.cipher.as_ptr() }.is_some(),
+            channels: features & FEATURE_CHANNELS != 0,
+            datagrams: features & FEATURE_DATAGRAMS != 0,
+            resume: features & FEATURE_RESUME != 0,
+            probes: features & FEATURE_PROBES != 0,
+            keyed_check: features & FEATURE_KEYED_CHECK != 0,
+            loss_reports: features & FEATURE_LOSS_REPORTS != 0,
+        }
+    }
+
+    fn enable_cipher(&self, client_salt: u32, server_salt: u32, client: bool) {
+        if let Some(ref auth_key) = self.auth_key {
+            let key = auth_key.session_key(self.session_id.get(), client_salt, server_salt);
+            self.cipher.set(Some(PacketCipher::new(key, client)));
+            info!("ucp session {} encrypted", self.session_id.get());
+        }
+    }
+
+    // Handshake packets arrive in clear, before the session key exists,
+    // and with a CRC32. Packets the peer sent before it learnt of keyed
+    // checks have one too, until a packet with a keyed check arrives.
+    fn decipher(&self, packet: &mut UcpPacket) -> bool {
+        let _l = self.lock();
+        let cipher = unsafe { &*self.cipher.as_ptr() };
+        let keyed = self.keyed_check.get();
+        if cipher.is_none() && !keyed {
+            return true;
+        }
+
+        if packet.size < UCP_PACKET_META_SIZE {
+            return false;
+        }
+
+        let cmd = packet.buf[UCP_PACKET_CMD_OFFSET];
+        let plain = matches!(cmd, CMD_SYN | CMD_SYN_ACK | CMD_SYN_COOKIE | CMD_RESET);
+        if plain && packet.is_crc32_correct() {
+            return true;
+        }
+
+        if let Some(cipher) = cipher {
+            cipher.decrypt(&mut packet.buf[4..packet.size], packet.auth_counter);
+        }
+
+        match self.auth_key {
+            Some(ref auth_key) if keyed => {
+                if packet.is_keyed_check_correct(auth_key) {
+                    packet.checked = true;
+                    self.peer_keyed.set(true);
+                    true
+                } else {
+                    !self.peer_keyed.get()
+                }
+            }
+            _ => true,
+        }
+    }
+
+    fn check_key(&self, cmd: u8) -> Option<&AuthKey> {
+        let plain = matches!(cmd, CMD_SYN | CMD_SYN_ACK | CMD_SYN_COOKIE | CMD_RESET);
+        if self.keyed_check.get() && !plain {
+            self.auth_key.as_deref()
+        } else {
+            None
+        }
+    }
+
+    fn enable_fec(&self, fec_group: u32) {
+        info!(
+            "ucp session {} fec group size {}",
+            self.session_id.get(),
+            fec_group
+        );
+        self.fec_group.set(fec_group);
+        self.fec_cache.set(FecCache::new(fec_group));
+    }
+
+    // Adds a first transmission to the current group, returns the parity
+    // packet once the group is complete.
+    fn fec_encode(&self, packet: &UcpPacket) -> Option<Box<UcpPacket>> {
+        let fec_group = self.fec_group.get();
+        if fec_group == 0 || packet.cmd != CMD_DATA {
+            return None;
+        }
+
+        let encoder = unsafe { &mut *self.fec_encoder.as_ptr() };
+        if !matches!(encoder, Some(group) if group.next_seq() == packet.seq) {
+            *encoder = Some(FecGroup::new(packet.seq));
+        }
+
+        let group = encoder.as_mut().unwrap();
+        let start = UCP_PACKET_META_SIZE;
+        group.add(&packet.buf[start..start + packet.payload as usize]);
+        if group.count < fec_group {
+            return None;
+        }
+
+        let group = encoder.take().unwrap();
+        let mut parity = self.new_noseq_packet(CMD_FEC);
+        parity.payload_write_u32(group.first_seq);
+        parity.payload_write_u32(group.count);
+        parity.payload_write_u32(group.len_xor);
+
+        // Groups cut before the packet size was reduced are dropped.
+        if !parity.payload_write_slice(&group.parity)
+            || parity.packet_size() > self.unsealed_size(self.packet_size.get())
         {
-            let send_queue = unsafe { &mut *self.send_queue.as_ptr() };
+            return None;
+        }
 
-            for packet in send_queue.iter_mut() {
-                let interval = now - packet.timestamp;
-                let skip_resend = packet.skip_times >= SKIP_RESEND_TIMES;
+        Some(parity)
+    }
 
-                if interval >= rto || skip_resend {
-                    packet.skip_times = 0;
-                    packet.window = self.local_window.get();
-                    packet.una = una;
-                    packet.timestamp = now;
-                    packet.xmit += 1;
+    fn process_fec(&self, mut packet: Box<UcpPacket>) {
+        if packet.payload as usize <= FEC_HEADER_SIZE {
+            return;
+        }
+
+        let mut group = FecGroup::new(packet.payload_read_u32());
+        group.count = packet.payload_read_u32();
+        group.len_xor = packet.payload_read_u32();
+        if group.count == 0 || group.count > MAX_FEC_GROUP {
+            return;
+        }
+
+        group.parity = vec![0; packet.payload_remaining()];
+        packet.payload_read_slice(&mut group.parity);
+
+        let una = self.una.get();
+        let recv_queue = unsafe { &*self.recv_queue.as_ptr() };
+        let fec_cache = unsafe { &*self.fec_cache.as_ptr() };
+        let received =
+            |seq: u32| serial::before(seq, una) || recv_queue.iter().any(|p| p.seq == seq);
+
+        let (seq, data) = match fec_cache.recover(&group, received) {
+            Some(recovered) => recovered,
+            None => return,
+        };
+
+        let mut whole = Box::new(UcpPacket::with_size(data.len() + UCP_PACKET_META_SIZE));
+        whole.payload_write_slice(&data);
+
+        whole.size = whole.packet_size();
+        whole.read_pos = UCP_PACKET_META_SIZE;
+        whole.session_id = packet.session_id;
+        whole.timestamp = packet.timestamp;
+        whole.window = packet.window;
+        whole.una = packet.una;
+        whole.seq = seq;
+        whole.cmd = CMD_DATA;
+
+        self.fec_recovered_packets
+            .set(self.fec_recovered_packets.get() + 1);
+        self.process_data(whole);
+    }
+
+    // A heartbeat of an adaptive client carries the interval the server
+    // heartbeats at from then on.
+    async fn process_heartbeat(&self, mut packet: Box<UcpPacket>) {
+        if packet.payload >= 4 && self.nat_probe.get().is_none() {
+            let interval = packet.payload_read_u32();
+            let max = (self.broken_timeout.get() / 2).max(1);
+            self.heartbeat_interval.set(interval.clamp(1, max));
+        }
+        self.nat_probe_held();
+
+        let mut heartbeat_ack = self.new_noseq_packet(CMD_HEARTBEAT_ACK);
+        self.send_packet_directly(&mut heartbeat_ack).await;
+    }
+
+    fn process_heartbeat_ack(&self) {
+        self.alive_time.set(Instant::now());
+    }
+
+    // The peer only sends FIN once we acknowledged all its data, after a
+    // simultaneous close the FIN_ACK for our own FIN finishes the stream.
+    async fn process_fin(&self) {
+        let mut fin_ack = self.new_noseq_packet(CMD_FIN_ACK);
+        self.send_packet_directly(&mut fin_ack).await;
+
+        if matches!(self.state.get(), UcpState::ESTABLISHED) {
+            self.set_closed("peer");
+        }
+    }
+
+    // The response leaves from whatever address the NAT maps us to now.
+    async fn process_path_challenge(&self, mut packet: Box<UcpPacket>) {
+        if packet.payload != 4 {
+            return;
+        }
+
+        let mut response = self.new_noseq_packet(CMD_PATH_RESPONSE);
+        response.payload_write_u32(packet.payload_read_u32());
+        self.send_packet_directly(&mut response).await;
+    }
+
+    fn process_fin_ack(&self) {
+        if matches!(self.state.get(), UcpState::FIN_WAIT) {
+            self.set_closed("local");
+        }
+    }
+
+    fn set_closed(&self, by: &str) {
+        self.state.set(UcpState::CLOSED);
+        info!(
+            "{} closed by {}, session: {}",
+            self.remote_addr.get(),
+            by,
+            self.session_id.get()
+        );
+
+        if let Some(w) = self.read_waker.take() {
+            w.wake()
+        }
+
+        if let Some(w) = self.write_waker.take() {
+            w.wake()
+        }
+
+        self.wake_channels();
+
+        if let Some(w) = self.udata_waker.take() {
+            w.wake()
+        }
+    }
+
+    fn process_an_ack(&self, seq: u32, timestamp: u32) -> bool {
+        let now = self.timestamp();
+        let rtt = now.wrapping_sub(timestamp);
 
-                    resend.push(packet.clone());
+        // The send queue is ordered by seq.
+        let send_queue = unsafe { &mut *self.send_queue.as_ptr() };
+        let pos = send_queue.binary_search_by(|p| serial::diff(p.seq, seq).cmp(&0));
+        let end = match pos {
+            Ok(i) | Err(i) => i,
+        };
+
+        let threshold = self.fast_resend_threshold.get();
+        let fast_resends = unsafe { &mut *self.fast_resends.as_ptr() };
+        for packet in send_queue.range_mut(..end) {
+            if !serial::before(timestamp, packet.timestamp) {
+                packet.dup_acks += 1;
+                if packet.dup_acks == threshold && !packet.fast_resent {
+                    fast_resends.push(packet.seq);
                 }
             }
         }
 
-        for packet in resend.iter_mut() {
-            self.send_packet_directly(packet).await;
+        match pos {
+            Ok(i) => {
+                let packet = send_queue.remove(i).unwrap();
+                self.note_acked(&packet);
+
+                // Karn's algorithm: the sample of a resent packet may
+                // belong to any of its transmissions.
+                let rtt = if packet.xmit == 0 {
+                    self.update_rto(rtt);
+                    rtt
+                } else {
+                    self.srtt.get()
+                };
+
+                let congestion = unsafe { &mut *self.congestion.as_ptr() };
+                congestion.on_ack(1, rtt, now);
+                true
+            }
+
+            Err(_) => false,
+        }
+    }
+
+    // RFC 6298, the calculation accuracy is milliseconds
+    fn update_rto(&self, rtt: u32) {
+        let (srtt, rttvar) = if self.rtt_sampled.get() {
+            let srtt = self.srtt.get();
+            let delta = rtt.abs_diff(srtt);
+            let rttvar = (self.rttvar.get() * 3 + delta) / 4;
+            ((srtt * 7 + rtt) / 8, rttvar)
+        } else {
+            self.rtt_sampled.set(true);
+            (rtt, rtt / 2)
+        };
+
+        self.srtt.set(srtt);
+        self.rttvar.set(rttvar);
+        self.reset_rto();
+    }
+
+    fn reset_rto(&self) {
+        self.rto.set(self.base_rto());
+    }
+
+    // The RTO from the RTT estimate, before any backoff.
+    fn base_rto(&self) -> u32 {
+        let rto = self.srtt.get() + (4 * self.rttvar.get()).max(CLOCK_GRANULARITY);
+        self.clamp_rto(rto)
+    }
+
+    fn clamp_rto(&self, rto: u32) -> u32 {
+        rto.max(self.min_rto.get()).min(self.max_rto.get())
+    }
+
+    // Each round with timeouts doubles the RTO until a new sample
+    // from a packet sent once recomputes it.
+    fn backoff_rto(&self) {
+        let rto = self.rto.get().saturating_mul(2);
+        self.rto.set(self.clamp_rto(rto));
+    }
+
+    fn new_packet(&self, cmd: u8) -> Box<UcpPacket> {
+        let size = self.unsealed_size(self.packet_size.get());
+        let mut packet = Box::new(UcpPacket::with_size(size));
+
+        packet.session_id = self.session_id.get();
+        packet.timestamp = self.timestamp();
+        packet.window = self.local_window.get();
+        packet.seq = self.next_seq();
+        packet.una = self.una.get();
+        packet.cmd = cmd;
+
+        packet
+    }
+
+    // Once congestion experienced marks arrived, acks lead with their
+    // count. Only a peer sending ECN capable packets gets them marked, so
+    // peers without ECN never see the longer acks.
+    // With loss reports the congestion experienced count is always there,
+    // followed by the highest data packet received and the holes below.
+    fn new_ack_packet(&self) -> Box<UcpPacket> {
+        let mut packet = self.new_noseq_packet(CMD_ACK);
+        if self.loss_reports.get() {
+            packet.payload_write_u32(self.ce_received.get());
+            packet.payload_write_u32(self.recv_highest.get().unwrap_or(self.una.get()));
+            packet.payload_write_u32(self.recv_holes());
+        } else if self.ce_received.get() > 0 {
+            packet.payload_write_u32(self.ce_received.get());
+        }
+        packet
+    }
+
+    // Packets not received between una and the highest one received.
+    fn recv_holes(&self) -> u32 {
+        let una = self.una.get();
+        let highest = match self.recv_highest.get() {
+            Some(highest) if !serial::before(highest, una) => highest,
+            _ => return 0,
+        };
+
+        let recv_queue = unsafe { &*self.recv_queue.as_ptr() };
+        let received = recv_queue
+            .iter()
+            .filter(|packet| !serial::before(packet.seq, una))
+            .count() as u32;
+        highest
+            .wrapping_sub(una)
+            .saturating_sub(received.saturating_sub(1))
+    }
+
+    fn new_noseq_packet(&self, cmd: u8) -> Box<UcpPacket> {
+        self.new_noseq_packet_with_size(cmd, self.packet_size.get())
+    }
+
+    fn new_noseq_packet_with_size(&self, cmd: u8, size: usize) -> Box<UcpPacket> {
+        let mut packet = Box::new(UcpPacket::with_size(self.unsealed_size(size)));
+
+        packet.session_id = self.session_id.get();
+        packet.timestamp = self.timestamp();
+        packet.window = self.local_window.get();
+        packet.una = self.una.get();
+        packet.cmd = cmd;
+
+        packet
+    }
+
+    fn timestamp(&self) -> u32 {
+        self.clock() as u32
+    }
+
+    // Milliseconds since the stream started, without wrapping.
+    fn clock(&self) -> u64 {
+        (Instant::now() - self.initial_time).as_millis() as u64
+    }
+
+    fn next_seq(&self) -> u32 {
+        let seq = unsafe { &mut *self.seq.as_ptr() };
+        *seq = seq.wrapping_add(1);
+        *seq
+    }
+
+    // Payload room of a data packet, leaving space for the parity header.
+    fn data_load(&self) -> usize {
+        let reserved = if self.fec_group.get() > 0 {
+            FEC_HEADER_SIZE
+        } else {
+            0
+        };
+
+        self.unsealed_size(self.packet_size.get()) - UCP_PACKET_META_SIZE - reserved
+    }
+
+    fn auth_overhead(&self) -> usize {
+        if self.auth_key.is_some() {
+            AUTH_TRAILER_SIZE
+        } else {
+            0
         }
     }
 
-    async fn send_pending_packets(&self) {
-        let now = self.timestamp();
-        let una = self.una.get();
-        let window = self.remote_window.get() as usize;
-        let mut pending = Vec::new();
-
-        {
-            let send_queue = unsafe { &mut *self.send_queue.as_ptr() };
-            let send_buffer = unsafe { &mut *self.send_buffer.as_ptr() };
+    // Room left for the packet in a datagram of `size` bytes.
+    fn unsealed_size(&self, size: usize) -> usize {
+        size - self.auth_overhead()
+    }
 
-            while send_queue.len() < window {
-                if let Some(q) = send_queue.front() {
-                    if let Some(p) = send_buffer.front() {
-                        let seq_diff = (p.seq - q.seq) as usize;
-                        if seq_diff >= window {
-                            break;
-                        }
-                    }
-                }
+    fn make_packet_send(&self, buf: &[u8]) {
+        let buf_len = buf.len();
 
-                if let Some(mut packet) = send_buffer.pop_front() {
-                    packet.window = self.local_window.get();
-                    packet.una = una;
-                    packet.timestamp = now;
+        let mut pos = 0;
+        while pos < buf_len {
+            let mut packet = self.new_packet(CMD_DATA);
+            let load = self.data_load();
+            let size = min(load, buf_len - pos);
+            let end_pos = pos + size;
 
-                    pending.push(packet.clone());
-                    send_queue.push_back(packet);
-                } else {
-                    break;
-                }
-            }
-        }
+            packet.payload_write_slice(&buf[pos..end_pos]);
+            self.send_packet(packet);
 
-        for packet in pending.iter_mut() {
-            self.send_packet_directly(packet).await;
+            pos = end_pos;
         }
-
-        self.try_wake_writer();
     }
 
-    fn connecting(&self) {
-        self.state.set(UcpState::CONNECTING);
-        self.session_id.set(random::<u32>());
+    fn send_packet(&self, packet: Box<UcpPacket>) {
+        let send_buffer = unsafe { &mut *self.send_buffer.as_ptr() };
+        send_buffer.push_back(packet);
+        self.wake_output();
+    }
 
-        let syn = self.new_packet(CMD_SYN);
-        self.send_packet(syn);
-        info!(
-            "connecting ucp server {}, session: {}",
-            self.remote_addr,
-            self.session_id.get()
-        );
+    // Called with the lock held.
+    fn wake_output(&self) {
+        self.output_due.set(true);
+        if let Some(w) = self.output_waker.take() {
+            w.wake();
+        }
     }
 
-    fn accepting(&self, packet: Box<UcpPacket>) {
-        self.state.set(UcpState::ACCEPTING);
-        self.session_id.set(packet.session_id);
-        self.una.set(packet.seq + 1);
-        self.remote_window.set(packet.window);
+    fn poll_output_due(&self, cx: &mut Context) -> Poll<()> {
+        let _l = self.lock();
+        if self.output_due.take() {
+            Poll::Ready(())
+        } else {
+            self.output_waker.set(Some(cx.waker().clone()));
+            Poll::Pending
+        }
+    }
 
-        let mut syn_ack = self.new_packet(CMD_SYN_ACK);
-        syn_ack.payload_write_u32(packet.seq);
-        syn_ack.payload_write_u32(packet.timestamp);
-        self.send_packet(syn_ack);
-        info!(
-            "accepting ucp client {}, session: {}",
-            self.remote_addr,
-            self.session_id.get()
-        );
+    // A tick while anything is queued, in flight or being set up, else
+    // until the heartbeat is due, within the idle tick.
+    fn output_delay(&self) -> Duration {
+        let _l = self.lock();
+        let busy = !unsafe { &*self.send_queue.as_ptr() }.is_empty()
+            || !unsafe { &*self.send_buffer.as_ptr() }.is_empty()
+            || !unsafe { &*self.ack_list.as_ptr() }.is_empty()
+            || !unsafe { &*self.udata_send_queue.as_ptr() }.is_empty()
+            || self.mtu_probe.get().is_some()
+            || self.path_challenge.get().is_some()
+            || !matches!(self.state.get(), UcpState::ESTABLISHED);
+
+        let millis = if busy {
+            OUTPUT_TICK_MILLIS
+        } else {
+            let idle = (Instant::now() - self.last_send.get()).as_millis() as u64;
+            (self.heartbeat_interval.get() as u64)
+                .saturating_sub(idle)
+                .clamp(OUTPUT_TICK_MILLIS, IDLE_OUTPUT_TICK_MILLIS)
+        };
+        Duration::from_millis(millis)
     }
 
-    async fn processing(&self, packet: Box<UcpPacket>) {
-        if self.session_id.get() != packet.session_id {
-            error!(
-                "unexpect session_id: {}, expect {}",
-                packet.session_id,
-                self.session_id.get()
-            );
-            return;
+    async fn send_packet_directly(&self, packet: &mut Box<UcpPacket>) {
+        if packet.cmd == CMD_DATA
+            && packet.packet_size() > self.unsealed_size(self.packet_size.get())
+        {
+            return self.send_fragments(packet).await;
         }
 
-        self.alive_time.set(Instant::now());
-        self.remote_window.set(packet.window);
+        self.send_datagram(packet, self.remote_addr.get()).await;
+    }
 
-        let state = self.state.get();
-        match state {
-            UcpState::ACCEPTING => {
-                self.process_state_accepting(packet);
+    async fn send_datagram(&self, packet: &mut UcpPacket, addr: SocketAddr) {
+        packet.pack(self.check_key(packet.cmd));
+        self.last_send.set(Instant::now());
+        self.trace(Direction::Sent, packet, addr);
+
+        let sealed = self.auth_key.as_ref().map(|auth_key| {
+            let counter = self.auth_counter.get();
+            self.auth_counter.set(counter.wrapping_add(1));
+
+            let cipher = unsafe { &*self.cipher.as_ptr() };
+            match cipher {
+                Some(cipher) if packet.cmd != CMD_SYN && packet.cmd != CMD_SYN_ACK => {
+                    let mut data = packet.packed_buffer().to_vec();
+                    cipher.encrypt(&mut data[4..], counter);
+                    auth_key.seal(&data, counter)
+                }
+                _ => auth_key.seal(packet.packed_buffer(), counter),
             }
-            UcpState::CONNECTING => {
-                self.process_state_connecting(packet).await;
+        });
+
+        // Within an output round datagrams wait for the round to end.
+        let outbox = unsafe { &mut *self.outbox.as_ptr() };
+        match (sealed, outbox) {
+            (Some(datagram), Some(outbox)) => outbox.push((datagram, addr)),
+            (None, Some(outbox)) => outbox.push((packet.packed_buffer().to_vec(), addr)),
+            (Some(datagram), None) => {
+                let _ = self.socket.send_to(&datagram, addr).await;
             }
-            UcpState::ESTABLISHED => {
-                self.process_state_established(packet).await;
+            (None, None) => {
+                let _ = self.socket.send_to(packet.packed_buffer(), addr).await;
             }
-            UcpState::NONE => {}
         }
     }
 
-    fn process_state_accepting(&self, mut packet: Box<UcpPacket>) {
-        if packet.cmd == CMD_ACK && packet.payload == 8 {
-            let seq = packet.payload_read_u32();
-            let timestamp = packet.payload_read_u32();
-
-            if self.process_an_ack(seq, timestamp) {
-                self.state.set(UcpState::ESTABLISHED);
-                info!(
-                    "{} established, session: {}",
-                    self.remote_addr,
-                    self.session_id.get()
-                );
-            }
+    // Data packets cut before the packet size was reduced travel as
+    // fragments, each carrying its byte offset and the total length.
+    async fn send_fragments(&self, packet: &UcpPacket) {
+        let size = self.unsealed_size(self.packet_size.get());
+        let load = size - UCP_PACKET_META_SIZE - 4;
+        let start = UCP_PACKET_META_SIZE;
+        let data = &packet.buf[start..start + packet.payload as usize];
+        let total = data.len() as u16;
+
+        for (index, chunk) in data.chunks(load).enumerate() {
+            let offset = (index * load) as u16;
+            let mut fragment = Box::new(UcpPacket::with_size(size));
+            fragment.session_id = packet.session_id;
+            fragment.timestamp = packet.timestamp;
+            fragment.window = packet.window;
+            fragment.xmit = packet.xmit;
+            fragment.una = packet.una;
+            fragment.seq = packet.seq;
+            fragment.cmd = CMD_FRAG;
+            fragment.payload_write_slice(&offset.to_be_bytes());
+            fragment.payload_write_slice(&total.to_be_bytes());
+            fragment.payload_write_slice(chunk);
+
+            self.send_datagram(&mut fragment, self.remote_addr.get())
+                .await;
         }
     }
+}
 
-    async fn process_state_connecting(&self, packet: Box<UcpPacket>) {
-        self.process_syn_ack(packet).await;
+pub struct UcpStream {
+    inner: Arc<InnerStream>,
+}
+
+// Handed out by the server when asked for with UcpConfig::resume, good
+// for one session to the same server with the same config.
+#[derive(Clone)]
+pub struct ResumeTicket {
+    server: SocketAddr,
+    issued: Instant,
+    features: u32,
+    fec_group: u32,
+    encrypt: bool,
+    ticket: Vec<u8>,
+}
+
+impl UcpStream {
+    pub async fn connect(server_addr: &str) -> error::Result<Self> {
+        UcpStream::connect_with_config(server_addr, None, &UcpConfig::default()).await
     }
 
-    async fn process_state_established(&self, packet: Box<UcpPacket>) {
-        self.process_una(packet.una);
+    // Signs every packet with a key derived from `key` when given, the
+    // server must use the same key. Forged and replayed packets are
+    // dropped, and with `config.encrypt` all packets after the handshake
+    // are encrypted with a session key.
+    pub async fn connect_with_config(
+        server_addr: &str,
+        key: Option<&[u8]>,
+        config: &UcpConfig,
+    ) -> error::Result<Self> {
+        UcpStream::connect_with_ticket(server_addr, key, config, None).await
+    }
 
-        match packet.cmd {
-            CMD_ACK => {
-                self.process_ack(packet);
-            }
-            CMD_DATA => {
-                self.process_data(packet);
-            }
-            CMD_SYN_ACK => {
-                self.process_syn_ack(packet).await;
-            }
-            CMD_HEARTBEAT => {
-                self.process_heartbeat().await;
-            }
-            CMD_HEARTBEAT_ACK => {
-                self.process_heartbeat_ack();
-            }
-            _ => {}
-        }
+    // Resumes the session `ticket` came from, the SYN carries the ticket
+    // and written data follows it right away instead of waiting for the
+    // handshake. A ticket of another server or config, or one that
+    // expired, is left out, and data sent before a server refusing the
+    // ticket is resent after the handshake.
+    pub async fn connect_with_ticket(
+        server_addr: &str,
+        key: Option<&[u8]>,
+        config: &UcpConfig,
+        ticket: Option<&ResumeTicket>,
+    ) -> error::Result<Self> {
+        let auth_key = key.map(|key| Arc::new(AuthKey::new(key)));
+        UcpStream::open(server_addr, auth_key, config, ticket).await
     }
 
-    fn process_una(&self, una: u32) {
-        let send_queue = unsafe { &mut *self.send_queue.as_ptr() };
+    // Asks the server to add one parity packet per `fec_group` data
+    // packets in both directions, which rebuilds a single lost packet of
+    // each group without waiting for a resend.
+    pub async fn connect_with_fec(server_addr: &str, fec_group: u32) -> error::Result<Self> {
+        let config = UcpConfig {
+            fec_group,
+            ..UcpConfig::default()
+        };
+        UcpStream::connect_with_config(server_addr, None, &config).await
+    }
 
-        while !send_queue.is_empty() {
-            let diff = send_queue
-                .front()
-                .map(|packet| (packet.seq - una) as i32)
-                .unwrap();
+    pub async fn connect_authenticated(
+        server_addr: &str,
+        fec_group: u32,
+        key: &[u8],
+    ) -> error::Result<Self> {
+        let config = UcpConfig {
+            fec_group,
+            ..UcpConfig::default()
+        };
+        UcpStream::connect_with_config(server_addr, Some(key), &config).await
+    }
 
-            if diff < 0 {
-                send_queue.pop_front();
-            } else {
-                break;
-            }
-        }
+    pub async fn connect_encrypted(
+        server_addr: &str,
+        fec_group: u32,
+        key: &[u8],
+    ) -> error::Result<Self> {
+        let config = UcpConfig {
+            fec_group,
+            encrypt: true,
+            ..UcpConfig::default()
+        };
+        UcpStream::connect_with_config(server_addr, Some(key), &config).await
     }
 
-    fn process_ack(&self, mut packet: Box<UcpPacket>) {
-        if packet.cmd == CMD_ACK && packet.payload % 8 == 0 {
-            while packet.payload_remaining() > 0 {
-                let seq = packet.payload_read_u32();
-                let timestamp = packet.payload_read_u32();
-                self.process_an_ack(seq, timestamp);
-            }
+    // `server_addr` is an IPv4 or IPv6 address with a port, or a host
+    // name with a port. A name with more than one address races them
+    // happy eyeballs style, see UcpStream::race.
+    async fn open(
+        server_addr: &str,
+        auth_key: Option<Arc<AuthKey>>,
+        config: &UcpConfig,
+        ticket: Option<&ResumeTicket>,
+    ) -> error::Result<Self> {
+        let targets = match SocketAddr::from_str(server_addr) {
+            Ok(addr) => vec![addr],
+            Err(_) => resolve(server_addr).await?,
+        };
+
+        if targets.len() == 1 {
+            UcpStream::open_to(targets[0], auth_key, config, ticket).await
+        } else {
+            UcpStream::race(targets, auth_key, config, ticket).await
         }
     }
 
-    fn process_data(&self, packet: Box<UcpPacket>) {
-        let ack_list = unsafe { &mut *self.ack_list.as_ptr() };
-        ack_list.push((packet.seq, packet.timestamp));
-        let una = self.una.get();
+    async fn open_to(
+        remote_addr: SocketAddr,
+        auth_key: Option<Arc<AuthKey>>,
+        config: &UcpConfig,
+        ticket: Option<&ResumeTicket>,
+    ) -> error::Result<Self> {
+        let socket = Arc::new(Transport::Udp(bind_for(remote_addr, config).await?));
+        UcpStream::start(socket, remote_addr, auth_key, config, ticket)
+    }
 
-        let una_diff = (packet.seq - una) as i32;
-        if una_diff < 0 {
-            return;
-        }
+    // Starts a handshake with the first of `targets`, then with the next
+    // each HAPPY_EYEBALLS_DELAY_MILLIS the ones started haven't finished
+    // theirs, and keeps the first session that comes up. When none does
+    // within the syn timeout after the last start, the first still
+    // alive is returned to fail as a single target would.
+    async fn race(
+        targets: Vec<SocketAddr>,
+        auth_key: Option<Arc<AuthKey>>,
+        config: &UcpConfig,
+        ticket: Option<&ResumeTicket>,
+    ) -> error::Result<Self> {
+        let delay = Duration::from_millis(HAPPY_EYEBALLS_DELAY_MILLIS);
+        let syn_timeout = Duration::from_millis(config.syn_timeout as u64);
+        let mut targets = targets.into_iter().peekable();
+        let mut attempts: Vec<UcpStream> = Vec::new();
+        let mut last_error = None;
+
+        while let Some(target) = targets.next() {
+            let start = Instant::now();
+            match UcpStream::open_to(target, auth_key.clone(), config, ticket).await {
+                Ok(stream) => attempts.push(stream),
+                Err(e) => last_error = Some(e),
+            }
 
-        let mut pos = 0;
-        let recv_queue = unsafe { &mut *self.recv_queue.as_ptr() };
-        for i in 0..recv_queue.len() {
-            let seq_diff = (packet.seq - recv_queue[i].seq) as i32;
+            // The next target starts after the delay, the last waits up
+            // to the syn timeout.
+            let wait = if targets.peek().is_some() {
+                delay
+            } else {
+                syn_timeout
+            };
+            let settled = std::future::poll_fn(|cx| UcpStream::poll_race(&attempts, cx));
+            let _ = future::timeout(wait.saturating_sub(start.elapsed()), settled).await;
 
-            if seq_diff == 0 {
-                return;
-            } else if seq_diff < 0 {
+            if attempts.iter().any(|s| s.is_established()) {
                 break;
-            } else {
-                pos += 1;
             }
         }
 
-        recv_queue.insert(pos, packet);
+        let chosen = attempts
+            .iter()
+            .position(|s| s.is_established())
+            .or_else(|| attempts.iter().position(|s| s.alive()));
+        match chosen {
+            Some(i) => {
+                let stream = attempts.swap_remove(i);
+                for attempt in attempts {
+                    attempt.shutdown();
+                }
+                Ok(stream)
+            }
+            None => match attempts.pop() {
+                Some(stream) => Ok(stream),
+                None => Err(last_error.unwrap()),
+            },
+        }
+    }
 
-        for i in pos..recv_queue.len() {
-            let una = self.una.get();
-            if recv_queue[i].seq == una {
-                self.una.set(una + 1);
-            } else {
-                break;
+    // Ready once an attempt established its session or all of them died.
+    fn poll_race(attempts: &[UcpStream], cx: &mut Context) -> Poll<()> {
+        let mut pending = false;
+        for attempt in attempts {
+            match attempt.inner.poll_settled(cx) {
+                Poll::Ready(true) => return Poll::Ready(()),
+                Poll::Ready(false) => {}
+                Poll::Pending => pending = true,
             }
         }
 
-        self.try_wake_reader();
+        if pending {
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
     }
 
-    async fn process_syn_ack(&self, mut packet: Box<UcpPacket>) {
-        if packet.cmd == CMD_SYN_ACK && packet.payload == 8 {
-            let seq = packet.payload_read_u32();
-            let timestamp = packet.payload_read_u32();
-
-            let mut ack = self.new_noseq_packet(CMD_ACK);
-            ack.payload_write_u32(packet.seq);
-            ack.payload_write_u32(packet.timestamp);
-            self.send_packet_directly(&mut ack).await;
+    // Connects to a peer behind a NAT that bound a listener with
+    // UcpListener::bind_rendezvous and the same `token`, meeting it
+    // through the introducer at `introducer_addr`, see
+    // rendezvous::Introducer.
+    pub async fn connect_rendezvous(
+        introducer_addr: &str,
+        token: &[u8],
+        key: Option<&[u8]>,
+        config: &UcpConfig,
+    ) -> error::Result<Self> {
+        let (socket, peer_addr) = rendezvous(introducer_addr, token, Role::Connect, config).await?;
+        let socket = Arc::new(Transport::Udp(socket));
+        let auth_key = key.map(|key| Arc::new(AuthKey::new(key)));
+        UcpStream::start(socket, peer_addr, auth_key, config, None)
+    }
 
-            match self.state.get() {
-                UcpState::CONNECTING => {
-                    if self.process_an_ack(seq, timestamp) {
-                        self.state.set(UcpState::ESTABLISHED);
-                        self.una.set(packet.seq + 1);
-                        info!(
-                            "{} established, session: {}",
-                            self.remote_addr,
-                            self.session_id.get()
-                        );
-                    }
-                }
-                _ => {}
-            }
-        }
+    // Connects over a simulated network instead of UDP, see
+    // sim::SimNetwork.
+    pub async fn connect_simulated(
+        network: &SimNetwork,
+        server_addr: &str,
+        key: Option<&[u8]>,
+        config: &UcpConfig,
+    ) -> error::Result<Self> {
+        let remote_addr = SocketAddr::from_str(server_addr)
+            .map_err(|_| error::Error::InvalidAddress(server_addr.to_string()))?;
+        let socket = network
+            .bind(SocketAddr::from(([0, 0, 0, 0], 0)))
+            .map_err(|e| error::Error::io("ucp bind", e))?;
+
+        let socket = Arc::new(Transport::Sim(socket));
+        let auth_key = key.map(|key| Arc::new(AuthKey::new(key)));
+        UcpStream::start(socket, remote_addr, auth_key, config, None)
     }
 
-    async fn process_heartbeat(&self) {
-        let mut heartbeat_ack = self.new_noseq_packet(CMD_HEARTBEAT_ACK);
-        self.send_packet_directly(&mut heartbeat_ack).await;
+    fn start(
+        socket: Arc<Transport>,
+        remote_addr: SocketAddr,
+        auth_key: Option<Arc<AuthKey>>,
+        config: &UcpConfig,
+        ticket: Option<&ResumeTicket>,
+    ) -> error::Result<Self> {
+        let inner = Arc::new(InnerStream::new(socket, remote_addr, auth_key, None, None));
+        inner.configure(config);
+        inner.connecting(config, ticket);
+
+        let sender = inner.clone();
+        task::spawn(async move {
+            UcpStream::send(sender).await;
+        });
+
+        let receiver = inner.clone();
+        let batch = if config.batch_io { MAX_BATCH } else { 1 };
+        task::spawn(async move {
+            UcpStream::recv(receiver, batch).await;
+        });
+
+        Ok(UcpStream { inner })
     }
 
-    fn process_heartbeat_ack(&self) {
-        self.alive_time.set(Instant::now());
+    pub fn shutdown(&self) {
+        self.inner.shutdown();
     }
 
-    fn process_an_ack(&self, seq: u32, timestamp: u32) -> bool {
-        let rtt = self.timestamp() - timestamp;
-        self.update_rto(rtt);
+    // Waits until all written data has been acknowledged by the peer and
+    // the FIN handshake finished, or the close timeout expires, then shuts
+    // the stream down.
+    pub async fn close(&self) {
+        let start = Instant::now();
 
-        let send_queue = unsafe { &mut *self.send_queue.as_ptr() };
-        for i in 0..send_queue.len() {
-            if send_queue[i].seq == seq {
-                send_queue.remove(i);
-                return true;
-            } else {
-                if send_queue[i].timestamp <= timestamp {
-                    send_queue[i].skip_times += 1;
-                }
+        while self.inner.alive() && !self.inner.finish() {
+            if (Instant::now() - start).as_millis() >= UCP_CLOSE_TIMEOUT_MILLIS {
+                break;
             }
+
+            task::sleep(Duration::from_millis(10)).await;
         }
 
-        false
+        self.inner.shutdown();
     }
 
-    fn update_rto(&self, rtt: u32) {
-        // The calculation accuracy is milliseconds
-        let mut srtt = self.srtt.get();
-        if srtt == 0 {
-            srtt = rtt;
-        }
-        srtt = (srtt * 9 + rtt) / 10;
+    pub fn alive(&self) -> bool {
+        self.inner.alive()
+    }
 
-        let mut rttvar = self.rttvar.get();
-        let delta = if rtt > srtt { rtt - srtt } else { srtt - rtt };
-        rttvar = (rttvar * 3 + delta) / 4;
+    // The handshake has finished, it stays true after the stream closed.
+    pub fn is_established(&self) -> bool {
+        self.inner.is_established()
+    }
 
-        let rto = srtt + 4 * rttvar;
+    // Follows the peer when it moves to another address.
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.inner.session().1
+    }
 
-        self.rto.set(rto);
-        self.srtt.set(srtt);
-        self.rttvar.set(rttvar);
+    // Maximum receive window in packets, the advertised window shrinks
+    // from it while received data waits to be read.
+    // Caps the bytes per second of data packets the stream sends,
+    // resends included, 0 lifts the cap. Takes effect right away, also
+    // for a stream just accepted from a listener.
+    pub fn set_max_send_rate(&self, bytes_per_sec: u64) {
+        self.inner.set_max_send_rate(bytes_per_sec);
     }
 
-    fn new_packet(&self, cmd: u8) -> Box<UcpPacket> {
-        let mut packet = Box::new(UcpPacket::new());
+    // Hands every packet header the stream sends or receives from now on
+    // to `tracer`, None stops tracing.
+    pub fn set_tracer(&self, tracer: Option<Arc<dyn PacketTracer>>) {
+        self.inner.set_tracer(tracer);
+    }
 
-        packet.session_id = self.session_id.get();
-        packet.timestamp = self.timestamp();
-        packet.window = self.local_window.get();
-        packet.seq = self.next_seq();
-        packet.una = self.una.get();
-        packet.cmd = cmd;
+    pub fn set_recv_window(&self, window: u32) {
+        self.inner.set_recv_window(window);
+    }
 
-        packet
+    // Bytes received data may occupy while waiting to be read, the
+    // advertised window shrinks with the free part of it as well.
+    pub fn set_recv_buffer(&self, bytes: usize) {
+        self.inner.set_recv_buffer(bytes);
     }
 
-    fn new_noseq_packet(&self, cmd: u8) -> Box<UcpPacket> {
-        let mut packet = Box::new(UcpPacket::new());
+    pub fn stats(&self) -> UcpStats {
+        self.inner.stats()
+    }
 
-        packet.session_id = self.session_id.get();
-        packet.timestamp = self.timestamp();
-        packet.window = self.local_window.get();
-        packet.una = self.una.get();
-        packet.cmd = cmd;
+    // What the session agreed on with the peer, complete once it is
+    // established.
+    pub fn capabilities(&self) -> UcpCapabilities {
+        self.inner.capabilities()
+    }
 
-        packet
+    pub fn set_congestion_control(&self, congestion: Box<dyn CongestionControl>) {
+        self.inner.set_congestion_control(congestion);
     }
 
-    fn timestamp(&self) -> u32 {
-        (Instant::now() - self.initial_time).as_millis() as u32
+    // Number of acks for packets sent later that triggers a fast resend,
+    // 0 leaves loss recovery to the RTO alone.
+    pub fn set_fast_resend_threshold(&self, threshold: u32) {
+        self.inner.set_fast_resend_threshold(threshold);
     }
 
-    fn next_seq(&self) -> u32 {
-        let seq = unsafe { &mut *self.seq.as_ptr() };
-        *seq += 1;
-        *seq
+    // Resends of a packet after which the stream is broken, 0 for no
+    // limit.
+    pub fn set_max_xmit(&self, max_xmit: u32) {
+        self.inner.set_max_xmit(max_xmit);
     }
 
-    fn make_packet_send(&self, buf: &[u8]) {
-        let buf_len = buf.len();
+    // Floor and ceiling of the retransmission timeout in milliseconds.
+    pub fn set_rto_bounds(&self, min_rto: u32, max_rto: u32) {
+        self.inner.set_rto_bounds(min_rto, max_rto);
+    }
 
-        let mut pos = 0;
-        while pos < buf_len {
-            let mut packet = self.new_packet(CMD_DATA);
-            let size = min(packet.remaining_load(), buf_len - pos);
-            let end_pos = pos + size;
+    // Multiple of the estimated bandwidth new packets are paced at,
+    // 0 sends up to the whole window at once.
+    pub fn set_pacing_gain(&self, gain: f64) {
+        self.inner.set_pacing_gain(gain);
+    }
 
-            packet.payload_write_slice(&buf[pos..end_pos]);
-            self.send_packet(packet);
+    // Acks for in order data are held until `every` packets arrived or
+    // the oldest waited `delay` milliseconds. The defaults, 1 and 0, ack
+    // every packet on the next tick.
+    pub fn set_delayed_ack(&self, every: u32, delay: u32) {
+        self.inner.set_delayed_ack(every, delay);
+    }
 
-            pos = end_pos;
-        }
+    // Largest datagram this stream sends, including the packet header.
+    // Path MTU discovery raises the packet size up to it.
+    pub fn set_max_packet_size(&self, size: usize) {
+        self.inner.set_max_packet_size(size);
     }
 
-    fn send_packet(&self, packet: Box<UcpPacket>) {
-        let send_buffer = unsafe { &mut *self.send_buffer.as_ptr() };
-        send_buffer.push_back(packet);
+    // Whether channels were asked for with UcpConfig::channels and the
+    // server granted them, known once the stream is established.
+    pub fn has_channels(&self) -> bool {
+        self.inner.has_channels()
     }
 
-    async fn send_packet_directly(&self, packet: &mut Box<UcpPacket>) {
-        packet.pack();
-        let _ = self
-            .socket
-            .send_to(packet.packed_buffer(), self.remote_addr)
-            .await;
+    // A logical channel of the session, ordered and flow controlled on
+    // its own, so a loss or a slow reader on one channel doesn't hold up
+    // the others. Either end opens a channel by writing to it, the
+    // stream itself reads and writes channel 0.
+    pub fn channel(&self, id: u32) -> Option<UcpChannel> {
+        if self.inner.add_channel(id) {
+            Some(UcpChannel {
+                inner: self.inner.clone(),
+                id,
+            })
+        } else {
+            None
+        }
     }
-}
 
-pub struct UcpStream {
-    inner: Arc<InnerStream>,
-}
+    // Whether datagrams were asked for with UcpConfig::datagrams and the
+    // server granted them, known once the stream is established.
+    pub fn has_datagrams(&self) -> bool {
+        self.inner.has_datagrams()
+    }
 
-impl UcpStream {
-    pub async fn connect(server_addr: &str) -> Self {
-        let socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await.unwrap());
-        let remote_addr = SocketAddr::from_str(server_addr).unwrap();
+    // Sends `buf` in a packet of its own on the next tick, without
+    // resends or ordering, next to the reliable data of the session.
+    // Datagrams larger than a packet are refused.
+    pub fn send_datagram(&self, buf: &[u8]) -> std::io::Result<()> {
+        self.inner.queue_udata(buf)
+    }
 
-        let inner = Arc::new(InnerStream::new(socket, remote_addr));
-        inner.connecting();
+    // Waits for the next datagram of the peer, None once the session
+    // ended. Datagrams not read in time are dropped, oldest first.
+    pub async fn recv_datagram(&self) -> Option<Vec<u8>> {
+        std::future::poll_fn(|cx| self.inner.poll_recv_udata(cx)).await
+    }
 
-        let sender = inner.clone();
-        task::spawn(async move {
-            UcpStream::send(sender).await;
-        });
+    // The ticket for resuming this session with connect_with_ticket once
+    // it broke, given with the handshake.
+    pub fn resume_ticket(&self) -> Option<ResumeTicket> {
+        self.inner.resume_ticket()
+    }
 
-        let receiver = inner.clone();
-        task::spawn(async move {
-            UcpStream::recv(receiver).await;
-        });
+    // Takes what fits the send buffer, Err(WouldBlock) while it is full,
+    // see writable. Writes through AsyncWrite wait for room instead.
+    pub fn try_send(&self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.try_send(buf)
+    }
 
-        UcpStream { inner: inner }
+    // Waits until try_send takes data again, or fails as it would once
+    // the stream finished.
+    pub async fn writable(&self) -> std::io::Result<()> {
+        std::future::poll_fn(|cx| self.inner.poll_writable(cx)).await
     }
 
-    pub fn shutdown(&self) {
-        self.inner.shutdown();
+    // Waits for a channel the peer opened, None once the session ended.
+    pub async fn accept_channel(&self) -> Option<UcpChannel> {
+        let id = std::future::poll_fn(|cx| self.inner.poll_accept_channel(cx)).await?;
+        Some(UcpChannel {
+            inner: self.inner.clone(),
+            id,
+        })
     }
 
+    // Output rounds run when something was queued or the stream's
+    // deadlines may be due, instead of polling every tick.
     async fn send(inner: Arc<InnerStream>) {
+        let tick = Duration::from_millis(OUTPUT_TICK_MILLIS);
+        let mut last_output = Instant::now();
+
         loop {
-            task::sleep(Duration::from_millis(10)).await;
+            let delay = inner.output_delay();
+            let due = std::future::poll_fn(|cx| inner.poll_output_due(cx));
+            let _ = future::timeout(delay, due).await;
+
+            let elapsed = last_output.elapsed();
+            if elapsed < tick {
+                task::sleep(tick - elapsed).await;
+            }
+            last_output = Instant::now();
             inner.output().await;
 
             if !inner.alive() {
@@ -936,23 +4681,21 @@ impl UcpStream {
         }
     }
 
-    async fn recv(inner: Arc<InnerStream>) {
+    async fn recv(inner: Arc<InnerStream>, batch: usize) {
+        let mut spare = Vec::new();
+
         loop {
-            let mut packet = Box::new(UcpPacket::new());
-            let result = io::timeout(
-                Duration::from_secs(5),
-                inner.socket.recv_from(&mut packet.buf),
-            )
-            .await;
+            let received =
+                recv_packets(&inner.socket, &mut spare, batch, Duration::from_secs(5)).await;
 
             if !inner.alive() {
                 break;
             }
 
-            if let Ok((size, remote_addr)) = result {
-                packet.size = size;
-
-                if packet.parse() {
+            for (mut packet, remote_addr) in received {
+                let auth_key = inner.auth_key.as_deref();
+                if packet.unseal(auth_key) && inner.decipher(&mut packet) && packet.parse() {
+                    packet.shrink();
                     inner.input(packet, remote_addr).await;
                 } else {
                     error!("recv illgal packet from {}", remote_addr);
@@ -962,6 +4705,63 @@ impl UcpStream {
     }
 }
 
+impl Drop for InnerStream {
+    fn drop(&mut self) {
+        if let Some(budget) = self.recv_budget.as_ref() {
+            budget.give(self.recv_memory.get());
+        }
+    }
+}
+
+impl Drop for UcpStream {
+    fn drop(&mut self) {
+        self.inner.shutdown();
+    }
+}
+
+// An ordered byte stream within a session, see UcpStream::channel.
+pub struct UcpChannel {
+    inner: Arc<InnerStream>,
+    id: u32,
+}
+
+impl UcpChannel {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+impl Read for &UcpChannel {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.inner.poll_read_channel(cx, self.id, buf)
+    }
+}
+
+impl Write for &UcpChannel {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.inner.poll_write_channel(cx, self.id, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    // The peer reads to the end of what was written, the session and
+    // the other channels carry on.
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<std::io::Result<()>> {
+        self.inner.close_channel(self.id);
+        Poll::Ready(Ok(()))
+    }
+}
+
 impl Read for &UcpStream {
     fn poll_read(
         self: Pin<&mut Self>,
@@ -990,56 +4790,599 @@ impl Write for &UcpStream {
     }
 }
 
+// Blocking reads and writes for code written against std::io, such as a
+// BufReader or io::copy to a std TcpStream. The session keeps running on
+// the async-std executor, so these block only the calling thread, which
+// must not be one of the executor's.
+impl std::io::Read for UcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        task::block_on(std::future::poll_fn(|cx| self.inner.poll_read(cx, buf)))
+    }
+}
+
+impl std::io::Write for UcpStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        task::block_on(std::future::poll_fn(|cx| self.inner.poll_write(cx, buf)))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
 type UcpStreamMap = HashMap<SocketAddr, Arc<InnerStream>>;
 
+// SYNs refused by every listener as they were at their session limit.
+static REFUSED_SYNS: AtomicU64 = AtomicU64::new(0);
+
+// Received packets dropped over a memory budget, by every stream.
+pub fn recv_dropped_packets() -> u64 {
+    budget::recv_dropped_packets()
+}
+
+pub fn refused_syns() -> u64 {
+    REFUSED_SYNS.load(Ordering::Relaxed)
+}
+
+// SYNs with a key dropped by every listener as too old or replayed.
+static STALE_SYNS: AtomicU64 = AtomicU64::new(0);
+
+pub fn stale_syns() -> u64 {
+    STALE_SYNS.load(Ordering::Relaxed)
+}
+
+// Resets sent by every listener for packets of unknown sessions.
+static SENT_RESETS: AtomicU64 = AtomicU64::new(0);
+
+pub fn sent_resets() -> u64 {
+    SENT_RESETS.load(Ordering::Relaxed)
+}
+
+// Packet buffers held for reuse by all threads and their bytes, and how
+// many packets took one instead of allocating.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PacketPoolStats {
+    pub buffers: usize,
+    pub bytes: usize,
+    pub reused: u64,
+    pub allocated: u64,
+}
+
+pub fn packet_pool_stats() -> PacketPoolStats {
+    pool::stats()
+}
+
+// The addresses of a host name with a port, IPv6 and IPv4 alternating
+// from an IPv6 one, the order happy eyeballs tries them in.
+async fn resolve(server_addr: &str) -> error::Result<Vec<SocketAddr>> {
+    let addrs = server_addr
+        .to_socket_addrs()
+        .await
+        .map_err(|e| error::Error::io(format!("ucp resolve {}", server_addr), e))?;
+    let (mut v6, mut v4): (VecDeque<_>, VecDeque<_>) = addrs.partition(|a| a.is_ipv6());
+    if v6.is_empty() && v4.is_empty() {
+        return Err(error::Error::InvalidAddress(server_addr.to_string()));
+    }
+
+    let mut targets = Vec::with_capacity(v6.len() + v4.len());
+    while !v6.is_empty() || !v4.is_empty() {
+        targets.extend(v6.pop_front());
+        targets.extend(v4.pop_front());
+    }
+    Ok(targets)
+}
+
+// A socket of the family of `remote_addr` on an ephemeral port.
+async fn bind_for(remote_addr: SocketAddr, config: &UcpConfig) -> error::Result<UdpSocket> {
+    let local_addr = match remote_addr {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    let socket = UdpSocket::bind(local_addr)
+        .await
+        .map_err(|e| error::Error::io(format!("ucp bind {}", local_addr), e))?;
+    sockopt::configure(&socket, config);
+    Ok(socket)
+}
+
+// A socket of the family of the introducer's address, with the NATs on
+// the way to the other peer punched, and the other peer's address.
+async fn rendezvous(
+    introducer_addr: &str,
+    token: &[u8],
+    role: Role,
+    config: &UcpConfig,
+) -> error::Result<(UdpSocket, SocketAddr)> {
+    let introducer = match SocketAddr::from_str(introducer_addr) {
+        Ok(addr) => addr,
+        Err(_) => resolve(introducer_addr).await?[0],
+    };
+    let socket = bind_for(introducer, config).await?;
+    let peer_addr = rendezvous::punch(&socket, introducer, token, role)
+        .await
+        .map_err(|e| error::Error::io(format!("ucp rendezvous {}", introducer_addr), e))?;
+    Ok((socket, peer_addr))
+}
+
+// The protocol version in the features word of a SYN.
+fn syn_version(syn: &UcpPacket) -> u32 {
+    match syn.payload_data().get(16..20) {
+        Some(word) => u32::from_be_bytes([word[0], word[1], word[2], word[3]]) >> VERSION_SHIFT,
+        None => 0,
+    }
+}
+
+// The cookie a SYN echoes, after the features word and any ticket.
+fn syn_cookie(syn: &UcpPacket) -> Option<&[u8]> {
+    let payload = syn.payload_data();
+    let rest = payload.len().checked_sub(20)?;
+    if rest == COOKIE_SIZE || rest == TICKET_SIZE + COOKIE_SIZE {
+        Some(&payload[payload.len() - COOKIE_SIZE..])
+    } else {
+        None
+    }
+}
+
+// Packets received into `spare`, up to `batch` once the first arrived or
+// none after `timeout`. Buffers not filled are kept for the next call.
+async fn recv_packets(
+    socket: &Transport,
+    spare: &mut Vec<UcpPacket>,
+    batch: usize,
+    timeout: Duration,
+) -> Vec<(Box<UcpPacket>, SocketAddr)> {
+    spare.resize_with(batch, UcpPacket::new);
+
+    let mut bufs: Vec<&mut [u8]> = spare.iter_mut().map(|p| &mut p.buf[..]).collect();
+    let received = io::timeout(timeout, socket.recv_batch(&mut bufs))
+        .await
+        .unwrap_or_default();
+
+    spare
+        .drain(..received.len())
+        .zip(received)
+        .map(|(mut packet, (size, remote_addr, ce))| {
+            packet.size = size;
+            packet.ce = ce;
+            (Box::new(packet), remote_addr)
+        })
+        .collect()
+}
+
+// A packet of an accepted session, still sealed, for a listener worker.
+type WorkerPacket = (Arc<InnerStream>, Box<UcpPacket>, SocketAddr);
+
+async fn listener_worker(mut packets: Receiver<WorkerPacket>) {
+    while let Some((inner, mut packet, remote_addr)) = packets.next().await {
+        let auth_key = inner.auth_key.as_deref();
+        if packet.unseal(auth_key) && inner.decipher(&mut packet) && packet.parse() {
+            packet.shrink();
+            inner.input(packet, remote_addr).await;
+        } else {
+            error!("recv illgal packet from {}", remote_addr);
+        }
+    }
+}
+
 pub struct UcpListener {
-    socket: Arc<UdpSocket>,
+    socket: Arc<Transport>,
     stream_map: UcpStreamMap,
+    // Sessions still in the handshake, with when their SYN came. They
+    // are handed out by incoming once established.
+    pending: HashMap<SocketAddr, Instant>,
+    // Packets of the last batch received not handled yet, and buffers
+    // for the next.
+    received: VecDeque<(Box<UcpPacket>, SocketAddr)>,
+    spare: Vec<UcpPacket>,
+    workers: Vec<Sender<WorkerPacket>>,
+    full: bool,
     timestamp: Instant,
+    config: UcpConfig,
+    auth_key: Option<Arc<AuthKey>>,
+    tickets: Arc<TicketBook>,
+    cookies: CookieJar,
+    syn_guard: SynGuard,
+    recv_budget: Arc<RecvBudget>,
+    tracer: Option<Arc<dyn PacketTracer>>,
+    closing: Arc<AtomicBool>,
+    // Resets sent since reset_time, see send_reset.
+    resets: u32,
+    reset_time: Instant,
+}
+
+// Stops the UcpListener it came from, see UcpListener::accept.
+#[derive(Clone)]
+pub struct ListenerShutdown(Arc<AtomicBool>);
+
+impl ListenerShutdown {
+    pub fn shutdown(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
 }
 
 impl UcpListener {
-    pub async fn bind(listen_addr: &str) -> Self {
-        let socket = Arc::new(UdpSocket::bind(listen_addr).await.unwrap());
+    pub async fn bind(listen_addr: &str) -> error::Result<Self> {
+        UcpListener::bind_with_config(listen_addr, UcpConfig::default()).await
+    }
+
+    pub async fn bind_with_config(listen_addr: &str, config: UcpConfig) -> error::Result<Self> {
+        let socket = UdpSocket::bind(listen_addr)
+            .await
+            .map_err(|e| error::Error::io(format!("ucp bind {}", listen_addr), e))?;
+        sockopt::configure(&socket, &config);
+        Ok(UcpListener::with_transport(Transport::Udp(socket), config))
+    }
+
+    // Listens for the one peer that connects with
+    // UcpStream::connect_rendezvous and the same `token`, on a socket the
+    // introducer at `introducer_addr` has seen and the NATs of both sides
+    // let the peer's datagrams through to. Sessions still come from
+    // incoming.
+    pub async fn bind_rendezvous(
+        introducer_addr: &str,
+        token: &[u8],
+        config: UcpConfig,
+    ) -> error::Result<Self> {
+        let (socket, _) = rendezvous(introducer_addr, token, Role::Listen, &config).await?;
+        Ok(UcpListener::with_transport(Transport::Udp(socket), config))
+    }
+
+    // Listens on a simulated network instead of UDP, see
+    // sim::SimNetwork.
+    pub fn bind_simulated(
+        network: &SimNetwork,
+        listen_addr: &str,
+        config: UcpConfig,
+    ) -> error::Result<Self> {
+        let addr = SocketAddr::from_str(listen_addr)
+            .map_err(|_| error::Error::InvalidAddress(listen_addr.to_string()))?;
+        let socket = network
+            .bind(addr)
+            .map_err(|e| error::Error::io(format!("ucp bind {}", listen_addr), e))?;
+        Ok(UcpListener::with_transport(Transport::Sim(socket), config))
+    }
+
+    fn with_transport(socket: Transport, config: UcpConfig) -> Self {
+        let workers = (0..config.workers)
+            .map(|_| {
+                let (sender, receiver) = mpsc::channel(WORKER_QUEUE_SIZE);
+                task::spawn(listener_worker(receiver));
+                sender
+            })
+            .collect();
         UcpListener {
-            socket: socket,
+            socket: Arc::new(socket),
             stream_map: UcpStreamMap::new(),
+            pending: HashMap::new(),
+            received: VecDeque::new(),
+            spare: Vec::new(),
+            workers,
+            full: false,
             timestamp: Instant::now(),
+            auth_key: None,
+            tickets: Arc::new(TicketBook::new()),
+            cookies: CookieJar::new(),
+            syn_guard: SynGuard::new(config.syn_max_age),
+            recv_budget: Arc::new(RecvBudget::new(config.max_recv_memory)),
+            tracer: None,
+            closing: Arc::new(AtomicBool::new(false)),
+            resets: 0,
+            reset_time: Instant::now(),
+            config,
         }
     }
 
+    // Traces the sessions accepted from now on, see UcpStream::set_tracer.
+    pub fn set_tracer(&mut self, tracer: Option<Arc<dyn PacketTracer>>) {
+        self.tracer = tracer;
+    }
+
+    // Bytes of received packets all streams may hold together, see
+    // UcpConfig::recv_memory.
+    pub fn set_max_recv_memory(&mut self, bytes: usize) {
+        self.config.max_recv_memory = bytes;
+        self.recv_budget.set_limit(bytes);
+    }
+
+    pub fn recv_memory(&self) -> usize {
+        self.recv_budget.used()
+    }
+
+    // Sessions kept at most, SYNs over it are dropped.
+    pub fn set_max_sessions(&mut self, max_sessions: usize) {
+        self.config.max_sessions = max_sessions;
+    }
+
+    pub fn sessions(&self) -> usize {
+        self.stream_map.len()
+    }
+
+    pub fn pending_handshakes(&self) -> usize {
+        self.pending.len()
+    }
+
+    // Largest FEC group size granted to clients, 0 refuses FEC.
+    pub fn set_max_fec_group(&mut self, max_fec_group: u32) {
+        self.config.max_fec_group = max_fec_group;
+    }
+
+    // Accepts only packets signed with `key`, see connect_authenticated.
+    pub fn set_auth_key(&mut self, key: &[u8]) {
+        self.auth_key = Some(Arc::new(AuthKey::new(key)));
+    }
+
+    // Like incoming, blocking the calling thread, which must not be one
+    // of the executor's. Accepted streams run on the executor as usual.
+    pub fn accept_blocking(&mut self) -> UcpStream {
+        task::block_on(self.incoming())
+    }
+
+    // Accepted sessions for a loop on a thread of its own, see
+    // accept_blocking.
+    pub fn incoming_blocking(&mut self) -> impl Iterator<Item = UcpStream> + '_ {
+        std::iter::from_fn(move || Some(self.accept_blocking()))
+    }
+
+    // The next session to finish its handshake.
+    // After a shutdown through a ListenerShutdown it waits forever, see
+    // accept.
     pub async fn incoming(&mut self) -> UcpStream {
+        match self.accept().await {
+            Some(stream) => stream,
+            None => future::pending().await,
+        }
+    }
+
+    // Like incoming, but once shutdown is called on a ListenerShutdown
+    // of the listener it refuses new sessions, finishes the ones it has
+    // and returns None when their FIN handshakes ended or the close
+    // timeout passed. Sessions still open then are shut down.
+    pub async fn accept(&mut self) -> Option<UcpStream> {
+        while !self.closing.load(Ordering::Relaxed) {
+            if let Some(stream) = self.accept_round().await {
+                return Some(stream);
+            }
+        }
+
+        self.drain().await;
+        None
+    }
+
+    // A handle stopping the listener from another task.
+    pub fn shutdown_handle(&self) -> ListenerShutdown {
+        ListenerShutdown(self.closing.clone())
+    }
+
+    async fn drain(&mut self) {
+        info!("ucp listener draining {} sessions", self.stream_map.len());
+        let start = Instant::now();
+
         loop {
-            let mut packet = Box::new(UcpPacket::new());
-            let result = io::timeout(
-                Duration::from_secs(1),
-                self.socket.recv_from(&mut packet.buf),
-            )
-            .await;
-
-            if let Ok((size, remote_addr)) = result {
-                packet.size = size;
-
-                if packet.parse() {
-                    if let Some(inner) = self.stream_map.get(&remote_addr) {
-                        inner.input(packet, remote_addr).await;
-                    } else if packet.is_syn() {
-                        return self.new_stream(packet, remote_addr).await;
-                    } else {
-                        error!("unknown ucp session packet from {}", remote_addr);
+            let open = self
+                .stream_map
+                .values()
+                .filter(|inner| inner.alive() && !inner.finish())
+                .count();
+            if open == 0 || start.elapsed().as_millis() >= UCP_CLOSE_TIMEOUT_MILLIS {
+                break;
+            }
+
+            // Streams handed out now are shut down with the rest.
+            let _ = self.accept_round().await;
+        }
+
+        for inner in self.stream_map.values() {
+            inner.shutdown();
+        }
+        self.stream_map.clear();
+        self.pending.clear();
+    }
+
+    // Handles the packets of a batch, received first when none is left,
+    // and returns a session once its handshake finished.
+    async fn accept_round(&mut self) -> Option<UcpStream> {
+        if self.received.is_empty() {
+            let batch = if self.config.batch_io { MAX_BATCH } else { 1 };
+            let timeout = if self.closing.load(Ordering::Relaxed) {
+                Duration::from_millis(100)
+            } else {
+                Duration::from_secs(1)
+            };
+            let received = recv_packets(&self.socket, &mut self.spare, batch, timeout).await;
+            self.received.extend(received);
+        }
+
+        if let Some((mut packet, remote_addr)) = self.received.pop_front() {
+            if let Some((worker, inner)) = self.worker(&remote_addr) {
+                // A full worker drops the packet rather than holding
+                // up the sessions of the others.
+                if worker.try_send((inner, packet, remote_addr)).is_err() {
+                    budget::count_recv_drop();
+                }
+            } else if !packet.unseal(self.auth_key.as_deref()) {
+                error!("recv illgal packet from {}", remote_addr);
+            } else if let Some(inner) = self.stream_map.get(&remote_addr).cloned() {
+                if inner.decipher(&mut packet) && packet.parse() {
+                    packet.shrink();
+                    inner.input(packet, remote_addr).await;
+                    if let Some(stream) = self.established(inner, remote_addr) {
+                        return Some(stream);
                     }
                 } else {
                     error!("recv illgal packet from {}", remote_addr);
                 }
+            } else {
+                packet.shrink();
+                let mut syn = packet.clone();
+                let parsed = syn.parse();
+                if parsed && syn.is_syn() {
+                    if let Some(stream) = self.accept_syn(syn, remote_addr).await {
+                        return Some(stream);
+                    }
+                } else if let Some((inner, packet)) = self.find_session(&packet) {
+                    self.migrate_stream(inner, packet, remote_addr).await;
+                } else {
+                    error!("unknown ucp session packet from {}", remote_addr);
+                    let session_id = if parsed { syn.session_id } else { 0 };
+                    self.send_reset(session_id, packet.auth_counter, remote_addr)
+                        .await;
+                }
+            }
+        }
+
+        self.remove_dead_stream();
+        None
+    }
+
+    // New sessions over the limit are refused, and while the handshake
+    // backlog is full only a SYN echoing a cookie gets one.
+    async fn accept_syn(
+        &mut self,
+        syn: Box<UcpPacket>,
+        remote_addr: SocketAddr,
+    ) -> Option<UcpStream> {
+        if self.closing.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        // Nothing is sent for a stale SYN, and a SYN replayed after its
+        // session opened gets no second one.
+        let dated = self.auth_key.is_some() && syn_version(&syn) >= DATED_SYN_VERSION;
+        if dated && !self.syn_guard.fresh(syn.auth_counter) {
+            STALE_SYNS.fetch_add(1, Ordering::Relaxed);
+            error!("stale ucp syn from {}", remote_addr);
+            return None;
+        }
+
+        if self.stream_map.len() >= self.config.max_sessions {
+            REFUSED_SYNS.fetch_add(1, Ordering::Relaxed);
+            if !self.full {
+                self.full = true;
+                error!(
+                    "ucp sessions at the limit of {}, refusing new ones",
+                    self.config.max_sessions
+                );
+            }
+            return None;
+        }
+        self.full = false;
+
+        if self.pending.len() >= self.config.syn_backlog {
+            let echoed = syn_cookie(&syn).is_some_and(|cookie| {
+                self.cookies
+                    .check(cookie, remote_addr, syn.session_id, syn.seq)
+            });
+            if !echoed {
+                self.send_syn_cookie(&syn, remote_addr).await;
+                return None;
             }
+        }
+
+        if dated && !self.syn_guard.open(syn.session_id, syn.auth_counter) {
+            STALE_SYNS.fetch_add(1, Ordering::Relaxed);
+            error!("replayed ucp syn from {}", remote_addr);
+            return None;
+        }
+
+        let inner = self.new_stream(syn, remote_addr).await;
+        self.pending.insert(remote_addr, Instant::now());
+        self.established(inner, remote_addr)
+    }
+
+    // The worker of a session handed out by incoming already, packets of
+    // handshakes stay here until established.
+    fn worker(
+        &mut self,
+        remote_addr: &SocketAddr,
+    ) -> Option<(&mut Sender<WorkerPacket>, Arc<InnerStream>)> {
+        if self.workers.is_empty() || self.pending.contains_key(remote_addr) {
+            return None;
+        }
+
+        let inner = self.stream_map.get(remote_addr)?.clone();
+        let shard = inner.session().0 as usize % self.workers.len();
+        Some((&mut self.workers[shard], inner))
+    }
+
+    // A resumed session is established with its SYN, others once the
+    // client acknowledged the SYN_ACK.
+    fn established(
+        &mut self,
+        inner: Arc<InnerStream>,
+        remote_addr: SocketAddr,
+    ) -> Option<UcpStream> {
+        if !self.pending.contains_key(&remote_addr) || !inner.is_established() || !inner.alive() {
+            return None;
+        }
+
+        self.pending.remove(&remote_addr);
+        Some(UcpStream { inner })
+    }
 
-            self.remove_dead_stream();
+    // Tells the peer of a packet for no session here to give up on it, see
+    // InnerStream::process_reset. Resets are limited a second, so packets
+    // with a forged source address don't turn the listener into a
+    // reflector.
+    async fn send_reset(&mut self, session_id: u32, auth_counter: u32, remote_addr: SocketAddr) {
+        if self.reset_time.elapsed() >= Duration::from_secs(1) {
+            self.reset_time = Instant::now();
+            self.resets = 0;
+        }
+        if self.resets >= MAX_RESETS_PER_SEC {
+            return;
         }
+        self.resets += 1;
+        SENT_RESETS.fetch_add(1, Ordering::Relaxed);
+
+        let mut packet = UcpPacket::with_size(UCP_PACKET_META_SIZE + 4);
+        packet.session_id = session_id;
+        packet.window = self.config.window;
+        packet.cmd = CMD_RESET;
+        packet.payload_write_u32(auth_counter);
+        packet.pack(None);
+
+        let datagram = match self.auth_key {
+            Some(ref auth_key) => auth_key.seal(packet.packed_buffer(), random::<u32>()),
+            None => packet.packed_buffer().to_vec(),
+        };
+        let _ = self.socket.send_to(&datagram, remote_addr).await;
+    }
+
+    // Answers a SYN without keeping anything of it.
+    async fn send_syn_cookie(&self, syn: &UcpPacket, remote_addr: SocketAddr) {
+        let mut packet = UcpPacket::with_size(UCP_PACKET_META_SIZE + COOKIE_SIZE);
+        packet.session_id = syn.session_id;
+        packet.timestamp = syn.timestamp;
+        packet.window = self.config.window;
+        packet.una = syn.seq.wrapping_add(1);
+        packet.cmd = CMD_SYN_COOKIE;
+        packet.payload_write_slice(&self.cookies.issue(remote_addr, syn.session_id, syn.seq));
+        packet.pack(None);
+
+        let datagram = match self.auth_key {
+            Some(ref auth_key) => auth_key.seal(packet.packed_buffer(), random::<u32>()),
+            None => packet.packed_buffer().to_vec(),
+        };
+        let _ = self.socket.send_to(&datagram, remote_addr).await;
     }
 
-    async fn new_stream(&mut self, packet: Box<UcpPacket>, remote_addr: SocketAddr) -> UcpStream {
+    async fn new_stream(
+        &mut self,
+        packet: Box<UcpPacket>,
+        remote_addr: SocketAddr,
+    ) -> Arc<InnerStream> {
         info!("new ucp client from {}", remote_addr);
-        let inner = Arc::new(InnerStream::new(self.socket.clone(), remote_addr));
+        let inner = Arc::new(InnerStream::new(
+            self.socket.clone(),
+            remote_addr,
+            self.auth_key.clone(),
+            Some(self.tickets.clone()),
+            Some(self.recv_budget.clone()),
+        ));
+        inner.configure(&self.config);
+        if self.tracer.is_some() {
+            inner.set_tracer(self.tracer.clone());
+        }
         inner.input(packet, remote_addr).await;
 
         let sender = inner.clone();
@@ -1048,7 +5391,37 @@ impl UcpListener {
         });
 
         self.stream_map.insert(remote_addr, inner.clone());
-        UcpStream { inner: inner }
+        inner
+    }
+
+    // Packets of encrypted sessions only parse with the right session key.
+    fn find_session(&self, packet: &UcpPacket) -> Option<(Arc<InnerStream>, Box<UcpPacket>)> {
+        for inner in self.stream_map.values() {
+            let mut candidate = Box::new(packet.clone());
+            if inner.decipher(&mut candidate)
+                && candidate.parse()
+                && candidate.session_id == inner.session().0
+            {
+                return Some((inner.clone(), candidate));
+            }
+        }
+
+        None
+    }
+
+    async fn migrate_stream(
+        &mut self,
+        inner: Arc<InnerStream>,
+        packet: Box<UcpPacket>,
+        remote_addr: SocketAddr,
+    ) {
+        let (_, old_addr) = inner.session();
+        inner.input(packet, remote_addr).await;
+
+        if inner.session().1 == remote_addr {
+            self.stream_map.remove(&old_addr);
+            self.stream_map.insert(remote_addr, inner);
+        }
     }
 
     fn remove_dead_stream(&mut self) {
@@ -1065,10 +5438,73 @@ impl UcpListener {
             }
         }
 
+        // Handshakes not finished in time are given up.
+        let syn_timeout = Duration::from_millis(self.config.syn_timeout as u64);
+        for (addr, since) in self.pending.iter() {
+            if now - *since >= syn_timeout {
+                if let Some(stream) = self.stream_map.get(addr) {
+                    info!("ucp handshake from {} timed out", addr);
+                    stream.shutdown();
+                }
+                keys.push(*addr);
+            }
+        }
+
         for addr in keys.iter() {
             self.stream_map.remove(addr);
+            self.pending.remove(addr);
         }
 
         self.timestamp = now;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::sim::SimConfig;
+    use super::*;
+
+    fn stream(network: &SimNetwork) -> InnerStream {
+        let socket = network.bind(SocketAddr::from(([10, 0, 0, 2], 0))).unwrap();
+        let remote_addr = SocketAddr::from(([10, 0, 0, 1], 4900));
+        InnerStream::new(
+            Arc::new(Transport::Sim(socket)),
+            remote_addr,
+            None,
+            None,
+            None,
+        )
+    }
+
+    fn fragment(offset: u16, total: u16, data: &[u8]) -> Box<UcpPacket> {
+        let mut packet = Box::new(UcpPacket::with_size(UCP_PACKET_META_SIZE + 4 + data.len()));
+        packet.cmd = CMD_FRAG;
+        packet.payload_write_slice(&offset.to_be_bytes());
+        packet.payload_write_slice(&total.to_be_bytes());
+        packet.payload_write_slice(data);
+        packet.pack(None);
+        assert!(packet.parse());
+        packet
+    }
+
+    #[test]
+    fn tiny_fragment_keeps_packet_size() {
+        let network = SimNetwork::new(SimConfig::default());
+        let inner = stream(&network);
+
+        inner.process_fragment(fragment(0, 1, &[]));
+        inner.process_fragment(fragment(0, 100, &[0; 10]));
+        inner.process_fragment(fragment(5, 2000, &[0; 1000]));
+        assert_eq!(inner.packet_size.get(), UCP_PACKET_SIZE_STEPS[0]);
+    }
+
+    #[test]
+    fn full_fragment_reduces_packet_size() {
+        let network = SimNetwork::new(SimConfig::default());
+        let inner = stream(&network);
+
+        let load = 1000 - UCP_PACKET_META_SIZE - 4;
+        inner.process_fragment(fragment(load as u16, 3000, &vec![0; load]));
+        assert_eq!(inner.packet_size.get(), 1000);
+    }
+}
@@ -0,0 +1,208 @@
+use crypto::blake2s::Blake2s;
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::vec::Vec;
+
+use super::serial;
+
+// Every datagram ends with a send counter and a truncated HMAC-SHA256
+// over the datagram and the counter.
+pub const AUTH_TRAILER_SIZE: usize = 20;
+const AUTH_TAG_SIZE: usize = 16;
+const AUTH_KEY_CONTEXT: &[u8] = b"stunnel ucp packet authentication";
+const CIPHER_KEY_CONTEXT: &[u8] = b"stunnel ucp packet encryption";
+const CHECK_KEY_CONTEXT: &[u8] = b"stunnel ucp frame check";
+
+// Datagrams a client may send before resending its SYN, which is how far
+// past the max age a SYN's counter may be ahead of the server's clock.
+const SYN_COUNTER_SLACK: i64 = 4096;
+// Sessions a SynGuard remembers at most, SYNs beyond are refused.
+const MAX_SYN_SESSIONS: usize = 65536;
+
+pub struct AuthKey {
+    mac_key: Vec<u8>,
+    cipher_key: Vec<u8>,
+    check_key: Vec<u8>,
+}
+
+impl AuthKey {
+    // Derived from the tunnel key, so it is never used for two purposes.
+    pub fn new(key: &[u8]) -> AuthKey {
+        AuthKey {
+            mac_key: hmac(key, &[AUTH_KEY_CONTEXT]),
+            cipher_key: hmac(key, &[CIPHER_KEY_CONTEXT]),
+            check_key: hmac(key, &[CHECK_KEY_CONTEXT]),
+        }
+    }
+
+    // Encryption key of a session, mixed with a salt from each end.
+    pub fn session_key(&self, session_id: u32, client_salt: u32, server_salt: u32) -> Vec<u8> {
+        hmac(
+            &self.cipher_key,
+            &[
+                &session_id.to_be_bytes(),
+                &client_salt.to_be_bytes(),
+                &server_salt.to_be_bytes(),
+            ],
+        )
+    }
+
+    pub fn seal(&self, datagram: &[u8], counter: u32) -> Vec<u8> {
+        let counter = counter.to_be_bytes();
+        let mut sealed = Vec::with_capacity(datagram.len() + AUTH_TRAILER_SIZE);
+        sealed.extend_from_slice(datagram);
+        sealed.extend_from_slice(&counter);
+        sealed.extend_from_slice(&self.tag(datagram, &counter));
+        sealed
+    }
+
+    // Returns the counter of a datagram whose tag matches.
+    pub fn open(&self, datagram: &[u8]) -> Option<u32> {
+        if datagram.len() < AUTH_TRAILER_SIZE {
+            return None;
+        }
+
+        let (data, trailer) = datagram.split_at(datagram.len() - AUTH_TRAILER_SIZE);
+        let (counter, tag) = trailer.split_at(4);
+        if !fixed_time_eq(&self.tag(data, counter), tag) {
+            return None;
+        }
+
+        Some(u32::from_be_bytes([
+            counter[0], counter[1], counter[2], counter[3],
+        ]))
+    }
+
+    // Keyed BLAKE2s in place of the CRC32 at the front of a packet, so
+    // the word neither repeats for equal headers nor can be forged.
+    pub fn check(&self, data: &[u8]) -> u32 {
+        let mut check = [0u8; 4];
+        let mut hasher = Blake2s::new_keyed(check.len(), &self.check_key);
+        Digest::input(&mut hasher, data);
+        Digest::result(&mut hasher, &mut check);
+        u32::from_be_bytes(check)
+    }
+
+    fn tag(&self, data: &[u8], counter: &[u8]) -> Vec<u8> {
+        let mut tag = hmac(&self.mac_key, &[data, counter]);
+        tag.truncate(AUTH_TAG_SIZE);
+        tag
+    }
+}
+
+pub fn hmac(key: &[u8], inputs: &[&[u8]]) -> Vec<u8> {
+    let mut mac = Hmac::new(Sha256::new(), key);
+    for input in inputs {
+        mac.input(input);
+    }
+    mac.result().code().to_vec()
+}
+
+// Counters of recently received datagrams, a counter seen before or
+// older than the window is a replay.
+#[derive(Default)]
+pub struct ReplayWindow {
+    highest: Option<u32>,
+    seen: u128,
+}
+
+impl ReplayWindow {
+    pub fn accept(&mut self, counter: u32) -> bool {
+        let highest = match self.highest {
+            Some(highest) => highest,
+            None => {
+                self.highest = Some(counter);
+                self.seen = 1;
+                return true;
+            }
+        };
+
+        let ahead = serial::diff(counter, highest);
+        if ahead > 0 {
+            self.seen = self.seen.checked_shl(ahead as u32).unwrap_or(0) | 1;
+            self.highest = Some(counter);
+            return true;
+        }
+
+        let behind = ahead.unsigned_abs();
+        if behind >= u128::BITS || self.seen & (1 << behind) != 0 {
+            return false;
+        }
+
+        self.seen |= 1 << behind;
+        true
+    }
+}
+
+// Seconds since the epoch, where streams start the counters of their
+// datagrams, so a listener can tell how old a SYN is.
+pub fn unix_time() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs() as u32)
+}
+
+// Keeps a captured SYN from opening sessions again: its counter has to
+// be within the max age of the listener's clock, and its session must
+// not have been opened before while its counter is.
+pub struct SynGuard {
+    max_age: u32,
+    sessions: HashMap<u32, u32>,
+    expiries: BinaryHeap<Reverse<(u32, u32)>>,
+}
+
+impl SynGuard {
+    pub fn new(max_age: u32) -> SynGuard {
+        SynGuard {
+            max_age,
+            sessions: HashMap::new(),
+            expiries: BinaryHeap::new(),
+        }
+    }
+
+    // Whether a SYN sealed with `counter` is recent enough, checked
+    // before anything is sent for it. A max age of 0 takes any.
+    pub fn fresh(&self, counter: u32) -> bool {
+        if self.max_age == 0 {
+            return true;
+        }
+
+        let age = serial::diff(unix_time(), counter) as i64;
+        let max_age = self.max_age as i64;
+        age <= max_age && age >= -(max_age + SYN_COUNTER_SLACK)
+    }
+
+    // Remembers the session of a fresh SYN until its counter is too old,
+    // false when it was opened before.
+    pub fn open(&mut self, session_id: u32, counter: u32) -> bool {
+        if self.max_age == 0 {
+            return true;
+        }
+
+        let now = unix_time();
+        while let Some(&Reverse((expiry, session))) = self.expiries.peek() {
+            if serial::diff(now, expiry) <= 0 {
+                break;
+            }
+            self.expiries.pop();
+            if self.sessions.get(&session) == Some(&expiry) {
+                self.sessions.remove(&session);
+            }
+        }
+
+        if self.sessions.contains_key(&session_id) || self.sessions.len() >= MAX_SYN_SESSIONS {
+            return false;
+        }
+
+        let expiry = counter.wrapping_add(self.max_age);
+        self.sessions.insert(session_id, expiry);
+        self.expiries.push(Reverse((expiry, session_id)));
+        true
+    }
+}
@@ -0,0 +1,290 @@
+use std::io;
+use std::net::SocketAddr;
+#[cfg(target_os = "linux")]
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use async_std::net::UdpSocket;
+
+use super::ecn;
+
+// Datagrams sent or received with one syscall at most.
+pub const MAX_BATCH: usize = 32;
+
+// Segments of one GSO message at most, and its bytes.
+#[cfg(target_os = "linux")]
+const MAX_GSO_SEGMENTS: usize = 64;
+#[cfg(target_os = "linux")]
+const MAX_GSO_SIZE: usize = 65000;
+
+// Set once the kernel or the interface refused a GSO message.
+#[cfg(target_os = "linux")]
+static GSO_UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+
+// A sealed datagram and where it goes.
+pub type Datagram = (Vec<u8>, SocketAddr);
+
+// Sends `datagrams` in order with sendmmsg, a run of equal sized ones to
+// one address as a single GSO message while the kernel takes them. As
+// with send_to, a datagram that fails is lost.
+#[cfg(target_os = "linux")]
+pub async fn send_all(socket: &UdpSocket, datagrams: &[Datagram]) {
+    use std::os::unix::io::AsRawFd;
+
+    let mut sent = 0;
+    while sent < datagrams.len() {
+        let rest = &datagrams[sent..];
+        match send_mmsg(socket.as_raw_fd(), rest) {
+            Ok(n) => sent += n,
+            // Waits for room by sending the next one the async way.
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                let (data, addr) = &rest[0];
+                let _ = socket.send_to(data, *addr).await;
+                sent += 1;
+            }
+            Err(e) if is_gso_error(&e) && gso_enabled() && gso_run(rest) > 1 => {
+                error!("ucp gso send error, sending datagrams one by one: {}", e);
+                GSO_UNSUPPORTED.store(true, Ordering::Relaxed);
+            }
+            Err(_) => sent += 1,
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn send_all(socket: &UdpSocket, datagrams: &[Datagram]) {
+    for (data, addr) in datagrams {
+        let _ = socket.send_to(data, *addr).await;
+    }
+}
+
+// Waits for a datagram, then takes as many as are queued up to one per
+// buffer with recvmmsg, each with its size, source and whether it
+// arrived with congestion experienced.
+#[cfg(target_os = "linux")]
+pub async fn recv_batch(
+    socket: &UdpSocket,
+    bufs: &mut [&mut [u8]],
+) -> io::Result<Vec<(usize, SocketAddr, bool)>> {
+    use std::os::unix::io::AsRawFd;
+
+    if bufs.len() == 1 {
+        return Ok(vec![ecn::recv_from(socket, bufs[0]).await?]);
+    }
+
+    loop {
+        socket.peek_from(&mut [0u8; 1]).await?;
+
+        match recv_mmsg(socket.as_raw_fd(), bufs) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            result => return result,
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn recv_batch(
+    socket: &UdpSocket,
+    bufs: &mut [&mut [u8]],
+) -> io::Result<Vec<(usize, SocketAddr, bool)>> {
+    Ok(vec![ecn::recv_from(socket, &mut bufs[0]).await?])
+}
+
+#[cfg(target_os = "linux")]
+fn gso_enabled() -> bool {
+    !GSO_UNSUPPORTED.load(Ordering::Relaxed)
+}
+
+// What a kernel without UDP_SEGMENT, or an interface without checksum
+// offload, answers a GSO message with.
+#[cfg(target_os = "linux")]
+fn is_gso_error(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EIO) | Some(libc::EINVAL) | Some(libc::EMSGSIZE) | Some(libc::ENOPROTOOPT)
+    )
+}
+
+// Datagrams from the first that can go as one GSO message: to the same
+// address and of the same size, but the last that may be shorter.
+#[cfg(target_os = "linux")]
+fn gso_run(datagrams: &[Datagram]) -> usize {
+    let (first, addr) = &datagrams[0];
+    let size = first.len();
+    let mut total = size;
+    let mut run = 1;
+
+    for (data, to) in &datagrams[1..] {
+        if to != addr
+            || data.len() > size
+            || run >= MAX_GSO_SEGMENTS
+            || total + data.len() > MAX_GSO_SIZE
+        {
+            break;
+        }
+
+        run += 1;
+        total += data.len();
+        if data.len() < size {
+            break;
+        }
+    }
+
+    run
+}
+
+// The datagrams the messages taken by one sendmmsg carried.
+#[cfg(target_os = "linux")]
+fn send_mmsg(fd: libc::c_int, datagrams: &[Datagram]) -> io::Result<usize> {
+    use std::mem;
+
+    let gso = gso_enabled();
+    let mut runs = Vec::with_capacity(MAX_BATCH);
+    let mut end = 0;
+    while end < datagrams.len() && runs.len() < MAX_BATCH {
+        let run = if gso { gso_run(&datagrams[end..]) } else { 1 };
+        runs.push((end, run));
+        end += run;
+    }
+
+    let mut addrs: Vec<_> = runs
+        .iter()
+        .map(|&(start, _)| sockaddr(&datagrams[start].1))
+        .collect();
+    let mut iovs: Vec<_> = datagrams[..end]
+        .iter()
+        .map(|(data, _)| libc::iovec {
+            iov_base: data.as_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        })
+        .collect();
+    // u64 keeps the control buffers aligned for cmsghdr.
+    let mut controls = vec![[0u64; 4]; runs.len()];
+    let mut msgs = Vec::with_capacity(runs.len());
+
+    for (i, &(start, run)) in runs.iter().enumerate() {
+        let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+        let header = &mut msg.msg_hdr;
+        header.msg_name = &mut addrs[i].0 as *mut libc::sockaddr_storage as *mut libc::c_void;
+        header.msg_namelen = addrs[i].1;
+        header.msg_iov = iovs[start..].as_mut_ptr();
+        header.msg_iovlen = run as _;
+
+        if run > 1 {
+            let segment = datagrams[start].0.len() as u16;
+            header.msg_control = controls[i].as_mut_ptr() as *mut libc::c_void;
+            header.msg_controllen = unsafe { libc::CMSG_SPACE(mem::size_of::<u16>() as u32) } as _;
+
+            unsafe {
+                let cmsg = libc::CMSG_FIRSTHDR(header);
+                (*cmsg).cmsg_level = libc::SOL_UDP;
+                (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+                (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as u32) as _;
+                (libc::CMSG_DATA(cmsg) as *mut u16).write_unaligned(segment);
+            }
+        }
+
+        msgs.push(msg);
+    }
+
+    let sent = unsafe {
+        libc::sendmmsg(
+            fd,
+            msgs.as_mut_ptr(),
+            msgs.len() as _,
+            libc::MSG_DONTWAIT as _,
+        )
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(runs[..sent as usize].iter().map(|&(_, run)| run).sum())
+}
+
+#[cfg(target_os = "linux")]
+fn recv_mmsg(
+    fd: libc::c_int,
+    bufs: &mut [&mut [u8]],
+) -> io::Result<Vec<(usize, SocketAddr, bool)>> {
+    use std::mem;
+
+    let count = bufs.len().min(MAX_BATCH);
+    let mut addrs: Vec<libc::sockaddr_storage> = vec![unsafe { mem::zeroed() }; count];
+    let mut iovs: Vec<_> = bufs[..count]
+        .iter_mut()
+        .map(|buf| libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        })
+        .collect();
+    // u64 keeps the control buffers aligned for cmsghdr.
+    let mut controls = vec![[0u64; 8]; count];
+    let mut msgs: Vec<_> = addrs
+        .iter_mut()
+        .zip(&mut iovs)
+        .zip(&mut controls)
+        .map(|((addr, iov), control)| {
+            let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+            let header = &mut msg.msg_hdr;
+            header.msg_name = addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+            header.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            header.msg_iov = iov;
+            header.msg_iovlen = 1;
+            header.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            header.msg_controllen = mem::size_of_val(control) as _;
+            msg
+        })
+        .collect();
+
+    let received = unsafe {
+        libc::recvmmsg(
+            fd,
+            msgs.as_mut_ptr(),
+            count as _,
+            libc::MSG_DONTWAIT as _,
+            std::ptr::null_mut(),
+        )
+    };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    msgs[..received as usize]
+        .iter()
+        .zip(&addrs)
+        .map(|(msg, addr)| {
+            Ok((
+                msg.msg_len as usize,
+                ecn::socket_addr(addr)?,
+                ecn::congestion_experienced(&msg.msg_hdr),
+            ))
+        })
+        .collect()
+}
+
+#[cfg(target_os = "linux")]
+fn sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    use std::mem;
+
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = addr.port().to_be();
+            sin.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = addr.port().to_be();
+            sin6.sin6_addr.s6_addr = addr.ip().octets();
+            sin6.sin6_flowinfo = addr.flowinfo();
+            sin6.sin6_scope_id = addr.scope_id();
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+
+    (storage, len as libc::socklen_t)
+}
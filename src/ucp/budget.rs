@@ -0,0 +1,55 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+// Received packets dropped over a budget, by every stream of the process.
+static RECV_DROPPED_PACKETS: AtomicU64 = AtomicU64::new(0);
+
+pub fn recv_dropped_packets() -> u64 {
+    RECV_DROPPED_PACKETS.load(Ordering::Relaxed)
+}
+
+pub fn count_recv_drop() {
+    RECV_DROPPED_PACKETS.fetch_add(1, Ordering::Relaxed);
+}
+
+// Bytes of received packets held by all streams of a listener, so many
+// sessions filling their own budgets can't exhaust the server together.
+pub struct RecvBudget {
+    limit: AtomicUsize,
+    used: AtomicUsize,
+}
+
+impl RecvBudget {
+    pub fn new(limit: usize) -> RecvBudget {
+        RecvBudget {
+            limit: AtomicUsize::new(limit),
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    pub fn set_limit(&self, limit: usize) {
+        self.limit.store(limit, Ordering::Relaxed);
+    }
+
+    // Takes `bytes` if they fit, or anyway when `always`.
+    pub fn take(&self, bytes: usize, always: bool) -> bool {
+        let limit = self.limit.load(Ordering::Relaxed);
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                let total = used + bytes;
+                if always || total <= limit {
+                    Some(total)
+                } else {
+                    None
+                }
+            })
+            .is_ok()
+    }
+
+    pub fn give(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
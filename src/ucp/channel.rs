@@ -0,0 +1,165 @@
+use std::collections::{HashMap, VecDeque};
+use std::task::Waker;
+
+use super::serial;
+use super::wire::{Reader, Writer};
+
+// Data packets of a session with channels lead with the channel id and
+// the packet's sequence number within the channel, so each channel is
+// delivered in order without waiting for losses on the others.
+pub const CHANNEL_HEADER_SIZE: usize = 8;
+// Carries window updates and closes, reliable and ordered like data.
+pub const CONTROL_CHANNEL: u32 = u32::MAX;
+// Bytes a channel may send beyond what the peer's reader consumed.
+pub const CHANNEL_WINDOW: u64 = 1024 * 1024;
+pub const MAX_CHANNELS: usize = 4096;
+
+const CONTROL_WINDOW: u8 = 1;
+const CONTROL_CLOSE: u8 = 2;
+pub const CONTROL_MESSAGE_SIZE: usize = 13;
+
+pub type ChannelMap = HashMap<u32, Channel>;
+
+pub enum Control {
+    // The channel may send up to this many bytes in total.
+    Window(u32, u64),
+    // The channel's last packet precedes this channel sequence number.
+    Close(u32, u32),
+}
+
+impl Control {
+    pub fn encode(&self) -> [u8; CONTROL_MESSAGE_SIZE] {
+        let (op, id, value) = match *self {
+            Control::Window(id, limit) => (CONTROL_WINDOW, id, limit),
+            Control::Close(id, end_seq) => (CONTROL_CLOSE, id, end_seq as u64),
+        };
+
+        let mut buf = [0u8; CONTROL_MESSAGE_SIZE];
+        let mut writer = Writer::new(&mut buf);
+        writer.u8(op);
+        writer.u32(id);
+        writer.u64(value);
+        buf
+    }
+
+    pub fn decode(buf: &[u8; CONTROL_MESSAGE_SIZE]) -> Option<Control> {
+        let mut reader = Reader::new(buf);
+        let op = reader.u8()?;
+        let id = reader.u32()?;
+        let value = reader.u64()?;
+
+        match op {
+            CONTROL_WINDOW => Some(Control::Window(id, value)),
+            CONTROL_CLOSE => Some(Control::Close(id, value as u32)),
+            _ => None,
+        }
+    }
+}
+
+pub struct Channel {
+    next_seq: u32,
+    pending: HashMap<u32, Vec<u8>>,
+    readable: VecDeque<u8>,
+    consumed: u64,
+    advertised: u64,
+    end_seq: Option<u32>,
+    pub read_waker: Option<Waker>,
+
+    pub send_seq: u32,
+    sent: u64,
+    limit: u64,
+    pub write_closed: bool,
+    pub write_waker: Option<Waker>,
+}
+
+impl Channel {
+    pub fn new() -> Channel {
+        Channel {
+            next_seq: 0,
+            pending: HashMap::new(),
+            readable: VecDeque::new(),
+            consumed: 0,
+            advertised: CHANNEL_WINDOW,
+            end_seq: None,
+            read_waker: None,
+            send_seq: 0,
+            sent: 0,
+            limit: CHANNEL_WINDOW,
+            write_closed: false,
+            write_waker: None,
+        }
+    }
+
+    // Buffers packet `seq` of the channel and returns the bytes taken.
+    pub fn receive(&mut self, seq: u32, data: Vec<u8>) -> usize {
+        if serial::before(seq, self.next_seq) || self.pending.contains_key(&seq) {
+            return 0;
+        }
+
+        let len = data.len();
+        self.pending.insert(seq, data);
+
+        while let Some(data) = self.pending.remove(&self.next_seq) {
+            self.readable.extend(data);
+            self.next_seq = self.next_seq.wrapping_add(1);
+        }
+
+        len
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let n = buf.len().min(self.readable.len());
+        for (dst, src) in buf.iter_mut().zip(self.readable.drain(..n)) {
+            *dst = src;
+        }
+
+        self.consumed += n as u64;
+        n
+    }
+
+    pub fn readable_len(&self) -> usize {
+        self.readable.len()
+    }
+
+    pub fn is_readable(&self) -> bool {
+        !self.readable.is_empty() || self.is_finished()
+    }
+
+    // The peer closed the channel and everything before was read.
+    pub fn is_finished(&self) -> bool {
+        self.end_seq == Some(self.next_seq) && self.readable.is_empty()
+    }
+
+    pub fn set_end(&mut self, end_seq: u32) {
+        self.end_seq = Some(end_seq);
+    }
+
+    // A new limit is due once the reader consumed half a window since
+    // the last one.
+    pub fn window_update(&mut self) -> Option<u64> {
+        let limit = self.consumed + CHANNEL_WINDOW;
+        if self.end_seq.is_none() && limit - self.advertised >= CHANNEL_WINDOW / 2 {
+            self.advertised = limit;
+            Some(limit)
+        } else {
+            None
+        }
+    }
+
+    pub fn send_credit(&self) -> usize {
+        self.limit.saturating_sub(self.sent) as usize
+    }
+
+    pub fn note_sent(&mut self, len: usize) {
+        self.sent += len as u64;
+    }
+
+    pub fn grant(&mut self, limit: u64) {
+        self.limit = self.limit.max(limit);
+    }
+
+    // Both ends closed, nothing left to read.
+    pub fn is_done(&self) -> bool {
+        self.write_closed && self.is_finished()
+    }
+}
@@ -0,0 +1,37 @@
+use crypto::chacha20::ChaCha20;
+use crypto::symmetriccipher::SynchronousStreamCipher;
+use std::vec::Vec;
+
+// ChaCha20 over everything after the CRC. Every session has its own key,
+// and the nonce is the direction and the send counter of the datagram,
+// which unlike seq is never reused by resends.
+pub struct PacketCipher {
+    key: Vec<u8>,
+    send_direction: u32,
+}
+
+impl PacketCipher {
+    pub fn new(key: Vec<u8>, client: bool) -> PacketCipher {
+        PacketCipher {
+            key,
+            send_direction: if client { 0 } else { 1 },
+        }
+    }
+
+    pub fn encrypt(&self, data: &mut [u8], counter: u32) {
+        self.apply(data, self.send_direction, counter);
+    }
+
+    pub fn decrypt(&self, data: &mut [u8], counter: u32) {
+        self.apply(data, 1 - self.send_direction, counter);
+    }
+
+    fn apply(&self, data: &mut [u8], direction: u32, counter: u32) {
+        let mut nonce = [0u8; 8];
+        nonce[..4].copy_from_slice(&direction.to_be_bytes());
+        nonce[4..].copy_from_slice(&counter.to_be_bytes());
+
+        let input = data.to_vec();
+        ChaCha20::new(&self.key, &nonce).process(&input, data);
+    }
+}
@@ -0,0 +1,373 @@
+use std::collections::VecDeque;
+use std::str::FromStr;
+
+use super::serial;
+
+// Windows are counted in packets and times in stream timestamp milliseconds.
+const INITIAL_WINDOW: u32 = 10;
+const MIN_WINDOW: u32 = 2;
+const MAX_WINDOW: u32 = 65536;
+
+pub trait CongestionControl: Send {
+    fn name(&self) -> &'static str;
+
+    // Maximum number of packets in flight.
+    fn window(&self) -> u32;
+
+    fn on_ack(&mut self, acked: u32, rtt: u32, now: u32);
+
+    // Called once per output round in which packets were resent.
+    fn on_loss(&mut self, now: u32, srtt: u32);
+
+    // Called when the peer echoed new congestion experienced marks, which
+    // loss based algorithms treat like a loss.
+    fn on_ecn(&mut self, now: u32, srtt: u32) {
+        self.on_loss(now, srtt);
+    }
+
+    // Called with each loss report of the peer: the packets missing below
+    // the highest it received from us. Their resends count as losses
+    // already, so none of the algorithms here act on it.
+    fn on_loss_report(&mut self, _holes: u32, _now: u32, _srtt: u32) {}
+
+    // Measured delivery rate in packets per millisecond, when the
+    // algorithm keeps one.
+    fn bandwidth(&self) -> Option<f64> {
+        None
+    }
+
+    // Called with each probe of the peer: the rate our packets reach it
+    // in packets per millisecond and how long they queue on the way.
+    // Loss based algorithms back off once the queue takes half the round
+    // trip, before a deep buffer overflows.
+    fn on_probe(&mut self, _rate: f64, queue_delay: u32, now: u32, srtt: u32) {
+        if srtt > 0 && queue_delay.saturating_mul(2) > srtt {
+            self.on_loss(now, srtt);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CongestionAlgorithm {
+    #[default]
+    Fixed,
+    Reno,
+    Cubic,
+    Bbr,
+}
+
+impl CongestionAlgorithm {
+    pub fn build(self) -> Box<dyn CongestionControl> {
+        match self {
+            CongestionAlgorithm::Fixed => Box::new(Fixed),
+            CongestionAlgorithm::Reno => Box::new(Reno::new()),
+            CongestionAlgorithm::Cubic => Box::new(Cubic::new()),
+            CongestionAlgorithm::Bbr => Box::new(Bbr::new()),
+        }
+    }
+}
+
+impl FromStr for CongestionAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fixed" => Ok(CongestionAlgorithm::Fixed),
+            "reno" => Ok(CongestionAlgorithm::Reno),
+            "cubic" => Ok(CongestionAlgorithm::Cubic),
+            "bbr" => Ok(CongestionAlgorithm::Bbr),
+            _ => Err(format!("unknown congestion control {}", s)),
+        }
+    }
+}
+
+// No congestion window, only the window advertised by the peer applies.
+pub struct Fixed;
+
+impl CongestionControl for Fixed {
+    fn name(&self) -> &'static str {
+        "fixed"
+    }
+
+    fn window(&self) -> u32 {
+        u32::MAX
+    }
+
+    fn on_ack(&mut self, _acked: u32, _rtt: u32, _now: u32) {}
+
+    fn on_loss(&mut self, _now: u32, _srtt: u32) {}
+}
+
+// Losses within one round trip of the previous reduction belong to the
+// same congestion event.
+fn in_recovery(recovery_end: Option<u32>, now: u32) -> bool {
+    recovery_end.is_some_and(|end| serial::before(now, end))
+}
+
+pub struct Reno {
+    cwnd: u32,
+    ssthresh: u32,
+    acked: u32,
+    recovery_end: Option<u32>,
+}
+
+impl Reno {
+    pub fn new() -> Reno {
+        Reno {
+            cwnd: INITIAL_WINDOW,
+            ssthresh: MAX_WINDOW,
+            acked: 0,
+            recovery_end: None,
+        }
+    }
+}
+
+impl Default for Reno {
+    fn default() -> Self {
+        Reno::new()
+    }
+}
+
+impl CongestionControl for Reno {
+    fn name(&self) -> &'static str {
+        "reno"
+    }
+
+    fn window(&self) -> u32 {
+        self.cwnd
+    }
+
+    fn on_ack(&mut self, acked: u32, _rtt: u32, _now: u32) {
+        if self.cwnd < self.ssthresh {
+            self.cwnd += acked;
+        } else {
+            self.acked += acked;
+            if self.acked >= self.cwnd {
+                self.acked -= self.cwnd;
+                self.cwnd += 1;
+            }
+        }
+
+        self.cwnd = self.cwnd.min(MAX_WINDOW);
+    }
+
+    fn on_loss(&mut self, now: u32, srtt: u32) {
+        if in_recovery(self.recovery_end, now) {
+            return;
+        }
+
+        self.ssthresh = (self.cwnd / 2).max(MIN_WINDOW);
+        self.cwnd = self.ssthresh;
+        self.acked = 0;
+        self.recovery_end = Some(now.wrapping_add(srtt));
+    }
+}
+
+const CUBIC_C: f64 = 0.4;
+const CUBIC_BETA: f64 = 0.7;
+
+pub struct Cubic {
+    cwnd: f64,
+    ssthresh: f64,
+    w_max: f64,
+    k: f64,
+    epoch_start: Option<u32>,
+    recovery_end: Option<u32>,
+}
+
+impl Cubic {
+    pub fn new() -> Cubic {
+        Cubic {
+            cwnd: INITIAL_WINDOW as f64,
+            ssthresh: MAX_WINDOW as f64,
+            w_max: 0.0,
+            k: 0.0,
+            epoch_start: None,
+            recovery_end: None,
+        }
+    }
+}
+
+impl Default for Cubic {
+    fn default() -> Self {
+        Cubic::new()
+    }
+}
+
+impl CongestionControl for Cubic {
+    fn name(&self) -> &'static str {
+        "cubic"
+    }
+
+    fn window(&self) -> u32 {
+        self.cwnd as u32
+    }
+
+    fn on_ack(&mut self, acked: u32, rtt: u32, now: u32) {
+        let acked = acked as f64;
+
+        if self.cwnd < self.ssthresh {
+            self.cwnd += acked;
+        } else {
+            if self.epoch_start.is_none() {
+                self.epoch_start = Some(now);
+                self.k = if self.w_max > self.cwnd {
+                    ((self.w_max - self.cwnd) / CUBIC_C).cbrt()
+                } else {
+                    0.0
+                };
+                self.w_max = self.w_max.max(self.cwnd);
+            }
+
+            let t = (now.wrapping_sub(self.epoch_start.unwrap()) + rtt) as f64 / 1000.0;
+            let target = CUBIC_C * (t - self.k).powi(3) + self.w_max;
+
+            if target > self.cwnd {
+                self.cwnd += (target - self.cwnd) / self.cwnd * acked;
+            } else {
+                self.cwnd += 0.01 * acked / self.cwnd;
+            }
+        }
+
+        self.cwnd = self.cwnd.min(MAX_WINDOW as f64);
+    }
+
+    fn on_loss(&mut self, now: u32, srtt: u32) {
+        if in_recovery(self.recovery_end, now) {
+            return;
+        }
+
+        self.w_max = self.cwnd;
+        self.cwnd = (self.cwnd * CUBIC_BETA).max(MIN_WINDOW as f64);
+        self.ssthresh = self.cwnd;
+        self.epoch_start = None;
+        self.recovery_end = Some(now.wrapping_add(srtt));
+    }
+}
+
+const BBR_GAIN: f64 = 2.0;
+const BBR_BW_SAMPLES: usize = 10;
+const BBR_MIN_RTT_EXPIRE_MS: u32 = 10000;
+const BBR_FULL_BW_ROUNDS: u32 = 3;
+
+// Model based: the window follows the estimated bandwidth-delay product
+// instead of reacting to loss. Startup grows exponentially until the
+// bandwidth estimate stops increasing.
+pub struct Bbr {
+    cwnd: u32,
+    delivered: u32,
+    sample_start: Option<u32>,
+    bw_samples: VecDeque<f64>,
+    min_rtt: u32,
+    min_rtt_stamp: u32,
+    full_bw: f64,
+    full_bw_rounds: u32,
+    filled_pipe: bool,
+}
+
+impl Bbr {
+    pub fn new() -> Bbr {
+        Bbr {
+            cwnd: INITIAL_WINDOW,
+            delivered: 0,
+            sample_start: None,
+            bw_samples: VecDeque::new(),
+            min_rtt: 0,
+            min_rtt_stamp: 0,
+            full_bw: 0.0,
+            full_bw_rounds: 0,
+            filled_pipe: false,
+        }
+    }
+
+    fn max_bw(&self) -> f64 {
+        self.bw_samples.iter().cloned().fold(0.0, f64::max)
+    }
+
+    fn check_full_pipe(&mut self) {
+        let max_bw = self.max_bw();
+
+        if max_bw >= self.full_bw * 1.25 {
+            self.full_bw = max_bw;
+            self.full_bw_rounds = 0;
+        } else {
+            self.full_bw_rounds += 1;
+            self.filled_pipe = self.full_bw_rounds >= BBR_FULL_BW_ROUNDS;
+        }
+    }
+}
+
+impl Default for Bbr {
+    fn default() -> Self {
+        Bbr::new()
+    }
+}
+
+impl CongestionControl for Bbr {
+    fn name(&self) -> &'static str {
+        "bbr"
+    }
+
+    fn window(&self) -> u32 {
+        self.cwnd
+    }
+
+    fn on_ack(&mut self, acked: u32, rtt: u32, now: u32) {
+        if rtt > 0
+            && (self.min_rtt == 0
+                || rtt < self.min_rtt
+                || now.wrapping_sub(self.min_rtt_stamp) > BBR_MIN_RTT_EXPIRE_MS)
+        {
+            self.min_rtt = rtt;
+            self.min_rtt_stamp = now;
+        }
+
+        self.delivered += acked;
+        let start = *self.sample_start.get_or_insert(now);
+        let elapsed = now.wrapping_sub(start);
+
+        // One bandwidth sample per round trip.
+        if elapsed > 0 && elapsed >= self.min_rtt {
+            self.bw_samples
+                .push_back(self.delivered as f64 / elapsed as f64);
+            if self.bw_samples.len() > BBR_BW_SAMPLES {
+                self.bw_samples.pop_front();
+            }
+
+            self.delivered = 0;
+            self.sample_start = Some(now);
+
+            if !self.filled_pipe {
+                self.check_full_pipe();
+            }
+        }
+
+        if self.filled_pipe {
+            let bdp = self.max_bw() * self.min_rtt.max(1) as f64;
+            self.cwnd = ((bdp * BBR_GAIN) as u32).max(MIN_WINDOW);
+        } else {
+            self.cwnd += acked;
+        }
+
+        self.cwnd = self.cwnd.min(MAX_WINDOW);
+    }
+
+    fn on_loss(&mut self, _now: u32, _srtt: u32) {}
+
+    // The window follows the bandwidth-delay product, which already
+    // leaves out the queue. Until acks measured the bandwidth, the rate
+    // seen by the peer stands in.
+    fn on_probe(&mut self, rate: f64, _queue_delay: u32, _now: u32, _srtt: u32) {
+        if self.bw_samples.is_empty() && rate > 0.0 {
+            self.bw_samples.push_back(rate);
+        }
+    }
+
+    fn bandwidth(&self) -> Option<f64> {
+        if self.bw_samples.is_empty() {
+            None
+        } else {
+            Some(self.max_bw())
+        }
+    }
+}
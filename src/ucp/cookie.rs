@@ -0,0 +1,69 @@
+use crypto::util::fixed_time_eq;
+use rand::random;
+use std::net::SocketAddr;
+use std::time::Instant;
+use std::vec::Vec;
+
+use super::auth::hmac;
+use super::wire::Reader;
+
+// A cookie holds the seconds since the listener started when it was
+// issued and a truncated HMAC-SHA256 over them, the client address, its
+// session id and first sequence number, with a secret of the listener.
+// It answers a SYN when the handshake backlog is full, and a SYN that
+// echoes it proves the client gets packets sent to its address.
+pub const COOKIE_SIZE: usize = 12;
+const COOKIE_LIFETIME_SECS: u32 = 30;
+const COOKIE_TAG_SIZE: usize = 8;
+const COOKIE_SECRET_SIZE: usize = 32;
+
+pub struct CookieJar {
+    secret: Vec<u8>,
+    start: Instant,
+}
+
+impl CookieJar {
+    pub fn new() -> CookieJar {
+        CookieJar {
+            secret: (0..COOKIE_SECRET_SIZE).map(|_| random::<u8>()).collect(),
+            start: Instant::now(),
+        }
+    }
+
+    pub fn issue(&self, addr: SocketAddr, session_id: u32, seq: u32) -> Vec<u8> {
+        let issued = self.now();
+        let mut cookie = Vec::with_capacity(COOKIE_SIZE);
+        cookie.extend_from_slice(&issued.to_be_bytes());
+        cookie.extend_from_slice(&self.tag(issued, addr, session_id, seq));
+        cookie
+    }
+
+    pub fn check(&self, cookie: &[u8], addr: SocketAddr, session_id: u32, seq: u32) -> bool {
+        let mut reader = Reader::new(cookie);
+        let (issued, tag) = match (reader.u32(), reader.bytes(COOKIE_TAG_SIZE)) {
+            (Some(issued), Some(tag)) => (issued, tag),
+            _ => return false,
+        };
+
+        self.now().wrapping_sub(issued) <= COOKIE_LIFETIME_SECS
+            && fixed_time_eq(&self.tag(issued, addr, session_id, seq), tag)
+    }
+
+    fn now(&self) -> u32 {
+        self.start.elapsed().as_secs() as u32
+    }
+
+    fn tag(&self, issued: u32, addr: SocketAddr, session_id: u32, seq: u32) -> Vec<u8> {
+        let mut tag = hmac(
+            &self.secret,
+            &[
+                &issued.to_be_bytes(),
+                addr.to_string().as_bytes(),
+                &session_id.to_be_bytes(),
+                &seq.to_be_bytes(),
+            ],
+        );
+        tag.truncate(COOKIE_TAG_SIZE);
+        tag
+    }
+}
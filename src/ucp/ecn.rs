@@ -0,0 +1,165 @@
+use std::io;
+use std::net::SocketAddr;
+
+use async_std::net::UdpSocket;
+
+#[cfg(target_os = "linux")]
+use super::sockopt::set_option;
+
+// ECN codepoints, the low two bits of the IPv4 TOS or IPv6 traffic class.
+#[cfg(target_os = "linux")]
+const ECN_ECT0: libc::c_int = 0b10;
+#[cfg(target_os = "linux")]
+const ECN_CE: u8 = 0b11;
+
+// Asks for the ECN bits of received datagrams, and with `mark` sends
+// datagrams as ECN capable so bottlenecks mark them instead of dropping.
+// The DSCP fills the six bits above them.
+#[cfg(target_os = "linux")]
+pub fn init(socket: &UdpSocket, mark: bool, dscp: u8) {
+    let (level, recv_name, mark_name) = match socket.local_addr() {
+        Ok(SocketAddr::V6(_)) => (libc::IPPROTO_IPV6, libc::IPV6_RECVTCLASS, libc::IPV6_TCLASS),
+        _ => (libc::IPPROTO_IP, libc::IP_RECVTOS, libc::IP_TOS),
+    };
+
+    if let Err(e) = set_option(socket, level, recv_name, 1) {
+        error!("set ucp socket recv ecn error: {}", e);
+    }
+
+    let ecn = if mark { ECN_ECT0 } else { 0 };
+    let tos = (dscp as libc::c_int & 0x3f) << 2 | ecn;
+    if tos != 0 {
+        if let Err(e) = set_option(socket, level, mark_name, tos) {
+            error!("set ucp socket traffic class {:#x} error: {}", tos, e);
+        }
+    }
+
+    // A dual-stack socket carries IPv4 datagrams with the IPv4 options,
+    // an IPv6 only socket refuses them, which is fine.
+    if level == libc::IPPROTO_IPV6 {
+        let _ = set_option(socket, libc::IPPROTO_IP, libc::IP_RECVTOS, 1);
+        if tos != 0 {
+            let _ = set_option(socket, libc::IPPROTO_IP, libc::IP_TOS, tos);
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn init(_socket: &UdpSocket, _mark: bool, _dscp: u8) {}
+
+// Like UdpSocket::recv_from, also telling whether the datagram arrived
+// with congestion experienced.
+#[cfg(target_os = "linux")]
+pub async fn recv_from(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, bool)> {
+    use std::os::unix::io::AsRawFd;
+
+    loop {
+        // Waits for a datagram without taking it, recvmsg takes it with
+        // its ancillary data.
+        socket.peek_from(&mut [0u8; 1]).await?;
+
+        match recv_msg(socket.as_raw_fd(), buf) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            result => return result,
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn recv_from(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, bool)> {
+    let (size, addr) = socket.recv_from(buf).await?;
+    Ok((size, addr, false))
+}
+
+#[cfg(target_os = "linux")]
+fn recv_msg(fd: libc::c_int, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, bool)> {
+    use std::mem;
+
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    // u64 keeps the control buffer aligned for cmsghdr.
+    let mut control = [0u64; 8];
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = &mut addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+    msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+
+    let size = unsafe { libc::recvmsg(fd, &mut msg, libc::MSG_DONTWAIT) };
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok((
+        size as usize,
+        socket_addr(&addr)?,
+        congestion_experienced(&msg),
+    ))
+}
+
+// Whether the TOS or traffic class received with a message is marked
+// congestion experienced.
+#[cfg(target_os = "linux")]
+pub fn congestion_experienced(msg: &libc::msghdr) -> bool {
+    let mut ce = false;
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(msg) };
+    while !cmsg.is_null() {
+        let header = unsafe { &*cmsg };
+        let data = unsafe { libc::CMSG_DATA(cmsg) };
+
+        // IP_TOS comes as a byte, IPV6_TCLASS as an int.
+        let tos = match (header.cmsg_level, header.cmsg_type) {
+            (libc::IPPROTO_IP, libc::IP_TOS) => Some(unsafe { *data }),
+            (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => {
+                Some(unsafe { (data as *const libc::c_int).read_unaligned() } as u8)
+            }
+            _ => None,
+        };
+
+        if tos.is_some_and(|tos| tos & ECN_CE == ECN_CE) {
+            ce = true;
+        }
+
+        cmsg = unsafe { libc::CMSG_NXTHDR(msg, cmsg) };
+    }
+
+    ce
+}
+
+#[cfg(target_os = "linux")]
+pub fn socket_addr(addr: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+
+    match addr.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            let port = u16::from_be(addr.sin_port);
+            Ok(SocketAddr::V4(SocketAddrV4::new(ip, port)))
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in6) };
+            let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+            let port = u16::from_be(addr.sin6_port);
+            Ok(SocketAddr::V6(SocketAddrV6::new(
+                ip,
+                port,
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )))
+        }
+        _ => Err(io::Error::from(io::ErrorKind::InvalidData)),
+    }
+}
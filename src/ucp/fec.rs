@@ -0,0 +1,119 @@
+use std::collections::VecDeque;
+use std::vec::Vec;
+
+// A parity packet carries the first seq, the number of data packets in
+// the group and the xor of their payload lengths, followed by the xor of
+// their payloads.
+pub const FEC_HEADER_SIZE: usize = 12;
+pub const MAX_FEC_GROUP: u32 = 16;
+
+// Payloads kept for rebuilding, in groups.
+const FEC_CACHE_GROUPS: usize = 4;
+
+pub struct FecGroup {
+    pub first_seq: u32,
+    pub count: u32,
+    pub len_xor: u32,
+    pub parity: Vec<u8>,
+}
+
+impl FecGroup {
+    pub fn new(first_seq: u32) -> FecGroup {
+        FecGroup {
+            first_seq,
+            count: 0,
+            len_xor: 0,
+            parity: Vec::new(),
+        }
+    }
+
+    pub fn next_seq(&self) -> u32 {
+        self.first_seq.wrapping_add(self.count)
+    }
+
+    pub fn add(&mut self, payload: &[u8]) {
+        xor_into(&mut self.parity, payload);
+        self.len_xor ^= payload.len() as u32;
+        self.count += 1;
+    }
+}
+
+// Recently received data payloads by seq, the parity of a group only
+// helps while the other members are still at hand.
+#[derive(Default)]
+pub struct FecCache {
+    capacity: usize,
+    payloads: VecDeque<(u32, Vec<u8>)>,
+}
+
+impl FecCache {
+    pub fn new(group: u32) -> FecCache {
+        FecCache {
+            capacity: group as usize * FEC_CACHE_GROUPS,
+            payloads: VecDeque::new(),
+        }
+    }
+
+    pub fn insert(&mut self, seq: u32, payload: &[u8]) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if self.payloads.len() >= self.capacity {
+            self.payloads.pop_front();
+        }
+
+        self.payloads.push_back((seq, payload.to_vec()));
+    }
+
+    fn get(&self, seq: u32) -> Option<&Vec<u8>> {
+        self.payloads
+            .iter()
+            .rev()
+            .find(|(s, _)| *s == seq)
+            .map(|(_, payload)| payload)
+    }
+
+    // Rebuilds the single member of the group that is neither cached nor
+    // received, returns its seq and payload.
+    pub fn recover<F: Fn(u32) -> bool>(
+        &self,
+        group: &FecGroup,
+        received: F,
+    ) -> Option<(u32, Vec<u8>)> {
+        let mut missing = None;
+        let mut data = group.parity.clone();
+        let mut len = group.len_xor;
+
+        for i in 0..group.count {
+            let seq = group.first_seq.wrapping_add(i);
+            match self.get(seq) {
+                Some(payload) => {
+                    xor_into(&mut data, payload);
+                    len ^= payload.len() as u32;
+                }
+
+                None if missing.is_none() => missing = Some(seq),
+                None => return None,
+            }
+        }
+
+        let seq = missing?;
+        if received(seq) || len as usize > data.len() {
+            return None;
+        }
+
+        data.truncate(len as usize);
+        Some((seq, data))
+    }
+}
+
+fn xor_into(parity: &mut Vec<u8>, payload: &[u8]) {
+    if parity.len() < payload.len() {
+        parity.resize(payload.len(), 0);
+    }
+
+    for (p, b) in parity.iter_mut().zip(payload.iter()) {
+        *p ^= b;
+    }
+}
@@ -0,0 +1,142 @@
+use std::cell::RefCell;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use super::{PacketPoolStats, UCP_MAX_PACKET_SIZE};
+
+// Capacities buffers are pooled by: small control packets, packets of a
+// usual path MTU, and jumbo packets along with receive buffers.
+const CLASSES: [usize; 3] = [256, 1500, UCP_MAX_PACKET_SIZE];
+
+// Bytes of free buffers a thread keeps of each class at most.
+const MAX_POOLED_BYTES: usize = 4 << 20;
+
+static POOLED_BUFFERS: AtomicUsize = AtomicUsize::new(0);
+static POOLED_BYTES: AtomicUsize = AtomicUsize::new(0);
+static REUSED_BUFFERS: AtomicU64 = AtomicU64::new(0);
+static ALLOCATED_BUFFERS: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    static FREE: RefCell<FreeLists> = RefCell::new(FreeLists::default());
+}
+
+#[derive(Default)]
+struct FreeLists([Vec<Vec<u8>>; CLASSES.len()]);
+
+impl Drop for FreeLists {
+    fn drop(&mut self) {
+        for list in self.0.iter() {
+            POOLED_BUFFERS.fetch_sub(list.len(), Ordering::Relaxed);
+            POOLED_BYTES.fetch_sub(
+                list.iter().map(|buf| buf.capacity()).sum(),
+                Ordering::Relaxed,
+            );
+        }
+    }
+}
+
+pub fn stats() -> PacketPoolStats {
+    PacketPoolStats {
+        buffers: POOLED_BUFFERS.load(Ordering::Relaxed),
+        bytes: POOLED_BYTES.load(Ordering::Relaxed),
+        reused: REUSED_BUFFERS.load(Ordering::Relaxed),
+        allocated: ALLOCATED_BUFFERS.load(Ordering::Relaxed),
+    }
+}
+
+// The buffer of a packet, taken from the pool of the thread making the
+// packet and given back to the pool of the thread dropping it, once it
+// was acked or read.
+pub struct PacketBuf(Vec<u8>);
+
+impl PacketBuf {
+    // `size` zeroed bytes.
+    pub fn new(size: usize) -> PacketBuf {
+        let mut buf = take(size);
+        buf.resize(size, 0);
+        PacketBuf(buf)
+    }
+
+    // Moves the first `size` bytes to a buffer of the smallest class
+    // holding them, so a received packet doesn't keep a receive buffer.
+    pub fn fit(&mut self, size: usize) {
+        let mut buf = take(size);
+        buf.extend_from_slice(&self.0[..size]);
+        give(mem::replace(&mut self.0, buf));
+    }
+}
+
+impl Clone for PacketBuf {
+    fn clone(&self) -> PacketBuf {
+        let mut buf = take(self.0.len());
+        buf.extend_from_slice(&self.0);
+        PacketBuf(buf)
+    }
+}
+
+impl Drop for PacketBuf {
+    fn drop(&mut self) {
+        give(mem::take(&mut self.0));
+    }
+}
+
+impl Deref for PacketBuf {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.0
+    }
+}
+
+impl DerefMut for PacketBuf {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.0
+    }
+}
+
+fn take(size: usize) -> Vec<u8> {
+    let class = match CLASSES.iter().position(|&capacity| size <= capacity) {
+        Some(class) => class,
+        None => {
+            ALLOCATED_BUFFERS.fetch_add(1, Ordering::Relaxed);
+            return Vec::with_capacity(size);
+        }
+    };
+
+    let pooled = FREE
+        .try_with(|free| free.borrow_mut().0[class].pop())
+        .ok()
+        .flatten();
+    match pooled {
+        Some(buf) => {
+            POOLED_BUFFERS.fetch_sub(1, Ordering::Relaxed);
+            POOLED_BYTES.fetch_sub(buf.capacity(), Ordering::Relaxed);
+            REUSED_BUFFERS.fetch_add(1, Ordering::Relaxed);
+            buf
+        }
+        None => {
+            ALLOCATED_BUFFERS.fetch_add(1, Ordering::Relaxed);
+            Vec::with_capacity(CLASSES[class])
+        }
+    }
+}
+
+// Only buffers of a class' capacity are kept, others are freed.
+fn give(mut buf: Vec<u8>) {
+    let capacity = buf.capacity();
+    let class = match CLASSES.iter().position(|&c| c == capacity) {
+        Some(class) => class,
+        None => return,
+    };
+
+    buf.clear();
+    let _ = FREE.try_with(|free| {
+        let list = &mut free.borrow_mut().0[class];
+        if (list.len() + 1) * capacity <= MAX_POOLED_BYTES {
+            list.push(buf);
+            POOLED_BUFFERS.fetch_add(1, Ordering::Relaxed);
+            POOLED_BYTES.fetch_add(capacity, Ordering::Relaxed);
+        }
+    });
+}
@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use async_std::io::timeout;
+use async_std::net::UdpSocket;
+
+use super::wire::Reader;
+use crate::error;
+
+// Every rendezvous datagram starts with it, so the few that arrive after
+// a session started fail the CRC check of UCP packets.
+const MAGIC: &[u8; 4] = b"UCPR";
+
+const KIND_REGISTER: u8 = 1;
+const KIND_PEER: u8 = 2;
+const KIND_PUNCH: u8 = 3;
+
+const MAX_TOKEN_SIZE: usize = 64;
+
+// How often a peer registers until the introducer names the other peer,
+// which also holds the NAT mapping to the introducer.
+const REGISTER_INTERVAL_MILLIS: u64 = 500;
+// How often punches are sent while waiting for the other peer's.
+const PUNCH_INTERVAL_MILLIS: u64 = 100;
+// Punches sent after the other peer's arrived, so it sees one as well.
+const FINAL_PUNCHES: usize = 3;
+// Ports after the other peer's observed port punched as well, for NATs
+// that map the next flow to the next port.
+const PREDICTED_PORTS: u16 = 2;
+const PUNCH_TIMEOUT_MILLIS: u64 = 10000;
+
+// How long an introducer remembers a registration, and how many it
+// keeps at most.
+const REGISTRATION_TIMEOUT_SECS: u64 = 30;
+const MAX_REGISTRATIONS: usize = 4096;
+
+// Which end of the session a peer takes: the listening peer accepts the
+// SYN of the connecting one once the NATs let datagrams through.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Role {
+    Listen,
+    Connect,
+}
+
+impl Role {
+    fn other(self) -> Role {
+        match self {
+            Role::Listen => Role::Connect,
+            Role::Connect => Role::Listen,
+        }
+    }
+}
+
+// Pairs a listening and a connecting peer registered with the same token
+// and tells each the address the other's registrations came from, see
+// UcpListener::bind_rendezvous and UcpStream::connect_rendezvous. It
+// relays no session data, and the peers authenticate each other with
+// the UCP key.
+pub struct Introducer {
+    socket: UdpSocket,
+}
+
+impl Introducer {
+    pub async fn bind(listen_addr: &str) -> error::Result<Introducer> {
+        let socket = UdpSocket::bind(listen_addr)
+            .await
+            .map_err(|e| error::Error::io(format!("ucp introducer bind {}", listen_addr), e))?;
+        Ok(Introducer { socket })
+    }
+
+    pub async fn run(&self) {
+        let lifetime = Duration::from_secs(REGISTRATION_TIMEOUT_SECS);
+        let mut registrations: HashMap<(Vec<u8>, Role), (SocketAddr, Instant)> = HashMap::new();
+        let mut buf = [0u8; 128];
+
+        loop {
+            let (size, from) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    error!("ucp introducer recv error: {}", e);
+                    continue;
+                }
+            };
+
+            let (role, token) = match parse_register(&buf[..size]) {
+                Some(register) => register,
+                None => continue,
+            };
+
+            let now = Instant::now();
+            if registrations.len() >= MAX_REGISTRATIONS {
+                registrations.retain(|_, (_, since)| now - *since < lifetime);
+                if registrations.len() >= MAX_REGISTRATIONS {
+                    continue;
+                }
+            }
+
+            registrations.insert((token.to_vec(), role), (from, now));
+            let other = match registrations.get(&(token.to_vec(), role.other())) {
+                Some((addr, since)) if now - *since < lifetime => *addr,
+                _ => continue,
+            };
+
+            let _ = self.socket.send_to(&peer_message(other), from).await;
+            let _ = self.socket.send_to(&peer_message(from), other).await;
+        }
+    }
+}
+
+// Registers `token` with `introducer` from `socket` until the introducer
+// names the other peer, then punches the NATs of both sides by sending to
+// it until its punches arrive. Returns where they came from, the address
+// the session runs to.
+pub async fn punch(
+    socket: &UdpSocket,
+    introducer: SocketAddr,
+    token: &[u8],
+    role: Role,
+) -> io::Result<SocketAddr> {
+    if token.is_empty() || token.len() > MAX_TOKEN_SIZE {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    }
+
+    let start = Instant::now();
+    let deadline = start + Duration::from_millis(PUNCH_TIMEOUT_MILLIS);
+    let register = register_message(role, token);
+    let punch = punch_message(token);
+    let mut peer: Option<SocketAddr> = None;
+    let mut next_register = start;
+    let mut buf = [0u8; 128];
+
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(io::Error::from(io::ErrorKind::TimedOut));
+        }
+
+        match peer {
+            Some(peer) => {
+                for addr in predicted(peer) {
+                    socket.send_to(&punch, addr).await?;
+                }
+            }
+            None if now >= next_register => {
+                socket.send_to(&register, introducer).await?;
+                next_register = now + Duration::from_millis(REGISTER_INTERVAL_MILLIS);
+            }
+            None => {}
+        }
+
+        let wait = Duration::from_millis(PUNCH_INTERVAL_MILLIS);
+        let (size, from) = match timeout(wait, socket.recv_from(&mut buf)).await {
+            Ok(received) => received,
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e),
+        };
+
+        let mut reader = Reader::new(&buf[..size]);
+        if reader.bytes(MAGIC.len()) != Some(&MAGIC[..]) {
+            continue;
+        }
+
+        match reader.u8() {
+            Some(KIND_PEER) if from == introducer => {
+                if let Some(addr) = read_addr(&mut reader) {
+                    peer = Some(addr);
+                }
+            }
+            // The NAT of the other peer may have mapped its socket to
+            // another port than the introducer saw, its punches tell.
+            Some(KIND_PUNCH) if peer.is_some_and(|p| p.ip() == from.ip()) => {
+                if reader.bytes(token.len()) != Some(token) {
+                    continue;
+                }
+
+                for _ in 0..FINAL_PUNCHES {
+                    socket.send_to(&punch, from).await?;
+                }
+                return Ok(from);
+            }
+            _ => {}
+        }
+    }
+}
+
+// The other peer's observed address and the ports after it.
+fn predicted(peer: SocketAddr) -> impl Iterator<Item = SocketAddr> {
+    (0..=PREDICTED_PORTS).filter_map(move |offset| {
+        let port = peer.port().checked_add(offset)?;
+        Some(SocketAddr::new(peer.ip(), port))
+    })
+}
+
+fn register_message(role: Role, token: &[u8]) -> Vec<u8> {
+    let mut message = MAGIC.to_vec();
+    message.push(KIND_REGISTER);
+    message.push(match role {
+        Role::Listen => 0,
+        Role::Connect => 1,
+    });
+    message.push(token.len() as u8);
+    message.extend_from_slice(token);
+    message
+}
+
+fn parse_register(buf: &[u8]) -> Option<(Role, &[u8])> {
+    let mut reader = Reader::new(buf);
+    if reader.bytes(MAGIC.len())? != &MAGIC[..] || reader.u8()? != KIND_REGISTER {
+        return None;
+    }
+
+    let role = match reader.u8()? {
+        0 => Role::Listen,
+        1 => Role::Connect,
+        _ => return None,
+    };
+    let size = reader.u8()? as usize;
+    if size == 0 || size > MAX_TOKEN_SIZE {
+        return None;
+    }
+    Some((role, reader.bytes(size)?))
+}
+
+fn peer_message(addr: SocketAddr) -> Vec<u8> {
+    let mut message = MAGIC.to_vec();
+    message.push(KIND_PEER);
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            message.push(4);
+            message.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            message.push(6);
+            message.extend_from_slice(&ip.octets());
+        }
+    }
+    message.extend_from_slice(&addr.port().to_be_bytes());
+    message
+}
+
+fn read_addr(reader: &mut Reader) -> Option<SocketAddr> {
+    let ip = match reader.u8()? {
+        4 => {
+            let mut octets = [0u8; 4];
+            octets.copy_from_slice(reader.bytes(4)?);
+            IpAddr::from(octets)
+        }
+        6 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(reader.bytes(16)?);
+            IpAddr::from(octets)
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, reader.u16()?))
+}
+
+fn punch_message(token: &[u8]) -> Vec<u8> {
+    let mut message = MAGIC.to_vec();
+    message.push(KIND_PUNCH);
+    message.extend_from_slice(token);
+    message
+}
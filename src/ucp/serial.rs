@@ -0,0 +1,12 @@
+// Serial number arithmetic (RFC 1982) for sequence numbers and
+// timestamps, which wrap around. Two numbers compare correctly while
+// they are less than 2^31 apart.
+
+// Distance from `b` to `a`, negative when `a` comes first.
+pub fn diff(a: u32, b: u32) -> i32 {
+    a.wrapping_sub(b) as i32
+}
+
+pub fn before(a: u32, b: u32) -> bool {
+    diff(a, b) < 0
+}
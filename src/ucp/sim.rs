@@ -0,0 +1,332 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::task::{Poll, Waker};
+use std::time::{Duration, Instant};
+
+use async_std::future;
+
+const FIRST_EPHEMERAL_PORT: u16 = 49152;
+
+// Impairments of a simulated network, applied to every datagram. Loss,
+// reordering and duplication are probabilities from 0 to 1.
+#[derive(Clone, Debug)]
+pub struct SimConfig {
+    pub loss: f64,
+    pub latency: Duration,
+    // Extra delay of up to this, drawn for each datagram.
+    pub jitter: Duration,
+    // Datagrams held back by another latency, so later ones overtake.
+    pub reorder: f64,
+    pub duplicate: f64,
+    // The same seed and the same sends give the same losses, delays and
+    // copies.
+    pub seed: u64,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        SimConfig {
+            loss: 0.1,
+            latency: Duration::from_millis(0),
+            jitter: Duration::from_millis(0),
+            reorder: 0.05,
+            duplicate: 0.05,
+            seed: 1,
+        }
+    }
+}
+
+// Datagrams sent and what became of them. Datagrams to an address no
+// socket is bound to count as lost.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SimStats {
+    pub sent: u64,
+    pub lost: u64,
+    pub reordered: u64,
+    pub duplicated: u64,
+    pub delivered: u64,
+}
+
+type Delivery = Reverse<(Instant, u64, SocketAddr, Vec<u8>)>;
+
+// Datagrams taken from a mailbox: size, sender and congestion mark.
+type Received = Vec<(usize, SocketAddr, bool)>;
+
+// Arrivals at a mailbox so far and when the next datagram is due.
+type Pending = (u64, Option<Instant>);
+
+#[derive(Default)]
+struct Mailbox {
+    queue: BinaryHeap<Delivery>,
+    arrivals: u64,
+    waker: Option<Waker>,
+}
+
+struct Network {
+    config: SimConfig,
+    rng: u64,
+    order: u64,
+    next_port: u16,
+    mailboxes: HashMap<SocketAddr, Mailbox>,
+    stats: SimStats,
+}
+
+impl Network {
+    // splitmix64, uniform in [0, 1).
+    fn random(&mut self) -> f64 {
+        self.rng = self.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.random() < probability
+    }
+
+    fn send(&mut self, from: SocketAddr, to: SocketAddr, data: &[u8]) {
+        self.stats.sent += 1;
+        if self.chance(self.config.loss) {
+            self.stats.lost += 1;
+            return;
+        }
+
+        let copies = if self.chance(self.config.duplicate) {
+            self.stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+
+        for _ in 0..copies {
+            let latency = self.config.latency;
+            let mut delay = latency + self.config.jitter.mul_f64(self.random());
+            if self.chance(self.config.reorder) {
+                self.stats.reordered += 1;
+                delay += latency.max(Duration::from_millis(1));
+            }
+
+            self.order += 1;
+            let delivery = Reverse((Instant::now() + delay, self.order, from, data.to_vec()));
+            match self.mailboxes.get_mut(&to) {
+                Some(mailbox) => {
+                    mailbox.queue.push(delivery);
+                    mailbox.arrivals += 1;
+                    if let Some(waker) = mailbox.waker.take() {
+                        waker.wake();
+                    }
+                }
+                None => self.stats.lost += 1,
+            }
+        }
+    }
+
+    // Datagrams due at `addr` copied to `bufs`, or when none is due yet,
+    // the arrivals so far and when the next is due.
+    fn take(&mut self, addr: SocketAddr, bufs: &mut [&mut [u8]]) -> Result<Received, Pending> {
+        let now = Instant::now();
+        let mailbox = match self.mailboxes.get_mut(&addr) {
+            Some(mailbox) => mailbox,
+            None => return Err((0, None)),
+        };
+
+        let mut received = Vec::new();
+        while received.len() < bufs.len() {
+            match mailbox.queue.peek() {
+                Some(Reverse((due, ..))) if *due <= now => {}
+                _ => break,
+            }
+
+            let Reverse((_, _, from, data)) = mailbox.queue.pop().unwrap();
+            let buf = &mut bufs[received.len()];
+            let size = data.len().min(buf.len());
+            buf[..size].copy_from_slice(&data[..size]);
+            received.push((size, from, false));
+        }
+
+        if received.is_empty() {
+            let next = mailbox.queue.peek().map(|Reverse((due, ..))| *due);
+            return Err((mailbox.arrivals, next));
+        }
+
+        self.stats.delivered += received.len() as u64;
+        Ok(received)
+    }
+}
+
+// A network in the process delivering datagrams between its sockets
+// with the impairments of its config, for reproducible tests of
+// resends, RTO and congestion control, see UcpListener::bind_simulated
+// and UcpStream::connect_simulated.
+#[derive(Clone)]
+pub struct SimNetwork {
+    network: Arc<Mutex<Network>>,
+}
+
+impl SimNetwork {
+    pub fn new(config: SimConfig) -> SimNetwork {
+        SimNetwork {
+            network: Arc::new(Mutex::new(Network {
+                rng: config.seed,
+                config,
+                order: 0,
+                next_port: FIRST_EPHEMERAL_PORT,
+                mailboxes: HashMap::new(),
+                stats: SimStats::default(),
+            })),
+        }
+    }
+
+    // Changes the impairments of datagrams sent from now on, the random
+    // sequence carries on from where it was.
+    pub fn set_config(&self, config: SimConfig) {
+        self.network.lock().unwrap().config = config;
+    }
+
+    pub fn stats(&self) -> SimStats {
+        self.network.lock().unwrap().stats
+    }
+
+    // A socket at `addr`, port 0 picks a free one.
+    pub fn bind(&self, mut addr: SocketAddr) -> io::Result<SimSocket> {
+        let mut network = self.network.lock().unwrap();
+
+        if addr.port() == 0 {
+            loop {
+                let port = network.next_port;
+                network.next_port = port.checked_add(1).unwrap_or(FIRST_EPHEMERAL_PORT);
+                addr.set_port(port);
+                if !network.mailboxes.contains_key(&addr) {
+                    break;
+                }
+            }
+        } else if network.mailboxes.contains_key(&addr) {
+            return Err(io::Error::from(io::ErrorKind::AddrInUse));
+        }
+
+        network.mailboxes.insert(addr, Mailbox::default());
+        Ok(SimSocket {
+            addr,
+            network: self.network.clone(),
+        })
+    }
+}
+
+pub struct SimSocket {
+    addr: SocketAddr,
+    network: Arc<Mutex<Network>>,
+}
+
+impl SimSocket {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn send_to(&self, buf: &[u8], addr: SocketAddr) -> usize {
+        self.network.lock().unwrap().send(self.addr, addr, buf);
+        buf.len()
+    }
+
+    // Waits for a datagram to be due, then takes as many as are due up
+    // to one per buffer. Simulated datagrams are never marked with
+    // congestion experienced.
+    pub async fn recv_batch(&self, bufs: &mut [&mut [u8]]) -> Received {
+        loop {
+            let result = self.network.lock().unwrap().take(self.addr, bufs);
+            let (arrivals, next) = match result {
+                Ok(received) => return received,
+                Err(pending) => pending,
+            };
+
+            let arrival = self.arrival(arrivals);
+            match next {
+                Some(due) => {
+                    let wait = due.saturating_duration_since(Instant::now());
+                    let _ = future::timeout(wait, arrival).await;
+                }
+                None => arrival.await,
+            }
+        }
+    }
+
+    // Until a datagram arrives after the first `seen`.
+    async fn arrival(&self, seen: u64) {
+        std::future::poll_fn(|cx| {
+            let mut network = self.network.lock().unwrap();
+            match network.mailboxes.get_mut(&self.addr) {
+                Some(mailbox) if mailbox.arrivals == seen => {
+                    mailbox.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+                _ => Poll::Ready(()),
+            }
+        })
+        .await
+    }
+}
+
+impl Drop for SimSocket {
+    fn drop(&mut self) {
+        self.network.lock().unwrap().mailboxes.remove(&self.addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::io::{ReadExt, WriteExt};
+    use async_std::task;
+    use futures::channel::oneshot;
+
+    use super::*;
+    use crate::ucp::{UcpConfig, UcpListener, UcpStream};
+
+    const SERVER_ADDR: &str = "10.0.0.1:4900";
+
+    #[test]
+    fn stream_over_lossy_network() {
+        let network = SimNetwork::new(SimConfig {
+            loss: 0.1,
+            latency: Duration::from_millis(5),
+            jitter: Duration::from_millis(5),
+            reorder: 0.05,
+            duplicate: 0.05,
+            seed: 7,
+        });
+        let data: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+
+        let received = task::block_on(future::timeout(Duration::from_secs(60), async {
+            let mut listener =
+                UcpListener::bind_simulated(&network, SERVER_ADDR, UcpConfig::default()).unwrap();
+            let client =
+                UcpStream::connect_simulated(&network, SERVER_ADDR, None, &UcpConfig::default())
+                    .await
+                    .unwrap();
+
+            // The listener takes in the packets of its sessions while
+            // accepting, so it keeps accepting once the stream is handed
+            // over.
+            let (accepted, server) = oneshot::channel();
+            task::spawn(async move {
+                let _ = accepted.send(listener.incoming().await);
+                loop {
+                    listener.incoming().await;
+                }
+            });
+
+            (&client).write_all(&data).await.unwrap();
+            let server = server.await.unwrap();
+            let mut received = vec![0; data.len()];
+            (&server).read_exact(&mut received).await.unwrap();
+            received
+        }))
+        .expect("stream stalled");
+
+        assert!(received == data);
+        assert!(network.stats().lost > 0);
+    }
+}
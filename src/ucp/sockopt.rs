@@ -0,0 +1,140 @@
+use std::io;
+#[cfg(target_os = "linux")]
+use std::net::SocketAddr;
+
+use async_std::net::UdpSocket;
+
+use super::{ecn, UcpConfig};
+
+// Applies the socket options of `config` to a stream's or listener's
+// socket. Failures are logged and the socket is used as it is.
+pub fn configure(socket: &UdpSocket, config: &UcpConfig) {
+    if config.dont_fragment {
+        set_dont_fragment(socket);
+    }
+
+    ecn::init(socket, config.ecn, config.dscp);
+
+    if config.socket_send_buffer > 0 {
+        if let Err(e) = set_buffer(socket, false, config.socket_send_buffer) {
+            error!("set ucp socket send buffer error: {}", e);
+        }
+    }
+
+    if config.socket_recv_buffer > 0 {
+        if let Err(e) = set_buffer(socket, true, config.socket_recv_buffer) {
+            error!("set ucp socket recv buffer error: {}", e);
+        }
+    }
+
+    if let Some(ref device) = config.device {
+        if let Err(e) = bind_to_device(socket, device) {
+            error!("bind ucp socket to device {} error: {}", device, e);
+        }
+    }
+}
+
+// Path MTU discovery needs datagrams the network drops instead of
+// fragmenting, Linux sets DF while ignoring its own path MTU cache.
+#[cfg(target_os = "linux")]
+fn set_dont_fragment(socket: &UdpSocket) {
+    let (level, name, value) = match socket.local_addr() {
+        Ok(SocketAddr::V6(_)) => (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_PROBE,
+        ),
+        _ => (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_PROBE,
+        ),
+    };
+
+    if let Err(e) = set_option(socket, level, name, value) {
+        error!("set ucp socket dont fragment error: {}", e);
+    }
+
+    // For the IPv4 datagrams of a dual-stack socket.
+    if level == libc::IPPROTO_IPV6 {
+        let _ = set_option(
+            socket,
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_PROBE,
+        );
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_dont_fragment(_socket: &UdpSocket) {}
+
+#[cfg(target_os = "linux")]
+fn set_buffer(socket: &UdpSocket, recv: bool, bytes: usize) -> io::Result<()> {
+    let name = if recv {
+        libc::SO_RCVBUF
+    } else {
+        libc::SO_SNDBUF
+    };
+    let bytes = bytes.min(libc::c_int::MAX as usize) as libc::c_int;
+    set_option(socket, libc::SOL_SOCKET, name, bytes)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_buffer(_socket: &UdpSocket, _recv: bool, _bytes: usize) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+// Sends and receives only through `device`, which takes CAP_NET_RAW on
+// kernels before 5.7.
+#[cfg(target_os = "linux")]
+fn bind_to_device(socket: &UdpSocket, device: &str) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            device.as_ptr() as *const libc::c_void,
+            device.len() as libc::socklen_t,
+        )
+    };
+
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_to_device(_socket: &UdpSocket, _device: &str) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+#[cfg(target_os = "linux")]
+pub fn set_option(
+    socket: &UdpSocket,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
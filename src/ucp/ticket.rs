@@ -0,0 +1,114 @@
+use crypto::util::fixed_time_eq;
+use rand::random;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+use std::vec::Vec;
+
+use super::auth::hmac;
+
+// A ticket holds the seconds since the listener started when it was
+// issued, a nonce, what the session was granted and a truncated
+// HMAC-SHA256 over them with a secret of the listener, so tickets die
+// with the process.
+pub const TICKET_SIZE: usize = 36;
+pub const TICKET_LIFETIME_SECS: u64 = 600;
+const TICKET_TAG_SIZE: usize = 16;
+const TICKET_SECRET_SIZE: usize = 32;
+const MAX_REDEEMED_TICKETS: usize = 65536;
+
+// What a session was granted, the session resuming it starts with the
+// same without waiting for the server.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Grant {
+    pub features: u32,
+    pub fec_group: u32,
+    pub server_salt: u32,
+}
+
+impl Grant {
+    pub fn read(ticket: &[u8]) -> Grant {
+        Grant {
+            features: read_u32(ticket, 8),
+            fec_group: read_u32(ticket, 12),
+            server_salt: read_u32(ticket, 16),
+        }
+    }
+}
+
+pub struct TicketBook {
+    secret: Vec<u8>,
+    start: Instant,
+    // Tags of redeemed tickets with their issue time, each ticket is
+    // taken once so a replayed SYN doesn't replay the data after it.
+    redeemed: Mutex<HashMap<Vec<u8>, u32>>,
+}
+
+impl TicketBook {
+    pub fn new() -> TicketBook {
+        TicketBook {
+            secret: (0..TICKET_SECRET_SIZE).map(|_| random::<u8>()).collect(),
+            start: Instant::now(),
+            redeemed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn issue(&self, grant: Grant) -> Vec<u8> {
+        let mut ticket = Vec::with_capacity(TICKET_SIZE);
+        ticket.extend_from_slice(&self.now().to_be_bytes());
+        ticket.extend_from_slice(&random::<u32>().to_be_bytes());
+        ticket.extend_from_slice(&grant.features.to_be_bytes());
+        ticket.extend_from_slice(&grant.fec_group.to_be_bytes());
+        ticket.extend_from_slice(&grant.server_salt.to_be_bytes());
+        let tag = self.tag(&ticket);
+        ticket.extend_from_slice(&tag);
+        ticket
+    }
+
+    // The grant of a ticket issued here, unexpired and not taken before.
+    pub fn redeem(&self, ticket: &[u8]) -> Option<Grant> {
+        if ticket.len() != TICKET_SIZE {
+            return None;
+        }
+
+        let (data, tag) = ticket.split_at(TICKET_SIZE - TICKET_TAG_SIZE);
+        if !fixed_time_eq(&self.tag(data), tag) {
+            return None;
+        }
+
+        let now = self.now();
+        let issued = read_u32(ticket, 0);
+        let expired = |issued: u32| (now.wrapping_sub(issued) as u64) > TICKET_LIFETIME_SECS;
+        if expired(issued) {
+            return None;
+        }
+
+        let mut redeemed = self.redeemed.lock().unwrap();
+        redeemed.retain(|_, issued| !expired(*issued));
+        if redeemed.len() >= MAX_REDEEMED_TICKETS || redeemed.contains_key(tag) {
+            return None;
+        }
+
+        redeemed.insert(tag.to_vec(), issued);
+        Some(Grant::read(ticket))
+    }
+
+    fn now(&self) -> u32 {
+        self.start.elapsed().as_secs() as u32
+    }
+
+    fn tag(&self, data: &[u8]) -> Vec<u8> {
+        let mut tag = hmac(&self.secret, &[data]);
+        tag.truncate(TICKET_TAG_SIZE);
+        tag
+    }
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
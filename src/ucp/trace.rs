@@ -0,0 +1,97 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, LineWriter, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Streams tracing to the same path share its file, so their lines don't
+// interleave.
+static FILES: Mutex<BTreeMap<String, Arc<FileTracer>>> = Mutex::new(BTreeMap::new());
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+// The header of a packet a stream sent or received, with the wall clock
+// time it went out or arrived. Received packets are traced once they
+// passed the frame check and decryption, `payload` is the bytes after
+// the header.
+#[derive(Clone, Debug)]
+pub struct PacketRecord {
+    pub time: SystemTime,
+    pub direction: Direction,
+    pub remote_addr: SocketAddr,
+    pub session_id: u32,
+    pub cmd: u8,
+    pub seq: u32,
+    pub una: u32,
+    pub xmit: u32,
+    pub window: u32,
+    pub timestamp: u32,
+    pub payload: u16,
+}
+
+// Gets every packet of the streams it is set on, see
+// UcpStream::set_tracer and the `trace` option. It is called with the
+// stream locked, so it should only record.
+pub trait PacketTracer: Send + Sync {
+    fn packet(&self, record: &PacketRecord);
+}
+
+impl<F: Fn(&PacketRecord) + Send + Sync> PacketTracer for F {
+    fn packet(&self, record: &PacketRecord) {
+        self(record)
+    }
+}
+
+// Appends a line per packet to a file: seconds since the epoch, sent or
+// received, the peer and the header fields.
+pub struct FileTracer {
+    file: Mutex<LineWriter<File>>,
+}
+
+impl PacketTracer for FileTracer {
+    fn packet(&self, record: &PacketRecord) {
+        let time = record.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let direction = match record.direction {
+            Direction::Sent => "sent",
+            Direction::Received => "recv",
+        };
+
+        let mut file = self.file.lock().unwrap();
+        let _ = writeln!(
+            file,
+            "{}.{:06} {} {} session={} cmd={} seq={} una={} xmit={} window={} timestamp={} payload={}",
+            time.as_secs(),
+            time.subsec_micros(),
+            direction,
+            record.remote_addr,
+            record.session_id,
+            record.cmd,
+            record.seq,
+            record.una,
+            record.xmit,
+            record.window,
+            record.timestamp,
+            record.payload
+        );
+    }
+}
+
+// The tracer appending to `path`, opened on first use.
+pub fn file_tracer(path: &str) -> io::Result<Arc<FileTracer>> {
+    let mut files = FILES.lock().unwrap();
+    if let Some(tracer) = files.get(path) {
+        return Ok(tracer.clone());
+    }
+
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let tracer = Arc::new(FileTracer {
+        file: Mutex::new(LineWriter::new(file)),
+    });
+    files.insert(path.to_string(), tracer.clone());
+    Ok(tracer)
+}
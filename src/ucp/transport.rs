@@ -0,0 +1,46 @@
+use std::io;
+use std::net::SocketAddr;
+
+use async_std::net::UdpSocket;
+
+use super::batch::{self, Datagram};
+use super::sim::SimSocket;
+
+// What a stream or listener sends and receives datagrams over: a UDP
+// socket, or a socket of a simulated network for reproducible tests.
+pub enum Transport {
+    Udp(UdpSocket),
+    Sim(SimSocket),
+}
+
+impl Transport {
+    pub async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        match self {
+            Transport::Udp(socket) => socket.send_to(buf, addr).await,
+            Transport::Sim(socket) => Ok(socket.send_to(buf, addr)),
+        }
+    }
+
+    // See batch::send_all.
+    pub async fn send_all(&self, datagrams: &[Datagram]) {
+        match self {
+            Transport::Udp(socket) => batch::send_all(socket, datagrams).await,
+            Transport::Sim(socket) => {
+                for (data, addr) in datagrams {
+                    socket.send_to(data, *addr);
+                }
+            }
+        }
+    }
+
+    // See batch::recv_batch.
+    pub async fn recv_batch(
+        &self,
+        bufs: &mut [&mut [u8]],
+    ) -> io::Result<Vec<(usize, SocketAddr, bool)>> {
+        match self {
+            Transport::Udp(socket) => batch::recv_batch(socket, bufs).await,
+            Transport::Sim(socket) => Ok(socket.recv_batch(bufs).await),
+        }
+    }
+}
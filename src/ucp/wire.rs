@@ -0,0 +1,154 @@
+// Big-endian fields of packets. Every access is checked against the end
+// of the slice, so a short or forged datagram fails a read instead of
+// reaching past its buffer, and nothing depends on how the buffer is
+// aligned.
+
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader { buf, pos: 0 }
+    }
+
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    pub fn bytes(&mut self, size: usize) -> Option<&'a [u8]> {
+        let end = self.pos.checked_add(size)?;
+        let bytes = self.buf.get(self.pos..end)?;
+        self.pos = end;
+        Some(bytes)
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    pub fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        self.bytes(4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn u64(&mut self) -> Option<u64> {
+        let b = self.bytes(8)?;
+        Some(u64::from_be_bytes([
+            b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7],
+        ]))
+    }
+}
+
+// Writes that don't fit leave the buffer as it was and return false.
+pub struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Writer<'a> {
+        Writer { buf, pos: 0 }
+    }
+
+    pub fn bytes(&mut self, data: &[u8]) -> bool {
+        let end = match self.pos.checked_add(data.len()) {
+            Some(end) if end <= self.buf.len() => end,
+            _ => return false,
+        };
+
+        self.buf[self.pos..end].copy_from_slice(data);
+        self.pos = end;
+        true
+    }
+
+    pub fn u8(&mut self, u: u8) -> bool {
+        self.bytes(&[u])
+    }
+
+    pub fn u32(&mut self, u: u32) -> bool {
+        self.bytes(&u.to_be_bytes())
+    }
+
+    pub fn u64(&mut self, u: u64) -> bool {
+        self.bytes(&u.to_be_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The fields of a header after its frame check: session id,
+    // timestamp, window, xmit, una, seq and cmd.
+    const HEADER_SIZE: usize = 25;
+
+    fn write_header(buf: &mut [u8], fields: [u32; 6], cmd: u8) -> bool {
+        let mut writer = Writer::new(buf);
+        fields.iter().all(|&field| writer.u32(field)) && writer.u8(cmd)
+    }
+
+    fn read_header(buf: &[u8]) -> Option<([u32; 6], u8)> {
+        let mut reader = Reader::new(buf);
+        let mut fields = [0u32; 6];
+        for field in fields.iter_mut() {
+            *field = reader.u32()?;
+        }
+        Some((fields, reader.u8()?))
+    }
+
+    #[test]
+    fn reader_rejects_short_input() {
+        assert_eq!(Reader::new(&[1]).u16(), None);
+        assert_eq!(Reader::new(&[1, 2, 3]).u32(), None);
+        assert_eq!(Reader::new(&[1, 2, 3, 4, 5, 6, 7]).u64(), None);
+        assert_eq!(Reader::new(&[1, 2]).bytes(3), None);
+        assert_eq!(Reader::new(&[]).u8(), None);
+    }
+
+    #[test]
+    fn failed_read_keeps_position() {
+        let mut reader = Reader::new(&[0, 1, 2, 3, 4]);
+        assert_eq!(reader.u32(), Some(0x00010203));
+        assert_eq!(reader.u16(), None);
+        assert_eq!(reader.position(), 4);
+        assert_eq!(reader.u8(), Some(4));
+        assert_eq!(reader.bytes(usize::MAX), None);
+    }
+
+    #[test]
+    fn writer_rejects_overflow() {
+        let mut buf = [0u8; 6];
+        let mut writer = Writer::new(&mut buf);
+        assert!(writer.u32(0xAABBCCDD));
+        assert!(!writer.u32(1));
+        assert!(!writer.u64(1));
+        assert!(writer.u8(0xEE));
+        assert!(writer.bytes(&[0xFF]));
+        assert!(!writer.u8(0));
+        assert_eq!(buf, [0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF]);
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let fields = [0xDEADBEEF, 1, 512, 3, u32::MAX, 0x80000000];
+        let mut buf = [0u8; HEADER_SIZE];
+        assert!(write_header(&mut buf, fields, 7));
+        assert_eq!(read_header(&buf), Some((fields, 7)));
+    }
+
+    #[test]
+    fn truncated_header() {
+        let mut buf = [0u8; HEADER_SIZE];
+        assert!(write_header(&mut buf, [1, 2, 3, 4, 5, 6], 7));
+        for size in 0..HEADER_SIZE {
+            assert_eq!(read_header(&buf[..size]), None);
+        }
+        assert!(!write_header(&mut buf[..HEADER_SIZE - 1], [0; 6], 0));
+    }
+}